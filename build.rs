@@ -0,0 +1,14 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/skanny.proto");
+        if std::env::var_os("PROTOC").is_none() {
+            std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        }
+        // Only the server is implemented here, clients use the proto file
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/skanny.proto"], &["proto"])
+            .unwrap();
+    }
+}
@@ -2,13 +2,92 @@ use std::env;
 use std::path::PathBuf;
 
 fn main() {
-    println!("cargo:rerun-if-changed=src/wrapper.h");
     println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "buildtime-bindgen")]
+    println!("cargo:rerun-if-changed=src/wrapper.h");
+    println!("cargo:rerun-if-env-changed=SANE_LIB_DIR");
+    println!("cargo:rerun-if-env-changed=SANE_INCLUDE_DIR");
+
+    #[cfg(feature = "vendored")]
+    let include_paths = build_vendored();
+    #[cfg(not(feature = "vendored"))]
+    let include_paths = if env::var_os("CARGO_FEATURE_DLOPEN").is_some() {
+        // libsane is loaded at runtime
+        env::var_os("SANE_INCLUDE_DIR")
+            .map(PathBuf::from)
+            .into_iter()
+            .collect()
+    } else {
+        find_sane()
+    };
+    for path in &include_paths {
+        println!("cargo:include={}", path.display());
+    }
+
+    #[cfg(feature = "buildtime-bindgen")]
+    generate_bindings(&include_paths);
+}
+
+#[cfg(all(feature = "vendored", feature = "dlopen"))]
+compile_error!("The vendored libsane is linked statically and can not be loaded at runtime");
+
+/// Builds and installs the backends listed in `SANE_VENDORED_BACKENDS`
+/// (default "net test") into libsane.a, links it and returns its include
+/// directory
+///
+/// The sources of a sane-backends release are expected in `sane-backends`,
+/// or in `SANE_BACKENDS_SRC`.
+#[cfg(feature = "vendored")]
+fn build_vendored() -> Vec<PathBuf> {
+    println!("cargo:rerun-if-env-changed=SANE_BACKENDS_SRC");
+    println!("cargo:rerun-if-env-changed=SANE_VENDORED_BACKENDS");
 
-    println!("cargo:rustc-link-lib=sane");
+    let source = env::var_os("SANE_BACKENDS_SRC")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("sane-backends"));
+    if !source.join("configure").is_file() {
+        panic!(
+            "\n\nNo sane-backends sources with a configure script in {}\n\n\
+             Extract a release tarball from \
+             https://gitlab.com/sane-project/backends/-/releases there, or \
+             point SANE_BACKENDS_SRC to one\n\n",
+            source.display()
+        );
+    }
+    let backends = env::var("SANE_VENDORED_BACKENDS").unwrap_or_else(|_| "net test".to_owned());
 
+    let prefix = autotools::Config::new(&source)
+        .env("BACKENDS", &backends)
+        .disable_shared()
+        .enable_static()
+        .enable("preload", None)
+        .disable("dynamic", None)
+        .disable("locking", None)
+        .disable("nls", None)
+        .without("usb", None)
+        .without("snmp", None)
+        .without("avahi", None)
+        .without("systemd", None)
+        .build();
+
+    // The pkg-config file knows the libraries the backends depend on
+    env::set_var("PKG_CONFIG_PATH", prefix.join("lib").join("pkgconfig"));
+    let library = pkg_config::Config::new()
+        .statik(true)
+        .probe("sane-backends")
+        .expect("The vendored sane-backends did not install sane-backends.pc");
+    library.include_paths
+}
+
+#[cfg(feature = "buildtime-bindgen")]
+fn generate_bindings(include_paths: &[PathBuf]) {
     let bindings = bindgen::Builder::default()
         .header("src/wrapper.h")
+        .clang_args(
+            include_paths
+                .iter()
+                .map(|path| format!("-I{}", path.display())),
+        )
         .parse_callbacks(Box::new(bindgen::CargoCallbacks))
         .generate()
         .expect("Unable to generate bindings to SANE");
@@ -18,3 +97,47 @@ fn main() {
         .write_to_file(out_path.join("bindings.rs"))
         .expect("Couldn't write SANE bindings");
 }
+
+/// Emits the linker flags for libsane and returns the directories to search
+/// for its headers
+///
+/// `SANE_LIB_DIR` and `SANE_INCLUDE_DIR` take precedence over pkg-config
+#[cfg(not(feature = "vendored"))]
+fn find_sane() -> Vec<PathBuf> {
+    let include_dir = env::var_os("SANE_INCLUDE_DIR").map(PathBuf::from);
+    if let Some(dir) = &include_dir {
+        if !dir.join("sane").join("sane.h").is_file() {
+            panic!(
+                "SANE_INCLUDE_DIR is set to {}, which does not contain sane/sane.h",
+                dir.display()
+            );
+        }
+    }
+
+    if let Some(lib_dir) = env::var_os("SANE_LIB_DIR").map(PathBuf::from) {
+        if !lib_dir.is_dir() {
+            panic!(
+                "SANE_LIB_DIR is set to {}, which is not a directory",
+                lib_dir.display()
+            );
+        }
+        println!("cargo:rustc-link-search=native={}", lib_dir.display());
+        println!("cargo:rustc-link-lib=sane");
+        return include_dir.into_iter().collect();
+    }
+
+    match pkg_config::Config::new().probe("sane-backends") {
+        Ok(library) => include_dir
+            .into_iter()
+            .chain(library.include_paths)
+            .collect(),
+        Err(e) => panic!(
+            "\n\nCould not find the SANE library: {}\n\n\
+             Install the development files of sane-backends (libsane-dev on \
+             Debian and Ubuntu, sane-backends-devel on Fedora), or point \
+             SANE_LIB_DIR and SANE_INCLUDE_DIR to the directories containing \
+             libsane and sane/sane.h\n\n",
+            e
+        ),
+    }
+}
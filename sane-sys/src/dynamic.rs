@@ -0,0 +1,117 @@
+//! Resolves the SANE functions when first used instead of linking libsane,
+//! so programs start without SANE installed
+//!
+//! Call [`load`] before any other function to find out whether libsane is
+//! available, the functions panic if it is not.
+
+use std::sync::OnceLock;
+
+use libloading::Library;
+
+use crate::bindings::*;
+
+#[cfg(target_os = "macos")]
+const LIBRARY_NAMES: &[&str] = &["libsane.1.dylib", "libsane.dylib"];
+#[cfg(not(target_os = "macos"))]
+const LIBRARY_NAMES: &[&str] = &["libsane.so.1", "libsane.so"];
+
+macro_rules! functions {
+    ($(fn $name:ident($($arg:ident: $ty:ty),*) $(-> $ret:ty)?;)*) => {
+        struct Functions {
+            $($name: unsafe extern "C" fn($($ty),*) $(-> $ret)?,)*
+        }
+
+        impl Functions {
+            unsafe fn resolve(library: &Library) -> Result<Self, libloading::Error> {
+                Ok(Self {
+                    $($name: *library.get(concat!(stringify!($name), "\0").as_bytes())?,)*
+                })
+            }
+        }
+
+        $(
+            /// # Safety
+            /// As for the function in libsane
+            pub unsafe fn $name($($arg: $ty),*) $(-> $ret)? {
+                (functions().$name)($($arg),*)
+            }
+        )*
+    };
+}
+
+functions! {
+    fn sane_init(version_code: *mut SANE_Int, authorize: SANE_Auth_Callback) -> SANE_Status;
+    fn sane_exit();
+    fn sane_get_devices(
+        device_list: *mut *mut *const SANE_Device,
+        local_only: SANE_Bool
+    ) -> SANE_Status;
+    fn sane_open(devicename: SANE_String_Const, handle: *mut SANE_Handle) -> SANE_Status;
+    fn sane_close(handle: SANE_Handle);
+    fn sane_get_option_descriptor(
+        handle: SANE_Handle,
+        option: SANE_Int
+    ) -> *const SANE_Option_Descriptor;
+    fn sane_control_option(
+        handle: SANE_Handle,
+        option: SANE_Int,
+        action: SANE_Action,
+        value: *mut ::std::os::raw::c_void,
+        info: *mut SANE_Int
+    ) -> SANE_Status;
+    fn sane_get_parameters(handle: SANE_Handle, params: *mut SANE_Parameters) -> SANE_Status;
+    fn sane_start(handle: SANE_Handle) -> SANE_Status;
+    fn sane_read(
+        handle: SANE_Handle,
+        data: *mut SANE_Byte,
+        max_length: SANE_Int,
+        length: *mut SANE_Int
+    ) -> SANE_Status;
+    fn sane_cancel(handle: SANE_Handle);
+    fn sane_set_io_mode(handle: SANE_Handle, non_blocking: SANE_Bool) -> SANE_Status;
+    fn sane_get_select_fd(handle: SANE_Handle, fd: *mut SANE_Int) -> SANE_Status;
+    fn sane_strstatus(status: SANE_Status) -> SANE_String_Const;
+}
+
+struct Loaded {
+    functions: Functions,
+    // Keeps the functions valid
+    _library: Library,
+}
+
+static LOADED: OnceLock<Result<Loaded, libloading::Error>> = OnceLock::new();
+
+/// Loads libsane if that was not done yet
+pub fn load() -> Result<(), &'static libloading::Error> {
+    loaded().as_ref().map(|_| ())
+}
+
+fn loaded() -> &'static Result<Loaded, libloading::Error> {
+    LOADED.get_or_init(|| {
+        let mut first_error = None;
+        for name in LIBRARY_NAMES {
+            let loaded = unsafe {
+                Library::new(name).and_then(|library| {
+                    Ok(Loaded {
+                        functions: Functions::resolve(&library)?,
+                        _library: library,
+                    })
+                })
+            };
+            match loaded {
+                Ok(loaded) => return Ok(loaded),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        Err(first_error.unwrap())
+    })
+}
+
+fn functions() -> &'static Functions {
+    match loaded() {
+        Ok(loaded) => &loaded.functions,
+        Err(e) => panic!("libsane could not be loaded: {}", e),
+    }
+}
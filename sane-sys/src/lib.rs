@@ -2,7 +2,30 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
-include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+// The linked functions are unused when loading libsane at runtime
+#[cfg_attr(feature = "dlopen", allow(dead_code))]
+mod bindings {
+    // The SANE 1.0 ABI is stable, regenerating is only needed for checking
+    // src/bindings.rs against a new sane.h
+    #[cfg(feature = "buildtime-bindgen")]
+    include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+    #[cfg(not(feature = "buildtime-bindgen"))]
+    include!("bindings.rs");
+}
+pub use bindings::*;
+
+#[cfg(feature = "dlopen")]
+mod dynamic;
+// Shadow the linked functions of the bindings
+#[cfg(feature = "dlopen")]
+pub use dynamic::{
+    load, sane_cancel, sane_close, sane_control_option, sane_exit, sane_get_devices,
+    sane_get_option_descriptor, sane_get_parameters, sane_get_select_fd, sane_init, sane_open,
+    sane_read, sane_set_io_mode, sane_start, sane_strstatus,
+};
+
+mod types;
+pub use types::{Fixed, Status, Version};
 
 pub const fn SANE_VERSION_MAJOR(code: SANE_Int) -> SANE_Word {
     (code >> 24) as SANE_Word & 0xff
@@ -31,9 +54,26 @@ mod tests {
     #[test]
     fn smokescreen() {
         use std::ptr::null_mut;
+        #[cfg(feature = "dlopen")]
+        load().unwrap();
         let status = unsafe { sane_init(null_mut(), None) };
         assert_eq!(status, SANE_Status_SANE_STATUS_GOOD);
 
         unsafe { sane_exit() }
     }
+
+    #[test]
+    fn conversions() {
+        assert_eq!(f64::from(Fixed::from(25.4)), 25.399993896484375);
+        assert_eq!(Fixed::from(-1.5), Fixed(-0x18000));
+
+        let version = Version::from_code(0x0100_0020);
+        assert_eq!(version.to_string(), "1.0.32");
+        assert_eq!(version.code(), 0x0100_0020);
+
+        for raw in 0..12 {
+            assert_eq!(Status::from_raw(raw).unwrap().to_raw(), raw);
+        }
+        assert_eq!(Status::from_raw(12), None);
+    }
 }
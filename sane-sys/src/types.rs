@@ -0,0 +1,103 @@
+//! Safe wrappers of the plain integers SANE passes around
+
+use crate::*;
+
+/// The status codes of SANE 1.0
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Status {
+    Good,
+    Unsupported,
+    Cancelled,
+    DeviceBusy,
+    Inval,
+    Eof,
+    Jammed,
+    NoDocs,
+    CoverOpen,
+    IoError,
+    NoMem,
+    AccessDenied,
+}
+
+impl Status {
+    /// Returns `None` for codes not known to SANE 1.0
+    pub fn from_raw(status: SANE_Status) -> Option<Self> {
+        Some(match status {
+            SANE_Status_SANE_STATUS_GOOD => Status::Good,
+            SANE_Status_SANE_STATUS_UNSUPPORTED => Status::Unsupported,
+            SANE_Status_SANE_STATUS_CANCELLED => Status::Cancelled,
+            SANE_Status_SANE_STATUS_DEVICE_BUSY => Status::DeviceBusy,
+            SANE_Status_SANE_STATUS_INVAL => Status::Inval,
+            SANE_Status_SANE_STATUS_EOF => Status::Eof,
+            SANE_Status_SANE_STATUS_JAMMED => Status::Jammed,
+            SANE_Status_SANE_STATUS_NO_DOCS => Status::NoDocs,
+            SANE_Status_SANE_STATUS_COVER_OPEN => Status::CoverOpen,
+            SANE_Status_SANE_STATUS_IO_ERROR => Status::IoError,
+            SANE_Status_SANE_STATUS_NO_MEM => Status::NoMem,
+            SANE_Status_SANE_STATUS_ACCESS_DENIED => Status::AccessDenied,
+            _ => return None,
+        })
+    }
+
+    pub fn to_raw(self) -> SANE_Status {
+        match self {
+            Status::Good => SANE_Status_SANE_STATUS_GOOD,
+            Status::Unsupported => SANE_Status_SANE_STATUS_UNSUPPORTED,
+            Status::Cancelled => SANE_Status_SANE_STATUS_CANCELLED,
+            Status::DeviceBusy => SANE_Status_SANE_STATUS_DEVICE_BUSY,
+            Status::Inval => SANE_Status_SANE_STATUS_INVAL,
+            Status::Eof => SANE_Status_SANE_STATUS_EOF,
+            Status::Jammed => SANE_Status_SANE_STATUS_JAMMED,
+            Status::NoDocs => SANE_Status_SANE_STATUS_NO_DOCS,
+            Status::CoverOpen => SANE_Status_SANE_STATUS_COVER_OPEN,
+            Status::IoError => SANE_Status_SANE_STATUS_IO_ERROR,
+            Status::NoMem => SANE_Status_SANE_STATUS_NO_MEM,
+            Status::AccessDenied => SANE_Status_SANE_STATUS_ACCESS_DENIED,
+        }
+    }
+}
+
+/// A fixed point number with 16 fractional bits
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(transparent)]
+pub struct Fixed(pub SANE_Fixed);
+
+impl From<f64> for Fixed {
+    fn from(v: f64) -> Self {
+        Fixed(SANE_FIX(v))
+    }
+}
+
+impl From<Fixed> for f64 {
+    fn from(v: Fixed) -> Self {
+        SANE_UNFIX(v.0)
+    }
+}
+
+/// A version code split into its parts
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: SANE_Word,
+    pub minor: SANE_Word,
+    pub build: SANE_Word,
+}
+
+impl Version {
+    pub const fn from_code(code: SANE_Int) -> Self {
+        Self {
+            major: SANE_VERSION_MAJOR(code),
+            minor: SANE_VERSION_MINOR(code),
+            build: SANE_VERSION_BUILD(code),
+        }
+    }
+
+    pub const fn code(self) -> SANE_Int {
+        ((self.major & 0xff) << 24) | ((self.minor & 0xff) << 16) | (self.build & 0xffff)
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.build)
+    }
+}
@@ -0,0 +1,84 @@
+/* C API of skanny, see skanny-c/src/lib.rs */
+
+#ifndef SKANNY_H
+#define SKANNY_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Functions returning int return SKANNY_OK, a SANE_Status for the errors
+ * reported by SANE, or one of the negative codes below */
+#define SKANNY_OK 0
+/* A pointer was null, a string not UTF-8, or an option unknown */
+#define SKANNY_ERROR_ARGUMENT -1
+/* The value does not suit the type of the option */
+#define SKANNY_ERROR_TYPE -2
+#define SKANNY_ERROR_NOT_INSTALLED -3
+#define SKANNY_ERROR_TIMED_OUT -4
+#define SKANNY_ERROR_OTHER -5
+
+typedef struct SkannyContext SkannyContext;
+typedef struct SkannyHandle SkannyHandle;
+
+typedef struct {
+    const char *name;
+    const char *vendor;
+    const char *model;
+    const char *type_;
+} SkannyDevice;
+
+/* The samples of a page, rows after each other without padding */
+typedef struct {
+    uint32_t width;
+    uint32_t height;
+    /* 1 for gray, 3 for RGB */
+    uint32_t channels;
+    /* Bits per sample: 1 for line art packed eight pixels to a byte, most
+     * significant bit first and rows padded to whole bytes, 8, or 16 in
+     * the byte order of the host */
+    uint32_t depth;
+    const uint8_t *data;
+    size_t len;
+} SkannyImage;
+
+typedef void (*SkannyDeviceCallback)(const SkannyDevice *device, void *user);
+typedef void (*SkannyOptionCallback)(const char *name, const char *title, const char *value,
+                                     void *user);
+typedef void (*SkannyImageCallback)(const SkannyImage *image, void *user);
+
+/* Describes the last error on this thread, valid until the next call */
+const char *skanny_last_error(void);
+
+int skanny_init(SkannyContext **context);
+/* Frees the context, the handles opened with it stay open */
+void skanny_exit(SkannyContext *context);
+/* Calls callback with every device, the strings valid during the call */
+int skanny_devices(const SkannyContext *context, int local_only, SkannyDeviceCallback callback,
+                   void *user);
+
+/* Opens the device of the name given, the first one if it is empty */
+int skanny_open(const SkannyContext *context, const char *name, SkannyHandle **handle);
+void skanny_close(SkannyHandle *handle);
+/* Calls callback with every option and its value, the strings valid during
+ * the call. The value is null for options without one. */
+int skanny_options(const SkannyHandle *handle, SkannyOptionCallback callback, void *user);
+/* Sets an option from a value written as on the command line of skanny,
+ * such as "300", "Color" or "yes" */
+int skanny_set_option(const SkannyHandle *handle, const char *name, const char *value);
+
+/* Scans a page and calls callback with it, the samples valid during the
+ * call. Returns SANE_STATUS_NO_DOCS once the feeder is empty. */
+int skanny_scan(const SkannyHandle *handle, SkannyImageCallback callback, void *user);
+/* Scans a page to a file, in the format its extension names, such as
+ * .png, .jpg or .tiff */
+int skanny_scan_to_file(const SkannyHandle *handle, const char *path);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
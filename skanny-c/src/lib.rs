@@ -0,0 +1,352 @@
+//! C API of skanny
+//!
+//! Builds `libskanny_c` as a shared and a static library for programs in
+//! other languages, with the declarations in `include/skanny.h`. It covers
+//! what most programs need of the safe wrapper: listing the devices,
+//! opening one, setting its options and scanning pages to a callback or to
+//! a file.
+//!
+//! Functions returning `int` return `SKANNY_OK`, a `SANE_Status` for the
+//! errors reported by SANE, or one of the negative `SKANNY_ERROR_` codes.
+//! `skanny_last_error` describes the last error on the calling thread.
+//! Panics are caught at the boundary and reported as `SKANNY_ERROR_OTHER`.
+
+// Every function checks its pointers for null, beyond that the callers
+// keep to the contract in the header
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use skanny::{Context, Handle, Image};
+
+pub const SKANNY_OK: c_int = 0;
+/// A pointer was null, a string not UTF-8, or an option unknown
+pub const SKANNY_ERROR_ARGUMENT: c_int = -1;
+/// The value does not suit the type of the option
+pub const SKANNY_ERROR_TYPE: c_int = -2;
+pub const SKANNY_ERROR_NOT_INSTALLED: c_int = -3;
+pub const SKANNY_ERROR_TIMED_OUT: c_int = -4;
+pub const SKANNY_ERROR_OTHER: c_int = -5;
+
+pub struct SkannyContext(Context);
+pub struct SkannyHandle(Handle);
+
+#[repr(C)]
+pub struct SkannyDevice {
+    pub name: *const c_char,
+    pub vendor: *const c_char,
+    pub model: *const c_char,
+    pub type_: *const c_char,
+}
+
+/// The samples of a page, rows after each other without padding
+#[repr(C)]
+pub struct SkannyImage {
+    pub width: u32,
+    pub height: u32,
+    /// 1 for gray, 3 for RGB
+    pub channels: u32,
+    /// Bits per sample: 1 for line art packed eight pixels to a byte, most
+    /// significant bit first and rows padded to whole bytes, 8, or 16 in
+    /// the byte order of the host
+    pub depth: u32,
+    pub data: *const u8,
+    pub len: usize,
+}
+
+pub type SkannyDeviceCallback = extern "C" fn(device: *const SkannyDevice, user: *mut c_void);
+pub type SkannyOptionCallback = extern "C" fn(
+    name: *const c_char,
+    title: *const c_char,
+    value: *const c_char,
+    user: *mut c_void,
+);
+pub type SkannyImageCallback = extern "C" fn(image: *const SkannyImage, user: *mut c_void);
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Why a call failed, as a code and a message
+struct Failure(c_int, String);
+
+impl From<skanny::Error> for Failure {
+    fn from(e: skanny::Error) -> Self {
+        let code = match e {
+            skanny::Error::Status(status) => status as c_int,
+            skanny::Error::WrongType => SKANNY_ERROR_TYPE,
+            skanny::Error::NotInstalled => SKANNY_ERROR_NOT_INSTALLED,
+            skanny::Error::TimedOut => SKANNY_ERROR_TIMED_OUT,
+        };
+        Failure(code, e.to_string())
+    }
+}
+
+fn argument(message: &str) -> Failure {
+    Failure(SKANNY_ERROR_ARGUMENT, message.to_owned())
+}
+
+/// Runs `f`, keeping the message of its failure for `skanny_last_error`
+fn call(f: impl FnOnce() -> Result<(), Failure>) -> c_int {
+    let Failure(code, message) = match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return SKANNY_OK,
+        Ok(Err(failure)) => failure,
+        Err(_) => Failure(SKANNY_ERROR_OTHER, "skanny panicked".to_owned()),
+    };
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    code
+}
+
+unsafe fn string<'a>(s: *const c_char, what: &str) -> Result<&'a str, Failure> {
+    if s.is_null() {
+        return Err(argument(&format!("The {} is null", what)));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| argument(&format!("The {} is not UTF-8", what)))
+}
+
+fn c_string(s: &str) -> CString {
+    CString::new(s.replace('\0', " ")).unwrap_or_default()
+}
+
+/// Describes the last error on this thread, valid until the next call
+#[no_mangle]
+pub extern "C" fn skanny_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn skanny_init(context: *mut *mut SkannyContext) -> c_int {
+    call(|| {
+        if context.is_null() {
+            return Err(argument("The context is null"));
+        }
+        let (sane, _version) = Context::init()?;
+        *context = Box::into_raw(Box::new(SkannyContext(sane)));
+        Ok(())
+    })
+}
+
+/// Frees the context, the handles opened with it stay open
+#[no_mangle]
+pub unsafe extern "C" fn skanny_exit(context: *mut SkannyContext) {
+    if !context.is_null() {
+        drop(Box::from_raw(context));
+    }
+}
+
+/// Calls `callback` with every device, the strings valid during the call
+#[no_mangle]
+pub unsafe extern "C" fn skanny_devices(
+    context: *const SkannyContext,
+    local_only: c_int,
+    callback: Option<SkannyDeviceCallback>,
+    user: *mut c_void,
+) -> c_int {
+    call(|| {
+        let context = context
+            .as_ref()
+            .ok_or_else(|| argument("The context is null"))?;
+        let callback = callback.ok_or_else(|| argument("The callback is null"))?;
+        for device in context.0.devices(local_only != 0)? {
+            let strings = [
+                device.name(),
+                device.vendor(),
+                device.model(),
+                device.type_(),
+            ]
+            .map(c_string);
+            let device = SkannyDevice {
+                name: strings[0].as_ptr(),
+                vendor: strings[1].as_ptr(),
+                model: strings[2].as_ptr(),
+                type_: strings[3].as_ptr(),
+            };
+            callback(&device, user);
+        }
+        Ok(())
+    })
+}
+
+/// Opens the device of the name given, the first one if it is empty
+#[no_mangle]
+pub unsafe extern "C" fn skanny_open(
+    context: *const SkannyContext,
+    name: *const c_char,
+    handle: *mut *mut SkannyHandle,
+) -> c_int {
+    call(|| {
+        let context = context
+            .as_ref()
+            .ok_or_else(|| argument("The context is null"))?;
+        let name = string(name, "device name")?;
+        if handle.is_null() {
+            return Err(argument("The handle is null"));
+        }
+        let opened = if name.is_empty() {
+            context
+                .0
+                .devices(false)?
+                .next()
+                .ok_or_else(|| Failure(SKANNY_ERROR_OTHER, "No devices found".to_owned()))?
+                .open()?
+        } else {
+            Handle::from_name(name)?
+        };
+        *handle = Box::into_raw(Box::new(SkannyHandle(opened)));
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn skanny_close(handle: *mut SkannyHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Calls `callback` with every option and its value, the strings valid
+/// during the call. The value is null for options without one.
+#[no_mangle]
+pub unsafe extern "C" fn skanny_options(
+    handle: *const SkannyHandle,
+    callback: Option<SkannyOptionCallback>,
+    user: *mut c_void,
+) -> c_int {
+    call(|| {
+        let handle = handle
+            .as_ref()
+            .ok_or_else(|| argument("The handle is null"))?;
+        let callback = callback.ok_or_else(|| argument("The callback is null"))?;
+        for opt in handle.0.options().filter(|opt| !opt.name().is_empty()) {
+            let value = opt
+                .get_value()
+                .ok()
+                .map(|value| c_string(&value.to_string()));
+            callback(
+                c_string(opt.name()).as_ptr(),
+                c_string(opt.title()).as_ptr(),
+                value
+                    .as_ref()
+                    .map_or(std::ptr::null(), |value| value.as_ptr()),
+                user,
+            );
+        }
+        Ok(())
+    })
+}
+
+/// Sets an option from a value written as on the command line of skanny,
+/// such as `300`, `Color` or `yes`
+#[no_mangle]
+pub unsafe extern "C" fn skanny_set_option(
+    handle: *const SkannyHandle,
+    name: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    call(|| {
+        let handle = handle
+            .as_ref()
+            .ok_or_else(|| argument("The handle is null"))?;
+        let name = string(name, "option name")?;
+        let value = string(value, "value")?;
+        let opt = handle
+            .0
+            .option(name)
+            .ok_or_else(|| argument(&format!("No option named {}", name)))?;
+        opt.set_value(&opt.descriptor().parse_value(value)?)?;
+        Ok(())
+    })
+}
+
+/// Scans a page and calls `callback` with it, the samples valid during the
+/// call. Returns `SANE_STATUS_NO_DOCS` once the feeder is empty.
+#[no_mangle]
+pub unsafe extern "C" fn skanny_scan(
+    handle: *const SkannyHandle,
+    callback: Option<SkannyImageCallback>,
+    user: *mut c_void,
+) -> c_int {
+    call(|| {
+        let handle = handle
+            .as_ref()
+            .ok_or_else(|| argument("The handle is null"))?;
+        let callback = callback.ok_or_else(|| argument("The callback is null"))?;
+        let image = handle.0.start()?.get_image()?;
+        let (channels, depth) = match &image {
+            Image::Rgb8(_) => (3, 8),
+            Image::Gray8(_) => (1, 8),
+            Image::Rgb16(_) => (3, 16),
+            Image::Gray16(_) => (1, 16),
+            Image::Bilevel(_) => (1, 1),
+        };
+        let data = image.as_bytes();
+        callback(
+            &SkannyImage {
+                width: image.width(),
+                height: image.height(),
+                channels,
+                depth,
+                data: data.as_ptr(),
+                len: data.len(),
+            },
+            user,
+        );
+        Ok(())
+    })
+}
+
+/// Scans a page to a file, in the format its extension names, such as
+/// `.png`, `.jpg` or `.tiff`
+#[no_mangle]
+pub unsafe extern "C" fn skanny_scan_to_file(
+    handle: *const SkannyHandle,
+    path: *const c_char,
+) -> c_int {
+    call(|| {
+        let handle = handle
+            .as_ref()
+            .ok_or_else(|| argument("The handle is null"))?;
+        let path = string(path, "path")?;
+        let image = handle.0.start()?.get_image()?;
+        image
+            .save(path)
+            .map_err(|e| Failure(SKANNY_ERROR_OTHER, format!("Saving {} failed: {}", path, e)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_errors_through_the_thread() {
+        let last = || {
+            unsafe { CStr::from_ptr(skanny_last_error()) }
+                .to_str()
+                .unwrap()
+        };
+        assert_eq!(last(), "");
+        let mut handle = std::ptr::null_mut();
+        let name = CString::new("test:0").unwrap();
+        let code = unsafe { skanny_open(std::ptr::null(), name.as_ptr(), &mut handle) };
+        assert_eq!(code, SKANNY_ERROR_ARGUMENT);
+        assert_eq!(last(), "The context is null");
+        assert!(handle.is_null());
+
+        let failure = Failure::from(skanny::Error::Status(7));
+        assert_eq!(failure.0, 7);
+        assert_eq!(Failure::from(skanny::Error::WrongType).0, SKANNY_ERROR_TYPE);
+        assert_eq!(call(|| panic!("in the library")), SKANNY_ERROR_OTHER);
+        assert_eq!(last(), "skanny panicked");
+        unsafe {
+            skanny_close(std::ptr::null_mut());
+            skanny_exit(std::ptr::null_mut());
+        }
+    }
+}
@@ -0,0 +1,392 @@
+//! Python bindings of skanny
+//!
+//! Built with maturin into the `skanny` module, see `pyproject.toml`:
+//!
+//! ```python
+//! import numpy, skanny
+//!
+//! context = skanny.Context()
+//! device = context.devices()[0]
+//! handle = device.open()
+//! handle["resolution"] = 300
+//! page = numpy.asarray(handle.scan())
+//! for page in handle.scan_job():
+//!     page.save(f"page-{page.width}.png")
+//! ```
+//!
+//! Scans carry the `__array_interface__` of numpy, so they become arrays of
+//! shape `(height, width)` or `(height, width, 3)` without the bindings
+//! depending on numpy. The samples are copied into `bytes` whenever the
+//! interface is read, so `numpy.asarray` gives a read-only array, and
+//! `numpy.array` a writable one. Line art comes as 0 and 255. SANE is called without holding the GIL, so other Python
+//! threads run while a page is scanned. Handles may only be used from the
+//! thread which opened them.
+
+// The methods of pyo3 convert their errors into PyErr, even when they are
+#![allow(clippy::useless_conversion)]
+
+use std::borrow::Cow;
+
+use pyo3::exceptions::{PyKeyError, PyStopIteration, PyTypeError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyBytes, PyDict};
+use skanny::{Context, Device, Handle, Image, OptionValue};
+
+// The macro checks a feature of pyo3 which this crate does not have
+#[allow(unexpected_cfgs)]
+mod exceptions {
+    pyo3::create_exception!(
+        skanny,
+        SaneError,
+        pyo3::exceptions::PyException,
+        "An error reported by SANE"
+    );
+}
+
+use exceptions::SaneError;
+
+fn error(e: skanny::Error) -> PyErr {
+    SaneError::new_err(e.to_string())
+}
+
+/// Lets a handle be used without the GIL. Handles are not `Sync`, but the
+/// Python classes holding them are unsendable, so they are only used on the
+/// thread which opened them, which is also the one running the closure.
+struct OnThisThread<'a>(&'a Handle);
+
+unsafe impl Send for OnThisThread<'_> {}
+
+impl<'a> OnThisThread<'a> {
+    fn get(self) -> &'a Handle {
+        self.0
+    }
+}
+
+fn without_gil<T: Send>(py: Python<'_>, handle: &Handle, f: impl FnOnce(&Handle) -> T + Send) -> T {
+    let handle = OnThisThread(handle);
+    py.allow_threads(move || f(handle.get()))
+}
+
+fn to_python(py: Python<'_>, value: OptionValue) -> PyObject {
+    match value {
+        OptionValue::Bool(b) => b.into_py(py),
+        OptionValue::Int(i) => i.into_py(py),
+        OptionValue::Fixed(x) => x.into_py(py),
+        OptionValue::String(s) => s.into_py(py),
+    }
+}
+
+fn from_python(value: &Bound<'_, PyAny>) -> PyResult<OptionValue> {
+    // bool is a subclass of int
+    if let Ok(b) = value.downcast::<PyBool>() {
+        return Ok(OptionValue::Bool(b.is_true()));
+    }
+    if let Ok(i) = value.extract() {
+        return Ok(OptionValue::Int(i));
+    }
+    if let Ok(x) = value.extract() {
+        return Ok(OptionValue::Fixed(x));
+    }
+    if let Ok(s) = value.extract() {
+        return Ok(OptionValue::String(s));
+    }
+    Err(PyTypeError::new_err("Expected a bool, int, float or str"))
+}
+
+/// Keeps SANE initialised
+#[pyclass(name = "Context", module = "skanny")]
+struct PyContext(Context);
+
+#[pymethods]
+impl PyContext {
+    #[new]
+    fn new() -> PyResult<Self> {
+        let (context, _version) = Context::init().map_err(error)?;
+        Ok(Self(context))
+    }
+
+    /// The devices SANE finds, which may take a while for network scanners
+    #[pyo3(signature = (local_only = false))]
+    fn devices(&self, py: Python<'_>, local_only: bool) -> PyResult<Vec<PyDevice>> {
+        let context = self.0.clone();
+        let devices = py
+            .allow_threads(move || context.devices(local_only).map(Iterator::collect::<Vec<_>>))
+            .map_err(error)?;
+        Ok(devices.into_iter().map(PyDevice).collect())
+    }
+
+    /// Opens the device with the name given
+    fn open(&self, py: Python<'_>, name: &str) -> PyResult<PyHandle> {
+        let handle = py
+            .allow_threads(|| Handle::from_name(name))
+            .map_err(error)?;
+        Ok(PyHandle(handle))
+    }
+}
+
+#[pyclass(name = "Device", module = "skanny", frozen)]
+struct PyDevice(Device);
+
+#[pymethods]
+impl PyDevice {
+    #[getter]
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    #[getter]
+    fn vendor(&self) -> &str {
+        self.0.vendor()
+    }
+
+    #[getter]
+    fn model(&self) -> &str {
+        self.0.model()
+    }
+
+    #[getter(r#type)]
+    fn type_(&self) -> &str {
+        self.0.type_()
+    }
+
+    fn open(&self, py: Python<'_>) -> PyResult<PyHandle> {
+        let handle = py.allow_threads(|| self.0.open()).map_err(error)?;
+        Ok(PyHandle(handle))
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "<Device {} ({} {})>",
+            self.0.name(),
+            self.0.vendor(),
+            self.0.model()
+        )
+    }
+}
+
+/// An open device, its options read and set as `handle["resolution"]`
+#[pyclass(name = "Handle", module = "skanny", unsendable)]
+struct PyHandle(Handle);
+
+#[pymethods]
+impl PyHandle {
+    #[getter]
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    /// The options with their values, `None` for those without one
+    fn options<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let options = PyDict::new_bound(py);
+        for opt in self.0.options().filter(|opt| !opt.name().is_empty()) {
+            let value = opt.get_value().ok().map(|value| to_python(py, value));
+            options.set_item(opt.name(), value)?;
+        }
+        Ok(options)
+    }
+
+    fn __getitem__(&self, py: Python<'_>, name: &str) -> PyResult<PyObject> {
+        let opt = self
+            .0
+            .option(name)
+            .ok_or_else(|| PyKeyError::new_err(name.to_owned()))?;
+        opt.get_value()
+            .map(|value| to_python(py, value))
+            .map_err(error)
+    }
+
+    fn __setitem__(&self, name: &str, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        let parsed = from_python(value)?;
+        without_gil(value.py(), &self.0, |handle| {
+            handle.option(name).map(|opt| opt.set_value(&parsed))
+        })
+        .ok_or_else(|| PyKeyError::new_err(name.to_owned()))?
+        .map_err(error)
+    }
+
+    /// Scans a page
+    fn scan(&self, py: Python<'_>) -> PyResult<PyScan> {
+        let image = without_gil(py, &self.0, |handle| {
+            handle.start().and_then(|scan| scan.get_image())
+        });
+        Ok(PyScan(image.map_err(error)?))
+    }
+
+    /// Scans the pages in the feeder one by one
+    fn scan_job(slf: Py<Self>) -> PyScanJob {
+        PyScanJob {
+            handle: slf,
+            pages: 0,
+        }
+    }
+}
+
+/// Iterates over the pages in the feeder, until it is empty
+#[pyclass(name = "ScanJob", module = "skanny", unsendable)]
+struct PyScanJob {
+    handle: Py<PyHandle>,
+    pages: usize,
+}
+
+#[pymethods]
+impl PyScanJob {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<PyScan> {
+        let handle = self.handle.borrow(py);
+        let image = without_gil(py, &handle.0, |handle| {
+            handle.start().and_then(|scan| scan.get_image())
+        });
+        match image {
+            Ok(image) => {
+                self.pages += 1;
+                Ok(PyScan(image))
+            }
+            // An empty feeder is only an error before the first page
+            Err(e) if e.is_no_docs() && self.pages > 0 => Err(PyStopIteration::new_err(())),
+            Err(e) => Err(error(e)),
+        }
+    }
+
+    /// Pages scanned so far
+    #[getter]
+    fn pages(&self) -> usize {
+        self.pages
+    }
+}
+
+/// A scanned page
+#[pyclass(name = "Scan", module = "skanny", frozen)]
+struct PyScan(Image);
+
+impl PyScan {
+    /// The image with one byte or more per sample
+    fn samples(&self) -> Cow<'_, Image> {
+        match &self.0 {
+            Image::Bilevel(_) => Cow::Owned(self.0.clone().into_8bit()),
+            image => Cow::Borrowed(image),
+        }
+    }
+}
+
+#[pymethods]
+impl PyScan {
+    #[getter]
+    fn width(&self) -> u32 {
+        self.0.width()
+    }
+
+    #[getter]
+    fn height(&self) -> u32 {
+        self.0.height()
+    }
+
+    /// 1 for gray, 3 for RGB
+    #[getter]
+    fn channels(&self) -> u32 {
+        match self.0 {
+            Image::Rgb8(_) | Image::Rgb16(_) => 3,
+            _ => 1,
+        }
+    }
+
+    /// Bits per sample, 1 for line art
+    #[getter]
+    fn depth(&self) -> u32 {
+        match self.0 {
+            Image::Bilevel(_) => 1,
+            Image::Rgb8(_) | Image::Gray8(_) => 8,
+            Image::Rgb16(_) | Image::Gray16(_) => 16,
+        }
+    }
+
+    /// The samples as scanned, 16 bit ones in the byte order of the host
+    /// and line art packed eight pixels to a byte
+    #[getter]
+    fn data<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, self.0.as_bytes())
+    }
+
+    #[getter]
+    fn __array_interface__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let image = self.samples();
+        let (height, width) = (image.height() as usize, image.width() as usize);
+        let shape: PyObject = if self.channels() == 3 {
+            (height, width, 3).into_py(py)
+        } else {
+            (height, width).into_py(py)
+        };
+        let typestr = match (self.depth(), cfg!(target_endian = "little")) {
+            (16, true) => "<u2",
+            (16, false) => ">u2",
+            _ => "|u1",
+        };
+        let interface = PyDict::new_bound(py);
+        interface.set_item("version", 3)?;
+        interface.set_item("shape", shape)?;
+        interface.set_item("typestr", typestr)?;
+        interface.set_item("data", PyBytes::new_bound(py, image.as_bytes()))?;
+        Ok(interface)
+    }
+
+    /// Saves the page in the format the extension of `path` names
+    fn save(&self, py: Python<'_>, path: std::path::PathBuf) -> PyResult<()> {
+        let image = &self.0;
+        py.allow_threads(|| image.save(&path))
+            .map_err(|e| SaneError::new_err(format!("Saving {} failed: {}", path.display(), e)))
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "<Scan {}x{}, {} channels of {} bits>",
+            self.width(),
+            self.height(),
+            self.channels(),
+            self.depth()
+        )
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "skanny")]
+fn init(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyContext>()?;
+    m.add_class::<PyDevice>()?;
+    m.add_class::<PyHandle>()?;
+    m.add_class::<PyScanJob>()?;
+    m.add_class::<PyScan>()?;
+    m.add("SaneError", m.py().get_type_bound::<SaneError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposes_scans_as_arrays() {
+        Python::with_gil(|py| {
+            let image = Image::Gray16(image::ImageBuffer::new(3, 2));
+            let scan = Bound::new(py, PyScan(image)).unwrap();
+            let interface = scan.getattr("__array_interface__").unwrap();
+            let shape: (usize, usize) = interface.get_item("shape").unwrap().extract().unwrap();
+            assert_eq!(shape, (2, 3));
+            let data: Vec<u8> = interface.get_item("data").unwrap().extract().unwrap();
+            assert_eq!(data.len(), 12);
+            assert_eq!(scan.getattr("depth").unwrap().extract::<u32>().unwrap(), 16);
+
+            for value in [
+                OptionValue::Bool(true),
+                OptionValue::Int(300),
+                OptionValue::Fixed(2.5),
+                OptionValue::String("Color".to_owned()),
+            ] {
+                let object = to_python(py, value.clone());
+                assert_eq!(from_python(object.bind(py)).unwrap(), value);
+            }
+            assert!(from_python(&py.None().into_bound(py)).is_err());
+        });
+    }
+}
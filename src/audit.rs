@@ -0,0 +1,145 @@
+//! Audit log of the scans of the services
+//!
+//! Offices which have to account for what was digitized can give the
+//! services `--audit FILE`. Every job which ends, done or not, adds a line
+//! of JSON to the file:
+//!
+//! ```json
+//! {"time":"2024-01-31T12:00:00Z","service":"server","client":"office","device":"pixma","profile":"letter","state":"done","error":null,"pages":1,"outputs":["scans/2024-01-31_12-00-00.png","s3://archive/2024-01-31_12-00-00.png"]}
+//! ```
+//!
+//! The client is the user or token name given with `--auth`, the address
+//! of the client otherwise, or the user id of a client of the daemon. The
+//! outputs are the files stored and where they were uploaded, or `client`
+//! for pages which were only sent to the client, as by eSCL and saned.
+//!
+//! The file is only ever appended to, a line with a single write which is
+//! synced to the disk before the job is reported as done.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::Serialize;
+
+use crate::jobs::{Job, JobObserver, JobState};
+use crate::template::DateTime;
+
+/// A job which ended
+#[derive(Debug, Serialize)]
+pub struct Record<'a> {
+    pub client: &'a str,
+    pub device: &'a str,
+    pub profile: &'a str,
+    pub state: JobState,
+    pub error: Option<&'a str>,
+    pub pages: usize,
+    pub outputs: Vec<String>,
+}
+
+pub struct Audit {
+    file: Mutex<File>,
+    /// Which service logs
+    service: &'static str,
+}
+
+impl Audit {
+    pub fn open(path: &Path, service: &'static str) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            service,
+        })
+    }
+
+    pub fn record(&self, record: &Record) {
+        #[derive(Serialize)]
+        struct Line<'a> {
+            time: String,
+            service: &'static str,
+            #[serde(flatten)]
+            record: &'a Record<'a>,
+        }
+
+        let mut line = serde_json::to_string(&Line {
+            time: DateTime::now().to_string(),
+            service: self.service,
+            record,
+        })
+        .unwrap();
+        line.push('\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file
+            .write_all(line.as_bytes())
+            .and_then(|()| file.sync_data())
+        {
+            tracing::error!("Writing the audit log failed: {}", e);
+        }
+    }
+}
+
+/// Logs the jobs of a [`crate::jobs::JobQueue`] as they end
+impl JobObserver for Audit {
+    fn changed(&self, job: &Job) {
+        if !matches!(
+            job.state,
+            JobState::Done | JobState::Failed | JobState::Skipped
+        ) {
+            return;
+        }
+        let outputs = job
+            .files
+            .iter()
+            .map(|path| path.display().to_string())
+            .chain(job.locations.iter().cloned())
+            .collect();
+        self.record(&Record {
+            client: job.client.as_deref().unwrap_or("unknown"),
+            device: &job.device,
+            profile: &job.profile,
+            state: job.state,
+            error: job.error.as_deref(),
+            pages: job.files.len(),
+            outputs,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_a_line_per_finished_job() {
+        let path = std::env::temp_dir().join(format!("skanny-audit-{}.log", std::process::id()));
+        std::fs::write(&path, "earlier\n").unwrap();
+        let audit = Audit::open(&path, "daemon").unwrap();
+        let mut job = Job {
+            id: 0,
+            device: "mock:demo".to_owned(),
+            profile: "letter".to_owned(),
+            options: Default::default(),
+            client: Some("uid 1000".to_owned()),
+            state: JobState::Scanning,
+            error: None,
+            files: vec!["scan.png".into()],
+            locations: vec!["s3://archive/scan.png".to_owned()],
+        };
+        audit.changed(&job);
+        job.state = JobState::Done;
+        audit.changed(&job);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "earlier");
+        let line: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(line["service"], "daemon");
+        assert_eq!(line["client"], "uid 1000");
+        assert_eq!(line["state"], "done");
+        assert_eq!(line["pages"], 1);
+        assert_eq!(line["outputs"][1], "s3://archive/scan.png");
+    }
+}
@@ -0,0 +1,397 @@
+//! Abstraction over the ways of talking to scanners
+//!
+//! A [`ScannerBackend`] enumerates and opens devices, which are then driven
+//! through [`ScannerDevice`]. The SANE library is a backend through
+//! [`Context`] and [`Handle`], the native saned client through
+//! [`crate::net::NetBackend`].
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use sane_sys::*;
+
+use crate::frame::Frame;
+#[cfg(feature = "image")]
+use crate::Image;
+use crate::{Context, Handle, OptionValue};
+
+pub type BackendError = Box<dyn std::error::Error>;
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub vendor: String,
+    pub model: String,
+    pub type_: String,
+}
+
+impl DeviceInfo {
+    /// The backend of the device, see [`backend_of`]
+    pub fn backend(&self) -> &'static str {
+        backend_of(&self.name)
+    }
+}
+
+/// The backend of a device named `name`, which `--device` takes as it is
+///
+/// Devices of backends other than SANE are named with the backend and a
+/// colon in front, such as `escl:http://192.168.1.20:80/eSCL` or
+/// `mock:demo`. SANE names its devices with the SANE backend in front,
+/// such as `pixma:04A9176D`, which is told apart by not being one of these.
+pub fn backend_of(name: &str) -> &'static str {
+    const BACKENDS: [&str; 6] = ["escl", "wsd", "mock", "net", "wia", "replay"];
+    let prefix = name.split(':').next().unwrap_or_default();
+    BACKENDS
+        .iter()
+        .find(|&&backend| backend == prefix && name.len() > prefix.len())
+        .copied()
+        .unwrap_or("sane")
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum Constraint {
+    None,
+    Range {
+        min: SANE_Word,
+        max: SANE_Word,
+        quant: SANE_Word,
+    },
+    WordList(Vec<SANE_Word>),
+    StringList(Vec<String>),
+}
+
+/// Description and current value of an option
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct OptionInfo {
+    pub name: String,
+    /// What user interfaces show, the name if the device gives no title
+    #[serde(default)]
+    pub title: String,
+    pub desc: String,
+    pub type_: SANE_Value_Type,
+    pub unit: SANE_Unit,
+    pub cap: SANE_Int,
+    pub constraint: Constraint,
+    /// `None` for options without a value, such as buttons and groups
+    pub value: Option<OptionValue>,
+}
+
+/// Layout of the samples of a frame
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+pub struct FrameParameters {
+    pub format: SANE_Frame,
+    pub last_frame: bool,
+    pub bytes_per_line: SANE_Int,
+    pub pixels_per_line: SANE_Int,
+    /// -1 if unknown until the frame has been read
+    pub lines: SANE_Int,
+    pub depth: SANE_Int,
+}
+
+pub trait ScannerBackend {
+    fn enumerate(&self) -> Result<Vec<DeviceInfo>, BackendError>;
+    fn open(&self, name: &str) -> Result<Box<dyn ScannerDevice>, BackendError>;
+}
+
+pub trait ScannerDevice {
+    fn name(&self) -> &str;
+    /// Options in the order the device lists them
+    fn options(&self) -> Result<Vec<OptionInfo>, BackendError>;
+    fn set_option(&self, name: &str, value: &OptionValue) -> Result<(), BackendError>;
+    /// Starts acquiring a frame
+    fn start(&self) -> Result<FrameParameters, BackendError>;
+    /// Reads the next chunk of the frame, returning 0 at its end
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, BackendError>;
+    /// Ends the acquisition, also when the frame was read completely
+    fn cancel(&self);
+
+    /// Acquires a single frame
+    fn scan_frame(&self) -> Result<Frame, BackendError> {
+        acquire(self)
+    }
+
+    /// Acquires a single frame as an image
+    #[cfg(feature = "image")]
+    fn scan(&self) -> Result<Image, BackendError> {
+        Ok(self
+            .scan_frame()?
+            .into_image()
+            .ok_or("The frame is no image")?)
+    }
+}
+
+fn acquire<D: ScannerDevice + ?Sized>(device: &D) -> Result<Frame, BackendError> {
+    let parameters = device.start()?;
+    tracing::debug!(?parameters, "Started");
+    let mut data = Vec::new();
+    let mut buffer = vec![0; 64 * 1024];
+    let result = loop {
+        match device.read(&mut buffer) {
+            Ok(0) => break Ok(()),
+            Ok(n) => data.extend_from_slice(&buffer[..n]),
+            Err(e) => break Err(e),
+        }
+    };
+    device.cancel();
+    if let Err(error) = result {
+        return Err(match salvage(&parameters, data, &error) {
+            Some(frame) => Box::new(PartialFrame { frame, error }),
+            None => error,
+        });
+    }
+    tracing::debug!(bytes = data.len(), "Read the frame");
+
+    if !parameters.last_frame {
+        return Err("Multi-pass frames are not supported".into());
+    }
+    Ok(Frame::decode(&parameters, data)?)
+}
+
+/// The lines of a frame which were read before reading failed
+///
+/// Devices return it when reading fails with an I/O error or is cancelled
+/// after whole lines were read, so the start of a long scan is not lost.
+#[derive(Debug)]
+pub struct PartialFrame {
+    pub frame: Frame,
+    /// Why reading failed
+    pub error: BackendError,
+}
+
+impl std::fmt::Display for PartialFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} after {} lines", self.error, self.frame.height())
+    }
+}
+
+impl std::error::Error for PartialFrame {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.error)
+    }
+}
+
+/// The whole lines of a frame whose reading failed with `error`, if it is
+/// worth keeping
+fn salvage(parameters: &FrameParameters, data: Vec<u8>, error: &BackendError) -> Option<Frame> {
+    #[allow(non_upper_case_globals)]
+    let failed = |e: &crate::Error| {
+        matches!(
+            e,
+            crate::Error::Status(
+                SANE_Status_SANE_STATUS_IO_ERROR | SANE_Status_SANE_STATUS_CANCELLED
+            )
+        )
+    };
+    let salvageable = if let Some(e) = error.downcast_ref::<crate::Error>() {
+        failed(e)
+    } else if let Some(e) = error.downcast_ref::<crate::net::Error>() {
+        match e {
+            crate::net::Error::Io(_) => true,
+            crate::net::Error::Sane(e) => failed(e),
+            _ => false,
+        }
+    } else {
+        error.is::<std::io::Error>()
+    };
+    if !salvageable || !parameters.last_frame {
+        return None;
+    }
+    Frame::new(parameters, data).filter(|frame| frame.height() > 0)
+}
+
+/// Cancels the page being read by a [`Cancellable`] device, from any thread
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the page being read, the pages after it are read as usual
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A device whose pages may be cancelled one at a time by a [`CancelToken`]
+///
+/// Reading a cancelled page fails with `SANE_STATUS_CANCELLED`, as when the
+/// device cancels it, so the lines read are kept as a [`PartialFrame`].
+/// Starting a page clears the token, so a page cannot be cancelled before it
+/// was started.
+pub struct Cancellable<'a> {
+    inner: &'a dyn ScannerDevice,
+    token: CancelToken,
+}
+
+impl<'a> Cancellable<'a> {
+    pub fn new(inner: &'a dyn ScannerDevice, token: CancelToken) -> Self {
+        Self { inner, token }
+    }
+}
+
+impl ScannerDevice for Cancellable<'_> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn options(&self) -> Result<Vec<OptionInfo>, BackendError> {
+        self.inner.options()
+    }
+
+    fn set_option(&self, name: &str, value: &OptionValue) -> Result<(), BackendError> {
+        self.inner.set_option(name, value)
+    }
+
+    fn start(&self) -> Result<FrameParameters, BackendError> {
+        self.token.0.store(false, Ordering::SeqCst);
+        self.inner.start()
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, BackendError> {
+        if self.token.0.swap(false, Ordering::SeqCst) {
+            return Err(crate::Error::Status(SANE_Status_SANE_STATUS_CANCELLED).into());
+        }
+        self.inner.read(buffer)
+    }
+
+    fn cancel(&self) {
+        self.inner.cancel()
+    }
+}
+
+/// Whether a scan failed because its page was cancelled, keeping the lines
+/// read or not
+pub fn is_cancelled(error: &BackendError) -> bool {
+    let error = match error.downcast_ref::<PartialFrame>() {
+        Some(partial) => &partial.error,
+        None => error,
+    };
+    matches!(
+        error.downcast_ref::<crate::Error>(),
+        Some(&crate::Error::Status(status)) if status == SANE_Status_SANE_STATUS_CANCELLED
+    )
+}
+
+/// Builds an image from the samples of a complete frame, dropping any
+/// padding at the end of the lines
+#[cfg(feature = "image")]
+pub fn image_from_frame(parameters: &FrameParameters, data: Vec<u8>) -> Option<Image> {
+    Frame::decode(parameters, data).ok()?.into_image()
+}
+
+impl ScannerBackend for Context {
+    fn enumerate(&self) -> Result<Vec<DeviceInfo>, BackendError> {
+        Ok(self
+            .devices(false)?
+            .map(|device| DeviceInfo {
+                name: device.name().to_owned(),
+                vendor: device.vendor().to_owned(),
+                model: device.model().to_owned(),
+                type_: device.type_().to_owned(),
+            })
+            .collect())
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn ScannerDevice>, BackendError> {
+        Ok(Box::new(Handle::from_name(name)?))
+    }
+}
+
+impl ScannerDevice for Handle {
+    fn name(&self) -> &str {
+        Handle::name(self)
+    }
+
+    fn options(&self) -> Result<Vec<OptionInfo>, BackendError> {
+        Ok(Handle::options(self)
+            .map(|opt| {
+                let descriptor = opt.descriptor();
+                #[allow(non_upper_case_globals)]
+                let constraint = match descriptor.constraint_type() {
+                    SANE_Constraint_Type_SANE_CONSTRAINT_RANGE => match opt.get_range() {
+                        Ok(range) => Constraint::Range {
+                            min: range.min(),
+                            max: range.max(),
+                            quant: range.quant(),
+                        },
+                        Err(_) => Constraint::None,
+                    },
+                    SANE_Constraint_Type_SANE_CONSTRAINT_WORD_LIST => opt
+                        .int_constraints()
+                        .map(|list| Constraint::WordList(list.to_vec()))
+                        .unwrap_or(Constraint::None),
+                    SANE_Constraint_Type_SANE_CONSTRAINT_STRING_LIST => opt
+                        .string_constraints()
+                        .map(|list| Constraint::StringList(list.map(str::to_owned).collect()))
+                        .unwrap_or(Constraint::None),
+                    _ => Constraint::None,
+                };
+                OptionInfo {
+                    name: opt.name().to_owned(),
+                    title: opt.title().to_owned(),
+                    desc: opt.desc().to_owned(),
+                    type_: descriptor.type_(),
+                    unit: descriptor.unit(),
+                    cap: descriptor.cap(),
+                    constraint,
+                    // Arrays such as gamma tables have no single value
+                    value: descriptor
+                        .is_single()
+                        .then(|| opt.get_value().ok())
+                        .flatten(),
+                }
+            })
+            .collect())
+    }
+
+    fn set_option(&self, name: &str, value: &OptionValue) -> Result<(), BackendError> {
+        let opt = self
+            .option(name)
+            .ok_or_else(|| format!("No option named {}", name))?;
+        Ok(opt.set_value(value)?)
+    }
+
+    fn start(&self) -> Result<FrameParameters, BackendError> {
+        self.start_raw()?;
+        let parameters = self.parameters()?;
+        Ok(FrameParameters {
+            format: parameters.format(),
+            last_frame: parameters.last_frame() != 0,
+            bytes_per_line: parameters.bytes_per_line(),
+            pixels_per_line: parameters.pixels_per_line(),
+            lines: parameters.lines(),
+            depth: parameters.depth(),
+        })
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, BackendError> {
+        Ok(self.read_raw(buffer)?)
+    }
+
+    fn cancel(&self) {
+        self.cancel_raw()
+    }
+
+    fn scan_frame(&self) -> Result<Frame, BackendError> {
+        let _page = tracing::info_span!("page").entered();
+        let mut retries = self.page_retries;
+        loop {
+            match acquire(self) {
+                Err(e) if retries > 0 && is_timeout(&e) => {
+                    tracing::warn!("Timed out, scanning the page again");
+                    retries -= 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn is_timeout(e: &BackendError) -> bool {
+    matches!(
+        e.downcast_ref::<crate::Error>(),
+        Some(crate::Error::TimedOut)
+    )
+}
@@ -0,0 +1,168 @@
+//! Throughput measurements across resolutions and modes
+//!
+//! Each combination is scanned `--repeat` times. The timings are split into
+//! starting the acquisition, reading the frame, encoding it as PNG and
+//! saving the file, so slow backends can be told apart from slow encoding.
+//! Throughput is taken over the read phase only.
+
+use std::time::{Duration, Instant};
+
+use gumdrop::Options;
+use skanny::backend::{image_from_frame, ScannerDevice};
+use skanny::OptionValue;
+
+#[derive(Debug, Options)]
+pub struct BenchOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(
+        help = "Resolutions to scan at, 75, 150 and 300 if not given",
+        meta = "DPI"
+    )]
+    resolution: Vec<i32>,
+    #[options(help = "Modes to scan in, Gray and Color if not given")]
+    mode: Vec<String>,
+    #[options(help = "Scans of each combination", default = "1")]
+    repeat: usize,
+    #[options(
+        no_short,
+        help = "Size of the read buffer",
+        meta = "BYTES",
+        default = "65536"
+    )]
+    buffer: usize,
+    #[options(help = "Keep the scanned images in this directory")]
+    dir: Option<String>,
+}
+
+/// Timings of a single scan
+struct Run {
+    bytes: usize,
+    lines: usize,
+    start: Duration,
+    read: Duration,
+    encode: Duration,
+    save: Duration,
+}
+
+pub fn run(
+    device: &dyn ScannerDevice,
+    opts: &BenchOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let resolutions = match &opts.resolution[..] {
+        [] => &[75, 150, 300][..],
+        resolutions => resolutions,
+    };
+    let default_modes = ["Gray".to_owned(), "Color".to_owned()];
+    let modes = match &opts.mode[..] {
+        [] => &default_modes[..],
+        modes => modes,
+    };
+    if let Some(dir) = &opts.dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut buffer = vec![0; opts.buffer.max(1)];
+
+    println!(
+        "{:>6} {:<8} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8} {:>9} {:>9}",
+        "dpi", "mode", "bytes", "start", "read", "encode", "save", "MB/s", "lines/s", "peak MiB"
+    );
+    for &resolution in resolutions {
+        for mode in modes {
+            let set = device
+                .set_option("resolution", &OptionValue::Int(resolution))
+                .and_then(|()| device.set_option("mode", &OptionValue::String(mode.clone())));
+            if let Err(e) = set {
+                eprintln!("Skipping {} dpi {}: {}", resolution, mode, e);
+                continue;
+            }
+            for _ in 0..opts.repeat {
+                let run = scan(device, opts, &mut buffer)?;
+                let read = run.read.as_secs_f64();
+                println!(
+                    "{:>6} {:<8} {:>10} {:>8.3} {:>8.3} {:>8.3} {:>8.3} {:>8.2} {:>9.0} {:>9}",
+                    resolution,
+                    mode,
+                    run.bytes,
+                    run.start.as_secs_f64(),
+                    read,
+                    run.encode.as_secs_f64(),
+                    run.save.as_secs_f64(),
+                    run.bytes as f64 / 1e6 / read,
+                    run.lines as f64 / read,
+                    peak_memory().map_or_else(|| "-".to_owned(), |kib| (kib / 1024).to_string()),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn scan(
+    device: &dyn ScannerDevice,
+    opts: &BenchOptions,
+    buffer: &mut [u8],
+) -> Result<Run, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    let parameters = device.start()?;
+    let start = started.elapsed();
+
+    let started = Instant::now();
+    let mut data = Vec::new();
+    let result = loop {
+        match device.read(buffer) {
+            Ok(0) => break Ok(()),
+            Ok(n) => data.extend_from_slice(&buffer[..n]),
+            Err(e) => break Err(e),
+        }
+    };
+    device.cancel();
+    result?;
+    let read = started.elapsed();
+
+    let bytes = data.len();
+    let lines = match parameters.bytes_per_line {
+        0 => 0,
+        bytes_per_line => bytes / bytes_per_line as usize,
+    };
+    let started = Instant::now();
+    let image = image_from_frame(&parameters, data).ok_or_else(|| {
+        format!(
+            "Unsupported frame format {} with depth {}",
+            parameters.format, parameters.depth
+        )
+    })?;
+    let mut png = Vec::new();
+    image.write_to(&mut png, image::ImageOutputFormat::Png)?;
+    let encode = started.elapsed();
+
+    let path = match &opts.dir {
+        Some(dir) => crate::timestamped_path(dir.as_ref()),
+        None => std::env::temp_dir().join(format!("skanny-bench-{}.png", std::process::id())),
+    };
+    let started = Instant::now();
+    std::fs::write(&path, &png)?;
+    let save = started.elapsed();
+    if opts.dir.is_none() {
+        let _ = std::fs::remove_file(&path);
+    }
+
+    Ok(Run {
+        bytes,
+        lines,
+        start,
+        read,
+        encode,
+        save,
+    })
+}
+
+/// Largest resident set size of the process so far in KiB, where the
+/// system reports it
+fn peak_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
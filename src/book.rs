@@ -0,0 +1,366 @@
+//! Processing of books scanned on the flatbed
+//!
+//! A spread of two pages has the binding in the middle, which shows up as a
+//! dark shadow. The spread is split at the darkest column near the middle,
+//! and each page is straightened by finding the rotation at which its lines
+//! of text line up with the rows of pixels best.
+//!
+//! Near the spine the page bends away from the glass, so the lines of text
+//! curve. Dewarping cuts the page into narrow strips and finds how far the
+//! lines in each strip are shifted up or down from those in its neighbour,
+//! then moves the pixels back by the shift of their strip.
+
+use std::convert::TryFrom;
+
+use image::{GrayImage, ImageBuffer, Pixel};
+use skanny::Image;
+
+/// Pages are assumed to be straight within this many degrees
+const MAX_SKEW: f64 = 5.0;
+const SKEW_STEP: f64 = 0.2;
+/// The page is dewarped in this many strips across and bands down
+const STRIPS: u32 = 24;
+const BANDS: u32 = 4;
+
+/// Splits a two-page spread at the gutter into the left and right page
+pub fn split_spread(image: &Image) -> (Image, Image) {
+    let gutter = gutter(&image.to_dynamic().to_luma8());
+    match image {
+        Image::Gray8(image) => {
+            let (left, right) = split(image, gutter);
+            (Image::Gray8(left), Image::Gray8(right))
+        }
+        Image::Rgb8(image) => {
+            let (left, right) = split(image, gutter);
+            (Image::Rgb8(left), Image::Rgb8(right))
+        }
+        // Books are processed at 8 bits
+        image => split_spread(&image.clone().into_8bit()),
+    }
+}
+
+fn split<P>(
+    image: &ImageBuffer<P, Vec<u8>>,
+    at: u32,
+) -> (ImageBuffer<P, Vec<u8>>, ImageBuffer<P, Vec<u8>>)
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let (width, height) = image.dimensions();
+    (
+        image::imageops::crop_imm(image, 0, 0, at, height).to_image(),
+        image::imageops::crop_imm(image, at, 0, width - at, height).to_image(),
+    )
+}
+
+/// Column of the binding, searched in the middle fifth of the spread
+fn gutter(gray: &GrayImage) -> u32 {
+    let (width, height) = gray.dimensions();
+    if width < 10 || height == 0 {
+        return width / 2;
+    }
+    let brightness: Vec<u64> = (0..width)
+        .map(|x| {
+            (0..height)
+                .map(|y| u64::from(gray.get_pixel(x, y)[0]))
+                .sum()
+        })
+        .collect();
+    // Smoothed over a few columns, so a single line of text does not count
+    let radius = (width / 200).max(1) as usize;
+    let smoothed = |x: usize| -> u64 {
+        let range = x.saturating_sub(radius)..(x + radius + 1).min(brightness.len());
+        let len = range.len() as u64;
+        brightness[range].iter().sum::<u64>() / len
+    };
+    let (start, end) = (width as usize * 2 / 5, width as usize * 3 / 5);
+    let darkest = match (start..end).map(smoothed).min() {
+        Some(darkest) => darkest,
+        None => return width / 2,
+    };
+    // The middle of the darkest stretch, as the shadow is often wide
+    let first = (start..end)
+        .find(|&x| smoothed(x) == darkest)
+        .unwrap_or(start);
+    let last = (first..end)
+        .take_while(|&x| smoothed(x) == darkest)
+        .last()
+        .unwrap_or(first);
+    ((first + last) / 2) as u32
+}
+
+/// Rotates a page so its lines of text are horizontal
+pub fn deskew(image: &Image) -> Image {
+    let angle = skew(&image.to_dynamic().to_luma8());
+    if angle == 0.0 {
+        return image.clone();
+    }
+    match image {
+        Image::Gray8(image) => Image::Gray8(rotate(image, -angle)),
+        Image::Rgb8(image) => Image::Rgb8(rotate(image, -angle)),
+        image => deskew(&image.clone().into_8bit()),
+    }
+}
+
+/// Angle in degrees by which the text on the page is rotated clockwise
+fn skew(gray: &GrayImage) -> f64 {
+    // A few hundred pixels across are plenty to find the lines
+    let scale = (400.0 / f64::from(gray.width().max(1))).min(1.0);
+    let (width, height) = (
+        ((f64::from(gray.width()) * scale) as u32).max(1),
+        ((f64::from(gray.height()) * scale) as u32).max(1),
+    );
+    let small = image::imageops::resize(gray, width, height, image::imageops::FilterType::Triangle);
+    let dark: Vec<(f64, f64)> = small
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel[0] < 128)
+        .map(|(x, y, _)| (f64::from(x), f64::from(y)))
+        .collect();
+    if dark.is_empty() {
+        return 0.0;
+    }
+
+    // Text lines give the sharpest profile of rows when they are level
+    let score = |angle: f64| -> u64 {
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let mut rows = vec![0_u64; (width + height) as usize * 2];
+        let last = rows.len() - 1;
+        for &(x, y) in &dark {
+            let row = y * cos - x * sin + f64::from(width);
+            rows[(row.max(0.0) as usize).min(last)] += 1;
+        }
+        rows.iter().map(|n| n * n).sum()
+    };
+    let steps = (MAX_SKEW / SKEW_STEP) as i32;
+    let best = (-steps..=steps)
+        .map(|step| f64::from(step) * SKEW_STEP)
+        .max_by_key(|&angle| score(angle))
+        .unwrap_or(0.0);
+    // Ties go to the level page
+    if score(best) > score(0.0) {
+        best
+    } else {
+        0.0
+    }
+}
+
+/// Rotates clockwise by `angle` degrees around the centre, filling the
+/// corners with white
+pub fn rotate<P>(image: &ImageBuffer<P, Vec<u8>>, angle: f64) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let (width, height) = image.dimensions();
+    let (sin, cos) = angle.to_radians().sin_cos();
+    let (cx, cy) = (f64::from(width) / 2.0, f64::from(height) / 2.0);
+    let channels = P::CHANNEL_COUNT as usize;
+    let samples = image.as_raw();
+    let mut rotated = ImageBuffer::<P, Vec<u8>>::new(width, height);
+    for (x, y, pixel) in rotated.enumerate_pixels_mut() {
+        // Where the pixel comes from, by the inverse rotation
+        let (dx, dy) = (f64::from(x) + 0.5 - cx, f64::from(y) + 0.5 - cy);
+        let sx = dx * cos + dy * sin + cx - 0.5;
+        let sy = -dx * sin + dy * cos + cy - 0.5;
+        let out = pixel.channels_mut();
+        if sx < 0.0 || sy < 0.0 || sx > f64::from(width - 1) || sy > f64::from(height - 1) {
+            out.iter_mut().for_each(|s| *s = u8::MAX);
+            continue;
+        }
+        // Bilinear interpolation between the four neighbours
+        let (x0, y0) = (sx.floor() as u32, sy.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+        let (fx, fy) = (sx - f64::from(x0), sy - f64::from(y0));
+        let at = |x: u32, y: u32, c: usize| {
+            f64::from(samples[(y as usize * width as usize + x as usize) * channels + c])
+        };
+        for (c, sample) in out.iter_mut().enumerate() {
+            let top = at(x0, y0, c) * (1.0 - fx) + at(x1, y0, c) * fx;
+            let bottom = at(x0, y1, c) * (1.0 - fx) + at(x1, y1, c) * fx;
+            *sample = (top * (1.0 - fy) + bottom * fy).round() as u8;
+        }
+    }
+    rotated
+}
+
+/// Straightens lines of text which curve towards the spine
+pub fn dewarp(image: &Image) -> Image {
+    let shifts = shifts(&image.to_dynamic().to_luma8());
+    match image {
+        Image::Gray8(image) => Image::Gray8(unshift(image, &shifts)),
+        Image::Rgb8(image) => Image::Rgb8(unshift(image, &shifts)),
+        image => dewarp(&image.clone().into_8bit()),
+    }
+}
+
+/// Vertical shift of the lines in each band and strip, relative to the
+/// strip in the middle
+fn shifts(gray: &GrayImage) -> Vec<Vec<f64>> {
+    let (width, height) = gray.dimensions();
+    let strip = (width / STRIPS).max(1);
+    let band = (height / BANDS).max(1);
+    // Neighbouring strips are never far apart, except in the very bend
+    let step = (band / 8).max(2) as i32;
+    let dark = |x: u32, y: u32| u64::from(gray.get_pixel(x, y)[0] < 128);
+    (0..BANDS)
+        .map(|b| {
+            let rows = b * band..((b + 1) * band).min(height);
+            // Dark pixels per row of each strip
+            let profiles: Vec<Vec<u64>> = (0..STRIPS)
+                .map(|s| {
+                    let columns = s * strip..((s + 1) * strip).min(width);
+                    (0..height)
+                        .map(|y| columns.clone().map(|x| dark(x, y)).sum())
+                        .collect()
+                })
+                .collect();
+            let offset = |from: &[u64], to: &[u64]| -> i32 {
+                if from[rows.start as usize..rows.end as usize]
+                    .iter()
+                    .all(|&n| n == 0)
+                {
+                    return 0;
+                }
+                let score = |k: i32| -> u64 {
+                    rows.clone()
+                        .filter_map(|y| {
+                            let shifted = y as i32 + k;
+                            let shifted = to.get(usize::try_from(shifted).ok()?)?;
+                            Some(from[y as usize] * shifted)
+                        })
+                        .sum()
+                };
+                let best = (-step..=step)
+                    .max_by_key(|&k| (score(k), -k.abs()))
+                    .unwrap_or(0);
+                if score(best) > score(0) {
+                    best
+                } else {
+                    0
+                }
+            };
+            // Chained outwards from the middle, strip by strip
+            let middle = STRIPS as usize / 2;
+            let mut shifts = vec![0.0; STRIPS as usize];
+            for s in (0..middle).rev() {
+                shifts[s] = shifts[s + 1] + f64::from(offset(&profiles[s + 1], &profiles[s]));
+            }
+            for s in middle + 1..STRIPS as usize {
+                shifts[s] = shifts[s - 1] + f64::from(offset(&profiles[s - 1], &profiles[s]));
+            }
+            shifts
+        })
+        .collect()
+}
+
+/// Moves every pixel up by the shift at its position, interpolated between
+/// the centres of the strips and bands
+fn unshift<P>(image: &ImageBuffer<P, Vec<u8>>, shifts: &[Vec<f64>]) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let (width, height) = image.dimensions();
+    let strip = f64::from((width / STRIPS).max(1));
+    let band = f64::from((height / BANDS).max(1));
+    // Index and weight of the cells on either side of a position
+    let between = |position: f64, size: f64, cells: usize| {
+        let cell = (position / size - 0.5).max(0.0);
+        let first = (cell.floor() as usize).min(cells - 1);
+        let second = (first + 1).min(cells - 1);
+        (first, second, (cell - first as f64).min(1.0))
+    };
+    let channels = P::CHANNEL_COUNT as usize;
+    let samples = image.as_raw();
+    let mut unshifted = ImageBuffer::<P, Vec<u8>>::new(width, height);
+    for (x, y, pixel) in unshifted.enumerate_pixels_mut() {
+        let (s0, s1, fs) = between(f64::from(x), strip, shifts[0].len());
+        let (b0, b1, fb) = between(f64::from(y), band, shifts.len());
+        let across = |b: usize| shifts[b][s0] * (1.0 - fs) + shifts[b][s1] * fs;
+        let shift = across(b0) * (1.0 - fb) + across(b1) * fb;
+
+        let sy = f64::from(y) + shift;
+        let out = pixel.channels_mut();
+        if sy < 0.0 || sy > f64::from(height - 1) {
+            out.iter_mut().for_each(|s| *s = u8::MAX);
+            continue;
+        }
+        let y0 = sy.floor() as u32;
+        let y1 = (y0 + 1).min(height - 1);
+        let fy = sy - f64::from(y0);
+        let at = |y: u32, c: usize| {
+            f64::from(samples[(y as usize * width as usize + x as usize) * channels + c])
+        };
+        for (c, sample) in out.iter_mut().enumerate() {
+            *sample = (at(y0, c) * (1.0 - fy) + at(y1, c) * fy).round() as u8;
+        }
+    }
+    unshifted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lines of text on white paper, with a shadow at `gutter`
+    fn spread(gutter: u32) -> GrayImage {
+        ImageBuffer::from_fn(400, 300, |x, y| {
+            let shadow = (x as i32 - gutter as i32).abs() < 6;
+            let text = y % 20 < 4 && x % 200 > 20 && x % 200 < 180;
+            image::Luma([if shadow || text { 30 } else { 240 }])
+        })
+    }
+
+    #[test]
+    fn splits_at_the_shadow() {
+        let (left, right) = split_spread(&Image::Gray8(spread(190)));
+        assert_eq!(left.to_dynamic().to_luma8().width(), 190);
+        assert_eq!(right.to_dynamic().to_luma8().width(), 210);
+    }
+
+    /// How sharply the rows of text stand out
+    fn sharpness(gray: &GrayImage) -> u64 {
+        (0..gray.height())
+            .map(|y| {
+                let dark = (0..gray.width())
+                    .filter(|&x| gray.get_pixel(x, y)[0] < 128)
+                    .count() as u64;
+                dark * dark
+            })
+            .sum()
+    }
+
+    #[test]
+    fn flattens_curved_lines() {
+        // Lines bending down by up to 10 pixels towards the spine on the right
+        let page = |bend: f64| -> GrayImage {
+            ImageBuffer::from_fn(480, 400, |x, y| {
+                let down = bend * (f64::from(x) / 480.0).powi(3);
+                let y = f64::from(y) - down;
+                let text = y > 10.0 && y < 390.0 && y.rem_euclid(24.0) < 5.0 && x > 16 && x < 464;
+                image::Luma([if text { 20 } else { 235 }])
+            })
+        };
+        let straight = sharpness(&page(0.0));
+        let curved = page(10.0);
+        assert!(sharpness(&curved) * 3 < straight * 2);
+        let flattened = dewarp(&Image::Gray8(curved)).to_dynamic().to_luma8();
+        let flattened = sharpness(&flattened);
+        assert!(
+            flattened * 10 > straight * 9,
+            "{} of {}",
+            flattened,
+            straight
+        );
+    }
+
+    #[test]
+    fn finds_the_skew() {
+        let page = image::imageops::crop_imm(&spread(1000), 0, 0, 200, 300).to_image();
+        assert_eq!(skew(&page), 0.0);
+        let skewed = rotate(&page, 3.0);
+        assert!(
+            (skew(&skewed) - 3.0).abs() <= SKEW_STEP,
+            "{}",
+            skew(&skewed)
+        );
+    }
+}
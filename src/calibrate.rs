@@ -0,0 +1,136 @@
+//! Maintenance actions of the device, calibration and cleaning
+//!
+//! Backends offer these as button options, named after what they do, such
+//! as `calibrate`, `shading` or `clean`. Pressing one blocks until the
+//! backend has started the action, after which the device may report being
+//! busy until it is done.
+
+use std::time::{Duration, Instant};
+
+use gumdrop::Options;
+use sane_sys::*;
+use skanny::backend::{BackendError, OptionInfo, ScannerDevice};
+use skanny::OptionValue;
+
+#[derive(Debug, Options)]
+pub struct CalibrateOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "Run the cleaning actions instead, which may need a cleaning sheet")]
+    clean: bool,
+    #[options(help = "List the actions without running them")]
+    list: bool,
+    #[options(
+        help = "Give up waiting for the device after this long",
+        meta = "SECS",
+        default = "300"
+    )]
+    timeout: u64,
+}
+
+/// Time between checks whether the device is done
+const POLL: Duration = Duration::from_secs(1);
+
+pub fn run(
+    device: &dyn ScannerDevice,
+    opts: &CalibrateOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let kind = if opts.clean {
+        "cleaning"
+    } else {
+        "calibration"
+    };
+    let actions = actions(device, opts.clean)?;
+    if actions.is_empty() {
+        return Err(format!("{} has no {} actions", device.name(), kind).into());
+    }
+    for action in actions {
+        if opts.list {
+            println!("{}: {}", action.name, action.title);
+            continue;
+        }
+        tracing::info!("Running {}", action.name);
+        let took = perform(device, &action.name, Duration::from_secs(opts.timeout))?;
+        println!("{}: done in {:.1} s", action.name, took.as_secs_f64());
+    }
+    Ok(())
+}
+
+/// Buttons of the device for calibration or, with `clean`, cleaning
+pub fn actions(device: &dyn ScannerDevice, clean: bool) -> Result<Vec<OptionInfo>, BackendError> {
+    Ok(device
+        .options()?
+        .into_iter()
+        .filter(|option| {
+            let name = option.name.to_lowercase();
+            let cleaning = name.contains("clean");
+            let calibration = name.contains("calibrat") || name.contains("shading");
+            option.type_ == SANE_Value_Type_SANE_TYPE_BUTTON
+                && option.cap & SANE_CAP_INACTIVE as SANE_Int == 0
+                && if clean { cleaning } else { calibration }
+        })
+        .collect())
+}
+
+/// Presses the button `name` and waits until the device is no longer busy
+fn perform(
+    device: &dyn ScannerDevice,
+    name: &str,
+    timeout: Duration,
+) -> Result<Duration, BackendError> {
+    let started = Instant::now();
+    // Buttons take no value, this one is ignored
+    device.set_option(name, &OptionValue::Bool(true))?;
+    loop {
+        match device.options() {
+            Ok(_) => return Ok(started.elapsed()),
+            Err(e) if is_busy(&e) && started.elapsed() < timeout => std::thread::sleep(POLL),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn is_busy(e: &BackendError) -> bool {
+    matches!(e.downcast_ref::<skanny::Error>(), Some(e) if e.is_busy())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use skanny::backend::Constraint;
+    use skanny::mock::{DeviceSpec, MockDevice};
+
+    fn button(name: &str) -> OptionInfo {
+        OptionInfo {
+            name: name.to_owned(),
+            title: name.to_owned(),
+            desc: String::new(),
+            type_: SANE_Value_Type_SANE_TYPE_BUTTON,
+            unit: SANE_Unit_SANE_UNIT_NONE,
+            cap: SANE_CAP_SOFT_SELECT as SANE_Int,
+            constraint: Constraint::None,
+            value: None,
+        }
+    }
+
+    #[test]
+    fn finds_maintenance_buttons() {
+        let mut spec = DeviceSpec::default();
+        spec.options.extend(
+            ["calibrate", "clean-rollers", "scan"]
+                .iter()
+                .map(|name| button(name)),
+        );
+        let device = MockDevice::new(spec);
+        let names = |clean| -> Vec<String> {
+            actions(&device, clean)
+                .unwrap()
+                .into_iter()
+                .map(|option| option.name)
+                .collect()
+        };
+        assert_eq!(names(false), ["calibrate"]);
+        assert_eq!(names(true), ["clean-rollers"]);
+        perform(&device, "calibrate", Duration::from_secs(1)).unwrap();
+    }
+}
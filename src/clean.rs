@@ -0,0 +1,131 @@
+//! Whitening of yellowed and recycled paper
+//!
+//! The colour of the paper is estimated in tiles as the brightest tenth of
+//! each channel, since text and pictures only ever darken the paper. Every
+//! sample is then scaled so the paper around it turns white. Tiles much darker
+//! than the page as a whole are covered by a photo or a dark area rather
+//! than paper, and take the colour of the paper of the whole page, so they
+//! keep their tones.
+
+use image::{ImageBuffer, Pixel};
+use skanny::Image;
+
+/// Tiles across the longer side of the page
+const TILES: u32 = 16;
+/// Tiles darker than this share of the page's paper are not paper
+const PAPER: f64 = 0.6;
+
+/// Turns the paper white, keeping text and pictures
+pub fn clean_background(image: Image) -> Image {
+    match image {
+        Image::Gray8(image) => Image::Gray8(clean(&image)),
+        Image::Rgb8(image) => Image::Rgb8(clean(&image)),
+        // Cleaned at 8 bits
+        image => clean_background(image.into_8bit()),
+    }
+}
+
+fn clean<P>(image: &ImageBuffer<P, Vec<u8>>) -> ImageBuffer<P, Vec<u8>>
+where
+    P: Pixel<Subpixel = u8> + 'static,
+{
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return image.clone();
+    }
+    let channels = P::CHANNEL_COUNT as usize;
+    let size = (width.max(height) / TILES).max(16);
+    let (columns, rows) = (width.div_ceil(size), height.div_ceil(size));
+
+    let mut paper: Vec<Vec<f64>> = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .map(|(column, row)| {
+            let mut histograms = vec![[0_u32; 256]; channels];
+            let mut count = 0;
+            for y in row * size..((row + 1) * size).min(height) {
+                for x in column * size..((column + 1) * size).min(width) {
+                    for (c, &sample) in image.get_pixel(x, y).channels().iter().enumerate() {
+                        histograms[c][sample as usize] += 1;
+                    }
+                    count += 1;
+                }
+            }
+            histograms
+                .iter()
+                .map(|histogram| f64::from(percentile(histogram, count * 9 / 10)))
+                .collect()
+        })
+        .collect();
+
+    // The paper of the page is that of the brighter tiles
+    let brightness = |colour: &[f64]| colour.iter().sum::<f64>() / channels as f64;
+    let mut sorted: Vec<usize> = (0..paper.len()).collect();
+    sorted.sort_by(|&a, &b| brightness(&paper[a]).total_cmp(&brightness(&paper[b])));
+    let page = paper[sorted[sorted.len() * 3 / 4]].clone();
+    for colour in &mut paper {
+        if brightness(colour) < brightness(&page) * PAPER {
+            colour.clone_from(&page);
+        }
+    }
+
+    // Paper colour at a position, between the centres of the tiles
+    let between = |position: u32, cells: u32| {
+        let cell = (f64::from(position) / f64::from(size) - 0.5).max(0.0);
+        let first = (cell.floor() as u32).min(cells - 1);
+        let second = (first + 1).min(cells - 1);
+        (first, second, (cell - f64::from(first)).min(1.0))
+    };
+    let tile = |column: u32, row: u32| &paper[(row * columns + column) as usize];
+    let mut cleaned = image.clone();
+    for (x, y, pixel) in cleaned.enumerate_pixels_mut() {
+        let (c0, c1, fx) = between(x, columns);
+        let (r0, r1, fy) = between(y, rows);
+        for (c, sample) in pixel.channels_mut().iter_mut().enumerate() {
+            let top = tile(c0, r0)[c] * (1.0 - fx) + tile(c1, r0)[c] * fx;
+            let bottom = tile(c0, r1)[c] * (1.0 - fx) + tile(c1, r1)[c] * fx;
+            let paper = (top * (1.0 - fy) + bottom * fy).max(1.0);
+            *sample = (f64::from(*sample) * 255.0 / paper).min(255.0).round() as u8;
+        }
+    }
+    cleaned
+}
+
+/// Smallest sample which `rank` samples of the histogram are at or below
+fn percentile(histogram: &[u32; 256], rank: u32) -> u8 {
+    let mut seen = 0;
+    for (sample, &n) in histogram.iter().enumerate() {
+        seen += n;
+        if seen > rank {
+            return sample as u8;
+        }
+    }
+    u8::MAX
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whitens_yellowed_paper() {
+        // Paper yellowing towards the right, with text and a dark photo
+        let page = Image::Rgb8(ImageBuffer::from_fn(400, 400, |x, y| {
+            let yellow = (x / 8) as u8;
+            if (200..300).contains(&x) && (200..300).contains(&y) {
+                image::Rgb([100, 100, 100])
+            } else if y % 20 < 3 && x % 7 < 4 {
+                image::Rgb([20, 20, 20])
+            } else {
+                image::Rgb([235 - yellow / 2, 225 - yellow, 190 - yellow * 2])
+            }
+        }));
+        let cleaned = clean_background(page).to_dynamic().to_rgb8();
+        for &(x, y) in &[(10, 10), (390, 10), (390, 390), (150, 150)] {
+            let paper = cleaned.get_pixel(x, y);
+            assert!(paper.0.iter().all(|&s| s >= 250), "{:?}", paper);
+        }
+        assert!(cleaned.get_pixel(0, 0).0.iter().all(|&s| s < 60));
+        let photo = cleaned.get_pixel(250, 250);
+        assert!(photo.0.iter().all(|&s| s < 160), "{:?}", photo);
+    }
+}
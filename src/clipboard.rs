@@ -0,0 +1,66 @@
+//! Scans put on the clipboard
+//!
+//! `--to-clipboard` puts the page on the clipboard as `image/png` instead
+//! of storing it, to paste it into an email or a chat. Someone has to hand
+//! the image to the programs pasting it after skanny exits, so it is given
+//! to `wl-copy` on Wayland and to `xclip` on X11, which stay in the
+//! background until the clipboard is taken over.
+
+use std::ffi::OsStr;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use skanny::Image;
+
+/// The program and its arguments taking the image for the display in use
+fn program(
+    wayland: Option<&OsStr>,
+    x11: Option<&OsStr>,
+) -> Option<(&'static str, &'static [&'static str])> {
+    match (wayland, x11) {
+        (Some(_), _) => Some(("wl-copy", &["--type", "image/png"])),
+        (None, Some(_)) => Some(("xclip", &["-selection", "clipboard", "-t", "image/png"])),
+        (None, None) => None,
+    }
+}
+
+/// Puts `image` on the clipboard
+pub fn copy(image: &Image) -> Result<(), Box<dyn std::error::Error>> {
+    let (name, args) = program(
+        std::env::var_os("WAYLAND_DISPLAY").as_deref(),
+        std::env::var_os("DISPLAY").as_deref(),
+    )
+    .ok_or("There is no clipboard without a Wayland or X11 display")?;
+    let mut png = Vec::new();
+    image.write_to(&mut png, image::ImageOutputFormat::Png)?;
+
+    let mut child = Command::new(name)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Could not run {}: {}", name, e))?;
+    let written = child.stdin.take().expect("stdin is piped").write_all(&png);
+    // The program goes to the background once it has read the image
+    let status = child.wait()?;
+    written?;
+    if !status.success() {
+        return Err(format!("{} failed with {}", name, status).into());
+    }
+    tracing::info!("Copied the page to the clipboard");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_program_of_the_display() {
+        let display = Some(OsStr::new(":0"));
+        let wayland = Some(OsStr::new("wayland-0"));
+        assert_eq!(program(wayland, display).unwrap().0, "wl-copy");
+        assert_eq!(program(None, display).unwrap().0, "xclip");
+        assert!(program(None, None).is_none());
+    }
+}
@@ -0,0 +1,445 @@
+//! Settings from configuration files and the environment
+//!
+//! Every flag before the command may be given in TOML, named as on the
+//! command line, or with underscores for dashes:
+//!
+//! ```toml
+//! device = "fujitsu:fi-7160:1234"
+//! dir = "/srv/scans"
+//! dest = ["s3://archive/scans/"]
+//! lamp-off = true
+//! ```
+//!
+//! The settings are taken from these places, each overriding the ones
+//! before it:
+//!
+//! 1. `/etc/skanny/config.toml`
+//! 2. `$XDG_CONFIG_HOME/skanny/config.toml`, or `~/.config/skanny/config.toml`
+//! 3. the file given with `--config`
+//! 4. environment variables named after the flags, such as
+//!    `SKANNY_READ_TIMEOUT` for `--read-timeout`, with lists separated by
+//!    spaces
+//! 5. the command line
+//!
+//! The settings of the files and the environment are passed on as flags in
+//! front of the ones on the command line.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use gumdrop::{Options, Parser, ParsingStyle};
+
+#[derive(Debug, Options)]
+pub struct ConfigOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(command)]
+    command: Option<ConfigCommand>,
+}
+
+#[derive(Debug, Options)]
+enum ConfigCommand {
+    #[options(help = "Print the configuration files, or the settings with --resolved")]
+    Show(ShowOptions),
+}
+
+#[derive(Debug, Options)]
+struct ShowOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "Print the effective settings and where each came from")]
+    resolved: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Kind {
+    Switch,
+    Count,
+    Value,
+    List,
+}
+
+/// The flags which can be configured, with their short forms
+const FLAGS: &[(&str, Option<char>, Kind)] = &[
+    ("verbose", Some('v'), Kind::Count),
+    ("log-file", None, Kind::Value),
+    ("log-json", None, Kind::Switch),
+    ("tui", None, Kind::Switch),
+    ("preview", None, Kind::Switch),
+    ("to-clipboard", None, Kind::Switch),
+    ("testdevice", Some('t'), Kind::Switch),
+    ("test-picture", None, Kind::Value),
+    ("test-mode", None, Kind::Value),
+    ("test-depth", None, Kind::Value),
+    ("test-width", None, Kind::Value),
+    ("test-height", None, Kind::Value),
+    ("test-three-pass", None, Kind::Switch),
+    ("test-hand-scanner", None, Kind::Switch),
+    ("test-options", None, Kind::Switch),
+    ("test-error", None, Kind::Value),
+    ("device", Some('d'), Kind::Value),
+    ("dir", Some('D'), Kind::Value),
+    ("page-name", None, Kind::Value),
+    ("manifest", None, Kind::Value),
+    ("organize", None, Kind::Value),
+    ("name-replacement", None, Kind::Value),
+    ("name-max-length", None, Kind::Value),
+    ("on-collision", None, Kind::Value),
+    ("thumbnails", None, Kind::Value),
+    ("drain-timeout", None, Kind::Value),
+    ("dedupe", None, Kind::Value),
+    ("dedupe-distance", None, Kind::Value),
+    ("separator", None, Kind::Value),
+    ("contact-sheet", None, Kind::Value),
+    ("on-jam", None, Kind::Value),
+    ("store", None, Kind::Value),
+    ("detect-double-feed", None, Kind::Switch),
+    ("split-pages", None, Kind::Switch),
+    ("dewarp", None, Kind::Switch),
+    ("dropout", None, Kind::Value),
+    ("clean-background", None, Kind::Switch),
+    ("restore", None, Kind::Value),
+    ("paper", None, Kind::Value),
+    ("page-height", None, Kind::Value),
+    ("lamp-off", None, Kind::Switch),
+    ("dest", None, Kind::List),
+    ("on-page", None, Kind::Value),
+    ("on-job", None, Kind::Value),
+    ("stage", None, Kind::List),
+    ("plugin", None, Kind::List),
+    ("record", None, Kind::Value),
+    ("retries", None, Kind::Value),
+    ("read-timeout", None, Kind::Value),
+    ("page-retries", None, Kind::Value),
+    ("include-network", None, Kind::Switch),
+    ("network-timeout", None, Kind::Value),
+    ("device-cache", None, Kind::Value),
+    ("device-cache-ttl", None, Kind::Value),
+    ("refresh", None, Kind::Switch),
+    ("spool", None, Kind::Value),
+    ("dump-unknown-frames", None, Kind::Value),
+    ("pdf-compression", None, Kind::Value),
+    ("pdf-dpi", None, Kind::Value),
+    ("pdfa", None, Kind::Switch),
+    ("pdf-encrypt", None, Kind::Switch),
+    ("pdf-password", None, Kind::Value),
+    ("pdf-owner-password", None, Kind::Value),
+    ("pdf-allow", None, Kind::Value),
+    ("ocr-lang", None, Kind::Value),
+    ("ocr-psm", None, Kind::Value),
+    ("ocr-engine", None, Kind::Value),
+    ("ocr-workers", None, Kind::Value),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Setting {
+    Switch(bool),
+    Count(u64),
+    Values(Vec<String>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    File(PathBuf),
+    Environment(String),
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Environment(name) => write!(f, "environment variable {}", name),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// The settings in effect, keyed by flag
+#[derive(Debug, Default)]
+pub struct Resolved {
+    settings: BTreeMap<&'static str, (Setting, Source)>,
+    /// Configuration files in the order they are read, and whether they
+    /// exist
+    files: Vec<(PathBuf, bool)>,
+}
+
+fn flag(name: &str) -> Option<(&'static str, Kind)> {
+    FLAGS
+        .iter()
+        .find(|(flag, _, _)| *flag == name)
+        .map(|&(flag, _, kind)| (flag, kind))
+}
+
+fn user_path() -> Option<PathBuf> {
+    let config = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => Path::new(&std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config.join("skanny").join("config.toml"))
+}
+
+/// Resolves the settings for the command line `args`, without the name of
+/// the program
+pub fn resolve(args: &[String]) -> Result<Resolved, Box<dyn std::error::Error>> {
+    let (command_line, config) = command_line(args);
+    let mut resolved = Resolved::default();
+    let mut files = vec![PathBuf::from("/etc/skanny/config.toml")];
+    files.extend(user_path());
+    for path in files {
+        resolved.read(&path, false)?;
+    }
+    if let Some(path) = config {
+        resolved.read(Path::new(&path), true)?;
+    }
+    resolved.environment(std::env::vars())?;
+    for (name, setting) in command_line {
+        resolved
+            .settings
+            .insert(name, (setting, Source::CommandLine));
+    }
+    Ok(resolved)
+}
+
+/// The flags given before the command, and the `--config` file
+fn command_line(args: &[String]) -> (Vec<(&'static str, Setting)>, Option<String>) {
+    let mut settings: Vec<(&'static str, Setting)> = Vec::new();
+    let mut config = None;
+    let mut parser = Parser::new(args, ParsingStyle::AllOptions);
+    while let Some(opt) = parser.next_opt() {
+        let (name, inline) = match opt {
+            gumdrop::Opt::Long(name) => (name, None),
+            gumdrop::Opt::LongWithArg(name, value) => (name, Some(value)),
+            gumdrop::Opt::Short(short) => {
+                match FLAGS.iter().find(|(_, flag, _)| *flag == Some(short)) {
+                    Some((name, _, _)) => (*name, None),
+                    None => continue,
+                }
+            }
+            // The command, whose flags are its own
+            gumdrop::Opt::Free(_) => break,
+        };
+        if name == "config" {
+            config = inline.or_else(|| parser.next_arg()).map(str::to_owned);
+            continue;
+        }
+        let (name, kind) = match flag(name) {
+            Some(flag) => flag,
+            None => continue,
+        };
+        let previous = settings.iter().position(|(flag, _)| *flag == name);
+        let setting = match (kind, previous.map(|i| settings.remove(i).1)) {
+            (Kind::Switch, _) => Setting::Switch(true),
+            (Kind::Count, Some(Setting::Count(n))) => Setting::Count(n + 1),
+            (Kind::Count, _) => Setting::Count(1),
+            (Kind::List, Some(Setting::Values(mut values))) => {
+                values.extend(inline.or_else(|| parser.next_arg()).map(str::to_owned));
+                Setting::Values(values)
+            }
+            (Kind::Value, _) | (Kind::List, _) => Setting::Values(
+                inline
+                    .or_else(|| parser.next_arg())
+                    .map(str::to_owned)
+                    .into_iter()
+                    .collect(),
+            ),
+        };
+        settings.push((name, setting));
+    }
+    (settings, config)
+}
+
+impl Resolved {
+    /// Reads a configuration file, which only has to exist if `required`
+    fn read(&mut self, path: &Path, required: bool) -> Result<(), Box<dyn std::error::Error>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => {
+                self.files.push((path.to_owned(), false));
+                return Ok(());
+            }
+            Err(e) => return Err(format!("Could not read {}: {}", path.display(), e).into()),
+        };
+        self.files.push((path.to_owned(), true));
+        self.merge(&contents, Source::File(path.to_owned()))
+            .map_err(|e| format!("{}: {}", path.display(), e).into())
+    }
+
+    fn merge(&mut self, contents: &str, source: Source) -> Result<(), String> {
+        let table: toml::value::Table = toml::from_str(contents).map_err(|e| e.to_string())?;
+        for (key, value) in table {
+            let (name, kind) =
+                flag(&key.replace('_', "-")).ok_or_else(|| format!("Unknown setting {}", key))?;
+            let single = |value: &toml::Value| match value {
+                toml::Value::String(s) => Some(s.clone()),
+                toml::Value::Integer(i) => Some(i.to_string()),
+                toml::Value::Float(f) => Some(f.to_string()),
+                _ => None,
+            };
+            let setting = match (kind, &value) {
+                (Kind::Switch, &toml::Value::Boolean(on)) => Some(Setting::Switch(on)),
+                (Kind::Count, &toml::Value::Integer(n)) if n >= 0 => Some(Setting::Count(n as u64)),
+                (Kind::List, toml::Value::Array(values)) => values
+                    .iter()
+                    .map(single)
+                    .collect::<Option<_>>()
+                    .map(Setting::Values),
+                (Kind::Value, value) | (Kind::List, value) => {
+                    single(value).map(|value| Setting::Values(vec![value]))
+                }
+                _ => None,
+            };
+            let setting = setting.ok_or_else(|| format!("Invalid value {} for {}", value, key))?;
+            self.settings.insert(name, (setting, source.clone()));
+        }
+        Ok(())
+    }
+
+    /// Takes the settings from `SKANNY_` variables among `vars`
+    fn environment(
+        &mut self,
+        vars: impl Iterator<Item = (String, String)>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let vars: BTreeMap<String, String> = vars.collect();
+        for &(name, _, kind) in FLAGS {
+            let variable = format!("SKANNY_{}", name.to_uppercase().replace('-', "_"));
+            let value = match vars.get(&variable) {
+                Some(value) => value,
+                None => continue,
+            };
+            let setting = match kind {
+                Kind::Switch => match &value[..] {
+                    "1" | "true" | "yes" => Setting::Switch(true),
+                    "0" | "false" | "no" | "" => Setting::Switch(false),
+                    _ => return Err(format!("{} must be true or false", variable).into()),
+                },
+                Kind::Count => Setting::Count(
+                    value
+                        .parse()
+                        .map_err(|_| format!("{} must be a number", variable))?,
+                ),
+                Kind::Value => Setting::Values(vec![value.clone()]),
+                Kind::List => {
+                    Setting::Values(value.split_whitespace().map(str::to_owned).collect())
+                }
+            };
+            self.settings
+                .insert(name, (setting, Source::Environment(variable)));
+        }
+        Ok(())
+    }
+
+    /// Flags for the settings which are not on the command line already
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for (name, (setting, source)) in &self.settings {
+            if *source == Source::CommandLine {
+                continue;
+            }
+            match setting {
+                Setting::Switch(true) => args.push(format!("--{}", name)),
+                Setting::Switch(false) => {}
+                Setting::Count(n) => args.extend((0..*n).map(|_| format!("--{}", name))),
+                Setting::Values(values) => {
+                    args.extend(values.iter().map(|value| format!("--{}={}", name, value)))
+                }
+            }
+        }
+        args
+    }
+}
+
+pub fn run(resolved: &Resolved, opts: &ConfigOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let show = match &opts.command {
+        Some(ConfigCommand::Show(show)) => show,
+        None => return Err("Expected a command, see skanny config --help".into()),
+    };
+    if !show.resolved {
+        for (path, exists) in &resolved.files {
+            let state = if *exists { "" } else { " (missing)" };
+            println!("{}{}", path.display(), state);
+        }
+        return Ok(());
+    }
+    for (name, (setting, source)) in &resolved.settings {
+        let kind = flag(name).map(|(_, kind)| kind);
+        let value = match setting {
+            &Setting::Switch(on) => toml::Value::Boolean(on),
+            &Setting::Count(n) => toml::Value::Integer(n as i64),
+            Setting::Values(values) if kind == Some(Kind::List) => {
+                toml::Value::Array(values.iter().cloned().map(toml::Value::String).collect())
+            }
+            // Numbers are kept as text, but shown as they would be written
+            Setting::Values(values) => {
+                let value = values.concat();
+                match (value.parse(), value.parse()) {
+                    (Ok(integer), _) => toml::Value::Integer(integer),
+                    (_, Ok(float)) => toml::Value::Float(float),
+                    _ => toml::Value::String(value),
+                }
+            }
+        };
+        println!("{} = {} # {}", name, value, source);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_layers_take_precedence() {
+        let mut resolved = Resolved::default();
+        let system = Source::File("/etc/skanny/config.toml".into());
+        resolved
+            .merge(
+                "dir = \"/srv/scans\"\nretries = 8\ndest = [\"s3://a/\"]\nlamp-off = true\nverbose = 1",
+                system.clone(),
+            )
+            .unwrap();
+        assert!(resolved.merge("colour = true", system.clone()).is_err());
+        assert!(resolved.merge("lamp-off = 1", system).is_err());
+        let vars = vec![
+            ("SKANNY_DIR", "/tmp/scans"),
+            ("SKANNY_LAMP_OFF", "false"),
+            ("SKANNY_OPT_resolution", "600"),
+        ];
+        resolved
+            .environment(
+                vars.into_iter()
+                    .map(|(key, value)| (key.to_owned(), value.to_owned())),
+            )
+            .unwrap();
+        let args: Vec<String> = [
+            "-vv",
+            "--dest",
+            "s3://b/",
+            "--config=my.toml",
+            "serve",
+            "-d",
+            "x",
+        ]
+        .iter()
+        .map(|&arg| arg.to_owned())
+        .collect();
+        let (command_line, config) = command_line(&args);
+        assert_eq!(config.as_deref(), Some("my.toml"));
+        for (name, setting) in command_line {
+            resolved
+                .settings
+                .insert(name, (setting, Source::CommandLine));
+        }
+
+        assert_eq!(
+            resolved.settings["dir"].1,
+            Source::Environment("SKANNY_DIR".to_owned())
+        );
+        assert_eq!(
+            resolved.settings["verbose"],
+            (Setting::Count(2), Source::CommandLine)
+        );
+        assert_eq!(resolved.args(), ["--dir=/tmp/scans", "--retries=8"]);
+    }
+}
@@ -0,0 +1,149 @@
+//! Contact sheets of batches
+//!
+//! `--contact-sheet FILE` composes the pages of a `--separator` batch into
+//! one image of numbered thumbnails once the batch is done, so a run of a
+//! hundred pages through the feeder can be reviewed at a glance. A file
+//! ending in `.pdf` becomes a PDF.
+//!
+//! The thumbnails written with `--thumbnails` are used where they exist,
+//! other pages are read back from their files. Pages which cannot be read,
+//! such as PDF files, are left as grey boxes.
+
+use std::path::{Path, PathBuf};
+
+use image::{Rgb, RgbImage};
+use skanny::Image;
+
+const COLUMNS: u32 = 5;
+/// Largest side of a thumbnail in pixels
+const CELL: u32 = 240;
+const MARGIN: u32 = 16;
+/// Pixels of a dot of the digits
+const DOT: u32 = 3;
+/// Height of the numbers below the thumbnails
+const LABEL: u32 = 5 * DOT + MARGIN / 2;
+
+const WHITE: Rgb<u8> = Rgb([255, 255, 255]);
+const GREY: Rgb<u8> = Rgb([200, 200, 200]);
+const BLACK: Rgb<u8> = Rgb([0, 0, 0]);
+
+/// Digits of three by five dots, a row to a byte with the left dot in the
+/// highest of three bits
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// Draws `number` centred on `x` with its top at `y`
+fn draw_number(sheet: &mut RgbImage, number: usize, x: u32, y: u32) {
+    let text = number.to_string();
+    let left = x - (text.len() as u32 * 4 - 1) * DOT / 2;
+    for (position, digit) in text.bytes().enumerate() {
+        let rows = DIGITS[usize::from(digit - b'0')];
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                let x = left + (position as u32 * 4 + column) * DOT;
+                let y = y + row as u32 * DOT;
+                for (dx, dy) in (0..DOT).flat_map(|dx| (0..DOT).map(move |dy| (dx, dy))) {
+                    sheet.put_pixel(x + dx, y + dy, BLACK);
+                }
+            }
+        }
+    }
+}
+
+/// The page at `path` as a thumbnail, from `--thumbnails` if one was
+/// written
+fn thumbnail(path: &Path) -> image::ImageResult<RgbImage> {
+    let written = crate::thumbnail::path(path);
+    let image = image::open(if written.exists() { &written } else { path })?;
+    Ok(image.thumbnail(CELL, CELL).into_rgb8())
+}
+
+/// Lays out the pages in rows of numbered thumbnails
+pub fn compose(pages: &[PathBuf]) -> RgbImage {
+    let rows = (pages.len() as u32).div_ceil(COLUMNS).max(1);
+    let mut sheet = RgbImage::from_pixel(
+        COLUMNS * (CELL + MARGIN) + MARGIN,
+        rows * (CELL + LABEL + MARGIN) + MARGIN,
+        WHITE,
+    );
+    for (n, path) in pages.iter().enumerate() {
+        let n = n as u32;
+        let x = MARGIN + n % COLUMNS * (CELL + MARGIN);
+        let y = MARGIN + n / COLUMNS * (CELL + LABEL + MARGIN);
+        match thumbnail(path) {
+            Ok(small) => {
+                let (dx, dy) = ((CELL - small.width()) / 2, (CELL - small.height()) / 2);
+                image::imageops::overlay(&mut sheet, &small, x + dx, y + dy);
+            }
+            Err(e) => {
+                tracing::warn!("Leaving {} out of the contact sheet: {}", path.display(), e);
+                for (px, py) in (0..CELL).flat_map(|px| (0..CELL).map(move |py| (px, py))) {
+                    sheet.put_pixel(x + px, y + py, GREY);
+                }
+            }
+        }
+        draw_number(
+            &mut sheet,
+            n as usize + 1,
+            x + CELL / 2,
+            y + CELL + MARGIN / 2,
+        );
+    }
+    sheet
+}
+
+/// Writes the contact sheet of `pages` to `path`
+pub fn write(pages: &[PathBuf], path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    crate::profile::save(&Image::Rgb8(compose(pages)), path)?;
+    tracing::info!("Wrote the contact sheet {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_the_pages_in_rows() {
+        let dir = std::env::temp_dir().join(format!("skanny-contact-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut pages = Vec::new();
+        for n in 0..6 {
+            let path = dir.join(format!("page_{}.png", n));
+            RgbImage::from_pixel(480, 600, Rgb([255, 0, 0]))
+                .save(&path)
+                .unwrap();
+            pages.push(path);
+        }
+        pages.push(dir.join("missing.png"));
+        let sheet = compose(&pages);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(sheet.width(), 5 * (CELL + MARGIN) + MARGIN);
+        assert_eq!(sheet.height(), 2 * (CELL + LABEL + MARGIN) + MARGIN);
+        // 480 by 600 pages are 192 by 240 thumbnails, centred in their cell
+        assert_eq!(sheet.get_pixel(MARGIN + 23, MARGIN), &WHITE);
+        assert_eq!(sheet.get_pixel(MARGIN + 24, MARGIN), &Rgb([255, 0, 0]));
+        // The seventh page could not be read
+        let (x, y) = (MARGIN + CELL + MARGIN, MARGIN + CELL + LABEL + MARGIN);
+        assert_eq!(sheet.get_pixel(x, y), &GREY);
+        // The middle column of the 7 below it
+        let label = y + CELL + MARGIN / 2;
+        assert_eq!(sheet.get_pixel(x + CELL / 2, label), &BLACK);
+        assert_eq!(sheet.get_pixel(x + CELL / 2, label + DOT), &WHITE);
+        assert_eq!(sheet.get_pixel(x + CELL / 2, label + 2 * DOT), &BLACK);
+    }
+}
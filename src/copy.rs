@@ -0,0 +1,131 @@
+//! Copying pages on a printer
+//!
+//! `skanny copy` scans a page at the size of the paper and prints it with
+//! `lp` on a CUPS printer, which makes a copier of a scanner and a printer:
+//!
+//! ```text
+//! skanny copy --printer office --copies 3 --paper letter
+//! ```
+//!
+//! The page is printed as PNG scaled to fit the paper, so the margins the
+//! printer cannot print on do not cut it off.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use gumdrop::Options;
+use skanny::backend::ScannerDevice;
+
+use crate::paper::Paper;
+
+#[derive(Debug, Options)]
+pub struct CopyOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "CUPS printer to print on, the default one if not given")]
+    printer: Option<String>,
+    #[options(help = "Number of copies to print", default = "1")]
+    copies: u32,
+    #[options(
+        help = "Paper to scan and print on",
+        meta = "a4|letter|legal",
+        default = "a4"
+    )]
+    paper: Paper,
+}
+
+/// The name of CUPS for `paper`
+fn media(paper: Paper) -> Result<&'static str, String> {
+    match paper {
+        Paper::A4 => Ok("A4"),
+        Paper::Letter => Ok("Letter"),
+        Paper::Legal => Ok("Legal"),
+        Paper::Receipt => Err("Receipts cannot be copied".to_owned()),
+    }
+}
+
+/// Arguments of `lp`, which reads the page from stdin without files
+fn lp_args(opts: &CopyOptions) -> Result<Vec<String>, String> {
+    if opts.copies == 0 {
+        return Err("At least one copy has to be printed".to_owned());
+    }
+    let mut args = Vec::new();
+    if let Some(printer) = &opts.printer {
+        args.extend(["-d".to_owned(), printer.clone()]);
+    }
+    args.extend([
+        "-n".to_owned(),
+        opts.copies.to_string(),
+        "-o".to_owned(),
+        format!("media={}", media(opts.paper)?),
+        "-o".to_owned(),
+        "fit-to-page".to_owned(),
+        "-t".to_owned(),
+        "skanny copy".to_owned(),
+    ]);
+    Ok(args)
+}
+
+pub fn run(
+    device: &dyn ScannerDevice,
+    opts: &CopyOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let args = lp_args(opts)?;
+    crate::paper::set(device, Some(opts.paper), None)?;
+    let image = device.scan()?;
+    let mut png = Vec::new();
+    image.write_to(&mut png, image::ImageOutputFormat::Png)?;
+
+    let mut lp = Command::new("lp")
+        .args(&args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Could not run lp: {}", e))?;
+    let written = lp.stdin.take().expect("stdin is piped").write_all(&png);
+    let output = lp.wait_with_output()?;
+    written?;
+    if !output.status.success() {
+        return Err(format!(
+            "Printing failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    // lp names the job it queued
+    tracing::info!("{}", String::from_utf8_lossy(&output.stdout).trim());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prints_copies_fitted_to_the_paper() {
+        let opts =
+            CopyOptions::parse_args_default(&["--copies", "3", "--paper", "letter"]).unwrap();
+        assert_eq!(
+            lp_args(&opts).unwrap(),
+            [
+                "-n",
+                "3",
+                "-o",
+                "media=Letter",
+                "-o",
+                "fit-to-page",
+                "-t",
+                "skanny copy"
+            ]
+        );
+        let opts = CopyOptions::parse_args_default(&["--printer", "office"]).unwrap();
+        assert_eq!(lp_args(&opts).unwrap()[..3], ["-d", "office", "-n"]);
+        assert_eq!(lp_args(&opts).unwrap()[3], "1");
+
+        let opts = CopyOptions::parse_args_default(&["--paper", "receipt"]).unwrap();
+        assert!(lp_args(&opts).is_err());
+        let opts = CopyOptions::parse_args_default(&["--copies", "0"]).unwrap();
+        assert!(lp_args(&opts).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+//! Long running scan service controlled over a Unix socket
+//!
+//! The context and device handle stay open between scans, which avoids
+//! paying the `sane_init`/`sane_open` latency for every page. Each
+//! connection sends a single line
+//!
+//! ```text
+//! scan PROFILE [OPTION=VALUE]...
+//! ```
+//!
+//! and receives `ok PATH` or `error MESSAGE` once the scan has been stored.
+//! `ping` can be used to check that the daemon is alive.
+//!
+//! Under systemd the daemon reports readiness, pings the watchdog and can
+//! be started through socket activation, see the [`systemd`](crate::systemd)
+//! module.
+
+use gumdrop::Options;
+
+#[derive(Debug, Options)]
+pub struct DaemonOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "TOML file with scan profiles", required)]
+    profiles: String,
+    #[options(help = "Path of the control socket")]
+    socket: Option<String>,
+    #[options(
+        no_short,
+        help = "MQTT broker to publish events to",
+        meta = "HOST[:PORT]"
+    )]
+    mqtt: Option<String>,
+    #[options(no_short, help = "Topic prefix for MQTT events", default = "skanny")]
+    mqtt_topic: String,
+    #[options(no_short, help = "Publish thumbnails of scanned pages over MQTT")]
+    mqtt_thumbnails: bool,
+    #[options(no_short, help = "URL to POST finished jobs to", meta = "URL")]
+    webhook: Vec<String>,
+    #[options(
+        no_short,
+        help = "Check for the device being disconnected and connected again this often",
+        meta = "SECS"
+    )]
+    hotplug: Option<u64>,
+    #[options(
+        no_short,
+        help = "SQLite database to keep the jobs in, see skanny jobs",
+        meta = "FILE"
+    )]
+    store: Option<String>,
+    #[options(
+        no_short,
+        help = "Append a line about every finished job to this file",
+        meta = "FILE"
+    )]
+    audit: Option<String>,
+}
+
+#[cfg(not(unix))]
+pub fn run(
+    _context: &skanny::Context,
+    _handle: skanny::Handle,
+    _opts: &DaemonOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("The daemon needs Unix domain sockets".into())
+}
+
+#[cfg(unix)]
+pub use imp::run;
+
+#[cfg(unix)]
+mod imp {
+    use std::collections::BTreeMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use skanny::{Context, Handle};
+
+    use super::DaemonOptions;
+    use crate::events::{Event, Sinks};
+    use crate::hotplug::{Attached, Monitor};
+    use crate::jobs::JobQueue;
+    use crate::profile::{self, Profile};
+
+    fn default_socket() -> PathBuf {
+        let dir = std::env::var_os("XDG_RUNTIME_DIR").unwrap_or_else(|| "/tmp".into());
+        Path::new(&dir).join("skanny.sock")
+    }
+
+    pub fn run(
+        context: &Context,
+        handle: Handle,
+        opts: &DaemonOptions,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let profiles = profile::load(Path::new(&opts.profiles))?;
+        let socket = opts
+            .socket
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(default_socket);
+
+        let mut sinks = Sinks::default();
+        if let Some(broker) = &opts.mqtt {
+            sinks.push(crate::mqtt::Publisher::connect(
+                broker,
+                &opts.mqtt_topic,
+                opts.mqtt_thumbnails,
+            )?);
+        }
+        for url in &opts.webhook {
+            sinks.push(crate::webhook::Webhook::new(url)?);
+        }
+
+        // A socket passed by systemd stays with systemd
+        let (listener, owned) = match crate::systemd::listener()? {
+            Some(listener) => (listener, false),
+            None => {
+                if socket.exists() {
+                    std::fs::remove_file(&socket)?;
+                }
+                (UnixListener::bind(&socket)?, true)
+            }
+        };
+        listener.set_nonblocking(true)?;
+        let address = match listener.local_addr()?.as_pathname() {
+            Some(path) => path.display().to_string(),
+            None => "the socket of systemd".to_owned(),
+        };
+        let mut monitor = Monitor::new(
+            context,
+            opts.hotplug.map(std::time::Duration::from_secs),
+            handle.name(),
+        )?;
+        let mut attached = Attached::Open(handle);
+
+        // Connections are served one at a time, each waiting for its job
+        let mut queue = JobQueue::new(1);
+        if let Some(store) = &opts.store {
+            crate::store::attach(&mut queue, Path::new(store))?;
+        }
+        if let Some(audit) = &opts.audit {
+            queue.observe(crate::audit::Audit::open(Path::new(audit), "daemon")?);
+        }
+        let queue = Arc::new(queue);
+        let stop = crate::stop_on_ctrlc();
+        let scanning = Arc::clone(&queue);
+        crate::shutdown::cancel_after_drain(Arc::clone(&stop), move || scanning.cancel_scan());
+        let mut watchdog = crate::systemd::Watchdog::from_env();
+        tracing::info!("Listening on {}, interrupt with ctrl-c", address);
+        crate::systemd::ready(&format!("Listening on {}", address));
+        while !stop.load(Ordering::SeqCst) {
+            watchdog.ping();
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let handle = attached.handle();
+                    if let Err(e) = serve(handle, &queue, &profiles, &sinks, stream) {
+                        tracing::warn!("Connection failed: {}", e);
+                    }
+                    // A failed scan may be the device being disconnected
+                    let changes = monitor.poll_now();
+                    attached = attached.update(&monitor, &changes, &sinks);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(std::time::Duration::from_millis(100));
+                    let changes = monitor.poll();
+                    attached = attached.update(&monitor, &changes, &sinks);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        crate::systemd::stopping();
+        if !queue.drain(crate::shutdown::timeout()) {
+            tracing::warn!("Stopped with pages not stored or uploaded");
+        }
+        if owned {
+            std::fs::remove_file(&socket)?;
+        }
+        Ok(())
+    }
+
+    /// The user id of the process at the other end of the socket
+    #[cfg(target_os = "linux")]
+    fn peer(stream: &UnixStream) -> Option<String> {
+        use std::os::unix::io::AsRawFd;
+
+        let mut credentials = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut credentials as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        (result == 0).then(|| format!("uid {}", credentials.uid))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn peer(_stream: &UnixStream) -> Option<String> {
+        None
+    }
+
+    fn serve(
+        handle: Option<&Handle>,
+        queue: &Arc<JobQueue>,
+        profiles: &BTreeMap<String, Profile>,
+        sinks: &Sinks,
+        stream: UnixStream,
+    ) -> std::io::Result<()> {
+        stream.set_nonblocking(false)?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+
+        let client = peer(&stream);
+        let mut stream = stream;
+        match execute(
+            handle,
+            queue,
+            profiles,
+            sinks,
+            line.trim(),
+            client.as_deref(),
+        ) {
+            Ok(reply) => writeln!(stream, "ok {}", reply),
+            Err(e) => {
+                sinks.send(&Event::Error {
+                    message: e.to_string(),
+                });
+                writeln!(stream, "error {}", e)
+            }
+        }
+    }
+
+    fn execute(
+        handle: Option<&Handle>,
+        queue: &Arc<JobQueue>,
+        profiles: &BTreeMap<String, Profile>,
+        sinks: &Sinks,
+        command: &str,
+        client: Option<&str>,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let mut words = command.split_whitespace();
+        match words.next() {
+            Some("ping") => Ok("pong".to_owned()),
+            Some("scan") => {
+                let handle = handle.ok_or("The device is disconnected")?;
+                let name = words.next().ok_or("Missing profile name")?;
+                let mut profile = profiles
+                    .get(name)
+                    .ok_or_else(|| format!("No profile named {}", name))?
+                    .clone();
+                for assignment in words {
+                    let mut split = assignment.splitn(2, '=');
+                    let (option, value) = match (split.next(), split.next()) {
+                        (Some(option), Some(value)) => (option, value),
+                        _ => {
+                            return Err(
+                                format!("{} is not of the form OPTION=VALUE", assignment).into()
+                            )
+                        }
+                    };
+                    let opt = handle
+                        .option(option)
+                        .ok_or_else(|| format!("Device has no option named {}", option))?;
+                    let value = opt.descriptor().parse_value(value)?;
+                    profile.requested.insert(option.to_owned(), value);
+                }
+
+                tracing::info!("Scanning with profile {}", name);
+                let started = Instant::now();
+                let id = queue.submit(handle.name(), name, &profile.requested, client)?;
+                let scan = match queue.run(id, &profile, handle).wait() {
+                    Ok(scan) => scan,
+                    Err(e) => {
+                        sinks.send(&Event::JobFailed {
+                            device: handle.name(),
+                            profile: name,
+                            message: e.to_string(),
+                            duration_secs: started.elapsed().as_secs_f64(),
+                        });
+                        return Err(e);
+                    }
+                };
+                sinks.send(&Event::PageScanned {
+                    path: &scan.path,
+                    image: &scan.image,
+                });
+                sinks.send(&Event::JobCompleted {
+                    device: handle.name(),
+                    profile: name,
+                    pages: 1,
+                    files: std::slice::from_ref(&scan.path),
+                    locations: &scan.locations,
+                    duration_secs: started.elapsed().as_secs_f64(),
+                });
+                Ok(scan.path.display().to_string())
+            }
+            Some(other) => Err(format!("Unknown command {}", other).into()),
+            None => Err("Empty command".into()),
+        }
+    }
+}
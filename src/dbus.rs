@@ -0,0 +1,225 @@
+//! D-Bus service for desktop integration
+//!
+//! Registers `dev.ulimoen.Skanny` on the session (or system) bus with the
+//! interface `dev.ulimoen.Skanny1.Scanner` at `/dev/ulimoen/Skanny`.
+//! Scans are started with `StartScan`, which returns a job number and
+//! reports its outcome through the `ScanStarted`, `ScanCompleted` and
+//! `ScanFailed` signals.
+
+use gumdrop::Options;
+use skanny::{Context, Handle};
+
+#[derive(Debug, Options)]
+pub struct DbusOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "Register on the system bus instead of the session bus")]
+    system: bool,
+    #[options(help = "TOML file with scan profiles")]
+    profiles: Option<String>,
+    #[options(help = "Directory to store images", default = ".")]
+    dir: String,
+}
+
+#[cfg(not(feature = "dbus"))]
+pub fn run(
+    _context: &Context,
+    _handle: &Handle,
+    _opts: &DbusOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("skanny was built without the dbus feature".into())
+}
+
+#[cfg(feature = "dbus")]
+pub use imp::run;
+
+#[cfg(feature = "dbus")]
+mod imp {
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::Mutex;
+
+    use skanny::{Context, Handle};
+    use zbus::{dbus_interface, fdo};
+
+    use super::DbusOptions;
+    use crate::device_thread::{self, Task};
+    use crate::profile::{self, Profile};
+
+    const NAME: &str = "dev.ulimoen.Skanny";
+    const PATH: &str = "/dev/ulimoen/Skanny";
+    const INTERFACE: &str = "dev.ulimoen.Skanny1.Scanner";
+
+    struct Scanner {
+        tasks: Mutex<Sender<Task>>,
+        connection: Mutex<Option<zbus::blocking::Connection>>,
+        profiles: BTreeMap<String, Profile>,
+        dir: PathBuf,
+        jobs: AtomicU32,
+    }
+
+    impl Scanner {
+        fn call<T, F>(&self, f: F) -> fdo::Result<T>
+        where
+            T: Send + 'static,
+            F: FnOnce(&Context, &Handle) -> Result<T, String> + Send + 'static,
+        {
+            let tasks = self.tasks.lock().unwrap().clone();
+            device_thread::call(&tasks, f)
+                .ok_or_else(|| fdo::Error::Failed("Device is shutting down".to_owned()))?
+                .map_err(fdo::Error::Failed)
+        }
+    }
+
+    fn emit<B>(connection: &zbus::blocking::Connection, signal: &str, body: &B)
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType,
+    {
+        if let Err(e) = connection.emit_signal(None::<()>, PATH, INTERFACE, signal, body) {
+            tracing::warn!("Failed to emit {}: {}", signal, e);
+        }
+    }
+
+    #[dbus_interface(name = "dev.ulimoen.Skanny1.Scanner")]
+    impl Scanner {
+        /// Devices as (name, vendor, model, type)
+        fn list_devices(&self) -> fdo::Result<Vec<(String, String, String, String)>> {
+            self.call(|context, _| {
+                let devices = context.devices(true).map_err(|e| e.to_string())?;
+                Ok(devices
+                    .map(|device| {
+                        (
+                            device.name().to_owned(),
+                            device.vendor().to_owned(),
+                            device.model().to_owned(),
+                            device.type_().to_owned(),
+                        )
+                    })
+                    .collect())
+            })
+        }
+
+        /// Options as (name, description, current value)
+        fn list_options(&self) -> fdo::Result<Vec<(String, String, String)>> {
+            self.call(|_, handle| {
+                Ok(handle
+                    .options()
+                    .filter(|opt| !opt.name().is_empty())
+                    .map(|opt| {
+                        let value = match opt.get_value() {
+                            Ok(value) => value.to_string(),
+                            Err(_) => String::new(),
+                        };
+                        (opt.name().to_owned(), opt.desc().to_owned(), value)
+                    })
+                    .collect())
+            })
+        }
+
+        fn set_option(&self, name: String, value: String) -> fdo::Result<()> {
+            self.call(move |_, handle| {
+                let opt = handle
+                    .option(&name)
+                    .ok_or_else(|| format!("No option named {}", name))?;
+                let value = opt
+                    .descriptor()
+                    .parse_value(&value)
+                    .map_err(|e| e.to_string())?;
+                opt.set_value(&value).map_err(|e| e.to_string())
+            })
+        }
+
+        /// Starts a scan with the given profile, or the current options if empty
+        fn start_scan(&self, profile: String) -> fdo::Result<u32> {
+            let mut settings = if profile.is_empty() {
+                Profile::default()
+            } else {
+                self.profiles.get(&profile).cloned().ok_or_else(|| {
+                    fdo::Error::InvalidArgs(format!("No profile named {}", profile))
+                })?
+            };
+            if settings.dir.is_none() {
+                settings.dir = Some(self.dir.clone());
+            }
+            let connection = self
+                .connection
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or_else(|| fdo::Error::Failed("Not connected".to_owned()))?;
+
+            let job = self.jobs.fetch_add(1, Ordering::SeqCst);
+            let task: Task = Box::new(move |_, handle| {
+                emit(&connection, "ScanStarted", &(job,));
+                match settings.scan(handle) {
+                    Ok(path) => emit(
+                        &connection,
+                        "ScanCompleted",
+                        &(job, path.display().to_string()),
+                    ),
+                    Err(e) => emit(&connection, "ScanFailed", &(job, e.to_string())),
+                }
+            });
+            self.tasks
+                .lock()
+                .unwrap()
+                .send(task)
+                .map_err(|_| fdo::Error::Failed("Device is shutting down".to_owned()))?;
+            Ok(job)
+        }
+
+        #[dbus_interface(signal)]
+        async fn scan_started(ctxt: &zbus::SignalContext<'_>, job: u32) -> zbus::Result<()>;
+
+        #[dbus_interface(signal)]
+        async fn scan_completed(
+            ctxt: &zbus::SignalContext<'_>,
+            job: u32,
+            path: &str,
+        ) -> zbus::Result<()>;
+
+        #[dbus_interface(signal)]
+        async fn scan_failed(
+            ctxt: &zbus::SignalContext<'_>,
+            job: u32,
+            error: &str,
+        ) -> zbus::Result<()>;
+    }
+
+    pub fn run(
+        context: &Context,
+        handle: &Handle,
+        opts: &DbusOptions,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let profiles = match &opts.profiles {
+            Some(path) => profile::load(Path::new(path))?,
+            None => BTreeMap::new(),
+        };
+
+        let (tasks, rx) = channel::<Task>();
+        let scanner = Scanner {
+            tasks: Mutex::new(tasks),
+            connection: Mutex::new(None),
+            profiles,
+            dir: PathBuf::from(&opts.dir),
+            jobs: AtomicU32::new(0),
+        };
+
+        let builder = if opts.system {
+            zbus::blocking::ConnectionBuilder::system()?
+        } else {
+            zbus::blocking::ConnectionBuilder::session()?
+        };
+        let connection = builder.name(NAME)?.serve_at(PATH, scanner)?.build()?;
+
+        let iface = connection.object_server().interface::<_, Scanner>(PATH)?;
+        *iface.get().connection.lock().unwrap() = Some(connection.clone());
+
+        let stop = crate::stop_on_ctrlc();
+        tracing::info!("Registered {} on D-Bus, interrupt with ctrl-c", NAME);
+        device_thread::serve(context, handle, &rx, &stop);
+        Ok(())
+    }
+}
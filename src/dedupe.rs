@@ -0,0 +1,116 @@
+//! Detection of pages scanned twice
+//!
+//! Pages are compared by a difference hash: the page is shrunk to 9x8 gray
+//! pixels, and each bit tells whether a pixel is brighter than its right
+//! neighbour. Rescans of the same sheet differ in noise and a little in
+//! position, which changes few bits, while different pages change about
+//! half of them.
+
+use std::str::FromStr;
+
+use image::imageops::FilterType;
+use skanny::Image;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PageHash(u64);
+
+impl PageHash {
+    pub fn of(image: &Image) -> Self {
+        let gray = image.to_dynamic().to_luma8();
+        let small = image::imageops::resize(&gray, 9, 8, FilterType::Triangle);
+        let mut hash = 0;
+        for y in 0..8 {
+            for x in 0..8 {
+                let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+                hash = hash << 1 | brighter as u64;
+            }
+        }
+        PageHash(hash)
+    }
+
+    /// Number of differing bits
+    pub fn distance(self, other: Self) -> u32 {
+        (self.0 ^ other.0).count_ones()
+    }
+}
+
+/// What to do with a page which looks like the previous one
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Drop the page
+    Skip,
+    /// Keep the page, but warn about it
+    Flag,
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Action::Skip),
+            "flag" => Ok(Action::Flag),
+            _ => Err(format!("Unknown action {}, expected skip or flag", s)),
+        }
+    }
+}
+
+/// Compares each page of a batch with the one before
+pub struct Dedupe {
+    pub action: Action,
+    /// Largest distance at which pages count as the same
+    pub distance: u32,
+    previous: Option<PageHash>,
+}
+
+impl Dedupe {
+    pub fn new(action: Action, distance: u32) -> Self {
+        Self {
+            action,
+            distance,
+            previous: None,
+        }
+    }
+
+    /// Whether `image` looks like the previous page
+    pub fn is_duplicate(&mut self, image: &Image) -> bool {
+        let hash = PageHash::of(image);
+        let previous = self.previous.replace(hash);
+        matches!(previous, Some(previous) if previous.distance(hash) <= self.distance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(f: impl Fn(u32, u32) -> u8) -> Image {
+        Image::Gray8(image::ImageBuffer::from_fn(90, 80, |x, y| {
+            image::Luma([f(x, y)])
+        }))
+    }
+
+    #[test]
+    fn rescans_are_duplicates() {
+        let mut dedupe = Dedupe::new(Action::Skip, 4);
+        let stripes = |x: u32, y: u32| {
+            if (x / 7 + y / 11).is_multiple_of(2) {
+                20
+            } else {
+                230
+            }
+        };
+        assert!(!dedupe.is_duplicate(&page(stripes)));
+        // The same sheet with some noise
+        assert!(dedupe.is_duplicate(&page(|x, y| {
+            stripes(x, y).saturating_add(((x * 31 + y * 17) % 5) as u8)
+        })));
+        assert!(!dedupe.is_duplicate(&page(|x, y| {
+            if (x / 13 + y / 5).is_multiple_of(2) {
+                230
+            } else {
+                20
+            }
+        })));
+    }
+}
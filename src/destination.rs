@@ -0,0 +1,116 @@
+//! Places completed scans are shipped to after being stored locally
+//!
+//! Destinations are given as URLs, e.g. `s3://bucket/prefix/`,
+//! `davs://cloud.example.com/remote.php/dav/files/me/Scans/`,
+//! `paperlesss://TOKEN@dms.example.com/` or
+//! `smtp://mail.example.com/?from=scanner@example.com&to=me@example.com`.
+
+use std::path::Path;
+use std::time::Duration;
+
+mod email;
+mod paperless;
+mod s3;
+mod webdav;
+
+pub trait Destination {
+    /// Uploads the file at `path`, returning the location it was stored at
+    fn store(&self, path: &Path) -> Result<String, Box<dyn std::error::Error>>;
+}
+
+/// Number of attempts made by [`store_all`] for each destination
+const ATTEMPTS: u32 = 4;
+
+pub fn open(url: &str) -> Result<Box<dyn Destination>, Box<dyn std::error::Error>> {
+    let scheme = url.split("://").next().unwrap_or("");
+    match scheme {
+        "s3" => Ok(Box::new(s3::S3::from_url(url)?)),
+        "dav" | "davs" => Ok(Box::new(webdav::WebDav::from_url(url)?)),
+        "smtp" | "smtps" => Ok(Box::new(email::Email::from_url(url)?)),
+        "paperless" | "paperlesss" => Ok(Box::new(paperless::Paperless::from_url(url)?)),
+        _ => Err(format!("Unsupported destination {}", url).into()),
+    }
+}
+
+/// Uploads `path` to every destination, retrying failed uploads with
+/// exponential backoff
+pub fn store_all(urls: &[String], path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut locations = Vec::with_capacity(urls.len());
+    for url in urls {
+        let destination = open(url)?;
+        let mut delay = Duration::from_secs(1);
+        let mut attempt = 1;
+        let location = loop {
+            match destination.store(path) {
+                Ok(location) => break location,
+                Err(e) if attempt < ATTEMPTS => {
+                    tracing::warn!(
+                        "Uploading {} to {} failed ({}), retrying in {:?}",
+                        path.display(),
+                        url,
+                        e,
+                        delay
+                    );
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        locations.push(location);
+    }
+    Ok(locations)
+}
+
+/// MIME type of a stored scan, judged by its extension
+pub fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("tif") | Some("tiff") => "image/tiff",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Percent-encodes everything except the unreserved characters of URLs
+#[cfg(any(feature = "s3", feature = "webdav"))]
+pub fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Decodes `%XX` escapes and `+` as used in URL query parameters
+pub fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex: Vec<u8> = iter.clone().take(2).collect();
+                match std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                {
+                    Some(decoded) if hex.len() == 2 => {
+                        bytes.push(decoded);
+                        iter.nth(1);
+                    }
+                    _ => bytes.push(b),
+                }
+            }
+            b'+' => bytes.push(b' '),
+            _ => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
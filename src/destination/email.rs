@@ -0,0 +1,145 @@
+//! Sending scans as email attachments
+//!
+//! `smtp://[USER[:PASSWORD]@]HOST[:PORT]/?from=ADDRESS&to=ADDRESS[&to=...]`
+//! connects with STARTTLS (port 587 by default), `smtps://` with implicit
+//! TLS (port 465). The password may also be given in `SKANNY_SMTP_PASSWORD`.
+//!
+//! `subject` and `body` parameters are templates accepting the
+//! placeholders of [`crate::template`], and default to a subject naming
+//! the file and an empty body.
+
+use std::path::Path;
+
+use super::Destination;
+
+pub struct Email {
+    #[cfg_attr(not(feature = "email"), allow(dead_code))]
+    implicit_tls: bool,
+    #[cfg_attr(not(feature = "email"), allow(dead_code))]
+    host: String,
+    #[cfg_attr(not(feature = "email"), allow(dead_code))]
+    port: Option<u16>,
+    #[cfg_attr(not(feature = "email"), allow(dead_code))]
+    credentials: Option<(String, String)>,
+    from: String,
+    to: Vec<String>,
+    subject: String,
+    body: String,
+}
+
+impl Email {
+    pub fn from_url(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let (implicit_tls, rest) = if let Some(rest) = url.strip_prefix("smtps://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("smtp://") {
+            (false, rest)
+        } else {
+            return Err("Expected an smtp:// or smtps:// url".into());
+        };
+        let (authority, query) = match rest.find(['/', '?']) {
+            Some(i) => (&rest[..i], rest[i..].trim_start_matches('/')),
+            None => (rest, ""),
+        };
+        let (userinfo, hostport) = match authority.rfind('@') {
+            Some(i) => (Some(&authority[..i]), &authority[i + 1..]),
+            None => (None, authority),
+        };
+        let (host, port) = match hostport.rfind(':') {
+            Some(i) => (&hostport[..i], Some(hostport[i + 1..].parse()?)),
+            None => (hostport, None),
+        };
+        let credentials = userinfo.map(|userinfo| {
+            let mut split = userinfo.splitn(2, ':');
+            let user = super::percent_decode(split.next().unwrap_or(""));
+            let password = match split.next() {
+                Some(password) => super::percent_decode(password),
+                None => std::env::var("SKANNY_SMTP_PASSWORD").unwrap_or_default(),
+            };
+            (user, password)
+        });
+
+        let mut email = Self {
+            implicit_tls,
+            host: host.to_owned(),
+            port,
+            credentials,
+            from: String::new(),
+            to: Vec::new(),
+            subject: "Scanned document {name}".to_owned(),
+            body: String::new(),
+        };
+        for pair in query.trim_start_matches('?').split('&') {
+            if pair.is_empty() {
+                continue;
+            }
+            let mut split = pair.splitn(2, '=');
+            let key = split.next().unwrap_or("");
+            let value = super::percent_decode(split.next().unwrap_or(""));
+            match key {
+                "from" => email.from = value,
+                "to" => email.to.push(value),
+                "subject" => email.subject = value,
+                "body" => email.body = value,
+                _ => return Err(format!("Unknown parameter {}", key).into()),
+            }
+        }
+        if email.from.is_empty() || email.to.is_empty() {
+            return Err("Both from and to addresses are required".into());
+        }
+        Ok(email)
+    }
+}
+
+impl Destination for Email {
+    #[cfg(not(feature = "email"))]
+    fn store(&self, _path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+        Err("skanny was built without the email feature".into())
+    }
+
+    #[cfg(feature = "email")]
+    fn store(&self, path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+        use lettre::message::header::ContentType;
+        use lettre::message::{Attachment, MultiPart, SinglePart};
+        use lettre::transport::smtp::authentication::Credentials;
+        use lettre::{Message, SmtpTransport, Transport};
+
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or("File name is not valid UTF-8")?;
+        let now = crate::template::DateTime::now();
+
+        let mut message = Message::builder()
+            .from(self.from.parse()?)
+            .subject(crate::template::expand(&self.subject, name, &now));
+        for to in &self.to {
+            message = message.to(to.parse()?);
+        }
+        let attachment = Attachment::new(name.to_owned()).body(
+            std::fs::read(path)?,
+            ContentType::parse(super::content_type(path))?,
+        );
+        let message = message.multipart(
+            MultiPart::mixed()
+                .singlepart(SinglePart::plain(crate::template::expand(
+                    &self.body, name, &now,
+                )))
+                .singlepart(attachment),
+        )?;
+
+        let mut transport = if self.implicit_tls {
+            SmtpTransport::relay(&self.host)?
+        } else {
+            SmtpTransport::starttls_relay(&self.host)?
+        };
+        if let Some(port) = self.port {
+            transport = transport.port(port);
+        }
+        if let Some((user, password)) = &self.credentials {
+            transport = transport.credentials(Credentials::new(user.clone(), password.clone()));
+        }
+        transport.build().send(&message)?;
+
+        Ok(format!("mailto:{}", self.to.join(",")))
+    }
+}
@@ -0,0 +1,200 @@
+//! Consumption by a paperless-ngx document management system
+//!
+//! `paperless://[TOKEN@]HOST[:PORT][/PATH]/` over HTTP, or `paperlesss://`
+//! over HTTPS, posts documents to the consume API of the instance at
+//! `PATH`. Without a token in the URL, `SKANNY_PAPERLESS_TOKEN` is used.
+//!
+//! The `title` parameter and any number of `tag` parameters accept the
+//! placeholders of [`crate::template`]. Tags are given by id or by name,
+//! missing tags are created, e.g.
+//! `paperlesss://dms.example.com/?title=Scan%20{date}&tag=inbox&tag={year}`.
+
+use std::path::Path;
+
+use super::Destination;
+
+pub struct Paperless {
+    base: String,
+    #[cfg_attr(not(feature = "paperless"), allow(dead_code))]
+    token: Option<String>,
+    #[cfg_attr(not(feature = "paperless"), allow(dead_code))]
+    title: Option<String>,
+    #[cfg_attr(not(feature = "paperless"), allow(dead_code))]
+    tags: Vec<String>,
+}
+
+impl Paperless {
+    pub fn from_url(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let (scheme, rest) = if let Some(rest) = url.strip_prefix("paperlesss://") {
+            ("https", rest)
+        } else if let Some(rest) = url.strip_prefix("paperless://") {
+            ("http", rest)
+        } else {
+            return Err("Expected a paperless:// or paperlesss:// url".into());
+        };
+        let (rest, query) = match rest.find('?') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (token, host) = match authority.rfind('@') {
+            Some(i) => (
+                Some(super::percent_decode(&authority[..i])),
+                &authority[i + 1..],
+            ),
+            None => (std::env::var("SKANNY_PAPERLESS_TOKEN").ok(), authority),
+        };
+
+        let mut paperless = Self {
+            base: format!("{}://{}{}", scheme, host, path),
+            token,
+            title: None,
+            tags: Vec::new(),
+        };
+        for pair in query.split('&') {
+            if pair.is_empty() {
+                continue;
+            }
+            let mut split = pair.splitn(2, '=');
+            let key = split.next().unwrap_or("");
+            let value = super::percent_decode(split.next().unwrap_or(""));
+            match key {
+                "title" => paperless.title = Some(value),
+                "tag" => paperless.tags.push(value),
+                _ => return Err(format!("Unknown parameter {}", key).into()),
+            }
+        }
+        Ok(paperless)
+    }
+}
+
+impl Destination for Paperless {
+    #[cfg(not(feature = "paperless"))]
+    fn store(&self, _path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+        Err("skanny was built without the paperless feature".into())
+    }
+
+    #[cfg(feature = "paperless")]
+    fn store(&self, path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or("File name is not valid UTF-8")?;
+        let now = crate::template::DateTime::now();
+
+        let mut form = Form::new();
+        if let Some(title) = &self.title {
+            form.field("title", &crate::template::expand(title, name, &now));
+        }
+        for tag in &self.tags {
+            let tag = crate::template::expand(tag, name, &now);
+            let id = match tag.parse::<u64>() {
+                Ok(id) => id,
+                Err(_) => self.tag_id(&tag)?,
+            };
+            form.field("tags", &id.to_string());
+        }
+        form.file(
+            "document",
+            name,
+            super::content_type(path),
+            &std::fs::read(path)?,
+        );
+
+        let response = self
+            .request("POST", "/api/documents/post_document/")
+            .set("Content-Type", &form.content_type())
+            .send_bytes(&form.finish())?;
+        // The consume API answers with the id of the consumption task
+        let task: String = serde_json::from_str(&response.into_string()?)?;
+        Ok(format!("{}/api/tasks/?task_id={}", self.base, task))
+    }
+}
+
+#[cfg(feature = "paperless")]
+impl Paperless {
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = ureq::request(method, &format!("{}{}", self.base, path));
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Token {}", token)),
+            None => request,
+        }
+    }
+
+    /// Looks up the tag named `name`, creating it if it does not exist
+    fn tag_id(&self, name: &str) -> Result<u64, Box<dyn std::error::Error>> {
+        let response = self
+            .request("GET", "/api/tags/")
+            .query("name__iexact", name)
+            .call()?;
+        let found: serde_json::Value = serde_json::from_str(&response.into_string()?)?;
+        if let Some(id) = found["results"][0]["id"].as_u64() {
+            return Ok(id);
+        }
+
+        let response = self
+            .request("POST", "/api/tags/")
+            .set("Content-Type", "application/json")
+            .send_string(&serde_json::json!({ "name": name }).to_string())?;
+        let created: serde_json::Value = serde_json::from_str(&response.into_string()?)?;
+        created["id"]
+            .as_u64()
+            .ok_or_else(|| format!("Could not create tag {}", name).into())
+    }
+}
+
+/// A `multipart/form-data` request body
+#[cfg(feature = "paperless")]
+struct Form {
+    boundary: String,
+    body: Vec<u8>,
+}
+
+#[cfg(feature = "paperless")]
+impl Form {
+    fn new() -> Self {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or(0);
+        Self {
+            boundary: format!("skanny-{:x}", nanos),
+            body: Vec::new(),
+        }
+    }
+
+    fn content_type(&self) -> String {
+        format!("multipart/form-data; boundary={}", self.boundary)
+    }
+
+    fn field(&mut self, name: &str, value: &str) {
+        self.body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                self.boundary, name, value
+            )
+            .as_bytes(),
+        );
+    }
+
+    fn file(&mut self, name: &str, filename: &str, content_type: &str, data: &[u8]) {
+        self.body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                self.boundary, name, filename.replace('"', "_"), content_type
+            )
+            .as_bytes(),
+        );
+        self.body.extend_from_slice(data);
+        self.body.extend_from_slice(b"\r\n");
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.body
+            .extend_from_slice(format!("--{}--\r\n", self.boundary).as_bytes());
+        self.body
+    }
+}
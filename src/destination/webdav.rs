@@ -0,0 +1,139 @@
+//! WebDAV servers such as Nextcloud and ownCloud
+//!
+//! `dav://[USER[:PASSWORD]@]HOST/PATH` over HTTP, or `davs://` over HTTPS.
+//! A path ending in `/` is a folder the file is stored in, otherwise the
+//! path names the file itself. The path may contain the placeholders of
+//! [`crate::template`], e.g.
+//! `davs://cloud.example.com/remote.php/dav/files/me/Scans/{year}/{month}/`.
+//! Missing folders are created. The user and password are percent-encoded,
+//! e.g. `%40` for an `@`.
+//!
+//! Without credentials in the URL, `SKANNY_WEBDAV_TOKEN` is sent as a
+//! bearer token, or `SKANNY_WEBDAV_USER` and `SKANNY_WEBDAV_PASSWORD`
+//! as basic authentication.
+
+use std::path::Path;
+
+use super::{percent_decode, Destination};
+
+pub struct WebDav {
+    base: String,
+    path: String,
+    #[cfg_attr(not(feature = "webdav"), allow(dead_code))]
+    auth: Option<String>,
+}
+
+impl WebDav {
+    pub fn from_url(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let (scheme, rest) = if let Some(rest) = url.strip_prefix("davs://") {
+            ("https", rest)
+        } else if let Some(rest) = url.strip_prefix("dav://") {
+            ("http", rest)
+        } else {
+            return Err("Expected a dav:// or davs:// url".into());
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (userinfo, host) = match authority.rfind('@') {
+            Some(i) => (Some(&authority[..i]), &authority[i + 1..]),
+            None => (None, authority),
+        };
+
+        let auth = match userinfo {
+            Some(userinfo) => {
+                let mut split = userinfo.splitn(2, ':');
+                let user = percent_decode(split.next().unwrap_or(""));
+                let password = match split.next() {
+                    Some(password) => percent_decode(password),
+                    None => std::env::var("SKANNY_WEBDAV_PASSWORD").unwrap_or_default(),
+                };
+                Some(basic_auth(&user, &password))
+            }
+            None => {
+                if let Ok(token) = std::env::var("SKANNY_WEBDAV_TOKEN") {
+                    Some(format!("Bearer {}", token))
+                } else if let Ok(user) = std::env::var("SKANNY_WEBDAV_USER") {
+                    let password = std::env::var("SKANNY_WEBDAV_PASSWORD").unwrap_or_default();
+                    Some(basic_auth(&user, &password))
+                } else {
+                    None
+                }
+            }
+        };
+
+        Ok(Self {
+            base: format!("{}://{}", scheme, host),
+            path: path.to_owned(),
+            auth,
+        })
+    }
+}
+
+#[cfg(feature = "webdav")]
+fn basic_auth(user: &str, password: &str) -> String {
+    use base64::Engine;
+    let credentials = format!("{}:{}", user, password);
+    format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode(credentials)
+    )
+}
+
+#[cfg(not(feature = "webdav"))]
+fn basic_auth(_user: &str, _password: &str) -> String {
+    String::new()
+}
+
+impl Destination for WebDav {
+    #[cfg(not(feature = "webdav"))]
+    fn store(&self, _path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+        Err("skanny was built without the webdav feature".into())
+    }
+
+    #[cfg(feature = "webdav")]
+    fn store(&self, path: &Path) -> Result<String, Box<dyn std::error::Error>> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or("File name is not valid UTF-8")?;
+        let mut remote =
+            crate::template::expand(&self.path, name, &crate::template::DateTime::now());
+        if remote.ends_with('/') {
+            remote.push_str(name);
+        }
+        let remote: String = remote
+            .split('/')
+            .map(super::percent_encode)
+            .collect::<Vec<_>>()
+            .join("/");
+
+        // Create the enclosing folders, MKCOL fails with 405 if they exist
+        let folders: Vec<&str> = remote.split('/').filter(|s| !s.is_empty()).collect();
+        let mut folder = String::new();
+        for segment in &folders[..folders.len().saturating_sub(1)] {
+            folder.push('/');
+            folder.push_str(segment);
+            match self.request("MKCOL", &format!("{}/", folder)).call() {
+                Ok(_) | Err(ureq::Error::Status(405, _)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let file = std::fs::File::open(path)?;
+        self.request("PUT", &remote).send(file)?;
+        Ok(format!("{}{}", self.base, remote))
+    }
+}
+
+#[cfg(feature = "webdav")]
+impl WebDav {
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = ureq::request(method, &format!("{}{}", self.base, path));
+        match &self.auth {
+            Some(auth) => request.set("Authorization", auth),
+            None => request,
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Cache of the devices SANE finds
+//!
+//! Listing the devices can take ten seconds or more while SANE probes the
+//! network, so the list is kept for `--device-cache-ttl` seconds, in memory
+//! and, with `--device-cache FILE`, on disk for the next runs:
+//!
+//! ```text
+//! skanny --include-network --device-cache ~/.cache/skanny/devices.json devices
+//! ```
+//!
+//! Lists with and without the network devices are kept apart. `--refresh`
+//! ignores the cached list and looks for the devices again. The command
+//! line shows a spinner on terminals while SANE looks, and the server keeps
+//! the list in memory only, looking again for `GET /devices?refresh`.
+
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use skanny::backend::DeviceInfo;
+use skanny::Context;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Listing {
+    /// When SANE listed the devices
+    listed: SystemTime,
+    /// Whether network devices were looked for
+    network: bool,
+    devices: Vec<DeviceInfo>,
+}
+
+pub struct DeviceCache {
+    /// Where the list is kept between runs, if anywhere
+    path: Option<PathBuf>,
+    ttl: Duration,
+    listings: Mutex<Vec<Listing>>,
+}
+
+impl DeviceCache {
+    /// Reads the lists kept in `path`, unless `refresh` asks for new ones
+    pub fn new(path: Option<&Path>, ttl: Duration, refresh: bool) -> Self {
+        let listings = match path {
+            Some(path) if !refresh => load(path),
+            _ => Vec::new(),
+        };
+        Self {
+            path: path.map(Path::to_owned),
+            ttl,
+            listings: Mutex::new(listings),
+        }
+    }
+
+    /// The devices of SANE, those on the network if `network_timeout` is
+    /// given, listed again once the cached ones are too old
+    pub fn devices(
+        &self,
+        context: &Context,
+        network_timeout: Option<Duration>,
+    ) -> Result<Vec<DeviceInfo>, skanny::Error> {
+        self.get_or_list(network_timeout.is_some(), SystemTime::now(), || {
+            let devices = match network_timeout {
+                Some(timeout) => context.devices_with_timeout(false, timeout)?,
+                None => context.devices(true)?.collect(),
+            };
+            Ok(devices
+                .iter()
+                .map(|device| DeviceInfo {
+                    name: device.name().to_owned(),
+                    vendor: device.vendor().to_owned(),
+                    model: device.model().to_owned(),
+                    type_: device.type_().to_owned(),
+                })
+                .collect())
+        })
+    }
+
+    /// Drops the listed devices, so the next call looks for them again
+    pub fn forget(&self) {
+        self.listings.lock().unwrap().clear();
+    }
+
+    fn get_or_list(
+        &self,
+        network: bool,
+        now: SystemTime,
+        list: impl FnOnce() -> Result<Vec<DeviceInfo>, skanny::Error>,
+    ) -> Result<Vec<DeviceInfo>, skanny::Error> {
+        let mut listings = self.listings.lock().unwrap();
+        let fresh = |listing: &Listing| {
+            now.duration_since(listing.listed)
+                .is_ok_and(|age| age < self.ttl)
+        };
+        if let Some(listing) = listings
+            .iter()
+            .find(|listing| listing.network == network && fresh(listing))
+        {
+            tracing::debug!("Using the devices listed at {:?}", listing.listed);
+            return Ok(listing.devices.clone());
+        }
+        let devices = list()?;
+        listings.retain(|listing| listing.network != network && fresh(listing));
+        listings.push(Listing {
+            listed: now,
+            network,
+            devices: devices.clone(),
+        });
+        if let Some(path) = &self.path {
+            if let Err(e) = save(path, &listings) {
+                tracing::warn!("Saving the devices to {} failed: {}", path.display(), e);
+            }
+        }
+        Ok(devices)
+    }
+}
+
+/// A missing or unreadable cache is only a reason to look again
+fn load(path: &Path) -> Vec<Listing> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            tracing::warn!("Reading the devices from {} failed: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+    serde_json::from_slice(&data).unwrap_or_else(|e| {
+        tracing::warn!("Ignoring the devices in {}: {}", path.display(), e);
+        Vec::new()
+    })
+}
+
+fn save(path: &Path, listings: &[Listing]) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let json = serde_json::to_vec_pretty(listings)?;
+    crate::output::write(path, |temporary| Ok(std::fs::write(temporary, json)?))
+}
+
+/// Runs `f` with a spinner on stderr, if it is a terminal, so slow calls do
+/// not look hung
+pub fn with_spinner<T>(message: &str, f: impl FnOnce() -> T) -> T {
+    if !std::io::stderr().is_terminal() {
+        return f();
+    }
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut stderr = std::io::stderr();
+            for frame in ['|', '/', '-', '\\'].iter().cycle() {
+                if done.load(Ordering::SeqCst) {
+                    break;
+                }
+                let _ = write!(stderr, "\r{} {}", frame, message);
+                let _ = stderr.flush();
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            // Clears the line for what is printed next
+            let _ = write!(stderr, "\r{:width$}\r", "", width = message.len() + 2);
+        });
+        let result = f();
+        done.store(true, Ordering::SeqCst);
+        result
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_devices_until_they_are_too_old() {
+        let path = std::env::temp_dir().join(format!("skanny-devices-{}.json", std::process::id()));
+        let ttl = Duration::from_secs(60);
+        let device = |name: &str| DeviceInfo {
+            name: name.to_owned(),
+            vendor: "Noname".to_owned(),
+            model: "Scanner".to_owned(),
+            type_: "flatbed scanner".to_owned(),
+        };
+        let names = |devices: Vec<DeviceInfo>| -> Vec<String> {
+            devices.into_iter().map(|device| device.name).collect()
+        };
+        let start = SystemTime::now();
+        let cache = DeviceCache::new(Some(&path), ttl, false);
+        let local = cache.get_or_list(false, start, || Ok(vec![device("test:0")]));
+        assert_eq!(names(local.unwrap()), ["test:0"]);
+        let network = cache.get_or_list(true, start, || Ok(vec![device("net:host:test:0")]));
+        assert_eq!(names(network.unwrap()), ["net:host:test:0"]);
+
+        // Kept across runs, and listed again once too old or refreshed
+        let cache = DeviceCache::new(Some(&path), ttl, false);
+        let later = start + Duration::from_secs(30);
+        let cached = cache.get_or_list(false, later, || panic!("listed again"));
+        assert_eq!(names(cached.unwrap()), ["test:0"]);
+        let later = start + ttl;
+        let listed = cache.get_or_list(false, later, || Ok(vec![device("test:1")]));
+        assert_eq!(names(listed.unwrap()), ["test:1"]);
+        let failed = cache.get_or_list(true, later, || Err(skanny::Error::TimedOut));
+        assert_eq!(failed.unwrap_err(), skanny::Error::TimedOut);
+
+        let cache = DeviceCache::new(Some(&path), ttl, true);
+        std::fs::remove_file(&path).unwrap();
+        let refreshed = cache.get_or_list(false, later, || Ok(vec![device("test:2")]));
+        assert_eq!(names(refreshed.unwrap()), ["test:2"]);
+        cache.forget();
+        let forgotten = cache.get_or_list(false, later, || Ok(vec![device("test:3")]));
+        assert_eq!(names(forgotten.unwrap()), ["test:3"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,39 @@
+//! Execution of device work on the thread owning the SANE handle
+//!
+//! SANE handles may not be used from several threads, so services which
+//! accept requests concurrently send [`Task`]s to the thread which opened
+//! the device.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+use skanny::{Context, Handle};
+
+/// Work which needs the device
+pub type Task = Box<dyn FnOnce(&Context, &Handle) + Send>;
+
+/// Executes tasks until `stop` is raised
+pub fn serve(context: &Context, handle: &Handle, tasks: &Receiver<Task>, stop: &AtomicBool) {
+    while !stop.load(Ordering::SeqCst) {
+        if let Ok(task) = tasks.recv_timeout(Duration::from_millis(100)) {
+            task(context, handle);
+        }
+    }
+}
+
+/// Runs `f` on the device thread and waits for its result
+///
+/// Returns `None` if the device thread has stopped
+pub fn call<T, F>(tasks: &Sender<Task>, f: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Context, &Handle) -> T + Send + 'static,
+{
+    let (tx, rx) = channel();
+    let task: Task = Box::new(move |context, handle| {
+        let _ = tx.send(f(context, handle));
+    });
+    tasks.send(task).ok()?;
+    rx.recv().ok()
+}
@@ -0,0 +1,119 @@
+//! Listing of the devices skanny can find
+//!
+//! `skanny devices` lists the devices of SANE, and with `--discover` the
+//! network scanners which announce eSCL (AirScan) through mDNS or answer a
+//! WS-Discovery probe, to show what is on the network:
+//!
+//! ```text
+//! skanny devices --discover --discover-time 5
+//! ```
+//!
+//! Every device is listed with its backend, and by a name `--device` takes
+//! as it is. Scans and the `info`, `bench`, `calibrate`, `lamp` and `copy`
+//! commands work with devices of every backend, WSD scanners are only
+//! listed. Synthetic `mock:NAME` devices exist for any name and are not
+//! listed. The devices of SANE come from the cache of
+//! [`crate::device_cache`], `--refresh` looks for them again.
+
+use std::time::Duration;
+
+use gumdrop::Options;
+use skanny::backend::{BackendError, DeviceInfo};
+use skanny::Context;
+
+use crate::device_cache::{with_spinner, DeviceCache};
+
+#[derive(Debug, Options)]
+pub struct DevicesOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "Also look for eSCL and WSD scanners on the network")]
+    discover: bool,
+    #[options(
+        help = "Listen for network scanners this long",
+        meta = "SECS",
+        default = "3"
+    )]
+    discover_time: u64,
+}
+
+#[cfg(feature = "escl")]
+fn escl_devices(time: Duration) -> Result<Vec<DeviceInfo>, BackendError> {
+    use skanny::backend::ScannerBackend;
+    skanny::escl::EsclBackend { browse_time: time }.enumerate()
+}
+
+#[cfg(not(feature = "escl"))]
+fn escl_devices(_time: Duration) -> Result<Vec<DeviceInfo>, BackendError> {
+    tracing::warn!("skanny was built without the escl feature, eSCL scanners are not looked for");
+    Ok(Vec::new())
+}
+
+/// Prints a device as every command listing them does
+pub fn print(device: &DeviceInfo) {
+    println!("Device:");
+    println!("\tname: {}", device.name);
+    println!("\tvendor: {}", device.vendor);
+    println!("\tmodel: {}", device.model);
+    println!("\ttype: {}", device.type_);
+    println!("\tbackend: {}", device.backend());
+}
+
+/// Lists the devices of SANE, those on the network if `network_timeout`
+/// is given, and the discovered ones
+pub fn run(
+    context: &Context,
+    cache: &DeviceCache,
+    network_timeout: Option<Duration>,
+    opts: &DevicesOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let time = Duration::from_secs(opts.discover_time);
+    // The network is browsed while SANE looks for its devices
+    let (sane, discovered) = std::thread::scope(|scope| {
+        // Errors are not sent between threads
+        let escl = opts
+            .discover
+            .then(|| scope.spawn(|| escl_devices(time).map_err(|e| e.to_string())));
+        let wsd = opts
+            .discover
+            .then(|| scope.spawn(|| skanny::wsd::discover(time).map_err(|e| e.to_string())));
+        let sane = with_spinner("Looking for devices", || {
+            cache.devices(context, network_timeout)
+        });
+        let mut discovered = Vec::new();
+        for (protocol, browse) in [("eSCL", escl), ("WSD", wsd)] {
+            match browse.map(|browse| browse.join().expect("discovery panicked")) {
+                Some(Ok(devices)) => discovered.extend(devices),
+                Some(Err(e)) => tracing::warn!("Looking for {} scanners failed: {}", protocol, e),
+                None => {}
+            }
+        }
+        (sane, discovered)
+    });
+    for device in sane?.iter().chain(&discovered) {
+        print(device);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use skanny::backend::{backend_of, ScannerBackend};
+    use skanny::mock::{DeviceSpec, MockBackend};
+
+    #[test]
+    fn tags_devices_with_their_backend() {
+        let spec = DeviceSpec {
+            name: "mock:demo".to_owned(),
+            ..DeviceSpec::default()
+        };
+        let devices = MockBackend::new(vec![spec]).enumerate().unwrap();
+        assert_eq!(devices[0].backend(), "mock");
+        assert_eq!(backend_of("escl:http://192.168.1.20:80/eSCL"), "escl");
+        assert_eq!(backend_of("wsd:http://192.168.1.30:5358/wsd"), "wsd");
+        assert_eq!(backend_of("net:scanhost:pixma:04A9176D"), "net");
+        assert_eq!(backend_of("pixma:04A9176D"), "sane");
+        assert_eq!(backend_of("test:0"), "sane");
+        assert_eq!(backend_of("mock"), "sane");
+    }
+}
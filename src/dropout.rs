@@ -0,0 +1,125 @@
+//! Removal of coloured form lines
+//!
+//! Forms are printed with lines in a colour the scanner can drop, so only
+//! what was filled in is left for binarization and OCR. Scanners which can
+//! drop a colour themselves, such as the Fujitsu and Epson ones, are asked to.
+//! Otherwise the page is scanned in colour and only the channel of the
+//! dropped colour is kept, in which lines of that colour are as bright as the
+//! paper.
+
+use std::str::FromStr;
+
+use skanny::backend::{Constraint, ScannerDevice};
+use skanny::{Image, OptionValue};
+
+/// Names of the options backends drop colours with
+pub const OPTIONS: &[&str] = &["dropout", "dropoutcolor", "dropout-color", "color-filter"];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Channel {
+    Red,
+    Green,
+    Blue,
+}
+
+impl Channel {
+    fn name(self) -> &'static str {
+        match self {
+            Channel::Red => "red",
+            Channel::Green => "green",
+            Channel::Blue => "blue",
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            Channel::Red => 0,
+            Channel::Green => 1,
+            Channel::Blue => 2,
+        }
+    }
+}
+
+impl FromStr for Channel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "red" => Ok(Channel::Red),
+            "green" => Ok(Channel::Green),
+            "blue" => Ok(Channel::Blue),
+            _ => Err(format!("Unknown colour {}, expected red, green or blue", s)),
+        }
+    }
+}
+
+/// Sets the dropout option of the device if it has one, returns whether the
+/// device drops the colour itself
+pub fn hardware(device: &dyn ScannerDevice, channel: Channel) -> bool {
+    let options = match device.options() {
+        Ok(options) => options,
+        Err(e) => {
+            tracing::warn!("Could not list the options: {}", e);
+            return false;
+        }
+    };
+    for option in options {
+        if !OPTIONS.contains(&&option.name[..]) {
+            continue;
+        }
+        let value = match &option.constraint {
+            Constraint::StringList(values) => values
+                .iter()
+                .find(|value| value.eq_ignore_ascii_case(channel.name())),
+            _ => None,
+        };
+        if let Some(value) = value {
+            match device.set_option(&option.name, &OptionValue::String(value.clone())) {
+                Ok(()) => {
+                    tracing::debug!("Dropping {} with {}", channel.name(), option.name);
+                    return true;
+                }
+                Err(e) => tracing::warn!("Could not set {}: {}", option.name, e),
+            }
+        }
+    }
+    false
+}
+
+/// Keeps only the channel of the dropped colour, as a gray image
+pub fn apply(image: Image, channel: Channel) -> Image {
+    match image {
+        Image::Rgb8(rgb) => Image::Gray8(image::ImageBuffer::from_fn(
+            rgb.width(),
+            rgb.height(),
+            |x, y| image::Luma([rgb.get_pixel(x, y)[channel.index()]]),
+        )),
+        Image::Rgb16(rgb) => apply(Image::Rgb16(rgb).into_8bit(), channel),
+        image => {
+            tracing::warn!("Colours can only be dropped from colour scans");
+            image
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_red_lines() {
+        // Red form lines with black writing over them on white paper
+        let form = Image::Rgb8(image::ImageBuffer::from_fn(40, 40, |x, y| {
+            if x == 20 {
+                image::Rgb([10, 10, 10])
+            } else if y % 10 == 0 {
+                image::Rgb([230, 40, 40])
+            } else {
+                image::Rgb([245, 245, 245])
+            }
+        }));
+        let gray = apply(form, Channel::Red).to_dynamic().to_luma8();
+        assert!(gray.get_pixel(5, 10)[0] > 200);
+        assert!(gray.get_pixel(20, 10)[0] < 50);
+    }
+}
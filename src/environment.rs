@@ -0,0 +1,132 @@
+//! Option values from the environment
+//!
+//! `SKANNY_OPT_resolution=600` sets the resolution of every scan, which
+//! suits containers and CI jobs where the command line is fixed. Dashes in
+//! option names may be written as underscores, as in
+//! `SKANNY_OPT_page_height=355.6`. The environment overrides profiles and
+//! `--restore`, while options given with a request, or flags such as
+//! `--paper`, override the environment.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+
+use sane_sys::*;
+use skanny::backend::{BackendError, OptionInfo, ScannerDevice};
+use skanny::OptionValue;
+
+const PREFIX: &str = "SKANNY_OPT_";
+
+/// Values keyed by the option names in the variables
+pub type Variables = BTreeMap<String, String>;
+
+pub fn variables() -> Variables {
+    parse(std::env::vars_os())
+}
+
+fn parse(vars: impl Iterator<Item = (OsString, OsString)>) -> Variables {
+    vars.filter_map(|(key, value)| {
+        let name = key.to_str()?.strip_prefix(PREFIX)?;
+        match value.into_string() {
+            Ok(value) => Some((name.to_owned(), value)),
+            Err(_) => {
+                tracing::warn!("Ignoring {}{}, which is not UTF-8", PREFIX, name);
+                None
+            }
+        }
+    })
+    .collect()
+}
+
+/// The value the environment gives `option`, if any
+pub fn value(
+    variables: &Variables,
+    option: &OptionInfo,
+) -> Result<Option<OptionValue>, BackendError> {
+    let underscored = option.name.replace('-', "_");
+    let (name, value) = match variables
+        .get_key_value(&option.name)
+        .or_else(|| variables.get_key_value(&underscored))
+    {
+        Some(variable) => variable,
+        None => return Ok(None),
+    };
+    match OptionValue::parse(option.type_, value) {
+        Ok(value) => Ok(Some(value)),
+        Err(_) => Err(format!(
+            "{}{}={} is not a valid value for {}",
+            PREFIX, name, value, option.name
+        )
+        .into()),
+    }
+}
+
+/// Sets the options given in the environment, warning about variables for
+/// options the device does not have
+pub fn apply(device: &dyn ScannerDevice) -> Result<(), BackendError> {
+    let variables = variables();
+    if variables.is_empty() {
+        return Ok(());
+    }
+    let mut used = Vec::new();
+    for option in device.options()? {
+        if option.cap & SANE_CAP_INACTIVE as SANE_Int != 0 {
+            continue;
+        }
+        if let Some(value) = value(&variables, &option)? {
+            tracing::debug!("Setting {} to {} from the environment", option.name, value);
+            device.set_option(&option.name, &value)?;
+            used.push(option.name.replace('-', "_"));
+        }
+    }
+    for name in variables.keys() {
+        if !used.contains(&name.replace('-', "_")) {
+            tracing::warn!("Ignoring {}{}, the device has no such option", PREFIX, name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use skanny::backend::Constraint;
+
+    #[test]
+    fn finds_options_in_variables() {
+        let variables = parse(
+            vec![
+                ("SKANNY_OPT_page_height", "355.6"),
+                ("SKANNY_OPT_resolution", "600"),
+                ("PATH", "/usr/bin"),
+            ]
+            .into_iter()
+            .map(|(key, value)| (key.into(), value.into())),
+        );
+        assert_eq!(variables.len(), 2);
+
+        let option = |name: &str, type_| OptionInfo {
+            name: name.to_owned(),
+            title: name.to_owned(),
+            desc: String::new(),
+            type_,
+            unit: SANE_Unit_SANE_UNIT_NONE,
+            cap: SANE_CAP_SOFT_SELECT as SANE_Int,
+            constraint: Constraint::None,
+            value: None,
+        };
+        let height = option("page-height", SANE_Value_Type_SANE_TYPE_FIXED);
+        assert_eq!(
+            value(&variables, &height).unwrap(),
+            Some(OptionValue::Fixed(355.6))
+        );
+        let resolution = option("resolution", SANE_Value_Type_SANE_TYPE_INT);
+        assert_eq!(
+            value(&variables, &resolution).unwrap(),
+            Some(OptionValue::Int(600))
+        );
+        let mode = option("mode", SANE_Value_Type_SANE_TYPE_STRING);
+        assert_eq!(value(&variables, &mode).unwrap(), None);
+        let preview = option("resolution", SANE_Value_Type_SANE_TYPE_BOOL);
+        assert!(value(&variables, &preview).is_err());
+    }
+}
@@ -0,0 +1,576 @@
+//! Client side of the eSCL (AirScan) protocol
+//!
+//! Network scanners and multi-function printers announce themselves as
+//! `_uscan._tcp` (or `_uscans._tcp` over TLS) through mDNS. Their devices
+//! are named `escl:URL`, where the URL is the root of the eSCL resources,
+//! such as `escl:http://192.168.1.20:80/eSCL`.
+//!
+//! The device is presented with SANE style options (`source`, `mode`,
+//! `resolution` and the scan area in mm) which are turned into the
+//! `ScanSettings` of a job once scanning starts. Pages are fetched through
+//! `NextDocument` and decoded, so frames are always complete 8 bit images.
+
+use std::cell::RefCell;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+use sane_sys::*;
+
+use crate::backend::{
+    BackendError, Constraint, DeviceInfo, FrameParameters, OptionInfo, ScannerBackend,
+    ScannerDevice,
+};
+pub use crate::xml::{element, elements};
+use crate::OptionValue;
+
+/// mDNS service types scanners announce themselves as
+pub const SERVICE_TYPES: [&str; 2] = ["_uscan._tcp.local.", "_uscans._tcp.local."];
+
+pub const VERSION: &str = "2.63";
+pub const NAMESPACES: &str = r#"xmlns:scan="http://schemas.hp.com/imaging/escl/2011/05/03" xmlns:pwg="http://www.pwg.org/schemas/2010/12/sm""#;
+/// eSCL measures lengths in 1/300 inch
+pub const UNITS_PER_MM: f64 = 300.0 / 25.4;
+
+/// How often to ask for a page the scanner is still busy with
+const BUSY_RETRIES: usize = 30;
+
+/// Scanners found through mDNS
+pub struct EsclBackend {
+    /// How long to listen for announcements
+    pub browse_time: Duration,
+}
+
+impl Default for EsclBackend {
+    fn default() -> Self {
+        Self {
+            browse_time: Duration::from_secs(3),
+        }
+    }
+}
+
+impl ScannerBackend for EsclBackend {
+    fn enumerate(&self) -> Result<Vec<DeviceInfo>, BackendError> {
+        let mdns = mdns_sd::ServiceDaemon::new()?;
+        let receivers = SERVICE_TYPES
+            .iter()
+            .map(|service| mdns.browse(service))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let deadline = Instant::now() + self.browse_time;
+        let mut devices = Vec::<DeviceInfo>::new();
+        while Instant::now() < deadline {
+            for (receiver, service) in receivers.iter().zip(SERVICE_TYPES.iter()) {
+                let info = match receiver.recv_timeout(Duration::from_millis(100)) {
+                    Ok(mdns_sd::ServiceEvent::ServiceResolved(info)) => info,
+                    _ => continue,
+                };
+                let address = match info
+                    .get_addresses()
+                    .iter()
+                    .min_by_key(|address| address.is_ipv6())
+                {
+                    Some(std::net::IpAddr::V6(address)) => format!("[{}]", address),
+                    Some(address) => address.to_string(),
+                    None => continue,
+                };
+                let scheme = if service.starts_with("_uscans") {
+                    "https"
+                } else {
+                    "http"
+                };
+                let root = info.get_property_val_str("rs").unwrap_or("eSCL");
+                let name = format!(
+                    "escl:{}://{}:{}/{}",
+                    scheme,
+                    address,
+                    info.get_port(),
+                    root.trim_matches('/')
+                );
+                if devices.iter().any(|device| device.name == name) {
+                    continue;
+                }
+                let instance = info.get_fullname().split("._").next().unwrap_or_default();
+                devices.push(DeviceInfo {
+                    name,
+                    vendor: info
+                        .get_property_val_str("mfg")
+                        .unwrap_or_default()
+                        .to_owned(),
+                    model: info
+                        .get_property_val_str("ty")
+                        .unwrap_or(instance)
+                        .to_owned(),
+                    type_: "eSCL scanner".to_owned(),
+                });
+            }
+        }
+        let _ = mdns.shutdown();
+        Ok(devices)
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn ScannerDevice>, BackendError> {
+        Ok(Box::new(EsclDevice::connect(name)?))
+    }
+}
+
+/// What the scanner can do, from its `ScannerCapabilities`
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    pub make_and_model: String,
+    pub resolutions: Vec<SANE_Int>,
+    /// eSCL color modes, such as `RGB24` and `Grayscale8`
+    pub color_modes: Vec<String>,
+    pub formats: Vec<String>,
+    pub platen: bool,
+    pub feeder: bool,
+    /// Largest scan area in 1/300 inch
+    pub max_width: u32,
+    pub max_height: u32,
+}
+
+impl Capabilities {
+    pub fn parse(xml: &str) -> Self {
+        let platen = element(xml, "Platen");
+        let feeder = element(xml, "Adf");
+        // The settings of the platen are usually shared by the feeder
+        let input = platen.or(feeder).unwrap_or(xml);
+
+        let mut resolutions = Vec::new();
+        for res in elements(input, "DiscreteResolution") {
+            if let Some(Ok(res)) = element(res, "XResolution").map(str::parse) {
+                if !resolutions.contains(&res) {
+                    resolutions.push(res);
+                }
+            }
+        }
+        if resolutions.is_empty() {
+            resolutions.push(300);
+        }
+        let mut formats = Vec::new();
+        for format in elements(input, "DocumentFormatExt")
+            .into_iter()
+            .chain(elements(input, "DocumentFormat"))
+        {
+            if !formats.iter().any(|f| f == format) {
+                formats.push(format.to_owned());
+            }
+        }
+
+        Self {
+            make_and_model: element(xml, "MakeAndModel").unwrap_or_default().to_owned(),
+            resolutions,
+            color_modes: elements(input, "ColorMode")
+                .into_iter()
+                .map(str::to_owned)
+                .collect(),
+            formats,
+            platen: platen.is_some(),
+            feeder: feeder.is_some(),
+            max_width: element(input, "MaxWidth")
+                .and_then(|w| w.parse().ok())
+                .unwrap_or((215.9 * UNITS_PER_MM) as u32),
+            max_height: element(input, "MaxHeight")
+                .and_then(|h| h.parse().ok())
+                .unwrap_or((297.0 * UNITS_PER_MM) as u32),
+        }
+    }
+}
+
+/// A scanner reached over eSCL, see the module documentation
+pub struct EsclDevice {
+    name: String,
+    /// Root of the eSCL resources, without a trailing slash
+    base: String,
+    agent: ureq::Agent,
+    caps: Capabilities,
+    state: RefCell<State>,
+}
+
+struct State {
+    source: &'static str,
+    mode: String,
+    resolution: SANE_Int,
+    /// tl-x, tl-y, br-x and br-y in mm
+    area: [f64; 4],
+    /// URL of the job pages are fetched from
+    job: Option<String>,
+    /// Samples of the current frame and how far they have been read
+    frame: Option<(Vec<u8>, usize)>,
+}
+
+const SOURCES: [(&str, &str); 2] = [("Flatbed", "Platen"), ("ADF", "Feeder")];
+const MODES: [(&str, &str); 2] = [("Color", "RGB24"), ("Gray", "Grayscale8")];
+const AREA: [&str; 4] = ["tl-x", "tl-y", "br-x", "br-y"];
+
+impl EsclDevice {
+    /// Opens the device named `escl:URL`, or just `URL`
+    pub fn connect(name: &str) -> Result<Self, BackendError> {
+        let base = name.strip_prefix("escl:").unwrap_or(name);
+        if !base.starts_with("http://") && !base.starts_with("https://") {
+            return Err(format!("Expected an http(s) URL for {}", name).into());
+        }
+        let base = base.trim_end_matches('/').to_owned();
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(10))
+            .build();
+        let xml = agent
+            .get(&format!("{}/ScannerCapabilities", base))
+            .call()?
+            .into_string()?;
+        let caps = Capabilities::parse(&xml);
+        if caps.formats.is_empty() {
+            return Err("Scanner supports no document formats".into());
+        }
+
+        let state = State {
+            source: if caps.platen { "Flatbed" } else { "ADF" },
+            mode: MODES
+                .iter()
+                .find(|(_, escl)| caps.color_modes.iter().any(|mode| mode == escl))
+                .map_or("Color", |(mode, _)| mode)
+                .to_owned(),
+            resolution: if caps.resolutions.contains(&300) {
+                300
+            } else {
+                caps.resolutions[0]
+            },
+            area: [
+                0.0,
+                0.0,
+                f64::from(caps.max_width) / UNITS_PER_MM,
+                f64::from(caps.max_height) / UNITS_PER_MM,
+            ],
+            job: None,
+            frame: None,
+        };
+        Ok(Self {
+            name: format!("escl:{}", base),
+            base,
+            agent,
+            caps,
+            state: RefCell::new(state),
+        })
+    }
+
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.caps
+    }
+
+    fn scan_settings(&self, state: &State) -> String {
+        let units = |mm: f64| (mm * UNITS_PER_MM).round().max(0.0) as u32;
+        let [tl_x, tl_y, br_x, br_y] = state.area;
+        let format = if self.caps.formats.iter().any(|f| f == "image/png") {
+            "image/png"
+        } else {
+            "image/jpeg"
+        };
+        let mode = MODES
+            .iter()
+            .find(|(mode, _)| *mode == state.mode)
+            .map_or("RGB24", |(_, escl)| escl);
+        let source = SOURCES
+            .iter()
+            .find(|(source, _)| *source == state.source)
+            .map_or("Platen", |(_, escl)| escl);
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?><scan:ScanSettings {}><pwg:Version>{}</pwg:Version><pwg:ScanRegions><pwg:ScanRegion><pwg:ContentRegionUnits>escl:ThreeHundredthsOfInches</pwg:ContentRegionUnits><pwg:XOffset>{}</pwg:XOffset><pwg:YOffset>{}</pwg:YOffset><pwg:Width>{}</pwg:Width><pwg:Height>{}</pwg:Height></pwg:ScanRegion></pwg:ScanRegions><pwg:InputSource>{}</pwg:InputSource><scan:ColorMode>{}</scan:ColorMode><scan:XResolution>{res}</scan:XResolution><scan:YResolution>{res}</scan:YResolution><pwg:DocumentFormat>{format}</pwg:DocumentFormat><scan:DocumentFormatExt>{format}</scan:DocumentFormatExt></scan:ScanSettings>"#,
+            NAMESPACES,
+            VERSION,
+            units(tl_x),
+            units(tl_y),
+            units(br_x - tl_x),
+            units(br_y - tl_y),
+            source,
+            mode,
+            res = state.resolution,
+            format = format,
+        )
+    }
+
+    /// Creates a job, returning its URL
+    fn create_job(&self, state: &State) -> Result<String, BackendError> {
+        let response = self
+            .agent
+            .post(&format!("{}/ScanJobs", self.base))
+            .set("Content-Type", "text/xml")
+            .send_string(&self.scan_settings(state))?;
+        let location = response
+            .header("Location")
+            .ok_or("Scanner did not return the location of the job")?
+            .trim_end_matches('/');
+        if location.starts_with("http://") || location.starts_with("https://") {
+            return Ok(location.to_owned());
+        }
+        // Relative to the host of the scanner
+        let host_end = self
+            .base
+            .find("://")
+            .and_then(|scheme| self.base[scheme + 3..].find('/').map(|i| scheme + 3 + i))
+            .unwrap_or(self.base.len());
+        Ok(format!("{}{}", &self.base[..host_end], location))
+    }
+
+    /// Fetches the next page of `job`, `None` when there are no more
+    fn next_document(&self, job: &str) -> Result<Option<Vec<u8>>, BackendError> {
+        for _ in 0..BUSY_RETRIES {
+            match self
+                .agent
+                .get(&format!("{}/NextDocument", job))
+                .timeout(Duration::from_secs(300))
+                .call()
+            {
+                Ok(response) => {
+                    let mut bytes = Vec::new();
+                    response.into_reader().read_to_end(&mut bytes)?;
+                    return Ok(Some(bytes));
+                }
+                Err(ureq::Error::Status(404, _)) => return Ok(None),
+                // Still warming up or busy with another page
+                Err(ureq::Error::Status(503, _)) => std::thread::sleep(Duration::from_secs(1)),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err("Scanner stayed busy".into())
+    }
+
+    fn delete_job(&self, job: &str) {
+        if let Err(e) = self.agent.delete(job).call() {
+            tracing::warn!("Deleting eSCL job {} failed: {}", job, e);
+        }
+    }
+}
+
+impl Drop for EsclDevice {
+    fn drop(&mut self) {
+        if let Some(job) = self.state.get_mut().job.take() {
+            self.delete_job(&job);
+        }
+    }
+}
+
+impl ScannerDevice for EsclDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn options(&self) -> Result<Vec<OptionInfo>, BackendError> {
+        let state = self.state.borrow();
+        let cap = (SANE_CAP_SOFT_SELECT | SANE_CAP_SOFT_DETECT) as SANE_Int;
+        let list = |type_, constraint, value| OptionInfo {
+            name: String::new(),
+            title: String::new(),
+            desc: String::new(),
+            type_,
+            unit: SANE_Unit_SANE_UNIT_NONE,
+            cap,
+            constraint,
+            value: Some(value),
+        };
+
+        let mut options = Vec::new();
+        let sources: Vec<String> = SOURCES
+            .iter()
+            .filter(|(_, escl)| match *escl {
+                "Platen" => self.caps.platen,
+                _ => self.caps.feeder,
+            })
+            .map(|(source, _)| source.to_string())
+            .collect();
+        options.push(OptionInfo {
+            name: "source".to_owned(),
+            title: "Scan source".to_owned(),
+            desc: "Selects the scan source, such as the document feeder".to_owned(),
+            ..list(
+                SANE_Value_Type_SANE_TYPE_STRING,
+                Constraint::StringList(sources),
+                OptionValue::String(state.source.to_owned()),
+            )
+        });
+        let modes: Vec<String> = MODES
+            .iter()
+            .filter(|(_, escl)| self.caps.color_modes.iter().any(|mode| mode == escl))
+            .map(|(mode, _)| mode.to_string())
+            .collect();
+        options.push(OptionInfo {
+            name: "mode".to_owned(),
+            title: "Scan mode".to_owned(),
+            desc: "Selects the scan mode".to_owned(),
+            ..list(
+                SANE_Value_Type_SANE_TYPE_STRING,
+                Constraint::StringList(modes),
+                OptionValue::String(state.mode.clone()),
+            )
+        });
+        options.push(OptionInfo {
+            name: "resolution".to_owned(),
+            title: "Scan resolution".to_owned(),
+            desc: "Sets the resolution of the scanned image".to_owned(),
+            unit: SANE_Unit_SANE_UNIT_DPI,
+            ..list(
+                SANE_Value_Type_SANE_TYPE_INT,
+                Constraint::WordList(self.caps.resolutions.clone()),
+                OptionValue::Int(state.resolution),
+            )
+        });
+        let max = [
+            f64::from(self.caps.max_width) / UNITS_PER_MM,
+            f64::from(self.caps.max_height) / UNITS_PER_MM,
+        ];
+        for (i, (&name, &value)) in AREA.iter().zip(state.area.iter()).enumerate() {
+            let corner = if i < 2 { "Top-left" } else { "Bottom-right" };
+            let axis = if i % 2 == 0 { "x" } else { "y" };
+            options.push(OptionInfo {
+                name: name.to_owned(),
+                title: format!("{} {}", corner, axis),
+                desc: format!("{} {} of the scan area", corner, axis),
+                unit: SANE_Unit_SANE_UNIT_MM,
+                ..list(
+                    SANE_Value_Type_SANE_TYPE_FIXED,
+                    Constraint::Range {
+                        min: 0,
+                        max: SANE_FIX(max[i % 2]),
+                        quant: 0,
+                    },
+                    OptionValue::Fixed(value),
+                )
+            });
+        }
+        Ok(options)
+    }
+
+    fn set_option(&self, name: &str, value: &OptionValue) -> Result<(), BackendError> {
+        let option = self
+            .options()?
+            .into_iter()
+            .find(|option| option.name == name)
+            .ok_or_else(|| format!("No option named {}", name))?;
+        let invalid = || crate::Error::Status(SANE_Status_SANE_STATUS_INVAL);
+        let mut state = self.state.borrow_mut();
+        match (&option.constraint, value) {
+            (Constraint::StringList(list), OptionValue::String(s)) => {
+                if !list.contains(s) {
+                    return Err(invalid().into());
+                }
+                if name == "source" {
+                    state.source = SOURCES
+                        .iter()
+                        .map(|(source, _)| *source)
+                        .find(|source| *source == s.as_str())
+                        .ok_or_else(invalid)?;
+                } else {
+                    state.mode = s.clone();
+                }
+            }
+            (Constraint::WordList(list), &OptionValue::Int(res)) => {
+                if !list.contains(&res) {
+                    return Err(invalid().into());
+                }
+                state.resolution = res;
+            }
+            (&Constraint::Range { max, .. }, &OptionValue::Int(_))
+            | (&Constraint::Range { max, .. }, &OptionValue::Fixed(_)) => {
+                let mm = match *value {
+                    OptionValue::Int(i) => f64::from(i),
+                    OptionValue::Fixed(f) => f,
+                    _ => unreachable!(),
+                };
+                let index = AREA.iter().position(|&area| area == name).unwrap();
+                state.area[index] = mm.max(0.0).min(SANE_UNFIX(max));
+            }
+            _ => return Err(crate::Error::WrongType.into()),
+        }
+        Ok(())
+    }
+
+    fn start(&self) -> Result<FrameParameters, BackendError> {
+        let mut state = self.state.borrow_mut();
+        state.frame = None;
+        let job = match state.job.take() {
+            Some(job) => job,
+            None => self.create_job(&state)?,
+        };
+        let bytes = match self.next_document(&job)? {
+            Some(bytes) => bytes,
+            None => return Err(crate::Error::Status(SANE_Status_SANE_STATUS_NO_DOCS).into()),
+        };
+        // Only the feeder has more pages to come
+        if state.source == "ADF" {
+            state.job = Some(job);
+        }
+
+        let image = image::load_from_memory(&bytes)?;
+        let (format, channels, width, height, data) = if state.mode == "Gray" {
+            let image = image.to_luma8();
+            let (width, height) = image.dimensions();
+            (
+                SANE_Frame_SANE_FRAME_GRAY,
+                1,
+                width,
+                height,
+                image.into_raw(),
+            )
+        } else {
+            let image = image.to_rgb8();
+            let (width, height) = image.dimensions();
+            (
+                SANE_Frame_SANE_FRAME_RGB,
+                3,
+                width,
+                height,
+                image.into_raw(),
+            )
+        };
+        state.frame = Some((data, 0));
+        Ok(FrameParameters {
+            format,
+            last_frame: true,
+            bytes_per_line: (width * channels) as SANE_Int,
+            pixels_per_line: width as SANE_Int,
+            lines: height as SANE_Int,
+            depth: 8,
+        })
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, BackendError> {
+        let mut state = self.state.borrow_mut();
+        let (data, offset) = state
+            .frame
+            .as_mut()
+            .ok_or(crate::Error::Status(SANE_Status_SANE_STATUS_INVAL))?;
+        let len = buffer.len().min(data.len() - *offset);
+        buffer[..len].copy_from_slice(&data[*offset..*offset + len]);
+        *offset += len;
+        Ok(len)
+    }
+
+    fn cancel(&self) {
+        let mut state = self.state.borrow_mut();
+        let unfinished = match state.frame.take() {
+            Some((data, offset)) => offset < data.len(),
+            None => false,
+        };
+        // A page which was not read completely aborts the batch
+        if unfinished {
+            if let Some(job) = state.job.take() {
+                self.delete_job(&job);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capabilities() {
+        let xml = r#"<?xml version="1.0"?><scan:ScannerCapabilities><pwg:MakeAndModel>Acme MFP</pwg:MakeAndModel><scan:Platen><scan:PlatenInputCaps><scan:MaxWidth>2550</scan:MaxWidth><scan:MaxHeight>3508</scan:MaxHeight><scan:SettingProfiles><scan:SettingProfile><scan:ColorModes><scan:ColorMode>RGB24</scan:ColorMode><scan:ColorMode>Grayscale8</scan:ColorMode></scan:ColorModes><scan:DocumentFormats><pwg:DocumentFormat>image/jpeg</pwg:DocumentFormat></scan:DocumentFormats><scan:SupportedResolutions><scan:DiscreteResolutions><scan:DiscreteResolution><scan:XResolution>150</scan:XResolution><scan:YResolution>150</scan:YResolution></scan:DiscreteResolution><scan:DiscreteResolution><scan:XResolution>300</scan:XResolution><scan:YResolution>300</scan:YResolution></scan:DiscreteResolution></scan:DiscreteResolutions></scan:SupportedResolutions></scan:SettingProfile></scan:SettingProfiles></scan:PlatenInputCaps></scan:Platen></scan:ScannerCapabilities>"#;
+        let caps = Capabilities::parse(xml);
+        assert_eq!(caps.make_and_model, "Acme MFP");
+        assert_eq!(caps.resolutions, [150, 300]);
+        assert_eq!(caps.color_modes, ["RGB24", "Grayscale8"]);
+        assert_eq!(caps.formats, ["image/jpeg"]);
+        assert!(caps.platen && !caps.feeder);
+        assert_eq!((caps.max_width, caps.max_height), (2550, 3508));
+    }
+}
@@ -0,0 +1,78 @@
+//! Lifecycle events of scans, reported to external systems
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use skanny::Image;
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    ButtonPressed {
+        button: &'a str,
+    },
+    PageScanned {
+        path: &'a Path,
+        #[serde(skip)]
+        image: &'a Image,
+    },
+    JobCompleted {
+        device: &'a str,
+        profile: &'a str,
+        pages: usize,
+        files: &'a [PathBuf],
+        /// Where the files were uploaded to, see [`crate::destination`]
+        locations: &'a [String],
+        duration_secs: f64,
+    },
+    JobFailed {
+        device: &'a str,
+        profile: &'a str,
+        message: String,
+        duration_secs: f64,
+    },
+    DeviceAdded {
+        device: &'a str,
+    },
+    DeviceRemoved {
+        device: &'a str,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl Event<'_> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ButtonPressed { .. } => "button_pressed",
+            Event::PageScanned { .. } => "page_scanned",
+            Event::JobCompleted { .. } => "job_completed",
+            Event::JobFailed { .. } => "job_failed",
+            Event::DeviceAdded { .. } => "device_added",
+            Event::DeviceRemoved { .. } => "device_removed",
+            Event::Error { .. } => "error",
+        }
+    }
+}
+
+pub trait Sink {
+    /// Reports the event, failures are logged rather than returned as
+    /// reporting must not interrupt scanning
+    fn send(&self, event: &Event);
+}
+
+/// All configured receivers of events
+#[derive(Default)]
+pub struct Sinks(Vec<Box<dyn Sink>>);
+
+impl Sinks {
+    pub fn push(&mut self, sink: impl Sink + 'static) {
+        self.0.push(Box::new(sink))
+    }
+    pub fn send(&self, event: &Event) {
+        for sink in &self.0 {
+            sink.send(event);
+        }
+    }
+}
@@ -0,0 +1,367 @@
+//! Scanned samples without decoding
+//!
+//! A [`Frame`] holds the samples as the device sent them, lines padded to
+//! [`Frame::stride`] bytes. It does not need the `image` crate, so embedded
+//! pipelines can build skanny without the `image` feature and hand the
+//! samples to their own code. With the feature, [`Frame::into_image`] turns
+//! the frame into an [`crate::Image`].
+//!
+//! Some backends send frames in formats beyond gray and RGB, such as the
+//! JPEG files of some network scanners. [`Frame::decode`] decodes JPEG
+//! frames with the `image` feature and gives back the data of others as an
+//! [`UnknownFrame`], which is written to the directory given to
+//! [`dump_unknown_frames`] so it can be looked into.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+use sane_sys::*;
+
+use crate::backend::FrameParameters;
+
+/// How the channels of a pixel are laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Gray,
+    /// Red, green and blue samples of a pixel after each other
+    Rgb,
+}
+
+impl Layout {
+    pub fn channels(self) -> usize {
+        match self {
+            Layout::Gray => 1,
+            Layout::Rgb => 3,
+        }
+    }
+}
+
+/// The samples of a single-pass frame
+///
+/// 16 bit samples are in the byte order of the host. 1 bit samples are
+/// packed eight to a byte, the first pixel in the highest bit and 1 for
+/// black.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    layout: Layout,
+    depth: u8,
+    width: u32,
+    height: u32,
+    stride: usize,
+    data: Vec<u8>,
+}
+
+impl Frame {
+    /// Wraps the samples read for a frame, if its format and depth are
+    /// supported
+    ///
+    /// The number of lines is taken from `data`, as it may be unknown until
+    /// the frame has been read, and an incomplete line at the end is
+    /// dropped.
+    pub fn new(parameters: &FrameParameters, mut data: Vec<u8>) -> Option<Self> {
+        #[allow(non_upper_case_globals)]
+        let layout = match parameters.format {
+            SANE_Frame_SANE_FRAME_GRAY => Layout::Gray,
+            SANE_Frame_SANE_FRAME_RGB => Layout::Rgb,
+            _ => return None,
+        };
+        let depth = match parameters.depth {
+            1 if layout == Layout::Gray => 1,
+            8 => 8,
+            16 => 16,
+            _ => return None,
+        };
+        let stride = parameters.bytes_per_line.max(0) as usize;
+        let mut frame = Self {
+            layout,
+            depth,
+            width: parameters.pixels_per_line.max(0) as u32,
+            height: 0,
+            stride,
+            data: Vec::new(),
+        };
+        if stride == 0 || stride < frame.row_len() {
+            return None;
+        }
+        let lines = data.len() / stride;
+        data.truncate(lines * stride);
+        frame.height = lines as u32;
+        frame.data = data;
+        Some(frame)
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    /// Bits per sample, 1, 8 or 16
+    pub fn depth(&self) -> u8 {
+        self.depth
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Bytes from the start of a line to the next, which may be more than
+    /// the pixels of the line take
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Bytes the pixels of a line take
+    pub fn row_len(&self) -> usize {
+        let samples = self.width as usize * self.layout.channels();
+        match self.depth {
+            1 => samples.div_ceil(8),
+            depth => samples * usize::from(depth / 8),
+        }
+    }
+
+    /// All lines with their padding
+    pub fn as_raw(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_raw(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// The pixels of line `y`, without padding
+    ///
+    /// # Panics
+    ///
+    /// If `y` is not less than the height.
+    pub fn row(&self, y: u32) -> &[u8] {
+        let start = y as usize * self.stride;
+        &self.data[start..start + self.row_len()]
+    }
+
+    /// Converts to an image, dropping the padding of the lines
+    #[cfg(feature = "image")]
+    pub fn into_image(self) -> Option<crate::Image> {
+        let row = self.row_len();
+        let mut data = self.data;
+        if self.stride > row {
+            data = data
+                .chunks_exact(self.stride)
+                .flat_map(|line| line[..row].iter().copied())
+                .collect();
+        }
+        let format = match self.layout {
+            Layout::Gray => SANE_Frame_SANE_FRAME_GRAY,
+            Layout::Rgb => SANE_Frame_SANE_FRAME_RGB,
+        };
+        crate::Image::from_raw(format, self.depth.into(), self.width, self.height, data)
+    }
+}
+
+/// A frame which [`Frame::decode`] could not read, with the data as received
+#[derive(Debug, Clone)]
+pub struct UnknownFrame {
+    pub parameters: FrameParameters,
+    pub data: Vec<u8>,
+}
+
+impl UnknownFrame {
+    /// Whether the data is a JPEG file, which starts with a start of image
+    /// marker
+    pub fn is_jpeg(&self) -> bool {
+        self.data.starts_with(&[0xff, 0xd8, 0xff])
+    }
+
+    /// Writes the data to a new file in `dir`, named after the format and
+    /// the size
+    pub fn dump(&self, dir: &Path) -> std::io::Result<PathBuf> {
+        static DUMPED: AtomicUsize = AtomicUsize::new(0);
+        let path = dir.join(format!(
+            "frame-{}-{}-format-{}-depth-{}-{}x{}.{}",
+            std::process::id(),
+            DUMPED.fetch_add(1, Ordering::SeqCst),
+            self.parameters.format,
+            self.parameters.depth,
+            self.parameters.pixels_per_line,
+            self.parameters.lines,
+            if self.is_jpeg() { "jpg" } else { "raw" }
+        ));
+        std::fs::write(&path, &self.data)?;
+        Ok(path)
+    }
+}
+
+impl std::fmt::Display for UnknownFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Unsupported frame format {} with depth {}",
+            self.parameters.format, self.parameters.depth
+        )
+    }
+}
+
+impl std::error::Error for UnknownFrame {}
+
+static DUMP: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Writes frames which cannot be read to `dir`, or stops doing so
+pub fn dump_unknown_frames(dir: Option<PathBuf>) {
+    *DUMP.lock().unwrap_or_else(PoisonError::into_inner) = dir;
+}
+
+impl Frame {
+    /// Reads the data of a frame as [`Frame::new`] does, and decodes JPEG
+    /// data of other formats
+    ///
+    /// Frames which cannot be read are written raw when
+    /// [`dump_unknown_frames`] is set up.
+    pub fn decode(parameters: &FrameParameters, data: Vec<u8>) -> Result<Self, UnknownFrame> {
+        #[allow(non_upper_case_globals)]
+        let known = matches!(
+            parameters.format,
+            SANE_Frame_SANE_FRAME_GRAY | SANE_Frame_SANE_FRAME_RGB
+        );
+        if known {
+            if let Some(frame) = Frame::new(parameters, data.clone()) {
+                return Ok(frame);
+            }
+        }
+        let unknown = UnknownFrame {
+            parameters: *parameters,
+            data,
+        };
+        #[cfg(feature = "image")]
+        if !known && unknown.is_jpeg() {
+            match Frame::from_jpeg(&unknown.data) {
+                Ok(frame) => return Ok(frame),
+                Err(e) => tracing::warn!("Decoding a JPEG frame failed: {}", e),
+            }
+        }
+        let dump = DUMP.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        if let Some(dir) = dump {
+            match unknown.dump(&dir) {
+                Ok(path) => tracing::warn!("{}, wrote it to {}", unknown, path.display()),
+                Err(e) => tracing::warn!("{}, and writing it failed: {}", unknown, e),
+            }
+        }
+        Err(unknown)
+    }
+
+    /// Decodes a JPEG file into a gray or RGB frame of 8 bit samples
+    #[cfg(feature = "image")]
+    fn from_jpeg(data: &[u8]) -> image::ImageResult<Self> {
+        let image = image::load_from_memory_with_format(data, image::ImageFormat::Jpeg)?;
+        let (layout, width, height, data) = match image {
+            image::DynamicImage::ImageLuma8(image) => (
+                Layout::Gray,
+                image.width(),
+                image.height(),
+                image.into_raw(),
+            ),
+            image => {
+                let image = image.to_rgb8();
+                (Layout::Rgb, image.width(), image.height(), image.into_raw())
+            }
+        };
+        Ok(Self {
+            layout,
+            depth: 8,
+            width,
+            height,
+            stride: width as usize * layout.channels(),
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_padded_lines() {
+        let parameters = FrameParameters {
+            format: SANE_Frame_SANE_FRAME_GRAY,
+            last_frame: true,
+            bytes_per_line: 4,
+            pixels_per_line: 3,
+            lines: -1,
+            depth: 8,
+        };
+        // Two lines and the start of a third
+        let frame = Frame::new(&parameters, vec![1, 2, 3, 0, 4, 5, 6, 0, 7]).unwrap();
+        assert_eq!((frame.width(), frame.height(), frame.stride()), (3, 2, 4));
+        assert_eq!(frame.as_raw().len(), 8);
+        assert_eq!(frame.row(1), [4, 5, 6]);
+
+        let bilevel = FrameParameters {
+            depth: 1,
+            pixels_per_line: 10,
+            bytes_per_line: 2,
+            ..parameters
+        };
+        assert_eq!(Frame::new(&bilevel, vec![0; 4]).unwrap().row_len(), 2);
+        let narrow = FrameParameters {
+            bytes_per_line: 2,
+            ..parameters
+        };
+        assert!(Frame::new(&narrow, vec![0; 4]).is_none());
+        let rgb_bits = FrameParameters {
+            format: SANE_Frame_SANE_FRAME_RGB,
+            ..bilevel
+        };
+        assert!(Frame::new(&rgb_bits, vec![0; 4]).is_none());
+
+        #[cfg(feature = "image")]
+        match frame.into_image() {
+            Some(crate::Image::Gray8(image)) => assert_eq!(image.as_raw(), &[1, 2, 3, 4, 5, 6]),
+            _ => panic!("Expected a gray image"),
+        }
+    }
+
+    #[test]
+    fn decodes_jpeg_and_dumps_unknown_frames() {
+        let parameters = FrameParameters {
+            // Not a format of SANE 1
+            format: 11,
+            last_frame: true,
+            bytes_per_line: 0,
+            pixels_per_line: 2,
+            lines: 2,
+            depth: 8,
+        };
+        #[cfg(feature = "image")]
+        {
+            let mut jpeg = Vec::new();
+            image::codecs::jpeg::JpegEncoder::new(&mut jpeg)
+                .encode(&[200; 12], 2, 2, image::ColorType::Rgb8)
+                .unwrap();
+            let frame = Frame::decode(&parameters, jpeg).unwrap();
+            assert_eq!(
+                (frame.layout(), frame.width(), frame.height()),
+                (Layout::Rgb, 2, 2)
+            );
+            assert!(frame.row(1).iter().all(|&sample| sample.abs_diff(200) < 8));
+        }
+
+        let dir = std::env::temp_dir().join(format!("skanny-frame-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dump_unknown_frames(Some(dir.clone()));
+        let unknown = Frame::decode(&parameters, vec![1, 2, 3]).unwrap_err();
+        dump_unknown_frames(None);
+        let dumped: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            unknown.to_string(),
+            "Unsupported frame format 11 with depth 8"
+        );
+        assert_eq!(dumped, [vec![1, 2, 3]]);
+    }
+}
@@ -0,0 +1,403 @@
+//! gRPC service for remote control of the scanner
+//!
+//! The service is defined in `proto/skanny.proto`. As with the HTTP
+//! server, requests are handled on a separate runtime and forwarded to
+//! the thread owning the device, which scans one job at a time. Scans are
+//! refused with `RESOURCE_EXHAUSTED` while `--max-queued` jobs wait, and
+//! fail with `DEADLINE_EXCEEDED` after waiting `--queue-timeout`.
+
+use gumdrop::Options;
+use skanny::{Context, Handle};
+
+#[derive(Debug, Options)]
+pub struct GrpcOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "Address to listen on", default = "127.0.0.1:50051")]
+    listen: String,
+    #[options(help = "TOML file with scan profiles")]
+    profiles: Option<String>,
+    #[options(help = "Directory to store images", default = ".")]
+    dir: String,
+    #[options(no_short, help = "Refuse scans while this many are queued", meta = "N")]
+    max_queued: Option<usize>,
+    #[options(
+        no_short,
+        help = "Fail scans which waited this long for the device",
+        meta = "SECS"
+    )]
+    queue_timeout: Option<u64>,
+    #[options(
+        no_short,
+        help = "Append a line about every finished scan to this file",
+        meta = "FILE"
+    )]
+    audit: Option<String>,
+}
+
+#[cfg(not(feature = "grpc"))]
+pub fn run(
+    _context: &Context,
+    _handle: &Handle,
+    _opts: &GrpcOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("skanny was built without the grpc feature".into())
+}
+
+#[cfg(feature = "grpc")]
+pub use imp::run;
+
+#[cfg(feature = "grpc")]
+// tonic::Status is large, but it is what every handler returns
+#[allow(clippy::result_large_err)]
+mod imp {
+    use std::collections::{BTreeMap, HashMap};
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use skanny::{Context, Handle, OptionValue};
+    use tokio::sync::{broadcast, mpsc};
+    use tokio_stream::wrappers::{BroadcastStream, ReceiverStream};
+    use tokio_stream::{Stream, StreamExt};
+    use tonic::{Request, Response, Status};
+
+    use super::GrpcOptions;
+    use crate::audit::{Audit, Record};
+    use crate::device_thread::{self, Task};
+    use crate::jobs::JobState;
+    use crate::profile::{self, Profile};
+
+    #[allow(clippy::all)]
+    mod pb {
+        tonic::include_proto!("skanny.v1");
+    }
+
+    use pb::job_event::State;
+    use pb::scanner_server::{Scanner, ScannerServer};
+
+    /// Size of the image chunks streamed by `Scan`
+    const CHUNK_SIZE: usize = 64 * 1024;
+
+    type BoxStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send>>;
+
+    struct Service {
+        tasks: Mutex<Sender<Task>>,
+        events: broadcast::Sender<pb::JobEvent>,
+        profiles: BTreeMap<String, Profile>,
+        dir: PathBuf,
+        jobs: AtomicU32,
+        /// Number of scans waiting for the device
+        waiting: Arc<AtomicUsize>,
+        max_waiting: Option<usize>,
+        timeout: Option<Duration>,
+        audit: Option<Audit>,
+        /// Name of the device, for the audit log
+        device: String,
+    }
+
+    impl Service {
+        /// Runs `f` on the device thread without blocking the runtime
+        async fn call<T, F>(&self, f: F) -> Result<T, Status>
+        where
+            T: Send + 'static,
+            F: FnOnce(&Context, &Handle) -> Result<T, String> + Send + 'static,
+        {
+            let tasks = self.tasks.lock().unwrap().clone();
+            tokio::task::spawn_blocking(move || device_thread::call(&tasks, f))
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .ok_or_else(|| Status::unavailable("Device is shutting down"))?
+                .map_err(Status::invalid_argument)
+        }
+    }
+
+    fn from_pb(value: pb::OptionValue) -> Result<OptionValue, Status> {
+        use pb::option_value::Value;
+        match value.value {
+            Some(Value::BoolValue(b)) => Ok(OptionValue::Bool(b)),
+            Some(Value::IntValue(i)) => Ok(OptionValue::Int(i)),
+            Some(Value::FixedValue(f)) => Ok(OptionValue::Fixed(f)),
+            Some(Value::StringValue(s)) => Ok(OptionValue::String(s)),
+            None => Err(Status::invalid_argument("Option value is missing")),
+        }
+    }
+
+    fn to_pb(value: OptionValue) -> pb::OptionValue {
+        use pb::option_value::Value;
+        let value = match value {
+            OptionValue::Bool(b) => Value::BoolValue(b),
+            OptionValue::Int(i) => Value::IntValue(i),
+            OptionValue::Fixed(f) => Value::FixedValue(f),
+            OptionValue::String(s) => Value::StringValue(s),
+        };
+        pb::OptionValue { value: Some(value) }
+    }
+
+    fn from_pb_map(
+        options: HashMap<String, pb::OptionValue>,
+    ) -> Result<BTreeMap<String, OptionValue>, Status> {
+        options
+            .into_iter()
+            .map(|(name, value)| Ok((name, from_pb(value)?)))
+            .collect()
+    }
+
+    fn options(handle: &Handle) -> pb::GetOptionsResponse {
+        pb::GetOptionsResponse {
+            options: handle
+                .options()
+                .filter(|opt| !opt.name().is_empty())
+                .map(|opt| pb::DeviceOption {
+                    name: opt.name().to_owned(),
+                    title: opt.title().to_owned(),
+                    desc: opt.desc().to_owned(),
+                    value: opt.get_value().ok().map(to_pb),
+                })
+                .collect(),
+        }
+    }
+
+    #[tonic::async_trait]
+    impl Scanner for Service {
+        async fn list_devices(
+            &self,
+            _request: Request<pb::ListDevicesRequest>,
+        ) -> Result<Response<pb::ListDevicesResponse>, Status> {
+            let devices = self
+                .call(|context, handle| {
+                    let devices = context.devices(true).map_err(|e| e.to_string())?;
+                    Ok(devices
+                        .map(|device| pb::Device {
+                            name: device.name().to_owned(),
+                            vendor: device.vendor().to_owned(),
+                            model: device.model().to_owned(),
+                            r#type: device.type_().to_owned(),
+                            open: device.name() == handle.name(),
+                        })
+                        .collect())
+                })
+                .await?;
+            Ok(Response::new(pb::ListDevicesResponse { devices }))
+        }
+
+        async fn get_options(
+            &self,
+            _request: Request<pb::GetOptionsRequest>,
+        ) -> Result<Response<pb::GetOptionsResponse>, Status> {
+            let options = self.call(|_, handle| Ok(options(handle))).await?;
+            Ok(Response::new(options))
+        }
+
+        async fn set_options(
+            &self,
+            request: Request<pb::SetOptionsRequest>,
+        ) -> Result<Response<pb::GetOptionsResponse>, Status> {
+            let settings = Profile {
+                requested: from_pb_map(request.into_inner().options)?,
+                ..Profile::default()
+            };
+            let options = self
+                .call(move |_, handle| {
+                    settings.apply(handle).map_err(|e| e.to_string())?;
+                    Ok(options(handle))
+                })
+                .await?;
+            Ok(Response::new(options))
+        }
+
+        type ScanStream = BoxStream<pb::ScanChunk>;
+
+        async fn scan(
+            &self,
+            request: Request<pb::ScanRequest>,
+        ) -> Result<Response<Self::ScanStream>, Status> {
+            let client = request
+                .remote_addr()
+                .map_or_else(|| "unknown".to_owned(), |addr| addr.ip().to_string());
+            let pb::ScanRequest { profile, options } = request.into_inner();
+            let mut settings = if profile.is_empty() {
+                Profile::default()
+            } else {
+                self.profiles
+                    .get(&profile)
+                    .cloned()
+                    .ok_or_else(|| Status::not_found(format!("No profile named {}", profile)))?
+            };
+            settings.requested.extend(from_pb_map(options)?);
+            if settings.dir.is_none() {
+                settings.dir = Some(self.dir.clone());
+            }
+
+            let waiting = self.waiting.fetch_add(1, Ordering::SeqCst);
+            if self.max_waiting.is_some_and(|max| waiting >= max) {
+                self.waiting.fetch_sub(1, Ordering::SeqCst);
+                return Err(Status::resource_exhausted(format!(
+                    "{} scans are waiting already",
+                    waiting
+                )));
+            }
+            let job = self.jobs.fetch_add(1, Ordering::SeqCst);
+            let event = |state, error: String, files: Vec<String>| pb::JobEvent {
+                job,
+                state: state as i32,
+                profile: profile.clone(),
+                error,
+                files,
+                position: 0,
+            };
+            // Nobody watching the jobs is not an error
+            let _ = self.events.send(pb::JobEvent {
+                position: waiting as u32 + 1,
+                ..event(State::Queued, String::new(), vec![])
+            });
+
+            let events = self.events.clone();
+            let scanning = event(State::Scanning, String::new(), vec![]);
+            let (submitted, timeout) = (Instant::now(), self.timeout);
+            let waiting = Arc::clone(&self.waiting);
+            let result = self
+                .call(move |_, handle| {
+                    waiting.fetch_sub(1, Ordering::SeqCst);
+                    if timeout.is_some_and(|timeout| submitted.elapsed() > timeout) {
+                        return Ok(None);
+                    }
+                    let _ = events.send(scanning);
+                    settings.scan(handle).map(Some).map_err(|e| e.to_string())
+                })
+                .await
+                .and_then(|path| {
+                    path.ok_or_else(|| {
+                        Status::deadline_exceeded("The scan waited too long for the device")
+                    })
+                });
+            if let Some(audit) = &self.audit {
+                let (state, error, outputs) = match &result {
+                    Ok(path) => (JobState::Done, None, vec![path.display().to_string()]),
+                    Err(status) => (JobState::Failed, Some(status.message()), vec![]),
+                };
+                audit.record(&Record {
+                    client: &client,
+                    device: &self.device,
+                    profile: if profile.is_empty() {
+                        "default"
+                    } else {
+                        &profile
+                    },
+                    state,
+                    error,
+                    pages: outputs.len(),
+                    outputs,
+                });
+            }
+            let path = match result {
+                Ok(path) => {
+                    let file = path.display().to_string();
+                    let _ = self
+                        .events
+                        .send(event(State::Done, String::new(), vec![file]));
+                    path
+                }
+                Err(status) => {
+                    let _ =
+                        self.events
+                            .send(event(State::Failed, status.message().to_owned(), vec![]));
+                    return Err(status);
+                }
+            };
+
+            let (tx, rx) = mpsc::channel(4);
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = stream_file(job, &path, &tx) {
+                    let _ = tx.blocking_send(Err(Status::internal(e.to_string())));
+                }
+            });
+            Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+        }
+
+        type WatchJobsStream = BoxStream<pb::JobEvent>;
+
+        async fn watch_jobs(
+            &self,
+            _request: Request<pb::WatchJobsRequest>,
+        ) -> Result<Response<Self::WatchJobsStream>, Status> {
+            let stream = BroadcastStream::new(self.events.subscribe())
+                // Slow watchers miss events rather than holding up scanning
+                .filter_map(|event| event.ok().map(Ok));
+            Ok(Response::new(Box::pin(stream)))
+        }
+    }
+
+    fn stream_file(
+        job: u32,
+        path: &Path,
+        tx: &mpsc::Sender<Result<pb::ScanChunk, Status>>,
+    ) -> std::io::Result<()> {
+        let mut file = std::fs::File::open(path)?;
+        let mut file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                return Ok(());
+            }
+            let chunk = pb::ScanChunk {
+                job,
+                file_name: std::mem::take(&mut file_name),
+                data: buffer[..n].to_vec(),
+            };
+            if tx.blocking_send(Ok(chunk)).is_err() {
+                // The client went away
+                return Ok(());
+            }
+        }
+    }
+
+    pub fn run(
+        context: &Context,
+        handle: &Handle,
+        opts: &GrpcOptions,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let profiles = match &opts.profiles {
+            Some(path) => profile::load(Path::new(path))?,
+            None => BTreeMap::new(),
+        };
+        let addr = opts.listen.parse()?;
+
+        let (tasks, rx) = channel::<Task>();
+        let service = Service {
+            tasks: Mutex::new(tasks),
+            events: broadcast::channel(64).0,
+            profiles,
+            dir: PathBuf::from(&opts.dir),
+            jobs: AtomicU32::new(0),
+            waiting: Arc::new(AtomicUsize::new(0)),
+            max_waiting: opts.max_queued,
+            timeout: opts.queue_timeout.map(Duration::from_secs),
+            audit: match &opts.audit {
+                Some(path) => Some(Audit::open(Path::new(path), "grpc")?),
+                None => None,
+            },
+            device: handle.name().to_owned(),
+        };
+        let runtime = tokio::runtime::Runtime::new()?;
+        let server = runtime.spawn(
+            tonic::transport::Server::builder()
+                .add_service(ScannerServer::new(service))
+                .serve(addr),
+        );
+
+        let stop = crate::stop_on_ctrlc();
+        tracing::info!("Listening on {}, interrupt with ctrl-c", addr);
+        crate::device_thread::serve(context, handle, &rx, &stop);
+        server.abort();
+        Ok(())
+    }
+}
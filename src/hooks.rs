@@ -0,0 +1,136 @@
+//! Shell commands run after pages and jobs
+//!
+//! `--on-page` runs after each page is saved and uploaded, `--on-job` after
+//! the last page of a job, such as `on-page = "ocr.sh {path}"` in the
+//! configuration. The commands may use the placeholders of
+//! [`crate::template`], with `{name}` the file name of the page, and
+//!
+//! | Placeholder | Expands to                            |
+//! |-------------|---------------------------------------|
+//! | `{path}`    | Path of the page                      |
+//! | `{page}`    | Number of the page in the job, from 1 |
+//! | `{paths}`   | Paths of the pages of the job         |
+//! | `{pages}`   | Number of pages in the job            |
+//! | `{dir}`     | Directory of the pages                |
+//!
+//! Paths and names are quoted for the shell. A command which exits with an
+//! error fails the job, the output of commands is logged.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::template::{self, DateTime};
+
+/// The hooks of a job, and the pages saved so far
+#[derive(Debug)]
+pub struct Hooks<'a> {
+    on_page: Option<&'a str>,
+    on_job: Option<&'a str>,
+    pages: Vec<PathBuf>,
+}
+
+#[cfg(unix)]
+fn quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[cfg(not(unix))]
+fn quote(arg: &str) -> String {
+    format!("\"{}\"", arg.replace('"', "\"\""))
+}
+
+fn quote_path(path: &Path) -> String {
+    quote(&path.to_string_lossy())
+}
+
+/// Expands the placeholders of `hook` for the pages of a job, `path` is the
+/// last one
+fn command(hook: &str, pages: &[PathBuf], path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|name| quote(&name.to_string_lossy()))
+        .unwrap_or_default();
+    let dir = path.parent().map(quote_path).unwrap_or_default();
+    let paths: Vec<String> = pages.iter().map(|page| quote_path(page)).collect();
+    let hook = hook
+        .replace("{paths}", &paths.join(" "))
+        .replace("{pages}", &pages.len().to_string())
+        .replace("{path}", &quote_path(path))
+        .replace("{page}", &pages.len().to_string())
+        .replace("{dir}", &dir);
+    template::expand(&hook, &name, &DateTime::now())
+}
+
+/// Runs `command` in the shell, failing if it exits with an error
+fn run(command: &str) -> Result<(), Box<dyn std::error::Error>> {
+    tracing::debug!("Running {}", command);
+    #[cfg(unix)]
+    let output = Command::new("sh").arg("-c").arg(command).output();
+    #[cfg(not(unix))]
+    let output = Command::new("cmd").arg("/C").arg(command).output();
+    let output = output.map_err(|e| format!("Could not run {}: {}", command, e))?;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        tracing::info!("{}", line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        tracing::warn!("{}", line);
+    }
+    if !output.status.success() {
+        return Err(format!("{} failed with {}", command, output.status).into());
+    }
+    Ok(())
+}
+
+impl<'a> Hooks<'a> {
+    pub fn new(on_page: Option<&'a str>, on_job: Option<&'a str>) -> Self {
+        Self {
+            on_page,
+            on_job,
+            pages: Vec::new(),
+        }
+    }
+
+    /// Runs the page hook for a saved page
+    pub fn page(&mut self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        self.pages.push(path.to_owned());
+        match self.on_page {
+            Some(hook) => run(&command(hook, &self.pages, path)),
+            None => Ok(()),
+        }
+    }
+
+    /// Runs the job hook after the last page, unless no page was saved
+    pub fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        match (self.on_job, self.pages.last()) {
+            (Some(hook), Some(path)) => run(&command(hook, &self.pages, path)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_commands_for_pages() {
+        let pages = vec![
+            PathBuf::from("scans/a b.png"),
+            PathBuf::from("scans/it's.png"),
+        ];
+        assert_eq!(
+            command("ocr.sh {path} {page}/{pages} {dir}", &pages, &pages[1]),
+            r"ocr.sh 'scans/it'\''s.png' 2/2 'scans'"
+        );
+        assert_eq!(
+            command("merge {paths} > {name}.pdf", &pages, &pages[1]),
+            r"merge 'scans/a b.png' 'scans/it'\''s.png' > 'it'\''s.png'.pdf"
+        );
+
+        let mut hooks = Hooks::new(Some("test -n {path}"), Some("exit 3"));
+        hooks.page(&pages[0]).unwrap();
+        let error = hooks.finish().unwrap_err().to_string();
+        assert!(error.starts_with("exit 3 failed"), "{}", error);
+        assert!(Hooks::new(None, Some("exit 3")).finish().is_ok());
+    }
+}
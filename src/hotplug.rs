@@ -0,0 +1,200 @@
+//! Scanners connected and disconnected while a service runs
+//!
+//! SANE has no notification of new devices either, so with `--hotplug` the
+//! local devices are listed every few seconds and compared with the last
+//! list. The device of the service is closed when it disappears, failing
+//! the scan in progress, and opened again with the same settings and
+//! profiles once it is connected again.
+
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use skanny::{Closed, Context, Error, Handle};
+
+use crate::events::{Event, Sinks};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added(String),
+    Removed(String),
+}
+
+struct Listing {
+    context: Context,
+    interval: Duration,
+    next: Instant,
+    devices: BTreeSet<String>,
+}
+
+/// Follows the local devices, or does nothing without an interval
+pub struct Monitor(Option<Listing>);
+
+impl Monitor {
+    /// Lists the devices every `interval`, checking that `name` is among
+    /// them so it can be followed
+    pub fn new(
+        context: &Context,
+        interval: Option<Duration>,
+        name: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let interval = match interval {
+            Some(interval) => interval,
+            None => return Ok(Monitor(None)),
+        };
+        let monitor = Monitor(Some(Listing {
+            context: context.clone(),
+            interval,
+            next: Instant::now() + interval,
+            devices: list(context)?,
+        }));
+        if !monitor.is_listed(name) {
+            return Err(format!(
+                "{} is not a local device, so it cannot be followed when reconnected",
+                name
+            )
+            .into());
+        }
+        Ok(monitor)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.is_some()
+    }
+
+    /// Changes since the last listing, once the interval has passed
+    pub fn poll(&mut self) -> Vec<Change> {
+        match &self.0 {
+            Some(listing) if Instant::now() >= listing.next => self.poll_now(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Changes since the last listing, such as after the device failed
+    pub fn poll_now(&mut self) -> Vec<Change> {
+        let listing = match &mut self.0 {
+            Some(listing) => listing,
+            None => return Vec::new(),
+        };
+        listing.next = Instant::now() + listing.interval;
+        match list(&listing.context) {
+            Ok(devices) => {
+                let changes = diff(&listing.devices, &devices);
+                listing.devices = devices;
+                changes
+            }
+            Err(e) => {
+                tracing::warn!("Listing devices failed: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Whether the device opened by `name` was connected at the last
+    /// listing, which is always assumed without monitoring
+    fn is_listed(&self, name: &str) -> bool {
+        match &self.0 {
+            Some(listing) => listing.devices.iter().any(|listed| is_device(listed, name)),
+            None => true,
+        }
+    }
+}
+
+fn list(context: &Context) -> Result<BTreeSet<String>, Error> {
+    Ok(context
+        .devices(true)?
+        .map(|device| device.name().to_owned())
+        .collect())
+}
+
+fn diff(before: &BTreeSet<String>, after: &BTreeSet<String>) -> Vec<Change> {
+    let added = after.difference(before).cloned().map(Change::Added);
+    let removed = before.difference(after).cloned().map(Change::Removed);
+    added.chain(removed).collect()
+}
+
+/// Whether the listed device is the one opened by `name`, which may leave
+/// out the number of the device, as `test` opens `test:0`
+fn is_device(listed: &str, name: &str) -> bool {
+    listed == name
+        || listed
+            .strip_prefix(name)
+            .is_some_and(|rest| rest.starts_with(':'))
+}
+
+/// The device of a service, closed while it is disconnected
+pub enum Attached {
+    Open(Handle),
+    Disconnected(Closed),
+}
+
+impl Attached {
+    pub fn handle(&self) -> Option<&Handle> {
+        match self {
+            Attached::Open(handle) => Some(handle),
+            Attached::Disconnected(_) => None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Attached::Open(handle) => handle.name(),
+            Attached::Disconnected(closed) => closed.name(),
+        }
+    }
+
+    /// Reports the `changes`, closes the device if it disappeared and
+    /// opens it again once it is back
+    pub fn update(self, monitor: &Monitor, changes: &[Change], sinks: &Sinks) -> Self {
+        for change in changes {
+            match change {
+                Change::Added(device) => {
+                    tracing::info!("{} was connected", device);
+                    sinks.send(&Event::DeviceAdded { device });
+                }
+                Change::Removed(device) => {
+                    tracing::info!("{} was disconnected", device);
+                    sinks.send(&Event::DeviceRemoved { device });
+                }
+            }
+        }
+        let listed = monitor.is_listed(self.name());
+        match self {
+            Attached::Open(handle) if !listed => {
+                tracing::warn!("Closing {} until it is connected again", handle.name());
+                Attached::Disconnected(handle.close())
+            }
+            Attached::Disconnected(closed) if listed => match closed.reopen() {
+                Ok(handle) => {
+                    tracing::info!("Opened {} again", handle.name());
+                    Attached::Open(handle)
+                }
+                Err(e) => {
+                    tracing::warn!("Opening {} again failed: {}", closed.name(), e);
+                    Attached::Disconnected(closed)
+                }
+            },
+            attached => attached,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_changed_devices() {
+        let set = |names: &[&str]| names.iter().map(|&name| name.to_owned()).collect();
+        assert_eq!(
+            diff(&set(&["fujitsu:1", "test:0"]), &set(&["test:0", "test:1"])),
+            [
+                Change::Added("test:1".to_owned()),
+                Change::Removed("fujitsu:1".to_owned())
+            ]
+        );
+        assert!(is_device("test:0", "test"));
+        assert!(is_device("test:0", "test:0"));
+        assert!(!is_device("test:0", "tes"));
+        assert!(!is_device("test:0", "test:1"));
+    }
+}
@@ -0,0 +1,228 @@
+//! Summary of what a device can do
+//!
+//! Rather than every option, `skanny info` reports what matters when
+//! choosing settings: the sources to scan from, the resolutions, modes and
+//! bit depths, the largest scan area and which of the features of skanny
+//! the device supports itself.
+
+use std::fmt::Write;
+
+use gumdrop::Options;
+use sane_sys::*;
+use skanny::backend::{BackendError, Constraint, OptionInfo, ScannerDevice};
+use skanny::OptionValue;
+
+#[derive(Debug, Options)]
+pub struct InfoOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+}
+
+pub fn run(
+    device: &dyn ScannerDevice,
+    _opts: &InfoOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    print!("{}", report(device)?);
+    Ok(())
+}
+
+fn report(device: &dyn ScannerDevice) -> Result<String, BackendError> {
+    let options: Vec<OptionInfo> = device
+        .options()?
+        .into_iter()
+        .filter(|option| option.cap & SANE_CAP_INACTIVE as SANE_Int == 0)
+        .collect();
+    let find = |name: &str| options.iter().find(|option| option.name == name);
+    let mut report = String::new();
+    writeln!(report, "Device: {}", device.name()).unwrap();
+
+    if let Some(Constraint::StringList(sources)) = find("source").map(|option| &option.constraint) {
+        let kinds: Vec<&str> = ["flatbed", "feeder", "duplex"]
+            .iter()
+            .copied()
+            .filter(|&kind| sources.iter().any(|source| source_kind(source, kind)))
+            .collect();
+        writeln!(
+            report,
+            "Sources: {} ({})",
+            sources.join(", "),
+            kinds.join(", ")
+        )
+        .unwrap();
+    }
+    if let Some(resolutions) = find("resolution").and_then(values) {
+        writeln!(report, "Resolutions: {} dpi", resolutions).unwrap();
+    }
+    if let Some(Constraint::StringList(modes)) = find("mode").map(|option| &option.constraint) {
+        writeln!(report, "Modes: {}", modes.join(", ")).unwrap();
+    }
+    if let Some(depths) = find("depth").and_then(values) {
+        writeln!(report, "Bit depths: {}", depths).unwrap();
+    }
+    if let (Some(width), Some(height)) = (
+        extent(find("tl-x"), find("br-x")),
+        extent(find("tl-y"), find("br-y")),
+    ) {
+        let pixels = find("br-x").map(|option| option.unit) == Some(SANE_Unit_SANE_UNIT_PIXEL);
+        let unit = if pixels { "pixels" } else { "mm" };
+        writeln!(report, "Scan area: {:.1} x {:.1} {}", width, height, unit).unwrap();
+    }
+
+    let has = |names: &[&str]| names.iter().any(|&name| find(name).is_some());
+    let features = [
+        (
+            "double feed detection",
+            has(crate::jam::DOUBLE_FEED_OPTIONS),
+        ),
+        ("colour dropout", has(crate::dropout::OPTIONS)),
+        ("long paper", has(&["page-height"])),
+        ("preview", has(&["preview"])),
+        (
+            "calibration",
+            !crate::calibrate::actions(device, false)?.is_empty(),
+        ),
+        (
+            "cleaning",
+            !crate::calibrate::actions(device, true)?.is_empty(),
+        ),
+        (
+            "lamp control",
+            options.iter().any(crate::lamp::is_lamp_option),
+        ),
+    ];
+    let features: Vec<&str> = features
+        .iter()
+        .filter(|(_, supported)| *supported)
+        .map(|(feature, _)| *feature)
+        .collect();
+    if !features.is_empty() {
+        writeln!(report, "Features: {}", features.join(", ")).unwrap();
+    }
+
+    let buttons: Vec<&str> = options
+        .iter()
+        .filter(|option| {
+            option.type_ == SANE_Value_Type_SANE_TYPE_BOOL
+                && option.cap & SANE_CAP_SOFT_DETECT as SANE_Int != 0
+                && option.cap & SANE_CAP_SOFT_SELECT as SANE_Int == 0
+        })
+        .map(|option| &option.name[..])
+        .collect();
+    if !buttons.is_empty() {
+        writeln!(report, "Buttons and sensors: {}", buttons.join(", ")).unwrap();
+    }
+    Ok(report)
+}
+
+/// Whether a source of the device is of `kind`
+fn source_kind(source: &str, kind: &str) -> bool {
+    let source = source.to_lowercase();
+    match kind {
+        "flatbed" => source.contains("flatbed"),
+        "feeder" => ["adf", "feeder", "document"]
+            .iter()
+            .any(|word| source.contains(word)),
+        "duplex" => source.contains("duplex") || source.contains("back"),
+        _ => false,
+    }
+}
+
+#[allow(non_upper_case_globals)]
+fn number(option: &OptionInfo, word: SANE_Word) -> f64 {
+    match option.type_ {
+        SANE_Value_Type_SANE_TYPE_FIXED => SANE_UNFIX(word),
+        _ => f64::from(word),
+    }
+}
+
+/// The values the option allows, as a range or a list
+fn values(option: &OptionInfo) -> Option<String> {
+    match &option.constraint {
+        &Constraint::Range { min, max, .. } => {
+            Some(format!("{}-{}", number(option, min), number(option, max)))
+        }
+        Constraint::WordList(words) => Some(
+            words
+                .iter()
+                .map(|&word| number(option, word).to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        _ => match &option.value {
+            Some(OptionValue::Int(value)) => Some(value.to_string()),
+            Some(OptionValue::Fixed(value)) => Some(value.to_string()),
+            _ => None,
+        },
+    }
+}
+
+/// Largest distance from the `start` to the `end` of the scan area
+fn extent(start: Option<&OptionInfo>, end: Option<&OptionInfo>) -> Option<f64> {
+    let bound = |option: &OptionInfo, largest: bool| match &option.constraint {
+        &Constraint::Range { min, max, .. } => {
+            Some(number(option, if largest { max } else { min }))
+        }
+        Constraint::WordList(words) => {
+            let word = if largest {
+                words.iter().max()
+            } else {
+                words.iter().min()
+            };
+            word.map(|&word| number(option, word))
+        }
+        _ => None,
+    };
+    let start = start.map_or(Some(0.0), |start| bound(start, false))?;
+    Some(bound(end?, true)? - start)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use skanny::mock::{DeviceSpec, MockDevice};
+
+    #[test]
+    fn summarizes_the_options() {
+        let mut spec = DeviceSpec::default();
+        let cap = (SANE_CAP_SOFT_SELECT | SANE_CAP_SOFT_DETECT) as SANE_Int;
+        let option = |name: &str, type_, constraint| OptionInfo {
+            name: name.to_owned(),
+            title: name.to_owned(),
+            desc: String::new(),
+            type_,
+            unit: SANE_Unit_SANE_UNIT_MM,
+            cap,
+            constraint,
+            value: None,
+        };
+        let range = |max: f64| Constraint::Range {
+            min: 0,
+            max: SANE_FIX(max),
+            quant: 0,
+        };
+        spec.options.extend(vec![
+            option(
+                "source",
+                SANE_Value_Type_SANE_TYPE_STRING,
+                Constraint::StringList(vec!["Flatbed".to_owned(), "ADF Duplex".to_owned()]),
+            ),
+            option("br-x", SANE_Value_Type_SANE_TYPE_FIXED, range(215.9)),
+            option("br-y", SANE_Value_Type_SANE_TYPE_FIXED, range(297.0)),
+            option(
+                "page-height",
+                SANE_Value_Type_SANE_TYPE_FIXED,
+                range(5588.0),
+            ),
+        ]);
+        let report = report(&MockDevice::new(spec)).unwrap();
+        assert_eq!(
+            report,
+            "Device: mock\n\
+             Sources: Flatbed, ADF Duplex (flatbed, feeder, duplex)\n\
+             Resolutions: 75-600 dpi\n\
+             Modes: Gray, Color\n\
+             Scan area: 215.9 x 297.0 mm\n\
+             Features: long paper\n"
+        );
+    }
+}
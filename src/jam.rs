@@ -0,0 +1,185 @@
+//! Recovery from paper jams, open covers and double feeds during feeder
+//! batches
+//!
+//! A jammed sheet is not stored, so it can be scanned again once the feeder
+//! is cleared and the batch goes on with the next page number. At the
+//! terminal the user is asked, unattended scanning keeps trying until the
+//! device is ready again.
+//!
+//! Backends detect double feeds by the thickness or length of the sheet
+//! with options named differently per vendor, which `--detect-double-feed`
+//! turns on. A device which reports one in its `double-feed` sensor after a
+//! page was scanned may have pulled in two sheets. The user may put them
+//! back and scan again, or keep the page, which is then marked as suspect.
+
+use std::io::BufRead;
+use std::str::FromStr;
+use std::time::Duration;
+
+use sane_sys::*;
+use skanny::backend::{BackendError, Constraint, ScannerDevice};
+use skanny::OptionValue;
+
+/// Time between attempts while waiting for the jam to be cleared
+const WAIT: Duration = Duration::from_secs(5);
+
+/// Options which turn on double feed detection, switches or the action to
+/// take on a double feed
+pub const DOUBLE_FEED_OPTIONS: &[&str] = &[
+    "df-thickness",
+    "df-length",
+    "df-action",
+    "double-feed-detection",
+];
+/// Values of string options which stop the feeder on double feeds
+const DOUBLE_FEED_VALUES: &[&str] = &["Stop", "On", "Normal"];
+/// Sensor which tells whether the last sheet was a double feed
+const DOUBLE_FEED_SENSOR: &str = "double-feed";
+
+/// What went wrong while feeding a sheet
+#[derive(Debug)]
+pub enum Misfeed<'a> {
+    /// The feeder jammed or its cover is open
+    Jam(&'a BackendError),
+    /// The sheet was scanned, but might have been two
+    DoubleFeed,
+}
+
+/// What the batch does next
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Recovery {
+    /// Scan the sheet again
+    Rescan,
+    /// Keep the page of a double feed, marked as suspect
+    Keep,
+    /// Stop with an error
+    Abort,
+}
+
+/// How to handle misfeeds, from `--on-jam`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OnJam {
+    /// Ask at the terminal whether to rescan
+    Prompt,
+    /// Try again every few seconds, keeping double feeds
+    Wait,
+    Abort,
+}
+
+impl FromStr for OnJam {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prompt" => Ok(OnJam::Prompt),
+            "wait" => Ok(OnJam::Wait),
+            "abort" => Ok(OnJam::Abort),
+            _ => Err(format!(
+                "Unknown action {}, expected prompt, wait or abort",
+                s
+            )),
+        }
+    }
+}
+
+/// Whether the error is one the user can clear at the device
+pub fn is_jam(e: &BackendError) -> bool {
+    #[allow(non_upper_case_globals)]
+    match e.downcast_ref::<skanny::Error>() {
+        Some(skanny::Error::Status(status)) => matches!(
+            *status,
+            SANE_Status_SANE_STATUS_JAMMED | SANE_Status_SANE_STATUS_COVER_OPEN
+        ),
+        _ => false,
+    }
+}
+
+impl OnJam {
+    /// Handles a misfeed of the `sheet`th sheet of the batch
+    pub fn recover(self, sheet: usize, misfeed: Misfeed) -> Recovery {
+        match (self, misfeed) {
+            (OnJam::Prompt, Misfeed::Jam(e)) => {
+                eprint!(
+                    "{} while feeding sheet {}. Clear it and press enter to scan the sheet again, or enter q to stop: ",
+                    e, sheet
+                );
+                match prompt().as_deref() {
+                    Some("q") | None => Recovery::Abort,
+                    Some(_) => Recovery::Rescan,
+                }
+            }
+            (OnJam::Prompt, Misfeed::DoubleFeed) => {
+                eprint!(
+                    "Sheet {} may have been fed together with another. Put them back and press enter to scan them again, enter k to keep the page or q to stop: ",
+                    sheet
+                );
+                match prompt().as_deref() {
+                    Some("q") | None => Recovery::Abort,
+                    Some("k") => Recovery::Keep,
+                    Some(_) => Recovery::Rescan,
+                }
+            }
+            (OnJam::Wait, Misfeed::Jam(e)) => {
+                tracing::warn!(
+                    "{} while feeding sheet {}, trying again in {:?}",
+                    e,
+                    sheet,
+                    WAIT
+                );
+                std::thread::sleep(WAIT);
+                Recovery::Rescan
+            }
+            (OnJam::Wait, Misfeed::DoubleFeed) => Recovery::Keep,
+            (OnJam::Abort, _) => Recovery::Abort,
+        }
+    }
+}
+
+/// Trimmed line from the terminal, `None` at its end
+fn prompt() -> Option<String> {
+    let mut answer = String::new();
+    match std::io::stdin().lock().read_line(&mut answer) {
+        Ok(n) if n > 0 => Some(answer.trim().to_owned()),
+        _ => None,
+    }
+}
+
+/// Turns on the double feed detection of the device, returns whether it
+/// has any
+pub fn detect_double_feeds(device: &dyn ScannerDevice) -> Result<bool, BackendError> {
+    let mut found = false;
+    for option in device.options()? {
+        if !DOUBLE_FEED_OPTIONS.contains(&&option.name[..]) {
+            continue;
+        }
+        #[allow(non_upper_case_globals)]
+        let value = match (option.type_, &option.constraint) {
+            (SANE_Value_Type_SANE_TYPE_BOOL, _) => OptionValue::Bool(true),
+            (SANE_Value_Type_SANE_TYPE_STRING, Constraint::StringList(values)) => {
+                match DOUBLE_FEED_VALUES
+                    .iter()
+                    .find_map(|&on| values.iter().find(|value| value.eq_ignore_ascii_case(on)))
+                {
+                    Some(value) => OptionValue::String(value.clone()),
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+        tracing::debug!("Setting {} to {}", option.name, value);
+        device.set_option(&option.name, &value)?;
+        found = true;
+    }
+    Ok(found)
+}
+
+/// Whether the sensor of the device reports a double feed
+pub fn is_double_feed(device: &dyn ScannerDevice) -> bool {
+    let options = match device.options() {
+        Ok(options) => options,
+        Err(_) => return false,
+    };
+    options.iter().any(|option| {
+        option.name == DOUBLE_FEED_SENSOR && option.value == Some(OptionValue::Bool(true))
+    })
+}
@@ -0,0 +1,397 @@
+//! Scan jobs of the long running services
+//!
+//! A job is `queued` until the device is free, `scanning` while the page is
+//! acquired, `processing` while it is stored and `uploading` while it is
+//! sent to the destinations of its profile, and ends as `done` or `failed`,
+//! or as `skipped` when its page is skipped while scanning.
+//! Jobs wait in the queue while the service is paused, see [`crate::pause`].
+//! Only scanning needs the device. The later stages run on worker threads so
+//! the next job can start scanning, with at most `limit` of them at once.
+//!
+//! The device scans one job at a time, the others wait their turn in the
+//! order they were submitted. The queue may be bounded with
+//! [`JobQueue::bound`], refusing jobs beyond a length and failing those
+//! which waited too long for the device. When the service stops the queue
+//! is drained with [`JobQueue::drain`], see [`crate::shutdown`].
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use skanny::backend::{is_cancelled, CancelToken, Cancellable, ScannerDevice};
+use skanny::{Image, OptionValue};
+
+use crate::profile::{Profile, Scan};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Scanning,
+    Processing,
+    Uploading,
+    Done,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: usize,
+    pub device: String,
+    pub profile: String,
+    /// Options set on the device for the job, from the profile and the
+    /// request
+    #[serde(default)]
+    pub options: BTreeMap<String, OptionValue>,
+    /// Who submitted the job, see [`crate::audit`]
+    #[serde(default)]
+    pub client: Option<String>,
+    pub state: JobState,
+    pub error: Option<String>,
+    /// Where the pages were stored locally
+    pub files: Vec<PathBuf>,
+    /// Where the pages were uploaded to, see [`crate::destination`]
+    pub locations: Vec<String>,
+}
+
+/// Told about every change of a job, for example to persist it
+pub trait JobObserver: Send + Sync {
+    fn changed(&self, job: &Job);
+}
+
+/// Lets other parts of a service share an observer
+impl<T: JobObserver> JobObserver for Arc<T> {
+    fn changed(&self, job: &Job) {
+        (**self).changed(job);
+    }
+}
+
+pub struct JobQueue {
+    jobs: Mutex<BTreeMap<usize, Job>>,
+    observers: Vec<Box<dyn JobObserver>>,
+    limit: usize,
+    /// Number of jobs being processed or uploaded
+    running: Mutex<usize>,
+    worker_done: Condvar,
+    /// The job being scanned, and how to skip its page
+    scanning: Mutex<Option<(usize, CancelToken)>>,
+    /// When the jobs waiting for the device were submitted
+    waiting: Mutex<BTreeMap<usize, Instant>>,
+    max_waiting: Option<usize>,
+    timeout: Option<Duration>,
+}
+
+/// The stages of a job after scanning, see [`JobQueue::run`]
+pub struct Pending(Receiver<Result<Scan, String>>);
+
+impl Pending {
+    /// Waits until the job is stored and uploaded
+    pub fn wait(self) -> Result<Scan, Box<dyn std::error::Error>> {
+        Ok(self.0.recv().map_err(|_| "The job was abandoned")??)
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl JobQueue {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            jobs: Mutex::new(BTreeMap::new()),
+            observers: Vec::new(),
+            limit: limit.max(1),
+            running: Mutex::new(0),
+            worker_done: Condvar::new(),
+            scanning: Mutex::new(None),
+            waiting: Mutex::new(BTreeMap::new()),
+            max_waiting: None,
+            timeout: None,
+        }
+    }
+
+    /// Refuses jobs while `max_waiting` wait for the device, and fails
+    /// those which waited longer than `timeout`
+    pub fn bound(&mut self, max_waiting: Option<usize>, timeout: Option<Duration>) {
+        self.max_waiting = max_waiting;
+        self.timeout = timeout;
+    }
+
+    pub fn observe(&mut self, observer: impl JobObserver + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Adds the jobs of an earlier run, failing those it did not finish
+    pub fn restore(&self, jobs: impl IntoIterator<Item = Job>) {
+        let mut restored = lock(&self.jobs);
+        for mut job in jobs {
+            if !matches!(
+                job.state,
+                JobState::Done | JobState::Failed | JobState::Skipped
+            ) {
+                job.state = JobState::Failed;
+                job.error = Some("Interrupted by a restart".to_owned());
+                self.notify(&job);
+            }
+            restored.insert(job.id, job);
+        }
+    }
+
+    /// Queues a job, returning its id, unless the queue is full
+    pub fn submit(
+        &self,
+        device: &str,
+        profile: &str,
+        options: &BTreeMap<String, OptionValue>,
+        client: Option<&str>,
+    ) -> Result<usize, String> {
+        let mut waiting = lock(&self.waiting);
+        if self.max_waiting.is_some_and(|max| waiting.len() >= max) {
+            return Err(format!("{} jobs are waiting already", waiting.len()));
+        }
+        let mut jobs = lock(&self.jobs);
+        let id = jobs.keys().next_back().map_or(0, |id| id + 1);
+        let job = Job {
+            id,
+            device: device.to_owned(),
+            profile: profile.to_owned(),
+            options: options.clone(),
+            client: client.map(str::to_owned),
+            state: JobState::Queued,
+            error: None,
+            files: Vec::new(),
+            locations: Vec::new(),
+        };
+        self.notify(&job);
+        jobs.insert(id, job);
+        waiting.insert(id, Instant::now());
+        Ok(id)
+    }
+
+    pub fn get(&self, id: usize) -> Option<Job> {
+        lock(&self.jobs).get(&id).cloned()
+    }
+
+    /// Place of a job waiting for the device, 1 if it is next
+    pub fn position(&self, id: usize) -> Option<usize> {
+        let waiting = lock(&self.waiting);
+        waiting
+            .contains_key(&id)
+            .then(|| waiting.range(..=id).count())
+    }
+
+    fn update(&self, id: usize, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = lock(&self.jobs).get_mut(&id) {
+            f(job);
+            tracing::debug!(state = ?job.state, "Job changed");
+            self.notify(job);
+        }
+    }
+
+    fn notify(&self, job: &Job) {
+        for observer in &self.observers {
+            observer.changed(job);
+        }
+    }
+
+    fn fail(&self, id: usize, message: &str) {
+        tracing::warn!("Job failed: {}", message);
+        self.update(id, |job| {
+            job.state = JobState::Failed;
+            job.error = Some(message.to_owned());
+        });
+    }
+
+    /// Skips the page of a job if it is being scanned, returns whether it
+    /// was
+    pub fn skip(&self, id: usize) -> bool {
+        match &*lock(&self.scanning) {
+            Some((scanning, token)) if *scanning == id => {
+                token.cancel();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Cancels the page being scanned, if any
+    pub fn cancel_scan(&self) {
+        if let Some((_, token)) = &*lock(&self.scanning) {
+            token.cancel();
+        }
+    }
+
+    /// Fails the jobs waiting for the device, and waits up to `timeout` for
+    /// those being stored and uploaded, returning whether they finished
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let waiting = std::mem::take(&mut *lock(&self.waiting));
+        for id in waiting.into_keys() {
+            self.fail(id, "The service stopped");
+        }
+        let deadline = Instant::now() + timeout;
+        let mut running = lock(&self.running);
+        while *running > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return false;
+            }
+            running = self
+                .worker_done
+                .wait_timeout(running, left)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        true
+    }
+
+    /// Scans the page of a job with `device`, and hands storing and
+    /// uploading it to a worker once one is free
+    pub fn run(
+        self: &Arc<Self>,
+        id: usize,
+        profile: &Profile,
+        device: &dyn ScannerDevice,
+    ) -> Pending {
+        let span = tracing::info_span!("job", id);
+        let _job = span.enter();
+        let (tx, rx) = channel();
+        let submitted = lock(&self.waiting).remove(&id);
+        if let (Some(submitted), Some(timeout)) = (submitted, self.timeout) {
+            if submitted.elapsed() > timeout {
+                let e = "The job waited too long for the device".to_owned();
+                self.fail(id, &e);
+                let _ = tx.send(Err(e));
+                return Pending(rx);
+            }
+        }
+        crate::pause::wait();
+        self.update(id, |job| job.state = JobState::Scanning);
+        crate::stats::record_job(device.name());
+        let token = CancelToken::new();
+        *lock(&self.scanning) = Some((id, token.clone()));
+        let cancellable = Cancellable::new(device, token);
+        let device = crate::stats::Counting::new(&cancellable);
+        let scanned = profile.apply(&device).and_then(|()| device.scan());
+        *lock(&self.scanning) = None;
+        let image = match scanned {
+            Ok(image) => image,
+            Err(e) if is_cancelled(&e) => {
+                tracing::info!("Skipped the page");
+                self.update(id, |job| job.state = JobState::Skipped);
+                let _ = tx.send(Err("The page was skipped".to_owned()));
+                return Pending(rx);
+            }
+            Err(e) => {
+                let e = e.to_string();
+                self.fail(id, &e);
+                let _ = tx.send(Err(e));
+                return Pending(rx);
+            }
+        };
+        // Taken here as the workers may finish in any order
+        let dir = profile.dir.as_deref().unwrap_or_else(|| Path::new("."));
+        let dir = crate::template::directory(dir, &crate::template::DateTime::now());
+        let path = crate::timestamped_path(&dir);
+
+        let mut running = lock(&self.running);
+        while *running >= self.limit {
+            running = self
+                .worker_done
+                .wait(running)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        *running += 1;
+        drop(running);
+
+        self.update(id, |job| job.state = JobState::Processing);
+        let queue = Arc::clone(self);
+        let profile = profile.clone();
+        let span = span.clone();
+        std::thread::spawn(move || {
+            let _job = span.enter();
+            let result = queue.process(id, &profile, path, image);
+            if let Err(e) = &result {
+                queue.fail(id, e);
+            }
+            *lock(&queue.running) -= 1;
+            // Both the next job and a drain may be waiting
+            queue.worker_done.notify_all();
+            let _ = tx.send(result);
+        });
+        Pending(rx)
+    }
+
+    fn process(
+        &self,
+        id: usize,
+        profile: &Profile,
+        path: PathBuf,
+        image: Image,
+    ) -> Result<Scan, String> {
+        let image = profile.save(image, &path).map_err(|e| e.to_string())?;
+        self.update(id, |job| {
+            job.files.push(path.clone());
+            job.state = JobState::Uploading;
+        });
+        let locations =
+            crate::destination::store_all(&profile.dest, &path).map_err(|e| e.to_string())?;
+        self.update(id, |job| {
+            job.locations = locations.clone();
+            job.state = JobState::Done;
+        });
+        Ok(Scan {
+            path,
+            image,
+            locations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use skanny::backend::ScannerBackend;
+    use skanny::mock::{DeviceSpec, MockBackend};
+
+    #[test]
+    fn bounds_the_jobs_waiting_for_the_device() {
+        let mut queue = JobQueue::new(1);
+        queue.bound(Some(2), Some(Duration::ZERO));
+        let queue = Arc::new(queue);
+        let first = queue
+            .submit("mock:demo", "default", &BTreeMap::new(), None)
+            .unwrap();
+        let second = queue
+            .submit("mock:demo", "default", &BTreeMap::new(), None)
+            .unwrap();
+        assert!(queue
+            .submit("mock:demo", "default", &BTreeMap::new(), None)
+            .is_err());
+        assert_eq!(queue.position(first), Some(1));
+        assert_eq!(queue.position(second), Some(2));
+
+        let device = MockBackend::new(vec![DeviceSpec {
+            name: "mock:demo".to_owned(),
+            ..DeviceSpec::default()
+        }])
+        .open("mock:demo")
+        .unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(queue
+            .run(first, &Profile::default(), &*device)
+            .wait()
+            .is_err());
+        let job = queue.get(first).unwrap();
+        assert_eq!(job.state, JobState::Failed);
+        assert_eq!(queue.position(first), None);
+        assert_eq!(queue.position(second), Some(1));
+        assert!(queue
+            .submit("mock:demo", "default", &BTreeMap::new(), None)
+            .is_ok());
+    }
+}
@@ -0,0 +1,224 @@
+//! Control of the lamp and the power saving of the device
+//!
+//! Backends of CCD scanners switch the lamp with a button per action, such
+//! as `lamp-off`, or with a `lamp-switch` option, and turn it off by
+//! themselves after the time in an option like `lamp-off-time` or
+//! `sleeptimer`, which most count in minutes. Turning the lamp off after a
+//! job, rather than leaving it to the backend, makes it last longer. The
+//! next scan turns it on again.
+
+use gumdrop::Options;
+use sane_sys::*;
+use skanny::backend::{BackendError, OptionInfo, ScannerDevice};
+use skanny::OptionValue;
+
+#[derive(Debug, Options)]
+pub struct LampOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(free, help = "on, off, warmup, status or power-save MINUTES")]
+    args: Vec<String>,
+}
+
+/// Options which switch the lamp on when true
+const SWITCHES: &[&str] = &["lamp-switch", "lamp"];
+/// Buttons which warm up the lamp
+const WARMUP: &[&str] = &["lamp-warmup", "warmup"];
+/// Options for the time until the lamp is turned off or the device saves
+/// power
+const TIMERS: &[&str] = &[
+    "power-save-time",
+    "power-save",
+    "sleeptimer",
+    "lamp-off-time",
+    "lampoff-time",
+    "lamp-timeout",
+];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Action {
+    On,
+    Off,
+    Warmup,
+    Status,
+    PowerSave(SANE_Int),
+}
+
+fn parse(args: &[String]) -> Result<Action, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["on"] => Ok(Action::On),
+        ["off"] => Ok(Action::Off),
+        ["warmup"] => Ok(Action::Warmup),
+        ["status"] | [] => Ok(Action::Status),
+        ["power-save", minutes] => minutes
+            .parse()
+            .map(Action::PowerSave)
+            .map_err(|_| format!("Invalid number of minutes {}", minutes)),
+        _ => Err("Expected on, off, warmup, status or power-save MINUTES".to_owned()),
+    }
+}
+
+pub fn run(
+    device: &dyn ScannerDevice,
+    opts: &LampOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let action = parse(&opts.args)?;
+    let found = match action {
+        Action::On => switch(device, true)?,
+        Action::Off => switch(device, false)?,
+        Action::Warmup => press(device, WARMUP)?,
+        Action::PowerSave(minutes) => power_save(device, minutes)?,
+        Action::Status => {
+            for option in device.options()? {
+                if is_lamp_option(&option) {
+                    match &option.value {
+                        Some(value) => println!("{}: {}", option.name, value),
+                        None => println!("{}", option.name),
+                    }
+                }
+            }
+            return Ok(());
+        }
+    };
+    if !found {
+        return Err(format!(
+            "{} has no option for lamp {}",
+            device.name(),
+            opts.args.join(" ")
+        )
+        .into());
+    }
+    Ok(())
+}
+
+pub fn is_lamp_option(option: &OptionInfo) -> bool {
+    option.cap & SANE_CAP_INACTIVE as SANE_Int == 0
+        && (option.name.contains("lamp")
+            || SWITCHES.contains(&&option.name[..])
+            || TIMERS.contains(&&option.name[..]))
+}
+
+/// Finds the first active option named one of `names`, in that order
+fn find(options: &[OptionInfo], names: &[&str]) -> Option<OptionInfo> {
+    names.iter().find_map(|&name| {
+        options
+            .iter()
+            .find(|option| option.name == name && option.cap & SANE_CAP_INACTIVE as SANE_Int == 0)
+            .cloned()
+    })
+}
+
+/// Turns the lamp on or off, returns whether the device can
+fn switch(device: &dyn ScannerDevice, on: bool) -> Result<bool, BackendError> {
+    let button = if on { "lamp-on" } else { "lamp-off" };
+    if press(device, &[button])? {
+        return Ok(true);
+    }
+    let options = device.options()?;
+    match find(&options, SWITCHES) {
+        Some(option) if option.type_ == SANE_Value_Type_SANE_TYPE_BOOL => {
+            tracing::debug!("Setting {} to {}", option.name, on);
+            device.set_option(&option.name, &OptionValue::Bool(on))?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Presses the first of the buttons the device has
+fn press(device: &dyn ScannerDevice, names: &[&str]) -> Result<bool, BackendError> {
+    let options = device.options()?;
+    match find(&options, names) {
+        Some(option) if option.type_ == SANE_Value_Type_SANE_TYPE_BUTTON => {
+            tracing::debug!("Pressing {}", option.name);
+            // Buttons take no value, this one is ignored
+            device.set_option(&option.name, &OptionValue::Bool(true))?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+#[allow(non_upper_case_globals)]
+fn power_save(device: &dyn ScannerDevice, minutes: SANE_Int) -> Result<bool, BackendError> {
+    let options = device.options()?;
+    let option = match find(&options, TIMERS) {
+        Some(option) => option,
+        None => return Ok(false),
+    };
+    let value = match option.type_ {
+        SANE_Value_Type_SANE_TYPE_INT => OptionValue::Int(minutes),
+        SANE_Value_Type_SANE_TYPE_FIXED => OptionValue::Fixed(f64::from(minutes)),
+        _ => return Ok(false),
+    };
+    tracing::debug!("Setting {} to {}", option.name, value);
+    device.set_option(&option.name, &value)?;
+    Ok(true)
+}
+
+/// Turns the lamp off when dropped, at the end of a job however it ended
+pub struct OffAfterJob<'a>(pub &'a dyn ScannerDevice);
+
+impl Drop for OffAfterJob<'_> {
+    fn drop(&mut self) {
+        match switch(self.0, false) {
+            Ok(true) => tracing::debug!("Turned the lamp off"),
+            Ok(false) => tracing::warn!("The device cannot turn its lamp off"),
+            Err(e) => tracing::warn!("Turning the lamp off failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use skanny::backend::Constraint;
+    use skanny::mock::{DeviceSpec, MockDevice};
+
+    fn option(name: &str, type_: SANE_Value_Type, value: Option<OptionValue>) -> OptionInfo {
+        OptionInfo {
+            name: name.to_owned(),
+            title: name.to_owned(),
+            desc: String::new(),
+            type_,
+            unit: SANE_Unit_SANE_UNIT_NONE,
+            cap: SANE_CAP_SOFT_SELECT as SANE_Int,
+            constraint: Constraint::None,
+            value,
+        }
+    }
+
+    #[test]
+    fn switches_the_lamp() {
+        let mut spec = DeviceSpec::default();
+        spec.options.extend(vec![
+            option(
+                "lamp-switch",
+                SANE_Value_Type_SANE_TYPE_BOOL,
+                Some(OptionValue::Bool(true)),
+            ),
+            option(
+                "sleeptimer",
+                SANE_Value_Type_SANE_TYPE_INT,
+                Some(OptionValue::Int(0)),
+            ),
+        ]);
+        let device = MockDevice::new(spec);
+        let value = |name: &str| {
+            let options = device.options().unwrap();
+            find(&options, &[name]).unwrap().value
+        };
+        drop(OffAfterJob(&device));
+        assert_eq!(value("lamp-switch"), Some(OptionValue::Bool(false)));
+        assert!(power_save(&device, 15).unwrap());
+        assert_eq!(value("sleeptimer"), Some(OptionValue::Int(15)));
+        assert!(!press(&device, WARMUP).unwrap());
+
+        assert_eq!(
+            parse(&["power-save".to_owned(), "5".to_owned()]),
+            Ok(Action::PowerSave(5))
+        );
+        assert!(parse(&["dim".to_owned()]).is_err());
+    }
+}
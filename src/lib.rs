@@ -0,0 +1,1461 @@
+//! Safe wrapper around the SANE scanner API
+
+use sane_sys::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::time::Duration;
+
+pub mod backend;
+#[cfg(feature = "escl")]
+pub mod escl;
+pub mod frame;
+pub mod mock;
+pub mod net;
+#[cfg(feature = "image")]
+pub mod pipeline;
+pub mod pixels;
+#[cfg(feature = "record")]
+pub mod record;
+pub mod sensors;
+pub mod spool;
+mod watchdog;
+#[cfg(all(windows, feature = "wia"))]
+pub mod wia;
+pub mod wsd;
+pub mod xml;
+
+use watchdog::Watchdog;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Error {
+    Status(SANE_Status),
+    WrongType,
+    /// libsane could not be loaded
+    NotInstalled,
+    /// The device stopped delivering data, see [`Handle::set_read_timeout`],
+    /// or listing devices took too long, see [`Context::devices_with_timeout`]
+    TimedOut,
+}
+
+impl Error {
+    pub fn is_eof(self) -> bool {
+        self == Error::Status(SANE_Status_SANE_STATUS_EOF)
+    }
+    pub fn is_no_docs(self) -> bool {
+        self == Error::Status(SANE_Status_SANE_STATUS_NO_DOCS)
+    }
+    pub fn is_busy(self) -> bool {
+        self == Error::Status(SANE_Status_SANE_STATUS_DEVICE_BUSY)
+    }
+}
+
+/// Retrying of operations failing as the device is briefly unavailable,
+/// with the delay doubling after every attempt
+#[derive(Debug, Copy, Clone)]
+pub struct Retry {
+    /// Attempts in total, 1 disables retrying
+    pub attempts: u32,
+    /// Delay before the first retry
+    pub delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            delay: Duration::from_millis(250),
+            max_delay: Duration::from_secs(4),
+        }
+    }
+}
+
+impl Retry {
+    fn run<T>(
+        &self,
+        transient: impl Fn(Error) -> bool,
+        mut f: impl FnMut() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut delay = self.delay;
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(e) if transient(e) && attempt < self.attempts => {
+                    tracing::debug!("{}, retrying in {:?}", e, delay);
+                    std::thread::sleep(delay);
+                    delay = (delay * 2).min(self.max_delay);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        #[allow(non_upper_case_globals)]
+        match *self {
+            Error::Status(status) => match status {
+                SANE_Status_SANE_STATUS_GOOD => write!(f, "No error"),
+                SANE_Status_SANE_STATUS_UNSUPPORTED => write!(f, "Unsupported"),
+                SANE_Status_SANE_STATUS_CANCELLED => write!(f, "Cancelled"),
+                SANE_Status_SANE_STATUS_DEVICE_BUSY => write!(f, "Device busy"),
+                SANE_Status_SANE_STATUS_INVAL => write!(f, "Invalid value"),
+                SANE_Status_SANE_STATUS_EOF => write!(f, "End of file"),
+                SANE_Status_SANE_STATUS_JAMMED => write!(f, "Document feeder is jammed"),
+                SANE_Status_SANE_STATUS_NO_DOCS => write!(f, "Document feed is empty"),
+                SANE_Status_SANE_STATUS_COVER_OPEN => write!(f, "Cover is open"),
+                SANE_Status_SANE_STATUS_IO_ERROR => write!(f, "Device IO failed"),
+                SANE_Status_SANE_STATUS_NO_MEM => write!(f, "Not enough memory available"),
+                SANE_Status_SANE_STATUS_ACCESS_DENIED => write!(f, "Access denied"),
+                _ => write!(f, "UNKNOWN ERROR: {}", status),
+            },
+            Error::WrongType => write!(f, "Expected another type here"),
+            Error::NotInstalled => write!(f, "SANE is not installed"),
+            Error::TimedOut => write!(f, "Timed out"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn checked(f: impl FnOnce() -> SANE_Status) -> Result<(), Error> {
+    let status = f();
+    if status != SANE_Status_SANE_STATUS_GOOD {
+        Err(Error::Status(status))
+    } else {
+        Ok(())
+    }
+}
+
+/// SANE being initialised, shared by every [`Context`] and [`Handle`] so
+/// `sane_exit` is only called once all of them are gone
+struct SaneRuntime {
+    version: SANE_Int,
+}
+
+struct Global {
+    runtime: Weak<SaneRuntime>,
+    /// Version code of the current initialisation, if any
+    version: Option<SANE_Int>,
+}
+
+/// Serialises initialising, exiting, listing devices, opening and closing,
+/// which SANE does not require backends to handle concurrently
+static GLOBAL: Mutex<Global> = Mutex::new(Global {
+    runtime: Weak::new(),
+    version: None,
+});
+
+fn global() -> MutexGuard<'static, Global> {
+    GLOBAL.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl SaneRuntime {
+    fn acquire() -> Result<Arc<Self>, Error> {
+        let mut global = global();
+        if let Some(runtime) = global.runtime.upgrade() {
+            return Ok(runtime);
+        }
+        let version = match global.version {
+            // The last runtime is being dropped, take over its initialisation
+            Some(version) => version,
+            None => {
+                #[cfg(feature = "dlopen")]
+                sane_sys::load().map_err(|_| Error::NotInstalled)?;
+                let mut version = -1;
+                unsafe {
+                    checked(|| sane_init(&mut version, None))?;
+                }
+                version
+            }
+        };
+        let runtime = Arc::new(Self { version });
+        global.runtime = Arc::downgrade(&runtime);
+        global.version = Some(version);
+        Ok(runtime)
+    }
+}
+
+impl Drop for SaneRuntime {
+    fn drop(&mut self) {
+        let mut global = global();
+        // Unless a new runtime took over in the meantime
+        if std::ptr::eq(global.runtime.as_ptr(), self) {
+            unsafe { sane_exit() }
+            global.version = None;
+        }
+    }
+}
+
+/// Keeps SANE initialised
+///
+/// Contexts and handles share one initialisation, so several contexts may
+/// exist at once, and handles stay usable after the context is dropped.
+#[derive(Clone)]
+pub struct Context {
+    runtime: Arc<SaneRuntime>,
+}
+
+impl Context {
+    pub fn init() -> Result<(Self, Version), Error> {
+        let runtime = SaneRuntime::acquire()?;
+        let version = Version(runtime.version);
+        Ok((Context { runtime }, version))
+    }
+
+    pub fn devices(
+        &self,
+        only_local: bool,
+    ) -> Result<impl ExactSizeIterator<Item = Device>, Error> {
+        let _global = global();
+        let mut device_list: *mut *const SANE_Device = std::ptr::null_mut();
+        unsafe {
+            checked(|| sane_get_devices(&mut device_list, only_local as _))?;
+        }
+
+        // The list is only valid until the next call, so copy it
+        let mut devices = Vec::new();
+        unsafe {
+            let mut traveller = device_list;
+            while !(*traveller).is_null() {
+                let device = &**traveller;
+                let string = |s| CStr::from_ptr(s).to_string_lossy().into_owned();
+                devices.push(Device {
+                    name: string(device.name),
+                    vendor: string(device.vendor),
+                    model: string(device.model),
+                    type_: string(device.type_),
+                    runtime: Arc::clone(&self.runtime),
+                });
+                traveller = traveller.offset(1);
+            }
+        }
+        Ok(devices.into_iter())
+    }
+
+    /// Lists devices, giving up after `timeout`
+    ///
+    /// Finding network devices may take long when hosts do not answer. The
+    /// listing then goes on in the background, and listing or opening
+    /// devices again waits for it to end.
+    pub fn devices_with_timeout(
+        &self,
+        only_local: bool,
+        timeout: Duration,
+    ) -> Result<Vec<Device>, Error> {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let context = self.clone();
+        std::thread::spawn(move || {
+            let _ = tx.send(context.devices(only_local).map(Iterator::collect));
+        });
+        rx.recv_timeout(timeout).unwrap_or(Err(Error::TimedOut))
+    }
+}
+
+#[derive(Copy, Clone)]
+#[repr(transparent)]
+pub struct Version(SANE_Int);
+
+impl Version {
+    pub fn major(self) -> SANE_Word {
+        sane_sys::Version::from_code(self.0).major
+    }
+    pub fn minor(self) -> SANE_Word {
+        sane_sys::Version::from_code(self.0).minor
+    }
+    pub fn build(self) -> SANE_Word {
+        sane_sys::Version::from_code(self.0).build
+    }
+}
+
+pub struct Device {
+    name: String,
+    vendor: String,
+    model: String,
+    type_: String,
+    runtime: Arc<SaneRuntime>,
+}
+
+impl Device {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn vendor(&self) -> &str {
+        &self.vendor
+    }
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+    pub fn type_(&self) -> &str {
+        &self.type_
+    }
+    pub fn open(&self) -> Result<Handle, Error> {
+        self.open_with_retry(Retry::default())
+    }
+    pub fn open_with_retry(&self, retry: Retry) -> Result<Handle, Error> {
+        Handle::open(Arc::clone(&self.runtime), &self.name, retry)
+    }
+}
+
+/// An open device
+///
+/// SANE backends need not be reentrant, so a handle can be moved to another
+/// thread but not shared between threads.
+pub struct Handle {
+    raw: SANE_Handle,
+    name: String,
+    /// Applied to starting, and was applied to opening
+    retry: Retry,
+    read_timeout: Option<Duration>,
+    /// Times a page is scanned again after timing out
+    page_retries: u32,
+    /// Watches the current acquisition if there is a read timeout
+    watchdog: RefCell<Option<Watchdog>>,
+    /// Read on first use, and again after the backend reloaded them
+    options: RefCell<Option<Options>>,
+    // Dropped after closing the handle
+    _runtime: Arc<SaneRuntime>,
+}
+
+// Handle is not Sync, so its calls come from one thread at a time, and the
+// global calls are serialised by GLOBAL
+unsafe impl Send for Handle {}
+
+impl std::fmt::Debug for Handle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Handle")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.watchdog.get_mut().take();
+        let _global = global();
+        unsafe { sane_close(self.raw) }
+    }
+}
+
+/// A device which was closed by [`Handle::close`]
+pub struct Closed {
+    name: String,
+    retry: Retry,
+    read_timeout: Option<Duration>,
+    page_retries: u32,
+    runtime: Arc<SaneRuntime>,
+}
+
+impl Closed {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Opens the device again with the settings of the closed handle
+    pub fn reopen(&self) -> Result<Handle, Error> {
+        let mut handle = Handle::open(Arc::clone(&self.runtime), &self.name, self.retry)?;
+        handle.read_timeout = self.read_timeout;
+        handle.page_retries = self.page_retries;
+        Ok(handle)
+    }
+}
+
+impl Handle {
+    /// Opens a device, initialising SANE if no context exists
+    pub fn from_name(name: &str) -> Result<Self, Error> {
+        Self::from_name_with_retry(name, Retry::default())
+    }
+
+    /// Opens a device, retrying while it is busy or unreachable
+    pub fn from_name_with_retry(name: &str, retry: Retry) -> Result<Self, Error> {
+        Self::open(SaneRuntime::acquire()?, name, retry)
+    }
+
+    fn open(runtime: Arc<SaneRuntime>, name: &str, retry: Retry) -> Result<Self, Error> {
+        let cname = std::ffi::CString::new(name)
+            .map_err(|_| Error::Status(SANE_Status_SANE_STATUS_INVAL))?;
+        let mut handle = std::ptr::null_mut();
+        // Network backends report failing connections as IO errors
+        let transient =
+            |e: Error| e.is_busy() || e == Error::Status(SANE_Status_SANE_STATUS_IO_ERROR);
+        retry.run(transient, || {
+            let _global = global();
+            unsafe { checked(|| sane_open(cname.as_ptr(), &mut handle)) }
+        })?;
+        tracing::debug!("Opened {}", name);
+        Ok(Self {
+            raw: handle,
+            name: name.to_owned(),
+            retry,
+            read_timeout: None,
+            page_retries: 0,
+            watchdog: RefCell::new(None),
+            options: RefCell::new(None),
+            _runtime: runtime,
+        })
+    }
+
+    /// Sets how starting is retried while the device is busy
+    pub fn set_retry(&mut self, retry: Retry) {
+        self.retry = retry;
+    }
+
+    /// Cancels acquisitions when no data arrives for `timeout`, failing
+    /// the read with [`Error::TimedOut`]
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Sets how often [`backend::ScannerDevice::scan`] scans a page again
+    /// after it timed out
+    pub fn set_page_retries(&mut self, retries: u32) {
+        self.page_retries = retries;
+    }
+
+    /// Closes the device, keeping what is needed to open it again with the
+    /// same settings, such as after it was disconnected
+    pub fn close(self) -> Closed {
+        Closed {
+            name: self.name.clone(),
+            retry: self.retry,
+            read_timeout: self.read_timeout,
+            page_retries: self.page_retries,
+            runtime: Arc::clone(&self._runtime),
+        }
+    }
+
+    /// Name of the device the handle was opened from
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn option(&self, name: &str) -> Option<Opt<'_>> {
+        let (index, descriptor) = self.cached(|options| {
+            let index = *options.by_name.get(name)?;
+            Some((index, options.descriptors[index].clone()))
+        })?;
+        Some(Opt {
+            handle: self,
+            index: index + 1, /* skipping first descriptor */
+            descriptor,
+        })
+    }
+    pub fn descriptors(&self) -> impl ExactSizeIterator<Item = Descriptor> + '_ {
+        self.cached(|options| options.descriptors.clone())
+            .into_iter()
+    }
+    /// Reads the options again on their next use, for backends which change
+    /// them without telling
+    pub fn reload_options(&self) {
+        self.options.borrow_mut().take();
+    }
+    fn cached<T>(&self, f: impl FnOnce(&Options) -> T) -> T {
+        let mut options = self.options.borrow_mut();
+        f(options.get_or_insert_with(|| Options::new(self.read_descriptors())))
+    }
+    fn read_descriptors(&self) -> Vec<Descriptor> {
+        // Guaranteed to exist
+        let first_desc = self.get_descriptor(0).unwrap();
+        assert_eq!(first_desc.type_(), SANE_Value_Type_SANE_TYPE_INT);
+        assert_eq!(
+            first_desc.size(),
+            std::mem::size_of::<SANE_Int>() as SANE_Int
+        );
+        let mut num_desc: SANE_Int = 0;
+        unsafe {
+            checked(|| {
+                sane_control_option(
+                    self.raw,
+                    0,
+                    SANE_Action_SANE_ACTION_GET_VALUE,
+                    &mut num_desc as *mut _ as _,
+                    std::ptr::null_mut(),
+                )
+            })
+            .unwrap()
+        };
+        (1..num_desc)
+            .map(|i| self.get_descriptor(i as _).unwrap())
+            .collect()
+    }
+    pub fn get_descriptor(&self, index: usize) -> Option<Descriptor> {
+        let desc = unsafe { sane_get_option_descriptor(self.raw, index as _) };
+        if desc.is_null() {
+            None
+        } else {
+            Some(Descriptor(desc))
+        }
+    }
+    pub fn options(&self) -> impl ExactSizeIterator<Item = Opt<'_>> {
+        self.descriptors()
+            .enumerate()
+            .map(move |(index, descriptor)| Opt {
+                handle: self,
+                index: index + 1, /* skipping first descriptor */
+                descriptor,
+            })
+    }
+
+    pub fn parameters(&self) -> Result<Parameters, Error> {
+        let mut parameters = std::mem::MaybeUninit::uninit();
+        unsafe { checked(|| sane_get_parameters(self.raw, parameters.as_mut_ptr()))? }
+        Ok(Parameters(unsafe { parameters.assume_init() }))
+    }
+    pub fn start(&self) -> Result<Acquisition<'_>, Error> {
+        self.start_raw()?;
+        Ok(Acquisition { handle: self })
+    }
+    pub(crate) fn start_raw(&self) -> Result<(), Error> {
+        // The previous frame may not have been cancelled
+        self.watchdog.borrow_mut().take();
+        self.retry.run(Error::is_busy, || unsafe {
+            checked(|| sane_start(self.raw))
+        })?;
+        *self.watchdog.borrow_mut() = self
+            .read_timeout
+            .map(|timeout| unsafe { Watchdog::start(self.raw, timeout) });
+        Ok(())
+    }
+    /// A single `sane_read`, returning 0 at the end of the frame
+    pub(crate) fn read_raw(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        match self.read_chunk(buffer) {
+            Err(e) if e.is_eof() => Ok(0),
+            result => result,
+        }
+    }
+    /// A single `sane_read`, keeping the watchdog informed
+    fn read_chunk(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut len = 0;
+        let result = unsafe {
+            checked(|| sane_read(self.raw, buffer.as_mut_ptr(), buffer.len() as _, &mut len))
+        };
+        if let Some(watchdog) = &*self.watchdog.borrow() {
+            if result.is_err() && watchdog.fired() {
+                return Err(Error::TimedOut);
+            }
+            if len > 0 {
+                watchdog.progress();
+            }
+        }
+        result.map(|()| len as usize)
+    }
+    pub(crate) fn cancel_raw(&self) {
+        self.watchdog.borrow_mut().take();
+        unsafe { sane_cancel(self.raw) }
+    }
+}
+
+/// The descriptors of the options of a handle. SANE keeps them at the same
+/// address until the handle is closed, but their number and contents may
+/// change when an option is set.
+struct Options {
+    descriptors: Vec<Descriptor>,
+    /// Indices into `descriptors`
+    by_name: HashMap<String, usize>,
+}
+
+impl Options {
+    fn new(descriptors: Vec<Descriptor>) -> Self {
+        // The first of options sharing a name is found
+        let by_name = descriptors
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, descriptor)| !descriptor.name().is_empty())
+            .map(|(index, descriptor)| (descriptor.name().to_owned(), index))
+            .collect();
+        Self {
+            descriptors,
+            by_name,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Descriptor(*const SANE_Option_Descriptor);
+
+impl Descriptor {
+    pub fn name(&self) -> &str {
+        let name = unsafe { (*self.0).name };
+        if name.is_null() {
+            ""
+        } else {
+            let cstr = unsafe { CStr::from_ptr(name) };
+            cstr.to_str().unwrap()
+        }
+    }
+    pub fn title(&self) -> &str {
+        let title = unsafe { (*self.0).title };
+        if title.is_null() {
+            ""
+        } else {
+            let cstr = unsafe { CStr::from_ptr(title) };
+            cstr.to_str().unwrap()
+        }
+    }
+    pub fn desc(&self) -> &str {
+        let desc = unsafe { (*self.0).desc };
+        if desc.is_null() {
+            ""
+        } else {
+            let cstr = unsafe { CStr::from_ptr(desc) };
+            cstr.to_str().unwrap()
+        }
+    }
+    pub fn type_(&self) -> SANE_Value_Type {
+        unsafe { (*self.0).type_ }
+    }
+    pub fn size(&self) -> SANE_Int {
+        unsafe { (*self.0).size }
+    }
+    pub fn cap(&self) -> SANE_Word {
+        unsafe { (*self.0).cap }
+    }
+    pub fn unit(&self) -> SANE_Unit {
+        unsafe { (*self.0).unit }
+    }
+    pub fn constraint_type(&self) -> SANE_Constraint_Type {
+        unsafe { (*self.0).constraint_type }
+    }
+    /// Interprets `s` as a value of the type of this option
+    pub fn parse_value(&self, s: &str) -> Result<OptionValue, Error> {
+        OptionValue::parse(self.type_(), s)
+    }
+    /// Whether the option holds a single value rather than an array, such
+    /// as a gamma table. Strings count as one value.
+    pub fn is_single(&self) -> bool {
+        self.type_() == SANE_Value_Type_SANE_TYPE_STRING
+            || self.size() == std::mem::size_of::<SANE_Word>() as SANE_Int
+    }
+    /// Read-only options reflecting the state of the hardware,
+    /// such as buttons and paper sensors
+    pub fn is_sensor(&self) -> bool {
+        let cap = self.cap() as u32;
+        cap & SANE_CAP_SOFT_DETECT != 0
+            && cap & SANE_CAP_SOFT_SELECT == 0
+            && cap & SANE_CAP_INACTIVE == 0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Parameters(SANE_Parameters);
+
+impl Parameters {
+    pub fn format(&self) -> SANE_Frame {
+        self.0.format
+    }
+    pub fn last_frame(&self) -> SANE_Bool {
+        self.0.last_frame
+    }
+    pub fn bytes_per_line(&self) -> SANE_Int {
+        self.0.bytes_per_line
+    }
+    pub fn pixels_per_line(&self) -> SANE_Int {
+        self.0.pixels_per_line
+    }
+    pub fn lines(&self) -> SANE_Int {
+        self.0.lines
+    }
+    pub fn depth(&self) -> SANE_Int {
+        self.0.depth
+    }
+}
+
+/// An option of a handle, which it borrows
+#[derive(Debug)]
+pub struct Opt<'a> {
+    handle: &'a Handle,
+    descriptor: Descriptor,
+    index: usize,
+}
+
+impl Opt<'_> {
+    fn raw(&self) -> SANE_Handle {
+        self.handle.raw
+    }
+    /// Sets the value, forgetting the options of the handle if the backend
+    /// reloaded them
+    fn set_raw(&self, value: *mut std::os::raw::c_void) -> Result<(), Error> {
+        let mut info = 0;
+        unsafe {
+            checked(|| {
+                sane_control_option(
+                    self.raw(),
+                    self.index as i32,
+                    SANE_Action_SANE_ACTION_SET_VALUE,
+                    value,
+                    &mut info,
+                )
+            })?;
+        }
+        if info as u32 & SANE_INFO_RELOAD_OPTIONS != 0 {
+            self.handle.reload_options();
+        }
+        Ok(())
+    }
+    pub fn descriptor(&self) -> &Descriptor {
+        &self.descriptor
+    }
+    pub fn name(&self) -> &str {
+        self.descriptor.name()
+    }
+    /// Title of the option, or its name if the backend gives none
+    pub fn title(&self) -> &str {
+        match self.descriptor.title() {
+            "" => self.name(),
+            title => title,
+        }
+    }
+    pub fn desc(&self) -> &str {
+        self.descriptor.desc()
+    }
+    pub fn string_constraints(&self) -> Result<impl ExactSizeIterator<Item = &str>, Error> {
+        #[allow(non_upper_case_globals)]
+        match unsafe { *self.descriptor.0 }.constraint_type {
+            SANE_Constraint_Type_SANE_CONSTRAINT_STRING_LIST => (),
+            typ => panic!("type {} is not a string constraint", typ),
+        }
+        let mut len = 0;
+        let mut walker = unsafe { { *self.descriptor.0 }.constraint.string_list };
+        unsafe {
+            while !(*walker).is_null() {
+                len += 1;
+                walker = walker.offset(1);
+            }
+        }
+        Ok((0..len).map(move |i| unsafe {
+            let list = (*self.descriptor.0).constraint.string_list;
+            let cstr = std::ffi::CStr::from_ptr(*list.offset(i) as _);
+            cstr.to_str().unwrap()
+        }))
+    }
+    pub fn get_string(&self) -> Result<String, Error> {
+        if self.descriptor.type_() != SANE_Value_Type_SANE_TYPE_STRING {
+            return Err(Error::WrongType);
+        }
+        let mut val: Vec<u8> = vec![0; self.descriptor.size() as _];
+        unsafe {
+            checked(|| {
+                sane_control_option(
+                    self.raw(),
+                    self.index as i32,
+                    SANE_Action_SANE_ACTION_GET_VALUE,
+                    val.as_mut_ptr() as *mut _,
+                    std::ptr::null_mut(),
+                )
+            })?;
+        }
+        let first_zero = val.iter().position(|&x| x == 0).unwrap_or(val.len());
+        val.resize(first_zero, 0);
+        Ok(String::from_utf8(val).unwrap())
+    }
+    pub fn set_string(&self, val: &str) -> Result<(), Error> {
+        assert_eq!(self.descriptor.type_(), SANE_Value_Type_SANE_TYPE_STRING);
+
+        let mut val = val.as_bytes().to_vec();
+        val.push(0);
+        self.set_raw(val.as_mut_ptr() as *mut _)
+    }
+    pub fn int_constraints(&self) -> Result<&[SANE_Word], Error> {
+        #[allow(non_upper_case_globals)]
+        match unsafe { *self.descriptor.0 }.constraint_type {
+            SANE_Constraint_Type_SANE_CONSTRAINT_WORD_LIST => (),
+            typ => panic!("type {} is not a word constraint", typ),
+        }
+        let list = unsafe { (*self.descriptor.0).constraint.word_list };
+        assert!(!list.is_null());
+        let len = unsafe { *list };
+        let list = unsafe { std::slice::from_raw_parts(list, len as usize + 1) };
+        Ok(&list[1..])
+    }
+    pub fn get_int(&self) -> Result<SANE_Int, Error> {
+        assert!(
+            self.descriptor.type_() == SANE_Value_Type_SANE_TYPE_INT
+                || self.descriptor.type_() == SANE_Value_Type_SANE_TYPE_FIXED
+        );
+        assert_eq!(
+            self.descriptor.size(),
+            std::mem::size_of::<SANE_Int>() as SANE_Int
+        );
+        let mut val = 0;
+        unsafe {
+            checked(|| {
+                sane_control_option(
+                    self.raw(),
+                    self.index as i32,
+                    SANE_Action_SANE_ACTION_GET_VALUE,
+                    &mut val as *mut _ as _,
+                    std::ptr::null_mut(),
+                )
+            })?;
+        }
+        Ok(val)
+    }
+    pub fn set_int(&self, val: &mut i32) -> Result<(), Error> {
+        assert!(
+            self.descriptor.type_() == SANE_Value_Type_SANE_TYPE_INT
+                || self.descriptor.type_() == SANE_Value_Type_SANE_TYPE_FIXED
+        );
+        assert_eq!(
+            self.descriptor.size(),
+            std::mem::size_of::<SANE_Int>() as SANE_Int
+        );
+        self.set_raw(val as *mut _ as _)
+    }
+    pub fn get_range(&self) -> Result<Range, Error> {
+        #[allow(non_upper_case_globals)]
+        match unsafe { *self.descriptor.0 }.constraint_type {
+            SANE_Constraint_Type_SANE_CONSTRAINT_RANGE => (),
+            _ => return Err(Error::WrongType),
+        }
+        let range = unsafe { *(*self.descriptor.0).constraint.range };
+        Ok(Range(range))
+    }
+    pub fn get_bool(&self) -> Result<bool, Error> {
+        assert_eq!(self.descriptor.type_(), SANE_Value_Type_SANE_TYPE_BOOL);
+        assert_eq!(
+            self.descriptor.size(),
+            std::mem::size_of::<SANE_Bool>() as SANE_Int
+        );
+        let mut val = 0;
+        unsafe {
+            checked(|| {
+                sane_control_option(
+                    self.raw(),
+                    self.index as i32,
+                    SANE_Action_SANE_ACTION_GET_VALUE,
+                    &mut val as *mut _ as _,
+                    std::ptr::null_mut(),
+                )
+            })?;
+        }
+        Ok(val == SANE_TRUE)
+    }
+    pub fn set_bool(&self, val: bool) -> Result<(), Error> {
+        assert_eq!(self.descriptor.type_(), SANE_Value_Type_SANE_TYPE_BOOL);
+        let mut val = if val { SANE_TRUE } else { SANE_FALSE } as SANE_Bool;
+        self.set_raw(&mut val as *mut _ as _)
+    }
+    pub fn press(&self) -> Result<(), Error> {
+        assert_eq!(self.descriptor.type_(), SANE_Value_Type_SANE_TYPE_BUTTON);
+        self.set_raw(std::ptr::null_mut())
+    }
+    /// Current value of the option, for the types which carry a single
+    /// value
+    pub fn get_value(&self) -> Result<OptionValue, Error> {
+        if !self.descriptor.is_single() {
+            return Err(Error::WrongType);
+        }
+        #[allow(non_upper_case_globals)]
+        match self.descriptor.type_() {
+            SANE_Value_Type_SANE_TYPE_BOOL => self.get_bool().map(OptionValue::Bool),
+            SANE_Value_Type_SANE_TYPE_INT => self.get_int().map(OptionValue::Int),
+            SANE_Value_Type_SANE_TYPE_FIXED => {
+                self.get_int().map(|v| OptionValue::Fixed(SANE_UNFIX(v)))
+            }
+            SANE_Value_Type_SANE_TYPE_STRING => self.get_string().map(OptionValue::String),
+            _ => Err(Error::WrongType),
+        }
+    }
+    /// Set the option from a loosely typed value, converting
+    /// integers to fixed point where the option requires it
+    pub fn set_value(&self, val: &OptionValue) -> Result<(), Error> {
+        let button = self.descriptor.type_() == SANE_Value_Type_SANE_TYPE_BUTTON;
+        if !button && !self.descriptor.is_single() {
+            return Err(Error::WrongType);
+        }
+        #[allow(non_upper_case_globals)]
+        match (self.descriptor.type_(), val) {
+            (SANE_Value_Type_SANE_TYPE_BOOL, &OptionValue::Bool(b)) => self.set_bool(b),
+            (SANE_Value_Type_SANE_TYPE_INT, &OptionValue::Int(i)) => self.set_int(&mut { i }),
+            (SANE_Value_Type_SANE_TYPE_FIXED, &OptionValue::Int(i)) => {
+                self.set_int(&mut SANE_FIX(i as f64))
+            }
+            (SANE_Value_Type_SANE_TYPE_FIXED, &OptionValue::Fixed(f)) => {
+                self.set_int(&mut SANE_FIX(f))
+            }
+            (SANE_Value_Type_SANE_TYPE_STRING, OptionValue::String(s)) => self.set_string(s),
+            (SANE_Value_Type_SANE_TYPE_BUTTON, _) => self.press(),
+            _ => Err(Error::WrongType),
+        }
+    }
+}
+
+/// Value for an option as written in a profile
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum OptionValue {
+    Bool(bool),
+    Int(SANE_Int),
+    Fixed(f64),
+    String(String),
+}
+
+impl OptionValue {
+    /// Parses a value for an option of type `type_`, as given on the
+    /// command line
+    pub fn parse(type_: SANE_Value_Type, s: &str) -> Result<Self, Error> {
+        #[allow(non_upper_case_globals)]
+        match type_ {
+            SANE_Value_Type_SANE_TYPE_BOOL => match s {
+                "true" | "yes" | "1" => Ok(OptionValue::Bool(true)),
+                "false" | "no" | "0" => Ok(OptionValue::Bool(false)),
+                _ => Err(Error::WrongType),
+            },
+            SANE_Value_Type_SANE_TYPE_INT => s
+                .parse()
+                .map(OptionValue::Int)
+                .map_err(|_| Error::WrongType),
+            SANE_Value_Type_SANE_TYPE_FIXED => s
+                .parse()
+                .map(OptionValue::Fixed)
+                .map_err(|_| Error::WrongType),
+            SANE_Value_Type_SANE_TYPE_STRING => Ok(OptionValue::String(s.to_owned())),
+            _ => Err(Error::WrongType),
+        }
+    }
+}
+
+impl std::fmt::Display for OptionValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OptionValue::Bool(b) => write!(f, "{}", b),
+            OptionValue::Int(i) => write!(f, "{}", i),
+            OptionValue::Fixed(x) => write!(f, "{}", x),
+            OptionValue::String(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Range(SANE_Range);
+
+impl Range {
+    pub fn min(&self) -> SANE_Word {
+        self.0.min
+    }
+    pub fn max(&self) -> SANE_Word {
+        self.0.max
+    }
+    pub fn quant(&self) -> SANE_Word {
+        self.0.quant
+    }
+}
+
+pub struct Acquisition<'a> {
+    handle: &'a Handle,
+}
+
+impl<'a> Acquisition<'a> {
+    pub fn cancel(self) {}
+    pub fn restart(&self) -> Result<(), Error> {
+        self.handle.start().map(std::mem::forget)
+    }
+
+    /// Reads the next chunk of the frame into `buffer`, returning 0 at
+    /// the end of the frame
+    pub fn read(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        self.handle.read_raw(buffer)
+    }
+
+    /// Streams the rest of the frame into `sink`, returning the number of
+    /// bytes written
+    ///
+    /// SANE errors are wrapped in the returned [`std::io::Error`], from
+    /// which [`std::io::Error::into_inner`] recovers them.
+    pub fn read_to<W: std::io::Write + ?Sized>(&self, sink: &mut W) -> std::io::Result<u64> {
+        let mut buffer = vec![0; 64 * 1024];
+        let mut written = 0;
+        loop {
+            match self.handle.read_chunk(&mut buffer) {
+                Ok(len) => {
+                    sink.write_all(&buffer[..len])?;
+                    written += len as u64;
+                }
+                Err(e) if e.is_eof() => return Ok(written),
+                Err(e) => return Err(std::io::Error::other(e)),
+            }
+        }
+    }
+
+    pub fn read_image(&self, mut buffer: &mut [u8]) -> Result<(), Error> {
+        loop {
+            match self.handle.read_chunk(buffer) {
+                Ok(len) => buffer = &mut buffer[len..],
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        assert_eq!(buffer.len(), 0);
+        Ok(())
+    }
+
+    /// Reads the rest of the frame
+    pub fn get_frame(self) -> Result<frame::Frame, Error> {
+        let parameters = self.handle.parameters()?;
+        let mut data = if parameters.lines() < 0 {
+            // Receipts and other long paper end where the paper does
+            Vec::new()
+        } else {
+            Vec::with_capacity((parameters.bytes_per_line() * parameters.lines()).max(0) as usize)
+        };
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            match self.handle.read_chunk(&mut buffer) {
+                Ok(len) => data.extend_from_slice(&buffer[..len]),
+                Err(e) if e.is_eof() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let parameters = backend::FrameParameters {
+            format: parameters.format(),
+            last_frame: parameters.last_frame() != 0,
+            bytes_per_line: parameters.bytes_per_line(),
+            pixels_per_line: parameters.pixels_per_line(),
+            lines: parameters.lines(),
+            depth: parameters.depth(),
+        };
+        frame::Frame::decode(&parameters, data)
+            .map_err(|_| Error::Status(SANE_Status_SANE_STATUS_UNSUPPORTED))
+    }
+
+    #[cfg(feature = "image")]
+    pub fn get_image(self) -> Result<Image, Error> {
+        self.get_frame()?
+            .into_image()
+            .ok_or(Error::Status(SANE_Status_SANE_STATUS_UNSUPPORTED))
+    }
+}
+
+impl Drop for Acquisition<'_> {
+    fn drop(&mut self) {
+        self.handle.cancel_raw()
+    }
+}
+
+#[cfg(feature = "image")]
+/// A scanned image, in the depth it was scanned in
+#[derive(Debug, Clone)]
+pub enum Image {
+    Rgb8(image::ImageBuffer<image::Rgb<u8>, Vec<u8>>),
+    Gray8(image::ImageBuffer<image::Luma<u8>, Vec<u8>>),
+    Rgb16(image::ImageBuffer<image::Rgb<u16>, Vec<u16>>),
+    Gray16(image::ImageBuffer<image::Luma<u16>, Vec<u16>>),
+    /// Line art
+    Bilevel(Bilevel),
+}
+
+#[cfg(feature = "image")]
+/// Black and white pixels packed eight to a byte as SANE sends them, the
+/// first pixel in the highest bit and 1 for black
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bilevel {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+#[cfg(feature = "image")]
+impl Bilevel {
+    /// Wraps packed lines, each starting on a new byte
+    pub fn from_raw(width: u32, height: u32, data: Vec<u8>) -> Option<Self> {
+        let row = (width as usize).div_ceil(8);
+        if data.len() != row * height as usize {
+            return None;
+        }
+        Some(Self {
+            width,
+            height,
+            data,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn as_raw(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Whether the pixel is black
+    pub fn get(&self, x: u32, y: u32) -> bool {
+        let row = (self.width as usize).div_ceil(8);
+        let byte = self.data[y as usize * row + x as usize / 8];
+        byte & (0x80 >> (x % 8)) != 0
+    }
+
+    /// Black as 0 and white as 255
+    pub fn to_gray8(&self) -> image::GrayImage {
+        let width = self.width as usize;
+        let mut gray = vec![0; width * self.height as usize];
+        if width > 0 {
+            let row = width.div_ceil(8);
+            for (line, pixels) in self
+                .data
+                .chunks_exact(row)
+                .zip(gray.chunks_exact_mut(width))
+            {
+                crate::pixels::unpack_bits(line, pixels);
+            }
+        }
+        image::ImageBuffer::from_raw(self.width, self.height, gray).unwrap()
+    }
+
+    /// Encodes as a PNG of 1 bit per pixel
+    fn write_png<W: std::io::Write>(&self, w: W) -> image::ImageResult<()> {
+        let to_image_error = |e: png::EncodingError| {
+            image::ImageError::Encoding(image::error::EncodingError::new(
+                image::ImageFormat::Png.into(),
+                e,
+            ))
+        };
+        let mut encoder = png::Encoder::new(w, self.width, self.height);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::One);
+        let mut writer = encoder.write_header().map_err(to_image_error)?;
+        // SANE uses 1 for black, PNG for white
+        let mut data = self.data.clone();
+        crate::pixels::invert(&mut data);
+        writer.write_image_data(&data).map_err(to_image_error)
+    }
+}
+
+#[cfg(feature = "image")]
+/// The samples of 16 bit images in the byte order of the host
+fn u16_bytes(samples: &[u16]) -> &[u8] {
+    // u8 has no stricter alignment than u16, and every byte is initialized
+    unsafe { std::slice::from_raw_parts(samples.as_ptr() as *const u8, samples.len() * 2) }
+}
+
+#[cfg(feature = "image")]
+impl Image {
+    /// Wraps the samples of a frame, if the format is supported
+    ///
+    /// 16 bit samples are in the byte order of the host, 1 bit samples are
+    /// packed as in [`Bilevel`].
+    pub fn from_raw(
+        format: SANE_Frame,
+        depth: SANE_Int,
+        width: u32,
+        height: u32,
+        data: Vec<u8>,
+    ) -> Option<Self> {
+        let samples = |data: Vec<u8>| -> Vec<u16> {
+            data.chunks_exact(2)
+                .map(|sample| u16::from_ne_bytes([sample[0], sample[1]]))
+                .collect()
+        };
+        #[allow(non_upper_case_globals)]
+        match (format, depth) {
+            (SANE_Frame_SANE_FRAME_GRAY, 1) => {
+                Bilevel::from_raw(width, height, data).map(Image::Bilevel)
+            }
+            (SANE_Frame_SANE_FRAME_GRAY, 8) => {
+                image::ImageBuffer::from_raw(width, height, data).map(Image::Gray8)
+            }
+            (SANE_Frame_SANE_FRAME_RGB, 8) => {
+                image::ImageBuffer::from_raw(width, height, data).map(Image::Rgb8)
+            }
+            (SANE_Frame_SANE_FRAME_GRAY, 16) => {
+                image::ImageBuffer::from_raw(width, height, samples(data)).map(Image::Gray16)
+            }
+            (SANE_Frame_SANE_FRAME_RGB, 16) => {
+                image::ImageBuffer::from_raw(width, height, samples(data)).map(Image::Rgb16)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        match self {
+            Image::Rgb8(im) => im.width(),
+            Image::Gray8(im) => im.width(),
+            Image::Rgb16(im) => im.width(),
+            Image::Gray16(im) => im.width(),
+            Image::Bilevel(im) => im.width(),
+        }
+    }
+
+    pub fn height(&self) -> u32 {
+        match self {
+            Image::Rgb8(im) => im.height(),
+            Image::Gray8(im) => im.height(),
+            Image::Rgb16(im) => im.height(),
+            Image::Gray16(im) => im.height(),
+            Image::Bilevel(im) => im.height(),
+        }
+    }
+
+    /// The samples, 16 bit ones in the byte order of the host and 1 bit
+    /// ones packed as in [`Bilevel`]
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Image::Rgb8(im) => im.as_raw(),
+            Image::Gray8(im) => im.as_raw(),
+            Image::Rgb16(im) => u16_bytes(im.as_raw()),
+            Image::Gray16(im) => u16_bytes(im.as_raw()),
+            Image::Bilevel(im) => im.as_raw(),
+        }
+    }
+
+    /// Converts to 8 bits per sample, which the processing of skanny works
+    /// on, keeping the high byte of 16 bit samples
+    pub fn into_8bit(self) -> Image {
+        match self {
+            Image::Rgb16(im) => Image::Rgb8(image::DynamicImage::ImageRgb16(im).into_rgb8()),
+            Image::Gray16(im) => Image::Gray8(image::DynamicImage::ImageLuma16(im).into_luma8()),
+            Image::Bilevel(im) => Image::Gray8(im.to_gray8()),
+            image => image,
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> image::ImageResult<()> {
+        match self {
+            Image::Gray8(im) => im.save(path),
+            Image::Rgb8(im) => im.save(path),
+            Image::Gray16(im) => im.save(path),
+            Image::Rgb16(im) => im.save(path),
+            Image::Bilevel(im) => match image::ImageFormat::from_path(&path)? {
+                image::ImageFormat::Png => {
+                    let file = std::fs::File::create(path)?;
+                    im.write_png(std::io::BufWriter::new(file))
+                }
+                _ => im.to_gray8().save(path),
+            },
+        }
+    }
+
+    /// Encodes the image into `w`
+    pub fn write_to<W: std::io::Write>(
+        &self,
+        w: &mut W,
+        format: image::ImageOutputFormat,
+    ) -> image::ImageResult<()> {
+        let color = match self {
+            Image::Gray8(_) => image::ColorType::L8,
+            Image::Rgb8(_) => image::ColorType::Rgb8,
+            Image::Gray16(_) => image::ColorType::L16,
+            Image::Rgb16(_) => image::ColorType::Rgb16,
+            Image::Bilevel(im) if format == image::ImageOutputFormat::Png => {
+                return im.write_png(w)
+            }
+            // Other formats get 8 bits
+            Image::Bilevel(_) => return self.clone().into_8bit().write_to(w, format),
+        };
+        let (bytes, width, height) = (self.as_bytes(), self.width(), self.height());
+        match format {
+            image::ImageOutputFormat::Png => {
+                image::png::PngEncoder::new(w).encode(bytes, width, height, color)
+            }
+            image::ImageOutputFormat::Jpeg(quality) => match self {
+                Image::Gray8(_) | Image::Rgb8(_) => {
+                    image::jpeg::JpegEncoder::new_with_quality(w, quality)
+                        .encode(bytes, width, height, color)
+                }
+                _ => self.clone().into_8bit().write_to(w, format),
+            },
+            format => self.to_dynamic().write_to(w, format),
+        }
+    }
+
+    pub fn to_dynamic(&self) -> image::DynamicImage {
+        self.clone().into()
+    }
+}
+
+#[cfg(feature = "image")]
+impl From<Image> for image::DynamicImage {
+    fn from(image: Image) -> Self {
+        match image {
+            Image::Gray8(im) => image::DynamicImage::ImageLuma8(im),
+            Image::Rgb8(im) => image::DynamicImage::ImageRgb8(im),
+            Image::Gray16(im) => image::DynamicImage::ImageLuma16(im),
+            Image::Rgb16(im) => image::DynamicImage::ImageRgb16(im),
+            Image::Bilevel(im) => image::DynamicImage::ImageLuma8(im.to_gray8()),
+        }
+    }
+}
+
+#[cfg(feature = "image")]
+/// Keeps gray and RGB images as they are, others lose their alpha channel
+/// or are converted to 8 bit RGB
+impl From<image::DynamicImage> for Image {
+    fn from(image: image::DynamicImage) -> Self {
+        use image::DynamicImage::*;
+        match image {
+            ImageLuma8(im) => Image::Gray8(im),
+            ImageRgb8(im) => Image::Rgb8(im),
+            ImageLuma16(im) => Image::Gray16(im),
+            ImageRgb16(im) => Image::Rgb16(im),
+            image @ ImageLumaA8(_) => Image::Gray8(image.into_luma8()),
+            image @ ImageLumaA16(_) => Image::Gray16(image.into_luma16()),
+            image @ ImageRgba16(_) => Image::Rgb16(image.into_rgb16()),
+            image => Image::Rgb8(image.into_rgb8()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_outlives_context() {
+        let (first, _) = match Context::init() {
+            Ok(context) => context,
+            // libsane is loaded at runtime and may be missing
+            Err(Error::NotInstalled) => return,
+            Err(e) => panic!("Initialising SANE failed: {}", e),
+        };
+        let (second, _) = Context::init().unwrap();
+        assert!(Arc::ptr_eq(&first.runtime, &second.runtime));
+        // Network backends would be probed for a long while
+        let device = first.devices(true).unwrap().next();
+        drop((first, second));
+
+        let handle = match device {
+            Some(device) => device.open().unwrap(),
+            None => return,
+        };
+        let handle = std::thread::spawn(move || {
+            handle.options().count();
+            handle
+        })
+        .join()
+        .unwrap();
+        assert!(global().version.is_some());
+        drop(handle);
+        assert!(global().version.is_none());
+    }
+
+    #[test]
+    fn retries_while_busy() {
+        let retry = Retry {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        };
+        let busy = Error::Status(SANE_Status_SANE_STATUS_DEVICE_BUSY);
+        let mut calls = 0;
+        let result = retry.run(Error::is_busy, || {
+            calls += 1;
+            if calls < 3 {
+                Err(busy)
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result, Ok(3));
+
+        calls = 0;
+        let result: Result<(), _> = retry.run(Error::is_busy, || {
+            calls += 1;
+            Err(busy)
+        });
+        assert_eq!((result, calls), (Err(busy), 3));
+
+        calls = 0;
+        let result: Result<(), _> = retry.run(Error::is_busy, || {
+            calls += 1;
+            Err(Error::WrongType)
+        });
+        assert_eq!((result, calls), (Err(Error::WrongType), 1));
+    }
+
+    #[test]
+    fn refuses_values_of_arrays() {
+        // Never opened, so never closed either
+        let handle = std::mem::ManuallyDrop::new(Handle {
+            raw: std::ptr::null_mut(),
+            name: "gamma".to_owned(),
+            retry: Retry::default(),
+            read_timeout: None,
+            page_retries: 0,
+            watchdog: RefCell::new(None),
+            options: RefCell::new(None),
+            _runtime: Arc::new(SaneRuntime { version: 0 }),
+        });
+        let raw = SANE_Option_Descriptor {
+            name: b"gamma-table\0".as_ptr() as _,
+            type_: SANE_Value_Type_SANE_TYPE_INT,
+            size: 256 * std::mem::size_of::<SANE_Word>() as SANE_Int,
+            ..unsafe { std::mem::zeroed() }
+        };
+        let opt = Opt {
+            handle: &handle,
+            descriptor: Descriptor(&raw),
+            index: 1,
+        };
+        assert!(!opt.descriptor().is_single());
+        assert_eq!(opt.get_value(), Err(Error::WrongType));
+        assert_eq!(opt.set_value(&OptionValue::Int(1)), Err(Error::WrongType));
+    }
+
+    #[test]
+    fn finds_options_by_name() {
+        let names = [&b"\0"[..], b"mode\0", b"resolution\0", b"mode\0"];
+        let raw: Vec<SANE_Option_Descriptor> = names
+            .iter()
+            .map(|name| SANE_Option_Descriptor {
+                name: name.as_ptr() as _,
+                ..unsafe { std::mem::zeroed() }
+            })
+            .collect();
+        let options = Options::new(raw.iter().map(|raw| Descriptor(raw)).collect());
+        assert_eq!(options.by_name.len(), 2);
+        assert_eq!(options.by_name["mode"], 1);
+        assert_eq!(options.by_name["resolution"], 2);
+        assert_eq!(options.descriptors[3].name(), "mode");
+    }
+
+    #[test]
+    #[cfg(feature = "image")]
+    fn converts_images() {
+        let samples: Vec<u8> = [1000u16, 2000, 65535]
+            .iter()
+            .flat_map(|sample| sample.to_ne_bytes().to_vec())
+            .collect();
+        let gray = Image::from_raw(SANE_Frame_SANE_FRAME_GRAY, 16, 3, 1, samples.clone()).unwrap();
+        assert_eq!((gray.width(), gray.height()), (3, 1));
+        assert_eq!(gray.as_bytes(), &samples[..]);
+        let dynamic = image::DynamicImage::from(gray.clone());
+        assert_eq!(dynamic.as_luma16().unwrap().get_pixel(2, 0)[0], 65535);
+        assert_eq!(Image::from(dynamic).as_bytes(), &samples[..]);
+        match gray.into_8bit() {
+            Image::Gray8(im) => assert_eq!(im.as_raw(), &[3, 7, 255]),
+            _ => panic!("expected 8 bit gray"),
+        }
+
+        // Two lines of 10 pixels, the first and last black
+        let bilevel = Image::from_raw(
+            SANE_Frame_SANE_FRAME_GRAY,
+            1,
+            10,
+            2,
+            vec![0x80, 0x40, 0x00, 0x00],
+        )
+        .unwrap();
+        assert!(Image::from_raw(SANE_Frame_SANE_FRAME_GRAY, 1, 10, 2, vec![0; 3]).is_none());
+        match &bilevel {
+            Image::Bilevel(im) => assert!(im.get(0, 0) && im.get(9, 0) && !im.get(1, 0)),
+            _ => panic!("expected line art"),
+        }
+        let mut png = Vec::new();
+        bilevel
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let decoded = image::load_from_memory(&png).unwrap().into_luma8();
+        assert_eq!(decoded.get_pixel(0, 0)[0], 0);
+        assert_eq!(decoded.get_pixel(1, 0)[0], 255);
+        assert_eq!(decoded.get_pixel(9, 0)[0], 0);
+        assert_eq!(decoded.get_pixel(0, 1)[0], 255);
+    }
+}
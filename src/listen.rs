@@ -0,0 +1,167 @@
+//! Addresses the servers listen on and the clients they accept
+//!
+//! `--listen` takes an IPv4 or IPv6 address with a port, such as
+//! `192.168.1.10:8080` or `[::1]:8080`, and may be given several times to
+//! listen on more than one. On Linux `[::]` also accepts IPv4 clients, so it
+//! is not combined with `0.0.0.0` on the same port. `unix:PATH` listens on
+//! a Unix socket instead, which only processes on the host reach, to put a
+//! reverse proxy in front of the server or keep it off the network.
+//!
+//! Clients from loopback addresses and over Unix sockets are always
+//! accepted, others only from the addresses and networks given with
+//! `--allow ADDRESS[/PREFIX]`, or from anywhere with `--allow '*'`.
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+
+/// An address to listen on
+#[derive(Debug, PartialEq)]
+pub enum Address {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Address {
+    /// The addresses `s` stands for, a host name may resolve to several
+    pub fn parse(s: &str) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("The Unix socket has no path".into());
+            }
+            return Ok(vec![Address::Unix(PathBuf::from(path))]);
+        }
+        let addrs = s
+            .to_socket_addrs()
+            .map_err(|e| format!("Cannot listen on {}: {}", s, e))?;
+        Ok(addrs.map(Address::Tcp).collect())
+    }
+
+    /// The addresses of `listen`, or of `default` if none are given
+    pub fn parse_all(
+        listen: &[String],
+        default: &str,
+    ) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        if listen.is_empty() {
+            return Self::parse(default);
+        }
+        let mut addresses = Vec::new();
+        for s in listen {
+            addresses.extend(Self::parse(s)?);
+        }
+        Ok(addresses)
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Tcp(addr) => addr.fmt(f),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// An address or network clients may connect from
+#[derive(Debug)]
+pub enum Allow {
+    Any,
+    Network(IpAddr, u32),
+}
+
+impl Allow {
+    pub fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if s == "*" {
+            return Ok(Allow::Any);
+        }
+        let mut split = s.splitn(2, '/');
+        let addr: IpAddr = split.next().unwrap_or("").parse()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match split.next() {
+            Some(prefix) => prefix.parse()?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(format!("Prefix of {} is too long", s).into());
+        }
+        Ok(Allow::Network(addr, prefix))
+    }
+
+    /// The entries of `--allow`
+    pub fn parse_all(allow: &[String]) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        allow.iter().map(|allow| Self::parse(allow)).collect()
+    }
+
+    fn matches(&self, peer: IpAddr) -> bool {
+        let bits = |addr: IpAddr| match addr {
+            IpAddr::V4(addr) => (u128::from(u32::from(addr)) << 96, true),
+            IpAddr::V6(addr) => match addr.to_ipv4() {
+                // IPv4 clients on a dual stack socket
+                Some(v4) if addr.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+                    (u128::from(u32::from(v4)) << 96, true)
+                }
+                _ => (u128::from(addr), false),
+            },
+        };
+        match *self {
+            Allow::Any => true,
+            Allow::Network(addr, prefix) => {
+                // IPv4 addresses occupy the top bits, so the prefix applies to both
+                let (network, network_v4) = bits(addr);
+                let (peer, peer_v4) = bits(peer);
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                network_v4 == peer_v4 && network & mask == peer & mask
+            }
+        }
+    }
+}
+
+/// Whether a client at `peer` is accepted, `None` for Unix sockets
+pub fn allowed(allow: &[Allow], peer: Option<IpAddr>) -> bool {
+    let peer = match peer {
+        Some(peer) => peer,
+        None => return true,
+    };
+    let loopback = match peer {
+        IpAddr::V6(addr) => addr
+            .to_ipv4_mapped()
+            .map_or(addr.is_loopback(), |v4| v4.is_loopback()),
+        IpAddr::V4(addr) => addr.is_loopback(),
+    };
+    loopback || allow.iter().any(|allow| allow.matches(peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_allowed_networks() {
+        let allow =
+            Allow::parse_all(&["192.168.1.0/24".to_owned(), "fd00::/8".to_owned()]).unwrap();
+        let ip = |s: &str| Some(s.parse::<IpAddr>().unwrap());
+        assert!(allowed(&allow, ip("192.168.1.20")));
+        assert!(allowed(&allow, ip("::ffff:192.168.1.20")));
+        assert!(allowed(&allow, ip("fd12::1")));
+        assert!(!allowed(&allow, ip("192.168.2.20")));
+        assert!(!allowed(&allow, ip("2001:db8::1")));
+        assert!(allowed(&allow, ip("127.0.0.1")));
+        assert!(allowed(&allow, ip("::ffff:127.0.0.1")));
+        assert!(allowed(&allow, ip("::1")));
+        assert!(allowed(&allow, None));
+        assert!(allowed(&[Allow::Any], ip("2001:db8::1")));
+        assert!(Allow::parse("10.0.0.0/33").is_err());
+
+        assert_eq!(
+            Address::parse("[::1]:8080").unwrap(),
+            [Address::Tcp("[::1]:8080".parse().unwrap())]
+        );
+        assert_eq!(
+            Address::parse_all(&[], "127.0.0.1:8080").unwrap(),
+            [Address::Tcp("127.0.0.1:8080".parse().unwrap())]
+        );
+        let unix = Address::parse("unix:/run/skanny.sock").unwrap();
+        assert_eq!(unix, [Address::Unix(PathBuf::from("/run/skanny.sock"))]);
+        assert_eq!(unix[0].to_string(), "unix:/run/skanny.sock");
+        assert!(Address::parse("unix:").is_err());
+    }
+}
@@ -1,627 +1,1028 @@
 #![allow(unused)]
 
-use sane_sys::*;
-use std::ffi::CStr;
-
 use gumdrop::Options;
+use skanny::backend::{ScannerBackend, ScannerDevice};
+use skanny::pipeline::StageSpec;
+use skanny::spool::Spool;
+use skanny::*;
+
+mod audit;
+mod bench;
+mod book;
+mod calibrate;
+mod clean;
+mod clipboard;
+mod config;
+mod contact;
+mod copy;
+mod daemon;
+mod dbus;
+mod dedupe;
+mod destination;
+mod device_cache;
+mod device_thread;
+mod devices;
+mod dropout;
+mod environment;
+mod events;
+mod grpc;
+mod hooks;
+mod hotplug;
+mod info;
+mod jam;
+mod jobs;
+mod lamp;
+mod listen;
+mod manifest;
+mod mqtt;
+mod ocr;
+mod options;
+mod output;
+mod paper;
+mod pause;
+mod pdf;
+mod preview;
+mod profile;
+mod saned;
+mod separate;
+mod server;
+mod shutdown;
+mod snapshot;
+mod stages;
+mod stats;
+mod store;
+#[cfg(unix)]
+mod systemd;
+mod template;
+mod testdevice;
+mod thumbnail;
+mod tui;
+mod watch;
+mod webhook;
+mod workers;
 
-#[derive(Debug, Copy, Clone, PartialEq)]
-enum Error {
-    Status(SANE_Status),
-    WrongType,
+#[derive(Debug, Options)]
+struct CliOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(count, help = "Log more details, repeat for even more")]
+    verbose: u32,
+    #[options(
+        no_short,
+        help = "Read settings from this file, over the system and user configuration",
+        meta = "FILE"
+    )]
+    config: Option<String>,
+    #[options(no_short, help = "Write the log to this file instead of stderr")]
+    log_file: Option<String>,
+    #[options(no_short, help = "Log as JSON lines")]
+    log_json: bool,
+    #[options(
+        no_short,
+        help = "Scan from a dashboard in the terminal, with s to scan, p to pause, c to cancel the page and q to quit"
+    )]
+    tui: bool,
+    #[options(
+        no_short,
+        help = "Show pages in a window as they are scanned, closing it or pressing Escape cancels the page"
+    )]
+    preview: bool,
+    #[options(
+        no_short,
+        help = "Put the page on the clipboard as PNG instead of storing it, with wl-copy or xclip"
+    )]
+    to_clipboard: bool,
+    #[options(help = "Use a destdevice")]
+    testdevice: bool,
+    #[options(
+        no_short,
+        help = "Picture of the test device: Solid black, Solid white, Color pattern or Grid",
+        meta = "PICTURE"
+    )]
+    test_picture: Option<String>,
+    #[options(
+        no_short,
+        help = "Mode of the test device: Lineart, Gray or Color",
+        meta = "MODE"
+    )]
+    test_mode: Option<String>,
+    #[options(no_short, help = "Bits per sample of the test device", meta = "BITS")]
+    test_depth: Option<i32>,
+    #[options(no_short, help = "Width of the test scans in mm", meta = "MM")]
+    test_width: Option<f64>,
+    #[options(no_short, help = "Height of the test scans in mm", meta = "MM")]
+    test_height: Option<f64>,
+    #[options(no_short, help = "Send colour test scans as a frame per channel")]
+    test_three_pass: bool,
+    #[options(no_short, help = "Send test scans without knowing their height")]
+    test_hand_scanner: bool,
+    #[options(
+        no_short,
+        help = "Turn on the options of the test device for frontends"
+    )]
+    test_options: bool,
+    #[options(
+        no_short,
+        help = "Make reads of the test device fail with this status, such as io-error, jammed or no-docs",
+        meta = "STATUS"
+    )]
+    test_error: Option<String>,
+    #[options(
+        help = "Device to open, net:HOST:DEVICE talks to saned directly, escl:URL to eSCL scanners, wia:ID to WIA devices on Windows and mock:NAME to a synthetic device (escl: and wia: alone pick one)",
+        meta = "NAME"
+    )]
+    device: Option<String>,
+    #[options(help = "Directory to store images")]
+    dir: Option<String>,
+    #[options(
+        no_short,
+        help = "Name the images in --dir after this, numbering them where it says {page}",
+        meta = "TEMPLATE"
+    )]
+    page_name: Option<String>,
+    #[options(
+        no_short,
+        help = "List the stored files with their sizes, checksums and scan times in this JSON or CSV file",
+        meta = "FILE"
+    )]
+    manifest: Option<String>,
+    #[options(
+        no_short,
+        help = "Store pages in a directory per day below --dir, as 2024/01/31",
+        meta = "flat|by-date",
+        default = "flat"
+    )]
+    organize: template::Organize,
+    #[options(
+        no_short,
+        help = "Put this in names instead of characters Windows does not allow",
+        meta = "CHAR",
+        default = "_"
+    )]
+    name_replacement: char,
+    #[options(
+        no_short,
+        help = "Cut names of pages and documents to this many bytes",
+        meta = "BYTES",
+        default = "255"
+    )]
+    name_max_length: usize,
+    #[options(
+        no_short,
+        help = "When a file of the name of a page exists, fail the page, replace the file or add a number to the name",
+        meta = "error|overwrite|suffix",
+        default = "suffix"
+    )]
+    on_collision: output::Collision,
+    #[options(
+        no_short,
+        help = "Write a JPEG of at most this many pixels high and wide next to every page",
+        meta = "PIXELS"
+    )]
+    thumbnails: Option<u32>,
+    #[options(
+        no_short,
+        help = "Time to finish the page and the uploads on SIGTERM (30 if not given)",
+        meta = "SECS"
+    )]
+    drain_timeout: Option<u64>,
+    #[options(
+        no_short,
+        help = "Skip or flag pages in --dir which look like the previous one",
+        meta = "skip|flag"
+    )]
+    dedupe: Option<dedupe::Action>,
+    #[options(
+        no_short,
+        help = "Bits in which the hashes of duplicate pages may differ",
+        default = "4"
+    )]
+    dedupe_distance: u32,
+    #[options(
+        no_short,
+        help = "Scan until the feeder is empty, starting a document in --dir at every page with a QR code starting with this. Ctrl-c skips the sheet being scanned, SIGUSR1 pauses and SIGUSR2 resumes",
+        meta = "PREFIX"
+    )]
+    separator: Option<String>,
+    #[options(
+        no_short,
+        help = "After a --separator batch, write its pages as numbered thumbnails to this image or PDF",
+        meta = "FILE"
+    )]
+    contact_sheet: Option<String>,
+    #[options(
+        no_short,
+        help = "When the feeder jams or feeds two sheets in a --separator batch, ask to rescan the sheet, wait until it is cleared or abort",
+        meta = "prompt|wait|abort",
+        default = "prompt"
+    )]
+    on_jam: jam::OnJam,
+    #[options(
+        no_short,
+        help = "Keep --separator batches in this SQLite database, so they can be resumed with skanny jobs",
+        meta = "FILE"
+    )]
+    store: Option<String>,
+    #[options(
+        no_short,
+        help = "Go on with this interrupted batch of --store, see skanny jobs resume",
+        meta = "ID"
+    )]
+    resume: Option<usize>,
+    #[options(no_short, help = "Turn on the double feed detection of the device")]
+    detect_double_feed: bool,
+    #[options(
+        no_short,
+        help = "Split two-page book spreads at the gutter into straightened pages"
+    )]
+    split_pages: bool,
+    #[options(
+        no_short,
+        help = "Straighten lines of text which curve towards the spine of a book"
+    )]
+    dewarp: bool,
+    #[options(
+        no_short,
+        help = "Drop form lines of this colour, by the scanner where it can",
+        meta = "red|green|blue"
+    )]
+    dropout: Option<dropout::Channel>,
+    #[options(no_short, help = "Turn yellowed or recycled paper white")]
+    clean_background: bool,
+    #[options(
+        no_short,
+        help = "Set the options saved by options export before scanning",
+        meta = "FILE"
+    )]
+    restore: Option<String>,
+    #[options(
+        no_short,
+        help = "Scan this size of paper, receipts as long as the device can feed",
+        meta = "a4|letter|legal|receipt"
+    )]
+    paper: Option<paper::Paper>,
+    #[options(
+        no_short,
+        help = "Scan this length of paper, beyond a page on devices which feed long paper",
+        meta = "MM"
+    )]
+    page_height: Option<f64>,
+    #[options(
+        no_short,
+        help = "Turn the lamp off after each job, so it lasts longer"
+    )]
+    lamp_off: bool,
+    #[options(no_short, help = "Upload images to this destination", meta = "URL")]
+    dest: Vec<String>,
+    #[options(
+        no_short,
+        help = "Run this shell command after each page, such as \"ocr.sh {path}\"",
+        meta = "COMMAND"
+    )]
+    on_page: Option<String>,
+    #[options(
+        no_short,
+        help = "Run this shell command after each job, with the pages as {paths}",
+        meta = "COMMAND"
+    )]
+    on_job: Option<String>,
+    #[options(
+        no_short,
+        help = "Run the pages through this processing stage, in the order given",
+        meta = "NAME[=SETTING]"
+    )]
+    stage: Vec<StageSpec>,
+    #[options(
+        no_short,
+        help = "Load processing stages from this shared library",
+        meta = "FILE"
+    )]
+    plugin: Vec<String>,
+    #[options(
+        no_short,
+        help = "Record the calls to the device, for replaying with --device replay:FILE",
+        meta = "FILE"
+    )]
+    record: Option<String>,
+    #[options(
+        no_short,
+        help = "Times to retry opening and starting while the device is busy",
+        default = "4"
+    )]
+    retries: u32,
+    #[options(
+        no_short,
+        help = "Cancel scans when no data arrives for this long",
+        meta = "SECS"
+    )]
+    read_timeout: Option<u64>,
+    #[options(no_short, help = "Times to scan a page again after a read timeout")]
+    page_retries: u32,
+    #[options(no_short, help = "Also list devices shared over the network")]
+    include_network: bool,
+    #[options(
+        no_short,
+        help = "Stop looking for network devices after this long",
+        meta = "SECS",
+        default = "10"
+    )]
+    network_timeout: u64,
+    #[options(
+        no_short,
+        help = "Keep the list of devices in this file",
+        meta = "FILE"
+    )]
+    device_cache: Option<String>,
+    #[options(
+        no_short,
+        help = "Look for the devices again once the listed ones are this old",
+        meta = "SECS",
+        default = "300"
+    )]
+    device_cache_ttl: u64,
+    #[options(
+        no_short,
+        help = "Look for the devices again instead of using the cached list"
+    )]
+    refresh: bool,
+    #[options(
+        no_short,
+        help = "Spool scans larger than this to disk and write them line by line",
+        meta = "MIB"
+    )]
+    spool: Option<usize>,
+    #[options(
+        no_short,
+        help = "Write frames in formats which cannot be read to this directory as received",
+        meta = "DIR"
+    )]
+    dump_unknown_frames: Option<String>,
+    #[options(
+        no_short,
+        help = "Compress the images of PDF files like this, auto picks by the content of the page",
+        meta = "auto|g4|jpeg|flate",
+        default = "auto"
+    )]
+    pdf_compression: pdf::Compression,
+    #[options(
+        no_short,
+        help = "Size the pages of PDF files for this resolution",
+        default = "300"
+    )]
+    pdf_dpi: u32,
+    #[options(no_short, help = "Write PDF files as PDF/A-2b, for archiving")]
+    pdfa: bool,
+    #[options(
+        no_short,
+        help = "Encrypt PDF files, so they need --pdf-password to be opened"
+    )]
+    pdf_encrypt: bool,
+    #[options(
+        no_short,
+        help = "Password to open encrypted PDF files",
+        meta = "PASSWORD"
+    )]
+    pdf_password: Option<String>,
+    #[options(
+        no_short,
+        help = "Password to lift the restrictions of encrypted PDF files, random if not given",
+        meta = "PASSWORD"
+    )]
+    pdf_owner_password: Option<String>,
+    #[options(
+        no_short,
+        help = "What readers of encrypted PDF files may do, from print, modify, copy, annotate, fill-forms and assemble (default: none)",
+        meta = "PERMISSION,..."
+    )]
+    pdf_allow: Option<pdf::Permissions>,
+    #[options(
+        no_short,
+        help = "Recognize these languages in the ocr stage, unless it says",
+        meta = "LANG[+LANG]"
+    )]
+    ocr_lang: Option<String>,
+    #[options(
+        no_short,
+        help = "Segment pages in this Tesseract mode in the ocr stage, unless it says",
+        meta = "MODE"
+    )]
+    ocr_psm: Option<u32>,
+    #[options(
+        no_short,
+        help = "Run this Tesseract command in the ocr stage, unless it says",
+        meta = "PATH"
+    )]
+    ocr_engine: Option<String>,
+    #[options(
+        no_short,
+        help = "Recognize text on this many threads while scanning, uploads and hooks may then run before the text is added",
+        meta = "N",
+        default = "0"
+    )]
+    ocr_workers: usize,
+    #[options(command)]
+    command: Option<Command>,
 }
 
-impl Error {
-    fn is_eof(self) -> bool {
-        self == Error::Status(SANE_Status_SANE_STATUS_EOF)
-    }
+#[derive(Debug, Options)]
+enum Command {
+    #[options(help = "List the devices, also those found on the network with --discover")]
+    Devices(devices::DevicesOptions),
+    #[options(help = "Wait for scanner buttons and scan with the bound profile")]
+    Watch(watch::WatchOptions),
+    #[options(help = "Keep the device open and scan on requests from a socket")]
+    Daemon(daemon::DaemonOptions),
+    #[options(help = "Serve an HTTP API for the device")]
+    Serve(server::ServerOptions),
+    #[options(help = "Provide a D-Bus service for the device")]
+    Dbus(dbus::DbusOptions),
+    #[options(help = "Serve a gRPC API for the device")]
+    Grpc(grpc::GrpcOptions),
+    #[options(help = "Export the device to SANE clients, like saned")]
+    Saned(saned::SanedOptions),
+    #[options(help = "Summarize what the device can do")]
+    Info(info::InfoOptions),
+    #[options(help = "List the options of the device")]
+    Options(options::OptionsOptions),
+    #[options(help = "Measure the throughput of the device at several settings")]
+    Bench(bench::BenchOptions),
+    #[options(help = "Calibrate or clean the device")]
+    Calibrate(calibrate::CalibrateOptions),
+    #[options(help = "Switch the lamp or set the power saving of the device")]
+    Lamp(lamp::LampOptions),
+    #[options(help = "Scan a page and print it with CUPS, like a copier")]
+    Copy(copy::CopyOptions),
+    #[options(help = "Print the number of pages, jams and jobs of each device")]
+    Stats(stats::StatsOptions),
+    #[options(help = "List the jobs a service kept with --store")]
+    Jobs(store::JobsOptions),
+    #[options(help = "Show the configuration files and the settings in effect")]
+    Config(config::ConfigOptions),
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        #[allow(non_upper_case_globals)]
-        match *self {
-            Error::Status(status) => match status {
-                SANE_Status_SANE_STATUS_GOOD => write!(f, "No error"),
-                SANE_Status_SANE_STATUS_UNSUPPORTED => write!(f, "Unsupported"),
-                SANE_Status_SANE_STATUS_CANCELLED => write!(f, "Cancelled"),
-                SANE_Status_SANE_STATUS_DEVICE_BUSY => write!(f, "Device busy"),
-                SANE_Status_SANE_STATUS_INVAL => write!(f, "Invalid value"),
-                SANE_Status_SANE_STATUS_EOF => write!(f, "End of file"),
-                SANE_Status_SANE_STATUS_JAMMED => write!(f, "Document feeder is jammed"),
-                SANE_Status_SANE_STATUS_NO_DOCS => write!(f, "Document feed is empty"),
-                SANE_Status_SANE_STATUS_COVER_OPEN => write!(f, "Cover is open"),
-                SANE_Status_SANE_STATUS_IO_ERROR => write!(f, "Device IO failed"),
-                SANE_Status_SANE_STATUS_NO_MEM => write!(f, "Not enough memory available"),
-                SANE_Status_SANE_STATUS_ACCESS_DENIED => write!(f, "Access denied"),
-                _ => write!(f, "UNKNOWN ERROR: {}", status),
-            },
-            Error::WrongType => write!(f, "Expected another type here"),
-        }
+/// Flag which is raised on ctrl-c and SIGTERM
+fn stop_on_ctrlc() -> std::sync::Arc<std::sync::atomic::AtomicBool> {
+    let shouldstop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let stop = shouldstop.clone();
+    let sigterm = shouldstop.clone();
+    ctrlc::set_handler(move || {
+        shouldstop.store(true, std::sync::atomic::Ordering::SeqCst);
+    })
+    .unwrap();
+    if let Err(e) = shutdown::on_sigterm(sigterm) {
+        tracing::warn!("Cannot stop gracefully on SIGTERM: {}", e);
     }
+    stop
 }
 
-impl std::error::Error for Error {}
+/// Skips the page being scanned on ctrl-c. Pressed twice within a second
+/// it also stops the batch, which keeps and delivers the pages scanned
+fn skip_on_ctrlc(token: skanny::backend::CancelToken) {
+    let mut pressed: Option<std::time::Instant> = None;
+    ctrlc::set_handler(move || {
+        if pressed.is_some_and(|pressed| pressed.elapsed() < std::time::Duration::from_secs(1)) {
+            tracing::warn!("Stopping after the pages scanned so far");
+            shutdown::stop();
+        } else {
+            tracing::warn!("Skipping the page, press ctrl-c twice quickly to stop");
+        }
+        pressed = Some(std::time::Instant::now());
+        token.cancel();
+    })
+    .unwrap();
+}
 
-fn checked(f: impl FnOnce() -> SANE_Status) -> Result<(), Error> {
-    let status = f();
-    if status != SANE_Status_SANE_STATUS_GOOD {
-        Err(Error::Status(status))
-    } else {
-        Ok(())
+/// Tells how many pages wait for their text, if any
+fn print_ocr_queue() {
+    let queued = ocr::queued();
+    if queued > 0 {
+        println!("OCR QUEUE {}", queued);
     }
 }
 
-/// Must be kept active during the scan session
-struct Context {}
-impl Context {
-    fn init() -> Result<(Self, Version), Error> {
-        let mut version_code = -1;
-        unsafe {
-            checked(|| sane_init(&mut version_code, None))?;
-        };
-        Ok((Context {}, Version(version_code)))
-    }
+/// Unique path for a new image in `dir`
+fn timestamped_path(dir: &std::path::Path) -> std::path::PathBuf {
+    loop {
+        let now = std::time::SystemTime::now();
+        let since_unix = now.duration_since(std::time::UNIX_EPOCH).unwrap();
 
-    fn devices(&self, only_local: bool) -> Result<impl ExactSizeIterator<Item = Device>, Error> {
-        let mut device_list: *mut *const SANE_Device = std::ptr::null_mut();
-        unsafe {
-            checked(|| sane_get_devices(&mut device_list, only_local as _))?;
+        let path = dir.join(format!(
+            "plate_{}_{}.png",
+            since_unix.as_secs(),
+            since_unix.subsec_millis()
+        ));
+        if !path.exists() {
+            return path;
         }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+}
 
-        let mut num_devices = 0;
-        unsafe {
-            let mut traveller = device_list;
-            while !(*traveller).is_null() {
-                traveller = traveller.offset(1);
-                num_devices += 1;
-            }
-        }
+/// Scans a page, counting it in the statistics of the device
+fn scan_counted(handle: &Handle) -> Result<Image, skanny::backend::BackendError> {
+    let started = std::time::Instant::now();
+    let image = ScannerDevice::scan(handle);
+    let error = image.as_ref().err().and_then(|e| e.downcast_ref());
+    stats::record_scan(handle.name(), started.elapsed(), error);
+    image
+}
 
-        Ok((0..num_devices).map(move |i| {
-            let device = unsafe { *device_list.offset(i) };
-            Device(device)
-        }))
+/// Stores the lines read by a scan which failed with `error`, if any, as
+/// `page.partial.png` for the page meant for `path`
+fn salvage(
+    error: &skanny::backend::BackendError,
+    path: &std::path::Path,
+    manifest: Option<&mut manifest::Manifest>,
+    scan: &manifest::Scan,
+) {
+    if let Some(partial) = error.downcast_ref::<skanny::backend::PartialFrame>() {
+        match store_partial(partial, path, manifest, scan) {
+            Ok(path) => tracing::warn!(
+                "Stored the {} lines read as {}",
+                partial.frame.height(),
+                path.display()
+            ),
+            Err(e) => tracing::error!("Storing the lines read failed: {}", e),
+        }
     }
 }
-impl Drop for Context {
-    fn drop(&mut self) {
-        unsafe { sane_exit() }
+
+fn store_partial(
+    partial: &skanny::backend::PartialFrame,
+    path: &std::path::Path,
+    manifest: Option<&mut manifest::Manifest>,
+    scan: &manifest::Scan,
+) -> Result<std::path::PathBuf, Box<dyn std::error::Error>> {
+    let extension = path.extension().unwrap_or_default().to_string_lossy();
+    let path = output::claim(&path.with_extension(format!("partial.{}", extension)))?;
+    let image = partial
+        .frame
+        .clone()
+        .into_image()
+        .ok_or("The frame is no image")?;
+    output::write(&path, |temporary| Ok(image.save(temporary)?))?;
+    if let Some(manifest) = manifest {
+        manifest.add_partial(&path, scan, image.width(), image.height())?;
+        manifest.write()?;
     }
+    Ok(path)
 }
 
-#[derive(Copy, Clone)]
-#[repr(transparent)]
-struct Version(SANE_Int);
-
-impl Version {
-    fn major(self) -> SANE_Word {
-        SANE_VERSION_MAJOR(self.0)
-    }
-    fn minor(self) -> SANE_Word {
-        SANE_VERSION_MINOR(self.0)
-    }
-    fn build(self) -> SANE_Word {
-        SANE_VERSION_BUILD(self.0)
+/// Where images go without --dir, the first page of a scan is test.png
+fn test_path(page: usize) -> std::path::PathBuf {
+    match page {
+        0 => "test.png".into(),
+        page => format!("test_{}.png", page + 1).into(),
     }
 }
 
-struct Device(*const SANE_Device);
-
-impl Device {
-    fn name(&self) -> &str {
-        let cstr = unsafe { CStr::from_ptr((*self.0).name) };
-        cstr.to_str().unwrap()
-    }
-    fn vendor(&self) -> &str {
-        let cstr = unsafe { CStr::from_ptr((*self.0).vendor) };
-        cstr.to_str().unwrap()
+/// Pages to store from a scanned image, after the processing asked for.
+/// `dropout` is the colour left to drop in software.
+fn process(
+    cliopts: &CliOptions,
+    dropout: Option<dropout::Channel>,
+    mut image: Image,
+) -> Vec<Image> {
+    if let Some(channel) = dropout {
+        image = dropout::apply(image, channel);
     }
-    fn model(&self) -> &str {
-        let cstr = unsafe { CStr::from_ptr((*self.0).model) };
-        cstr.to_str().unwrap()
-    }
-    fn type_(&self) -> &str {
-        let cstr = unsafe { CStr::from_ptr((*self.0).type_) };
-        cstr.to_str().unwrap()
-    }
-    fn open(&self) -> Result<Handle, Error> {
-        let mut handle = std::ptr::null_mut();
-        unsafe { checked(|| sane_open((*self.0).name, &mut handle))? };
-
-        Ok(Handle(handle))
+    if cliopts.clean_background {
+        image = clean::clean_background(image);
     }
+    let pages = if cliopts.split_pages {
+        let (left, right) = book::split_spread(&image);
+        vec![left, right]
+    } else {
+        vec![image]
+    };
+    pages
+        .into_iter()
+        .map(|mut page| {
+            if cliopts.dewarp {
+                page = book::dewarp(&page);
+            }
+            if cliopts.split_pages {
+                page = book::deskew(&page);
+            }
+            page
+        })
+        .collect()
 }
 
-struct Handle(SANE_Handle);
+fn device_cache(cliopts: &CliOptions) -> device_cache::DeviceCache {
+    device_cache::DeviceCache::new(
+        cliopts.device_cache.as_deref().map(std::path::Path::new),
+        std::time::Duration::from_secs(cliopts.device_cache_ttl),
+        cliopts.refresh,
+    )
+}
 
-impl Drop for Handle {
-    fn drop(&mut self) {
-        unsafe { sane_close(self.0) }
-    }
+/// Whether scans are changed before they are stored
+fn is_processed(cliopts: &CliOptions) -> bool {
+    cliopts.split_pages || cliopts.dewarp || cliopts.clean_background
 }
 
-impl Handle {
-    fn from_name(name: &str) -> Result<Self, Error> {
-        let name = std::ffi::CString::new(name).unwrap();
-        let mut handle = std::ptr::null_mut();
-        unsafe { checked(|| sane_open(name.as_ptr(), &mut handle))? };
-        Ok(Self(handle))
-    }
-    fn descriptors(&self) -> impl ExactSizeIterator<Item = Descriptor> + '_ {
-        // Guaranteed to exist
-        let first_desc = self.get_descriptor(0).unwrap();
-        assert_eq!(first_desc.type_(), SANE_Value_Type_SANE_TYPE_INT);
-        assert_eq!(first_desc.size(), std::mem::size_of::<SANE_Int>() as _);
-        let mut num_desc: SANE_Int = 0;
-        unsafe {
-            checked(|| {
-                sane_control_option(
-                    self.0,
-                    0,
-                    SANE_Action_SANE_ACTION_GET_VALUE,
-                    &mut num_desc as *mut _ as _,
-                    std::ptr::null_mut(),
-                )
-            })
-            .unwrap()
-        };
-        (1..num_desc).map(move |i| self.get_descriptor(i as _).unwrap())
-    }
-    fn get_descriptor(&self, index: usize) -> Option<Descriptor> {
-        let desc = unsafe { sane_get_option_descriptor(self.0, index as _) };
-        if desc.is_null() {
-            None
-        } else {
-            Some(Descriptor(desc))
+/// Sends the log of skanny to stderr or `--log-file`, other crates only
+/// log warnings
+fn init_logging(cliopts: &CliOptions) -> Result<(), Box<dyn std::error::Error>> {
+    use tracing::Level;
+    use tracing_subscriber::filter::Targets;
+    use tracing_subscriber::fmt::writer::BoxMakeWriter;
+    use tracing_subscriber::prelude::*;
+
+    let level = match cliopts.verbose {
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    let targets = Targets::new()
+        .with_target("skanny", level)
+        .with_default(Level::WARN);
+    let builder = tracing_subscriber::fmt().with_max_level(level);
+    let builder = match &cliopts.log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Could not open {}: {}", path, e))?;
+            builder
+                .with_ansi(false)
+                .with_writer(BoxMakeWriter::new(std::sync::Mutex::new(file)))
         }
+        None if cliopts.tui => builder
+            .with_ansi(false)
+            .with_writer(BoxMakeWriter::new(|| tui::LogWriter)),
+        None => builder.with_writer(BoxMakeWriter::new(std::io::stderr)),
+    };
+    if cliopts.log_json {
+        builder.json().finish().with(targets).try_init()?;
+    } else {
+        builder.finish().with(targets).try_init()?;
     }
-    fn options(&self) -> impl ExactSizeIterator<Item = Opt> + '_ {
-        self.descriptors()
-            .enumerate()
-            .map(move |(index, descriptor)| Opt {
-                handle: &self.0,
-                index: index + 1, /* skipping first descriptor */
-                descriptor,
-            })
-    }
+    Ok(())
+}
 
-    fn parameters(&self) -> Result<Parameters, Error> {
-        let mut parameters = std::mem::MaybeUninit::uninit();
-        unsafe { checked(|| sane_get_parameters(self.0, parameters.as_mut_ptr()))? }
-        Ok(Parameters(unsafe { parameters.assume_init() }))
-    }
-    fn start(&self) -> Result<Acquisition, Error> {
-        unsafe { checked(|| sane_start(self.0)) };
-        Ok(Acquisition { handle: &self })
+/// Parses the command line after the settings of the configuration, which
+/// it overrides, exiting with the usage on errors and --help like
+/// `parse_args_default_or_exit`
+fn parse_args() -> (CliOptions, config::Resolved) {
+    let args: Vec<String> = std::env::args().collect();
+    let exit = |e: &dyn std::fmt::Display| -> ! {
+        eprintln!("{}: {}", args[0], e);
+        std::process::exit(2);
+    };
+    let resolved = config::resolve(&args[1..]).unwrap_or_else(|e| exit(&e));
+    let cliopts = CliOptions::parse_args_default(&[resolved.args(), args[1..].to_vec()].concat())
+        .unwrap_or_else(|e| exit(&e));
+    if cliopts.help_requested() {
+        let mut command: &dyn Options = &cliopts;
+        let mut command_str = String::new();
+        while let Some(subcommand) = command.command() {
+            command = subcommand;
+            if let Some(name) = subcommand.command_name() {
+                command_str.push(' ');
+                command_str.push_str(name);
+            }
+        }
+        eprintln!("Usage: {}{} [OPTIONS]", args[0], command_str);
+        eprintln!();
+        eprintln!("{}", command.self_usage());
+        if let Some(commands) = command.self_command_list() {
+            eprintln!();
+            eprintln!("Available commands:");
+            eprintln!("{}", commands);
+        }
+        std::process::exit(0);
     }
+    (cliopts, resolved)
 }
 
-#[derive(Debug)]
-struct Descriptor(*const SANE_Option_Descriptor);
-
-impl Descriptor {
-    fn name(&self) -> &str {
-        let name = unsafe { (*self.0).name };
-        if name.is_null() {
-            ""
-        } else {
-            let cstr = unsafe { CStr::from_ptr(name) };
-            cstr.to_str().unwrap()
-        }
+fn main() {
+    let (cliopts, resolved) = parse_args();
+    if let Err(e) = init_logging(&cliopts) {
+        eprintln!("Could not set up logging: {}", e);
+        std::process::exit(1);
     }
-    fn desc(&self) -> &str {
-        let desc = unsafe { (*self.0).desc };
-        if desc.is_null() {
-            ""
-        } else {
-            let cstr = unsafe { CStr::from_ptr(desc) };
-            cstr.to_str().unwrap()
+    let defaults = ocr::Settings::default();
+    let ocr = ocr::Settings {
+        lang: cliopts.ocr_lang.clone().unwrap_or(defaults.lang),
+        psm: cliopts.ocr_psm,
+        engine: cliopts
+            .ocr_engine
+            .clone()
+            .map_or(defaults.engine, Into::into),
+    };
+    let _ocr = match ocr::init(ocr, cliopts.ocr_workers) {
+        Ok(finish) => finish,
+        Err(e) => {
+            tracing::error!("Setting up OCR failed: {}", e);
+            std::process::exit(1);
         }
+    };
+    if let Err(e) = stages::init(&cliopts.plugin) {
+        tracing::error!("Setting up the processing stages failed: {}", e);
+        std::process::exit(1);
     }
-    fn type_(&self) -> SANE_Value_Type {
-        unsafe { (*self.0).type_ }
+    if let Err(e) = output::init(cliopts.on_collision) {
+        tracing::error!("Setting up the output files failed: {}", e);
+        std::process::exit(1);
     }
-    fn size(&self) -> SANE_Int {
-        unsafe { (*self.0).size }
+    if let Err(e) = thumbnail::init(cliopts.thumbnails) {
+        tracing::error!("Setting up thumbnails failed: {}", e);
+        std::process::exit(1);
     }
-    fn cap(&self) -> SANE_Word {
-        unsafe { (*self.0).cap }
+    if let Err(e) = shutdown::init(cliopts.drain_timeout) {
+        tracing::error!("Setting up the shutdown failed: {}", e);
+        std::process::exit(1);
     }
-}
-
-#[derive(Debug, Clone)]
-struct Parameters(SANE_Parameters);
-
-impl Parameters {
-    fn format(&self) -> SANE_Frame {
-        self.0.format
+    let naming = template::Settings {
+        organize: cliopts.organize,
+        replacement: cliopts.name_replacement,
+        max_length: cliopts.name_max_length,
+    };
+    if let Err(e) = template::init(naming) {
+        tracing::error!("Setting up the output directory failed: {}", e);
+        std::process::exit(1);
     }
-    fn last_frame(&self) -> SANE_Bool {
-        self.0.last_frame
+    let encryption = match (cliopts.pdf_encrypt, &cliopts.pdf_password) {
+        (true, Some(password)) => Ok(Some(pdf::Encryption {
+            user_password: password.clone(),
+            owner_password: cliopts.pdf_owner_password.clone(),
+            permissions: cliopts.pdf_allow.unwrap_or_default(),
+        })),
+        (true, None) => Err("--pdf-encrypt needs --pdf-password"),
+        (false, _)
+            if cliopts.pdf_password.is_some()
+                || cliopts.pdf_owner_password.is_some()
+                || cliopts.pdf_allow.is_some() =>
+        {
+            Err("--pdf-password, --pdf-owner-password and --pdf-allow need --pdf-encrypt")
+        }
+        (false, _) => Ok(None),
+    };
+    let pdf = encryption.map(|encryption| pdf::Settings {
+        compression: cliopts.pdf_compression,
+        dpi: cliopts.pdf_dpi,
+        pdfa: cliopts.pdfa,
+        encryption,
+    });
+    if let Err(e) = pdf.map_err(Into::into).and_then(pdf::init) {
+        tracing::error!("Setting up PDF files failed: {}", e);
+        std::process::exit(1);
     }
-    fn bytes_per_line(&self) -> SANE_Int {
-        self.0.bytes_per_line
+    if let Some(dir) = &cliopts.dump_unknown_frames {
+        skanny::frame::dump_unknown_frames(Some(dir.into()));
     }
-    fn pixels_per_line(&self) -> SANE_Int {
-        self.0.pixels_per_line
+    if !cliopts.testdevice && test_settings(&cliopts) != testdevice::Settings::default() {
+        tracing::error!("The --test-* flags need --testdevice");
+        std::process::exit(1);
     }
-    fn lines(&self) -> SANE_Int {
-        self.0.lines
+    if cliopts.contact_sheet.is_some() && cliopts.separator.is_none() {
+        tracing::error!("--contact-sheet needs --separator");
+        std::process::exit(1);
     }
-    fn depth(&self) -> SANE_Int {
-        self.0.depth
+    // Only batches split into documents are kept, see store.rs
+    if (cliopts.store.is_some() || cliopts.resume.is_some()) && cliopts.separator.is_none() {
+        tracing::error!("--store and --resume need --separator");
+        std::process::exit(1);
     }
-}
-
-#[derive(Debug)]
-struct Opt {
-    handle: *const SANE_Handle,
-    descriptor: Descriptor,
-    index: usize,
-}
-
-impl Opt {
-    fn name(&self) -> &str {
-        self.descriptor.name()
-    }
-    fn desc(&self) -> &str {
-        self.descriptor.desc()
-    }
-    fn string_constraints(&self) -> Result<impl ExactSizeIterator<Item = &str>, Error> {
-        #[allow(non_upper_case_globals)]
-        match unsafe { (*self.descriptor.0) }.constraint_type {
-            SANE_Constraint_Type_SANE_CONSTRAINT_STRING_LIST => (),
-            typ => panic!("type {} is not a string constraint", typ),
-        }
-        let mut len = 0;
-        let mut walker = unsafe { { *self.descriptor.0 }.constraint.string_list };
-        unsafe {
-            while !(*walker).is_null() {
-                len += 1;
-                walker = walker.offset(1);
-            }
-        }
-        Ok((0..len).map(move |i| unsafe {
-            let list = (*self.descriptor.0).constraint.string_list;
-            let cstr = std::ffi::CStr::from_ptr(*list.offset(i) as _);
-            cstr.to_str().unwrap()
-        }))
-    }
-    fn get_string(&self) -> Result<String, Error> {
-        if self.descriptor.type_() != SANE_Value_Type_SANE_TYPE_STRING {
-            return Err(Error::WrongType);
-        }
-        let mut val: Vec<u8> = vec![0; self.descriptor.size() as _];
-        unsafe {
-            checked(|| {
-                sane_control_option(
-                    *self.handle,
-                    self.index as i32,
-                    SANE_Action_SANE_ACTION_GET_VALUE,
-                    val.as_mut_ptr() as *mut _,
-                    std::ptr::null_mut(),
-                )
-            })?;
-        }
-        let first_zero = val.iter().position(|&x| x == 0).unwrap_or(val.len());
-        val.resize(first_zero, 0);
-        Ok(String::from_utf8(val).unwrap())
-    }
-    fn set_string(&self, val: &str) -> Result<(), Error> {
-        assert_eq!(self.descriptor.type_(), SANE_Value_Type_SANE_TYPE_STRING);
-
-        let mut val = val.as_bytes().to_vec();
-        val.push(0);
-
-        let mut info = 0;
-        unsafe {
-            checked(|| {
-                sane_control_option(
-                    *self.handle,
-                    self.index as _,
-                    SANE_Action_SANE_ACTION_SET_VALUE,
-                    val.as_mut_ptr() as *mut _,
-                    &mut info,
-                )
-            })?;
-        };
 
-        Ok(())
-    }
-    fn int_constraints(&self) -> Result<&[SANE_Word], Error> {
-        #[allow(non_upper_case_globals)]
-        match unsafe { (*self.descriptor.0) }.constraint_type {
-            SANE_Constraint_Type_SANE_CONSTRAINT_WORD_LIST => (),
-            typ => panic!("type {} is not a word constraint", typ),
+    if let Some(Command::Config(opts)) = &cliopts.command {
+        if let Err(e) = config::run(&resolved, opts) {
+            tracing::error!("Showing the configuration failed: {}", e);
+            std::process::exit(1);
         }
-        let list = unsafe { (*self.descriptor.0).constraint.word_list };
-        assert!(!list.is_null());
-        let len = unsafe { *list };
-        let list = unsafe { std::slice::from_raw_parts(list, len as usize + 1) };
-        Ok(&list[1..])
-    }
-    fn get_int(&self) -> Result<SANE_Int, Error> {
-        assert!(
-            self.descriptor.type_() == SANE_Value_Type_SANE_TYPE_INT
-                || self.descriptor.type_() == SANE_Value_Type_SANE_TYPE_FIXED
-        );
-        assert_eq!(self.descriptor.size(), std::mem::size_of::<SANE_Int>() as _);
-        let mut val = 0;
-        unsafe {
-            checked(|| {
-                sane_control_option(
-                    *self.handle,
-                    self.index as i32,
-                    SANE_Action_SANE_ACTION_GET_VALUE,
-                    &mut val as *mut _ as _,
-                    std::ptr::null_mut(),
-                )
-            })?;
-        }
-        Ok(val)
-    }
-    fn set_int(&self, val: &mut i32) -> Result<(), Error> {
-        assert!(
-            self.descriptor.type_() == SANE_Value_Type_SANE_TYPE_INT
-                || self.descriptor.type_() == SANE_Value_Type_SANE_TYPE_FIXED
-        );
-        assert_eq!(self.descriptor.size(), std::mem::size_of::<SANE_Int>() as _);
-        unsafe {
-            checked(|| {
-                sane_control_option(
-                    *self.handle,
-                    self.index as i32,
-                    SANE_Action_SANE_ACTION_SET_VALUE,
-                    val as *mut _ as _,
-                    std::ptr::null_mut(),
-                )
-            })?;
-        }
-        Ok(())
-    }
-    fn get_range(&self) -> Result<Range, Error> {
-        #[allow(non_upper_case_globals)]
-        match unsafe { (*self.descriptor.0) }.constraint_type {
-            SANE_Constraint_Type_SANE_CONSTRAINT_RANGE => (),
-            typ => return Err(Error::WrongType),
-        }
-        let range = unsafe { *(*self.descriptor.0).constraint.range };
-        Ok(Range(range))
-    }
-    fn get_bool(&self) -> Result<bool, Error> {
-        assert_eq!(self.descriptor.type_(), SANE_Value_Type_SANE_TYPE_BOOL);
-        assert_eq!(
-            self.descriptor.size(),
-            std::mem::size_of::<SANE_Bool>() as _
-        );
-        let mut val = 0;
-        unsafe {
-            checked(|| {
-                sane_control_option(
-                    *self.handle,
-                    self.index as i32,
-                    SANE_Action_SANE_ACTION_GET_VALUE,
-                    &mut val as *mut _ as _,
-                    std::ptr::null_mut(),
-                )
-            })?;
-        }
-        Ok(val == SANE_TRUE)
+        return;
     }
-}
-
-#[derive(Debug, Copy, Clone)]
-struct Range(SANE_Range);
-
-impl Range {
-    fn min(&self) -> SANE_Word {
-        self.0.min
+    if let Some(Command::Stats(opts)) = &cliopts.command {
+        if let Err(e) = stats::run(opts) {
+            tracing::error!("Reading the statistics failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
     }
-    fn max(&self) -> SANE_Word {
-        self.0.max
+    if let Some(Command::Jobs(opts)) = &cliopts.command {
+        if let Err(e) = store::run(opts) {
+            tracing::error!("Reading the jobs failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
     }
-    fn quant(&self) -> SANE_Word {
-        self.0.quant
+    if let Some((host, device)) = cliopts.device.as_deref().and_then(net::split_device_name) {
+        if let Some(command) = &cliopts.command {
+            backend_command(&net::NetBackend::new(host), Some(device), command);
+            return;
+        }
+        if let Err(e) = scan_backend(&cliopts, &net::NetBackend::new(host), Some(device)) {
+            tracing::error!("Scanning on {} failed: {}", host, e);
+            std::process::exit(1);
+        }
+        return;
     }
-}
-
-struct Acquisition<'a> {
-    handle: &'a Handle,
-}
-
-impl<'a> Acquisition<'a> {
-    fn cancel(self) {}
-    fn restart(&self) -> Result<(), Error> {
-        self.handle.start().map(|x| std::mem::forget(x))
-    }
-
-    fn read_image(&self, mut buffer: &mut [u8]) -> Result<(), Error> {
-        unsafe {
-            'read_loop: loop {
-                let mut len = 0;
-                let e = checked(|| {
-                    sane_read(
-                        self.handle.0,
-                        buffer.as_mut_ptr(),
-                        buffer.len() as _,
-                        &mut len,
-                    )
-                });
-                buffer = &mut buffer[len as usize..];
-                if let Err(err) = e {
-                    if err.is_eof() {
-                        break 'read_loop;
-                    } else {
-                        return Err(err);
+    if let Some(name) = cliopts.device.as_deref() {
+        let backend = match name.split_once(':') {
+            Some(("escl", device)) => Some((escl_backend(), device)),
+            Some(("wia", device)) => Some((wia_backend(), device)),
+            Some(("mock", device)) => Some((mock_backend(device), device)),
+            Some(("replay", path)) => Some((replay_backend(path), "")),
+            _ => None,
+        };
+        if let Some((backend, device)) = backend {
+            let device = if device.is_empty() { None } else { Some(name) };
+            if let Some(command) = &cliopts.command {
+                match backend {
+                    Ok(backend) => backend_command(&*backend, device, command),
+                    Err(e) => {
+                        tracing::error!("Opening {} failed: {}", name, e);
+                        std::process::exit(1);
                     }
                 }
+                return;
             }
-        }
-        assert_eq!(buffer.len(), 0);
-        Ok(())
-    }
-
-    fn get_image(self) -> Result<Image, Error> {
-        let parameters = self.handle.parameters()?;
-
-        let bytesize = parameters.pixels_per_line()
-            * (parameters.depth() / 8)
-            * parameters.lines()
-            * if parameters.format() == SANE_Frame_SANE_FRAME_GRAY {
-                1
-            } else {
-                3
-            };
-        let mut image = vec![0_u8; bytesize as _];
-
-        #[allow(non_upper_case_globals)]
-        match parameters.format() {
-            SANE_Frame_SANE_FRAME_GRAY => self.read_image(&mut image[..])?,
-            SANE_Frame_SANE_FRAME_RGB => {
-                self.read_image(&mut image[..])?;
+            if let Err(e) = backend.and_then(|backend| scan_backend(&cliopts, &*backend, device)) {
+                tracing::error!("Scanning failed: {}", e);
+                std::process::exit(1);
             }
-            format => todo!("format: {}", format),
-        };
-
-        #[allow(non_upper_case_globals)]
-        match (parameters.format(), parameters.depth()) {
-            (SANE_Frame_SANE_FRAME_GRAY, 8) => Ok(Image::Gray8(
-                image::ImageBuffer::from_raw(
-                    parameters.pixels_per_line() as _,
-                    parameters.lines() as _,
-                    image,
-                )
-                .unwrap(),
-            )),
-            (SANE_Frame_SANE_FRAME_RGB, 8) => Ok(Image::Rgb8(
-                image::ImageBuffer::from_raw(
-                    parameters.pixels_per_line() as _,
-                    parameters.lines() as _,
-                    image,
-                )
-                .unwrap(),
-            )),
-            (format, depth) => unimplemented!("format: {} depth: {}", format, depth),
+            return;
         }
     }
-}
 
-impl Drop for Acquisition<'_> {
-    fn drop(&mut self) {
-        unsafe { sane_cancel(self.handle.0) }
+    if cliopts.include_network && std::env::var_os("SANE_NET_TIMEOUT").is_none() {
+        // Read by the net backend when initialised, so it gives up on
+        // hosts which do not answer as well
+        std::env::set_var("SANE_NET_TIMEOUT", cliopts.network_timeout.to_string());
     }
-}
-
-enum Image {
-    Rgb8(image::ImageBuffer<image::Rgb<u8>, Vec<u8>>),
-    Gray8(image::ImageBuffer<image::Luma<u8>, Vec<u8>>),
-}
-
-impl Image {
-    fn save(&self, path: impl AsRef<std::path::Path>) -> image::ImageResult<()> {
-        match self {
-            Image::Gray8(im) => im.save(path),
-            Image::Rgb8(im) => im.save(path),
+    let (context, version) = match Context::init() {
+        Ok(init) => init,
+        Err(e) => {
+            tracing::error!("Could not initialise SANE: {}", e);
+            std::process::exit(1);
         }
-    }
-}
-
-#[derive(Debug, Options)]
-struct CliOptions {
-    #[options(help = "Use a destdevice")]
-    testdevice: bool,
-    #[options(help = "Directory to store images")]
-    dir: Option<String>,
-}
-
-fn main() {
-    let cliopts = CliOptions::parse_args_default_or_exit();
-
-    let (context, version) = Context::init().unwrap();
-    println!(
-        "Version: major: {} minor: {} build: {}",
+    };
+    tracing::info!(
+        "SANE version {}.{}.{}",
         version.major(),
         version.minor(),
         version.build()
     );
-    let handle = if cliopts.testdevice {
-        Handle::from_name("test").unwrap()
+    if cliopts.record.is_some()
+        || cliopts.spool.is_some()
+        || cliopts.separator.is_some()
+        || cliopts.tui
+        || cliopts.preview
+        || cliopts.to_clipboard
+    {
+        if cliopts.command.is_some() {
+            tracing::error!(
+                "Only plain scans can be recorded, spooled, separated, previewed, copied or shown in the dashboard"
+            );
+            std::process::exit(1);
+        }
+        let device = if cliopts.testdevice {
+            Some("test")
+        } else {
+            cliopts.device.as_deref()
+        };
+        if let Err(e) = scan_backend(&cliopts, &context, device) {
+            tracing::error!("Scanning failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(Command::Devices(opts)) = &cliopts.command {
+        let network_timeout = cliopts
+            .include_network
+            .then(|| std::time::Duration::from_secs(cliopts.network_timeout));
+        if let Err(e) = devices::run(&context, &device_cache(&cliopts), network_timeout, opts) {
+            tracing::error!("Listing devices failed: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let retry = Retry {
+        attempts: cliopts.retries + 1,
+        ..Retry::default()
+    };
+    let mut handle = if cliopts.testdevice {
+        Handle::from_name_with_retry("test", retry).unwrap()
+    } else if let Some(name) = &cliopts.device {
+        Handle::from_name_with_retry(name, retry).unwrap()
     } else {
+        let network_timeout = cliopts
+            .include_network
+            .then(|| std::time::Duration::from_secs(cliopts.network_timeout));
+        let devices = device_cache::with_spinner("Looking for devices", || {
+            device_cache(&cliopts).devices(&context, network_timeout)
+        });
+        let devices = match devices {
+            Ok(devices) => devices,
+            Err(e) => {
+                tracing::error!("Listing devices failed: {}", e);
+                std::process::exit(1);
+            }
+        };
         let mut chosen_device = None;
-        for device in context.devices(true).unwrap() {
-            println!("Device:");
-            let name = device.name();
-            println!("\tname: {}", name);
-            println!("\tvendor: {}", device.vendor());
-            println!("\tmodel: {}", device.model());
-            println!("\ttype: {}", device.type_());
+        for device in devices {
+            devices::print(&device);
             chosen_device = Some(device);
         }
 
         let device = chosen_device.unwrap();
-        device.open().unwrap()
+        Handle::from_name_with_retry(&device.name, retry).unwrap()
     };
+    handle.set_read_timeout(cliopts.read_timeout.map(std::time::Duration::from_secs));
+    handle.set_page_retries(cliopts.page_retries);
+    let _device = tracing::info_span!("device", name = handle.name()).entered();
 
-    let mut scanbutton = None;
+    if let Some(command) = &cliopts.command {
+        if device_command(&handle, command) {
+            return;
+        }
+    }
+    match &cliopts.command {
+        Some(Command::Watch(opts)) => {
+            if let Err(e) = watch::run(&context, handle, opts) {
+                tracing::error!("Watching failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Daemon(opts)) => {
+            if let Err(e) = daemon::run(&context, handle, opts) {
+                tracing::error!("Daemon failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Serve(opts)) => {
+            if let Err(e) = server::run(&context, &handle, opts) {
+                tracing::error!("Server failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Dbus(opts)) => {
+            if let Err(e) = dbus::run(&context, &handle, opts) {
+                tracing::error!("D-Bus service failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Grpc(opts)) => {
+            if let Err(e) = grpc::run(&context, &handle, opts) {
+                tracing::error!("gRPC service failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Saned(opts)) => {
+            if let Err(e) = saned::run(&context, &handle, opts) {
+                tracing::error!("SANE network daemon failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Options(opts)) => {
+            if let Err(e) = options::run(&handle, opts) {
+                tracing::error!("Listing the options failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        // Handled for devices of every backend
+        Some(Command::Info(_))
+        | Some(Command::Bench(_))
+        | Some(Command::Calibrate(_))
+        | Some(Command::Lamp(_))
+        | Some(Command::Copy(_)) => {}
+        // Handled before opening the device
+        Some(Command::Stats(_))
+        | Some(Command::Jobs(_))
+        | Some(Command::Config(_))
+        | Some(Command::Devices(_))
+        | None => {}
+    }
 
     println!("Options:");
-    for option in handle.options() {
-        let optname = option.name();
-        if optname.is_empty() {
-            continue;
-        }
-        println!("\t{}", optname);
-        for line in option.desc().lines() {
-            println!("\t\t{}", line);
+    match ScannerDevice::options(&handle) {
+        Ok(listed) => options::print(&listed),
+        Err(e) => {
+            tracing::error!("Listing the options failed: {}", e);
+            std::process::exit(1);
         }
-        match optname {
+    }
+    let mut scanbutton = None;
+    for option in handle.options() {
+        match option.name() {
             "mode" => {
                 if !cliopts.testdevice {
                     option.set_string("Color").unwrap()
                 }
-                let active_mode = option.get_string().unwrap();
-                print!("\t\t");
-                for opt in option.string_constraints().unwrap() {
-                    if opt == active_mode {
-                        print!("[{}]\t", opt);
-                    } else {
-                        print!("{}\t", opt);
-                    }
-                }
-                println!();
                 option.set_string("color").unwrap()
             }
-            "depth" => {
-                println!("\t\tCurrent depth: {}", option.get_int().unwrap());
-            }
-            "resolution" => {
-                if let Ok(range) = option.get_range() {
-                    let active_resolution = option.get_int().unwrap();
-                    println!(
-                        "\t\tmin:{} max:{} quant:{} :: current: {}",
-                        range.min(),
-                        range.max(),
-                        range.quant(),
-                        active_resolution
-                    );
-                } else {
-                    option.set_int(&mut 600).unwrap();
-                    let active_resolution = option.get_int().unwrap();
-                    let resolutions = option.int_constraints().unwrap();
-                    print!("\t\t");
-                    for &res in resolutions {
-                        if res == active_resolution {
-                            print!("[{}]\t", res);
-                        } else {
-                            print!("{}\t", res);
-                        }
-                    }
-                    println!();
-                }
-            }
-            "test-picture" => {
-                option.set_string("Color pattern");
+            "resolution" if option.get_range().is_err() => {
+                option.set_int(&mut 600).unwrap();
             }
             "scan" | "bool-soft-detect" => {
                 scanbutton = Some(option);
@@ -630,47 +1031,579 @@ fn main() {
         }
     }
 
-    if let Some(dir) = cliopts.dir.as_ref() {
-        let dir = std::path::Path::new(dir);
+    if cliopts.testdevice {
+        if let Err(e) = testdevice::apply(&handle, &test_settings(&cliopts)) {
+            tracing::error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = &cliopts.restore {
+        let restored =
+            snapshot::load(path.as_ref()).and_then(|state| snapshot::restore(&handle, &state));
+        if let Err(e) = restored {
+            tracing::error!("Restoring the options from {} failed: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+    if let Err(e) = environment::apply(&handle) {
+        tracing::error!("Setting the options from the environment failed: {}", e);
+        std::process::exit(1);
+    }
+    if cliopts.paper.is_some() || cliopts.page_height.is_some() {
+        if let Err(e) = paper::set(&handle, cliopts.paper, cliopts.page_height) {
+            tracing::error!("Setting the paper size failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if cliopts.detect_double_feed {
+        match jam::detect_double_feeds(&handle) {
+            Ok(true) => {}
+            Ok(false) => tracing::warn!("The device cannot detect double feeds"),
+            Err(e) => {
+                tracing::error!("Turning on double feed detection failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    let dropout = cliopts
+        .dropout
+        .filter(|&channel| !dropout::hardware(&handle, channel));
+    let pipeline = match stages::registry().pipeline(&cliopts.stage) {
+        Ok(pipeline) => pipeline,
+        Err(e) => {
+            tracing::error!("Setting up the processing stages failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(dir_template) = cliopts.dir.as_ref() {
+        let dir_template = std::path::Path::new(dir_template);
+        let mut dir = template::directory(dir_template, &template::DateTime::now());
         std::fs::create_dir_all(&dir).unwrap();
+        let mut numbering = match &cliopts.page_name {
+            Some(template) => match template::Numbering::resume(&dir, template) {
+                Ok(numbering) => Some(numbering),
+                Err(e) => {
+                    tracing::error!("Numbering the pages failed: {}", e);
+                    std::process::exit(1);
+                }
+            },
+            None => None,
+        };
+        let mut dedupe = cliopts
+            .dedupe
+            .map(|action| dedupe::Dedupe::new(action, cliopts.dedupe_distance));
         let scanbutton = scanbutton.unwrap();
-        let shouldstop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-        let stop = shouldstop.clone();
-        ctrlc::set_handler(move || {
-            shouldstop.store(true, std::sync::atomic::Ordering::SeqCst);
-        })
-        .unwrap();
+        let stop = stop_on_ctrlc();
+        let mut manifest = cliopts
+            .manifest
+            .as_ref()
+            .map(|path| manifest::Manifest::new(path.into(), &handle));
+        let mut scans = 0;
 
         'image_loop: loop {
-            println!("Scan by pushing scan, or interrupt with ctrl-c");
+            tracing::info!("Scan by pushing scan, or interrupt with ctrl-c");
             'button_loop: loop {
                 if stop.load(std::sync::atomic::Ordering::SeqCst) {
                     break 'image_loop;
                 }
                 if scanbutton.get_bool().unwrap() {
-                    println!("SCANNING...");
+                    tracing::info!("Scanning");
                     break 'button_loop;
                 }
             }
-            let acq = handle.start().unwrap();
-            let image = acq.get_image().unwrap();
+            stats::record_job(handle.name());
+            // Batches running past midnight go on in the directory of the
+            // next day
+            let today = template::directory(dir_template, &template::DateTime::now());
+            if today != dir {
+                dir = today;
+                let resumed =
+                    std::fs::create_dir_all(&dir).and_then(|()| match &cliopts.page_name {
+                        Some(template) => template::Numbering::resume(&dir, template).map(Some),
+                        None => Ok(None),
+                    });
+                match resumed {
+                    Ok(resumed) => numbering = resumed,
+                    Err(e) => {
+                        tracing::error!("Creating {} failed: {}", dir.display(), e);
+                        continue;
+                    }
+                }
+            }
+            let _lamp = cliopts.lamp_off.then(|| lamp::OffAfterJob(&handle));
+            let mut hooks =
+                hooks::Hooks::new(cliopts.on_page.as_deref(), cliopts.on_job.as_deref());
+            scans += 1;
+            let (image, scan) = manifest::Scan::time(scans, || scan_counted(&handle));
+            let image = match image {
+                Ok(image) => image,
+                Err(e) => {
+                    let imagepath = match &mut numbering {
+                        Some(numbering) => numbering.next_path(),
+                        None => timestamped_path(&dir),
+                    };
+                    salvage(&e, &imagepath, manifest.as_mut(), &scan);
+                    tracing::error!("The job failed: {}", e);
+                    continue;
+                }
+            };
+
+            if let Some(dedupe) = &mut dedupe {
+                if dedupe.is_duplicate(&image) {
+                    if dedupe.action == dedupe::Action::Skip {
+                        tracing::warn!("Skipping a duplicate of the previous page");
+                        continue;
+                    }
+                    tracing::warn!("The page looks like a duplicate of the previous one");
+                }
+            }
+
+            for image in process(&cliopts, dropout, image) {
+                let imagepath = match &mut numbering {
+                    Some(numbering) => numbering.next_path(),
+                    None => timestamped_path(&dir),
+                };
+                let imagepath = match output::claim(&imagepath) {
+                    Ok(imagepath) => imagepath,
+                    Err(e) => {
+                        tracing::error!("The job failed: {}", e);
+                        continue 'image_loop;
+                    }
+                };
+
+                tracing::info!("Saving image");
+                let image = match stages::save(&pipeline, image, &imagepath) {
+                    Ok(image) => image,
+                    Err(e) => {
+                        tracing::error!("The job failed: {}", e);
+                        continue 'image_loop;
+                    }
+                };
+                if let Some(manifest) = &mut manifest {
+                    if let Err(e) = manifest.add(&imagepath, &scan, image.width(), image.height()) {
+                        tracing::error!(
+                            "Adding {} to the manifest failed: {}",
+                            imagepath.display(),
+                            e
+                        );
+                    }
+                }
+                if let Err(e) = destination::store_all(&cliopts.dest, &imagepath) {
+                    tracing::error!("Storing {} failed: {}", imagepath.display(), e);
+                    continue 'image_loop;
+                }
+                if let Err(e) = hooks.page(&imagepath) {
+                    tracing::error!("The job failed: {}", e);
+                    continue 'image_loop;
+                }
+            }
+            if let Some(Err(e)) = manifest.as_ref().map(manifest::Manifest::write) {
+                tracing::error!("Writing the manifest failed: {}", e);
+            }
+            if let Err(e) = hooks.finish() {
+                tracing::error!("The job failed: {}", e);
+            }
+        }
+    } else {
+        if let Err(e) = shutdown::finish_on_sigterm() {
+            tracing::warn!("Cannot finish the scan on SIGTERM: {}", e);
+        }
+        stats::record_job(handle.name());
+        let _lamp = cliopts.lamp_off.then(|| lamp::OffAfterJob(&handle));
+        let mut hooks = hooks::Hooks::new(cliopts.on_page.as_deref(), cliopts.on_job.as_deref());
+        let mut manifest = cliopts
+            .manifest
+            .as_ref()
+            .map(|path| manifest::Manifest::new(path.into(), &handle));
+        let (image, scan) = manifest::Scan::time(1, || scan_counted(&handle));
+        let image = match image {
+            Ok(image) => image,
+            Err(e) => {
+                salvage(&e, &test_path(0), manifest.as_mut(), &scan);
+                tracing::error!("Scanning failed: {}", e);
+                std::process::exit(1);
+            }
+        };
+        for (page, image) in process(&cliopts, dropout, image).into_iter().enumerate() {
+            let imagepath = match output::claim(&test_path(page)) {
+                Ok(imagepath) => imagepath,
+                Err(e) => {
+                    tracing::error!("The job failed: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            let image = match stages::save(&pipeline, image, &imagepath) {
+                Ok(image) => image,
+                Err(e) => {
+                    tracing::error!("The job failed: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if let Some(manifest) = &mut manifest {
+                if let Err(e) = manifest.add(&imagepath, &scan, image.width(), image.height()) {
+                    tracing::error!(
+                        "Adding {} to the manifest failed: {}",
+                        imagepath.display(),
+                        e
+                    );
+                    std::process::exit(1);
+                }
+            }
+            if let Err(e) = destination::store_all(&cliopts.dest, &imagepath) {
+                tracing::error!("Storing {} failed: {}", imagepath.display(), e);
+                std::process::exit(1);
+            }
+            if let Err(e) = hooks.page(&imagepath) {
+                tracing::error!("The job failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        if let Some(Err(e)) = manifest.as_ref().map(manifest::Manifest::write) {
+            tracing::error!("Writing the manifest failed: {}", e);
+            std::process::exit(1);
+        }
+        if let Err(e) = hooks.finish() {
+            tracing::error!("The job failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "escl")]
+fn escl_backend() -> Result<Box<dyn ScannerBackend>, Box<dyn std::error::Error>> {
+    Ok(Box::new(escl::EsclBackend::default()))
+}
+
+#[cfg(not(feature = "escl"))]
+fn escl_backend() -> Result<Box<dyn ScannerBackend>, Box<dyn std::error::Error>> {
+    Err("skanny was built without the escl feature".into())
+}
+
+/// Synthetic device for trying out skanny without a scanner
+fn mock_backend(name: &str) -> Result<Box<dyn ScannerBackend>, Box<dyn std::error::Error>> {
+    let spec = mock::DeviceSpec {
+        name: format!("mock:{}", name),
+        ..mock::DeviceSpec::default()
+    };
+    Ok(Box::new(mock::MockBackend::new(vec![spec])))
+}
+
+#[cfg(feature = "record")]
+fn replay_backend(path: &str) -> Result<Box<dyn ScannerBackend>, Box<dyn std::error::Error>> {
+    Ok(Box::new(record::ReplayBackend::load(path.as_ref())?))
+}
+
+#[cfg(not(feature = "record"))]
+fn replay_backend(_path: &str) -> Result<Box<dyn ScannerBackend>, Box<dyn std::error::Error>> {
+    Err("skanny was built without the record feature".into())
+}
+
+#[cfg(feature = "record")]
+fn record(
+    device: Box<dyn ScannerDevice>,
+    path: &str,
+) -> Result<Box<dyn ScannerDevice>, Box<dyn std::error::Error>> {
+    Ok(Box::new(record::Recorder::new(device, path.as_ref())?))
+}
+
+#[cfg(not(feature = "record"))]
+fn record(
+    _device: Box<dyn ScannerDevice>,
+    _path: &str,
+) -> Result<Box<dyn ScannerDevice>, Box<dyn std::error::Error>> {
+    Err("skanny was built without the record feature".into())
+}
+
+#[cfg(all(windows, feature = "wia"))]
+fn wia_backend() -> Result<Box<dyn ScannerBackend>, Box<dyn std::error::Error>> {
+    Ok(Box::new(wia::WiaBackend::new()?))
+}
+
+#[cfg(not(all(windows, feature = "wia")))]
+fn wia_backend() -> Result<Box<dyn ScannerBackend>, Box<dyn std::error::Error>> {
+    Err("skanny was built without the wia feature, which needs Windows".into())
+}
+
+/// The settings of the test device from the `--test-*` flags
+fn test_settings(cliopts: &CliOptions) -> testdevice::Settings {
+    let defaults = testdevice::Settings::default();
+    testdevice::Settings {
+        picture: cliopts.test_picture.clone().unwrap_or(defaults.picture),
+        mode: cliopts.test_mode.clone(),
+        depth: cliopts.test_depth,
+        width: cliopts.test_width,
+        height: cliopts.test_height,
+        three_pass: cliopts.test_three_pass,
+        hand_scanner: cliopts.test_hand_scanner,
+        test_options: cliopts.test_options,
+        error: cliopts.test_error.clone(),
+    }
+}
 
-            let now = std::time::SystemTime::now();
-            let since_unix = now.duration_since(std::time::UNIX_EPOCH).unwrap();
+/// Runs the commands which work with devices of every backend, returns
+/// whether `command` is one of them
+fn device_command(device: &dyn ScannerDevice, command: &Command) -> bool {
+    let (action, result) = match command {
+        Command::Info(opts) => ("Probing the device", info::run(device, opts)),
+        Command::Bench(opts) => ("Benchmark", bench::run(device, opts)),
+        Command::Calibrate(opts) => ("Calibration", calibrate::run(device, opts)),
+        Command::Lamp(opts) => ("Lamp control", lamp::run(device, opts)),
+        Command::Copy(opts) => ("Copying", copy::run(device, opts)),
+        _ => return false,
+    };
+    if let Err(e) = result {
+        tracing::error!("{} failed: {}", action, e);
+        std::process::exit(1);
+    }
+    true
+}
 
-            let mut imagepath = dir.join(format!(
-                "plate_{}_{}.png",
-                since_unix.as_secs(),
-                since_unix.subsec_millis()
-            ));
-            assert!(!imagepath.exists());
+/// Runs `command` with a device of a backend other than SANE
+fn backend_command(backend: &dyn ScannerBackend, device: Option<&str>, command: &Command) {
+    let device = match open_device(backend, device) {
+        Ok(device) => device,
+        Err(e) => {
+            tracing::error!("Opening the device failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let _device = tracing::info_span!("device", name = device.name()).entered();
+    if !device_command(&*device, command) {
+        tracing::error!(
+            "Only info, bench, calibrate, lamp and copy work with {} devices",
+            skanny::backend::backend_of(device.name())
+        );
+        std::process::exit(1);
+    }
+}
 
-            println!("SAVING IMAGE...");
-            image.save(imagepath).unwrap();
+/// Opens a device of `backend`, the last one found if no name is given
+fn open_device(
+    backend: &dyn ScannerBackend,
+    device: Option<&str>,
+) -> Result<Box<dyn ScannerDevice>, Box<dyn std::error::Error>> {
+    match device {
+        Some(name) => Ok(backend.open(name)?),
+        None => {
+            let mut chosen_device = None;
+            for device in backend.enumerate()? {
+                devices::print(&device);
+                chosen_device = Some(device.name);
+            }
+            Ok(backend.open(&chosen_device.ok_or("No devices found")?)?)
         }
+    }
+}
+
+/// Lists the options of a device of `backend` and scans a page, the last
+/// device found is used if no name is given
+fn scan_backend(
+    cliopts: &CliOptions,
+    backend: &dyn ScannerBackend,
+    device: Option<&str>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    shutdown::finish_on_sigterm()?;
+    let device = open_device(backend, device)?;
+    let device = match &cliopts.record {
+        Some(path) => record(device, path)?,
+        None => device,
+    };
+    let device = if cliopts.preview {
+        preview::wrap(device)?
     } else {
-        let acq = handle.start().unwrap();
-        let image = acq.get_image().unwrap();
-        image.save("test.png").unwrap();
+        device
+    };
+    if cliopts.testdevice {
+        testdevice::apply(&*device, &test_settings(cliopts))?;
+    }
+    let counting = stats::Counting::new(&*device);
+    let device: &dyn ScannerDevice = &counting;
+    stats::record_job(device.name());
+    let _device = tracing::info_span!("device", name = device.name()).entered();
+    let _lamp = cliopts.lamp_off.then(|| lamp::OffAfterJob(device));
+
+    println!("Options:");
+    options::print(&device.options()?);
+
+    if let Some(path) = &cliopts.restore {
+        snapshot::restore(device, &snapshot::load(path.as_ref())?)?;
+    }
+    environment::apply(device)?;
+    if cliopts.paper.is_some() || cliopts.page_height.is_some() {
+        paper::set(device, cliopts.paper, cliopts.page_height)?;
+    }
+    if cliopts.detect_double_feed && !jam::detect_double_feeds(device)? {
+        tracing::warn!("The device cannot detect double feeds");
+    }
+    let dropout = cliopts
+        .dropout
+        .filter(|&channel| !dropout::hardware(device, channel));
+    let mut hooks = hooks::Hooks::new(cliopts.on_page.as_deref(), cliopts.on_job.as_deref());
+    let pipeline = stages::registry().pipeline(&cliopts.stage)?;
+    let mut manifest = cliopts
+        .manifest
+        .as_ref()
+        .map(|path| manifest::Manifest::new(path.into(), device));
+
+    if let Some(prefix) = &cliopts.separator {
+        let dir = cliopts
+            .dir
+            .as_deref()
+            .ok_or("Separating documents needs --dir")?;
+        let dir = template::directory(dir.as_ref(), &template::DateTime::now());
+        let (batch, resumed) = match (&cliopts.store, cliopts.resume) {
+            (Some(store), Some(id)) => {
+                let (batch, resumed) = store::Batch::resume(store.as_ref(), id)?;
+                (Some(batch), Some(resumed))
+            }
+            (Some(store), None) => {
+                let args = std::env::args().skip(1).collect();
+                let batch = store::Batch::start(store.as_ref(), device.name(), &dir, args)?;
+                (Some(batch), None)
+            }
+            (None, Some(_)) => return Err("--resume needs --store".into()),
+            (None, None) => (None, None),
+        };
+        let (dir, from, mut pages) = match resumed {
+            Some(resumed) => (resumed.dir, resumed.progress, resumed.pages),
+            None => (dir, Default::default(), Vec::new()),
+        };
+        let token = skanny::backend::CancelToken::new();
+        skip_on_ctrlc(token.clone());
+        pause::on_signals()?;
+        let device = skanny::backend::Cancellable::new(device, token);
+        let scanned = separate::scan_batch(
+            &device,
+            &dir,
+            prefix,
+            separate::codes,
+            &pipeline,
+            |sheet, misfeed| cliopts.on_jam.recover(sheet, misfeed),
+            shutdown::stopping,
+            from,
+            |progress, page| {
+                if let Some(batch) = &batch {
+                    batch.sheet(progress, page);
+                }
+            },
+        );
+        let deliver = move || -> Result<(), Box<dyn std::error::Error>> {
+            pages.extend(scanned?);
+            if let Some(path) = &cliopts.contact_sheet {
+                let paths: Vec<_> = pages.iter().map(|page| page.path.clone()).collect();
+                contact::write(&paths, path.as_ref())?;
+            }
+            for page in pages {
+                destination::store_all(&cliopts.dest, &page.path)?;
+                println!("SAVED IMAGE {}", page.path.display());
+                print_ocr_queue();
+                if page.double_feed {
+                    println!("DOUBLE FEED {}", page.path.display());
+                }
+                if let Some(manifest) = &mut manifest {
+                    manifest.add(&page.path, &page.scan, page.width, page.height)?;
+                }
+                hooks.page(&page.path)?;
+            }
+            if let Some(manifest) = &manifest {
+                manifest.write()?;
+            }
+            hooks.finish()
+        };
+        let delivered = deliver();
+        if let Some(batch) = &batch {
+            batch.finish(delivered.as_ref().err().map(ToString::to_string));
+        }
+        return delivered;
+    }
+
+    let dir = cliopts
+        .dir
+        .as_deref()
+        .map(|dir| template::directory(dir.as_ref(), &template::DateTime::now()));
+    let dir = dir.as_deref();
+    if let Some(dir) = dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut numbering = match (dir, &cliopts.page_name) {
+        (Some(dir), Some(template)) => Some(template::Numbering::resume(dir, template)?),
+        _ => None,
+    };
+    let mut next_path = |page| {
+        output::claim(&match (&mut numbering, dir) {
+            (Some(numbering), _) => numbering.next_path(),
+            (None, Some(dir)) => timestamped_path(dir),
+            (None, None) => test_path(page),
+        })
+    };
+    if cliopts.tui {
+        tui::run(device, |image| {
+            let mut stored = None;
+            for (page, image) in process(cliopts, dropout, image).into_iter().enumerate() {
+                let imagepath = next_path(page)?;
+                let image = stages::save(&pipeline, image, &imagepath)?;
+                destination::store_all(&cliopts.dest, &imagepath)?;
+                hooks.page(&imagepath)?;
+                stored = Some((imagepath, image));
+            }
+            Ok(stored.ok_or("Processing left no pages")?)
+        })?;
+        return hooks.finish();
+    }
+    let mut saved = |imagepath: &std::path::Path| -> Result<(), Box<dyn std::error::Error>> {
+        destination::store_all(&cliopts.dest, imagepath)?;
+        println!("SAVED IMAGE {}", imagepath.display());
+        print_ocr_queue();
+        hooks.page(imagepath)
+    };
+    if let Some(mib) = cliopts.spool {
+        if is_processed(cliopts) || dropout.is_some() || !pipeline.is_empty() {
+            return Err(
+                "Spooled scans are written as they are read and cannot be processed".into(),
+            );
+        }
+        let imagepath = next_path(0)?;
+        let (spool, scan) = manifest::Scan::time(1, || {
+            Spool::acquire(device, mib.saturating_mul(1024 * 1024))
+        });
+        let mut spool = spool?;
+        output::write(&imagepath, |temporary| {
+            let file = std::fs::File::create(temporary)?;
+            spool.write_png(std::io::BufWriter::new(file))
+        })?;
+        if let Some(manifest) = &mut manifest {
+            let width = spool.parameters().pixels_per_line.max(0) as u32;
+            manifest.add(&imagepath, &scan, width, spool.lines())?;
+            manifest.write()?;
+        }
+        saved(&imagepath)?;
+        return hooks.finish();
+    }
+    let (image, scan) = manifest::Scan::time(1, || device.scan());
+    if cliopts.to_clipboard {
+        let image = process(cliopts, dropout, image?)
+            .into_iter()
+            .next()
+            .ok_or("Processing left no pages")?;
+        let page = pipeline.run(image.into());
+        ocr::forget();
+        clipboard::copy(&page?.image)?;
+        return hooks.finish();
+    }
+    if let Err(e) = &image {
+        salvage(e, &next_path(0)?, manifest.as_mut(), &scan);
+    }
+    for (page, image) in process(cliopts, dropout, image?).into_iter().enumerate() {
+        let imagepath = next_path(page)?;
+        let image = stages::save(&pipeline, image, &imagepath)?;
+        if let Some(manifest) = &mut manifest {
+            manifest.add(&imagepath, &scan, image.width(), image.height())?;
+        }
+        saved(&imagepath)?;
+    }
+    if let Some(manifest) = &manifest {
+        manifest.write()?;
     }
+    hooks.finish()
 }
@@ -0,0 +1,262 @@
+//! Manifests of the files stored by a batch
+//!
+//! With `--manifest FILE` every stored file is listed with its page number,
+//! the sheet and side it was scanned from, its size in pixels, the
+//! resolution, its size in bytes, its SHA-256 checksum and when and for how
+//! long it was scanned, so archives can check what they ingest. Manifests
+//! ending in `.csv` get a header and a line per file, others are a JSON
+//! array. The manifest is written after every job, so it also lists the
+//! files of a batch which was interrupted.
+//!
+//! Scans which failed partway are stored with the lines read, and listed
+//! with `partial` set.
+//!
+//! Sheets are counted by the scans of the batch. When the source of the
+//! device is a duplex one, every other scan is the back of the sheet before.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use skanny::backend::ScannerDevice;
+use skanny::OptionValue;
+
+use crate::template::DateTime;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Front,
+    Back,
+}
+
+/// A stored file
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Entry {
+    pub path: String,
+    /// Counting from 1 through the batch
+    pub page: usize,
+    pub sheet: usize,
+    pub side: Side,
+    pub width: u32,
+    pub height: u32,
+    pub dpi: Option<f64>,
+    pub bytes: u64,
+    pub sha256: String,
+    /// When the scan started
+    pub scanned: String,
+    pub scan_seconds: f64,
+    /// Only the start of the page, the scan failed
+    pub partial: bool,
+}
+
+/// A scan of one side of a sheet
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Scan {
+    /// Counting from 1 through the batch
+    pub number: usize,
+    pub started: SystemTime,
+    pub took: Duration,
+}
+
+impl Scan {
+    /// Times `scan`, the scan `number` of the batch
+    pub fn time<T>(number: usize, scan: impl FnOnce() -> T) -> (T, Self) {
+        let started = SystemTime::now();
+        let result = scan();
+        let took = started.elapsed().unwrap_or_default();
+        (
+            result,
+            Self {
+                number,
+                started,
+                took,
+            },
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct Manifest {
+    path: PathBuf,
+    dpi: Option<f64>,
+    duplex: bool,
+    entries: Vec<Entry>,
+}
+
+impl Manifest {
+    /// An empty manifest for the scans of `device` with its current
+    /// resolution and source
+    pub fn new(path: PathBuf, device: &dyn ScannerDevice) -> Self {
+        let options = device.options().unwrap_or_else(|e| {
+            tracing::warn!("Reading the resolution for the manifest failed: {}", e);
+            Vec::new()
+        });
+        let value = |name: &str| {
+            options
+                .iter()
+                .find(|option| option.name == name)
+                .and_then(|option| option.value.clone())
+        };
+        let dpi = match value("resolution") {
+            Some(OptionValue::Int(dpi)) => Some(f64::from(dpi)),
+            Some(OptionValue::Fixed(dpi)) => Some(dpi),
+            _ => None,
+        };
+        let duplex = matches!(
+            value("source"),
+            Some(OptionValue::String(source)) if source.to_lowercase().contains("duplex")
+        );
+        Self {
+            path,
+            dpi,
+            duplex,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Lists `file`, of `width` by `height` pixels, from `scan`
+    pub fn add(
+        &mut self,
+        file: &Path,
+        scan: &Scan,
+        width: u32,
+        height: u32,
+    ) -> std::io::Result<()> {
+        let data = std::fs::read(file)?;
+        let (sheet, side) = match (self.duplex, scan.number % 2) {
+            (true, 0) => (scan.number / 2, Side::Back),
+            (true, _) => (scan.number / 2 + 1, Side::Front),
+            (false, _) => (scan.number, Side::Front),
+        };
+        self.entries.push(Entry {
+            path: file.display().to_string(),
+            page: self.entries.len() + 1,
+            sheet,
+            side,
+            width,
+            height,
+            dpi: self.dpi,
+            bytes: data.len() as u64,
+            sha256: Sha256::digest(&data)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            scanned: DateTime::from(scan.started).to_string(),
+            scan_seconds: scan.took.as_secs_f64(),
+            partial: false,
+        });
+        Ok(())
+    }
+
+    /// Lists `file`, the lines read by `scan` before it failed
+    pub fn add_partial(
+        &mut self,
+        file: &Path,
+        scan: &Scan,
+        width: u32,
+        height: u32,
+    ) -> std::io::Result<()> {
+        self.add(file, scan, width, height)?;
+        if let Some(entry) = self.entries.last_mut() {
+            entry.partial = true;
+        }
+        Ok(())
+    }
+
+    pub fn write(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let contents = if self.path.extension().and_then(|ext| ext.to_str()) == Some("csv") {
+            csv(&self.entries)?
+        } else {
+            serde_json::to_string_pretty(&self.entries)? + "\n"
+        };
+        crate::output::write(&self.path, |temporary| {
+            Ok(std::fs::write(temporary, contents)?)
+        })
+    }
+}
+
+/// The entries as CSV, with the fields named as in JSON
+fn csv(entries: &[Entry]) -> serde_json::Result<String> {
+    fn field(value: &serde_json::Value) -> String {
+        let text = match value {
+            serde_json::Value::Null => return String::new(),
+            serde_json::Value::String(text) => text.clone(),
+            value => value.to_string(),
+        };
+        if text.contains(&[',', '"', '\n', '\r'][..]) {
+            format!("\"{}\"", text.replace('"', "\"\""))
+        } else {
+            text
+        }
+    }
+
+    let mut csv = String::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let entry = match serde_json::to_value(entry)? {
+            serde_json::Value::Object(entry) => entry,
+            _ => unreachable!("Entries are structs"),
+        };
+        if i == 0 {
+            let names: Vec<&str> = entry.keys().map(String::as_str).collect();
+            csv += &names.join(",");
+            csv += "\n";
+        }
+        let fields: Vec<String> = entry.values().map(field).collect();
+        csv += &fields.join(",");
+        csv += "\n";
+    }
+    Ok(csv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use skanny::mock::{DeviceSpec, MockDevice};
+
+    #[test]
+    fn lists_sheets_and_checksums() {
+        let dir = std::env::temp_dir().join(format!("skanny-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut spec = DeviceSpec::default();
+        let mut source = spec.options[0].clone();
+        source.name = "source".to_owned();
+        source.value = Some(OptionValue::String("ADF Duplex".to_owned()));
+        spec.options.push(source);
+        let device = MockDevice::new(spec);
+        let mut manifest = Manifest::new(dir.join("manifest.csv"), &device);
+
+        let page = dir.join("page, 1.txt");
+        std::fs::write(&page, "abc").unwrap();
+        for number in 1..=3 {
+            let (_, scan) = Scan::time(number, || ());
+            manifest.add(&page, &scan, 20, 10).unwrap();
+        }
+        manifest.write().unwrap();
+        let csv = std::fs::read_to_string(dir.join("manifest.csv")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let sides: Vec<_> = manifest
+            .entries
+            .iter()
+            .map(|entry| (entry.page, entry.sheet, entry.side))
+            .collect();
+        assert_eq!(
+            sides,
+            [(1, 1, Side::Front), (2, 1, Side::Back), (3, 2, Side::Front)]
+        );
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next().unwrap(),
+            "path,page,sheet,side,width,height,dpi,bytes,sha256,scanned,scan_seconds,partial"
+        );
+        let first = lines.next().unwrap();
+        assert!(first.starts_with(&format!("\"{}\",1,1,front,20,10,300.0,", page.display())));
+        assert!(
+            first.contains(",3,ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad,")
+        );
+        assert_eq!(lines.count(), 2);
+    }
+}
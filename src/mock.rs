@@ -0,0 +1,496 @@
+//! In-process backend serving synthetic devices
+//!
+//! Every device is described by a [`DeviceSpec`]: its options, the frames it
+//! produces, how many pages its feeder holds and which errors to inject.
+//! Frames are filled with a pattern depending on the position and page
+//! number, see [`pattern`], so tests can check what they received.
+//!
+//! ```
+//! use skanny::backend::{ScannerBackend, ScannerDevice};
+//! use skanny::mock::{DeviceSpec, Fault, MockBackend};
+//!
+//! let backend = MockBackend::new(vec![DeviceSpec {
+//!     pages: Some(2),
+//!     faults: vec![Fault::jammed(1)],
+//!     ..DeviceSpec::default()
+//! }]);
+//! let device = backend.open("mock").unwrap();
+//! assert!(device.scan().is_ok());
+//! assert!(device.scan().is_err());
+//! ```
+
+use std::cell::RefCell;
+
+use sane_sys::*;
+
+use crate::backend::{
+    BackendError, Constraint, DeviceInfo, FrameParameters, OptionInfo, ScannerBackend,
+    ScannerDevice,
+};
+use crate::OptionValue;
+
+/// Description of a synthetic device
+#[derive(Debug, Clone)]
+pub struct DeviceSpec {
+    pub name: String,
+    /// Options with their initial values. A `mode` option switches the
+    /// frame format between `Gray` and `Color`.
+    pub options: Vec<OptionInfo>,
+    pub format: SANE_Frame,
+    pub depth: SANE_Int,
+    pub pixels_per_line: SANE_Int,
+    pub lines: SANE_Int,
+    /// Report the number of lines as unknown until the frame is read
+    pub unknown_lines: bool,
+    /// Largest chunk returned by a single read
+    pub chunk: usize,
+    /// Pages in the feeder, unlimited if `None`
+    pub pages: Option<usize>,
+    pub faults: Vec<Fault>,
+    /// Acquisitions after which the `double-feed` sensor reports that two
+    /// sheets were fed together, counting from 0. The sensor is only listed
+    /// when there are any.
+    pub double_feeds: Vec<usize>,
+}
+
+/// An error returned once, by `start` or after part of a frame was read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fault {
+    /// Number of the acquisition to fail, counting from 0
+    pub page: usize,
+    /// Bytes to read before failing, `None` to fail when starting
+    pub after: Option<usize>,
+    pub status: SANE_Status,
+}
+
+impl Fault {
+    pub fn jammed(page: usize) -> Self {
+        Self {
+            page,
+            after: None,
+            status: SANE_Status_SANE_STATUS_JAMMED,
+        }
+    }
+
+    pub fn no_docs(page: usize) -> Self {
+        Self {
+            page,
+            after: None,
+            status: SANE_Status_SANE_STATUS_NO_DOCS,
+        }
+    }
+
+    /// The device turns busy after `after` bytes of the page were read
+    pub fn busy_while_reading(page: usize, after: usize) -> Self {
+        Self {
+            page,
+            after: Some(after),
+            status: SANE_Status_SANE_STATUS_DEVICE_BUSY,
+        }
+    }
+
+    /// Reading fails with an I/O error after `after` bytes of the page
+    pub fn io_error(page: usize, after: usize) -> Self {
+        Self {
+            page,
+            after: Some(after),
+            status: SANE_Status_SANE_STATUS_IO_ERROR,
+        }
+    }
+}
+
+impl Default for DeviceSpec {
+    fn default() -> Self {
+        let cap = (SANE_CAP_SOFT_SELECT | SANE_CAP_SOFT_DETECT) as SANE_Int;
+        Self {
+            name: "mock".to_owned(),
+            options: vec![
+                OptionInfo {
+                    name: "mode".to_owned(),
+                    title: "Scan mode".to_owned(),
+                    desc: "Selects the scan mode".to_owned(),
+                    type_: SANE_Value_Type_SANE_TYPE_STRING,
+                    unit: SANE_Unit_SANE_UNIT_NONE,
+                    cap,
+                    constraint: Constraint::StringList(vec!["Gray".to_owned(), "Color".to_owned()]),
+                    value: Some(OptionValue::String("Gray".to_owned())),
+                },
+                OptionInfo {
+                    name: "resolution".to_owned(),
+                    title: "Scan resolution".to_owned(),
+                    desc: "Sets the resolution of the scanned image".to_owned(),
+                    type_: SANE_Value_Type_SANE_TYPE_INT,
+                    unit: SANE_Unit_SANE_UNIT_DPI,
+                    cap,
+                    constraint: Constraint::Range {
+                        min: 75,
+                        max: 600,
+                        quant: 0,
+                    },
+                    value: Some(OptionValue::Int(300)),
+                },
+            ],
+            format: SANE_Frame_SANE_FRAME_GRAY,
+            depth: 8,
+            pixels_per_line: 32,
+            lines: 16,
+            unknown_lines: false,
+            chunk: 1000,
+            pages: None,
+            faults: Vec::new(),
+            double_feeds: Vec::new(),
+        }
+    }
+}
+
+/// Sample at `offset` into page `page`
+pub fn pattern(page: usize, offset: usize) -> u8 {
+    (offset.wrapping_mul(7).wrapping_add(page.wrapping_mul(31))) as u8
+}
+
+pub struct MockBackend {
+    devices: Vec<DeviceSpec>,
+}
+
+impl MockBackend {
+    pub fn new(devices: Vec<DeviceSpec>) -> Self {
+        Self { devices }
+    }
+}
+
+impl ScannerBackend for MockBackend {
+    fn enumerate(&self) -> Result<Vec<DeviceInfo>, BackendError> {
+        Ok(self
+            .devices
+            .iter()
+            .map(|spec| DeviceInfo {
+                name: spec.name.clone(),
+                vendor: "skanny".to_owned(),
+                model: "mock".to_owned(),
+                type_: "virtual device".to_owned(),
+            })
+            .collect())
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn ScannerDevice>, BackendError> {
+        let spec = self
+            .devices
+            .iter()
+            .find(|spec| spec.name == name)
+            .ok_or(crate::Error::Status(SANE_Status_SANE_STATUS_INVAL))?;
+        Ok(Box::new(MockDevice::new(spec.clone())))
+    }
+}
+
+/// A device opened from a [`DeviceSpec`]
+pub struct MockDevice {
+    spec: DeviceSpec,
+    state: RefCell<State>,
+}
+
+struct State {
+    options: Vec<OptionInfo>,
+    faults: Vec<Fault>,
+    /// Acquisitions started so far
+    started: usize,
+    /// Pages taken from the feeder
+    fed: usize,
+    frame: Option<Frame>,
+    /// State of the `double-feed` sensor
+    double_feed: bool,
+}
+
+struct Frame {
+    page: usize,
+    len: usize,
+    offset: usize,
+    /// Offset to fail at and with what
+    fault: Option<(usize, SANE_Status)>,
+}
+
+impl MockDevice {
+    pub fn new(spec: DeviceSpec) -> Self {
+        let state = State {
+            options: spec.options.clone(),
+            faults: spec.faults.clone(),
+            started: 0,
+            fed: 0,
+            frame: None,
+            double_feed: false,
+        };
+        Self {
+            spec,
+            state: RefCell::new(state),
+        }
+    }
+
+    /// Layout of the frames at the current settings
+    fn parameters(&self, state: &State) -> FrameParameters {
+        let mode = state.options.iter().find(|option| option.name == "mode");
+        let format = match mode.and_then(|option| option.value.as_ref()) {
+            Some(OptionValue::String(mode)) if mode == "Color" => SANE_Frame_SANE_FRAME_RGB,
+            Some(OptionValue::String(mode)) if mode == "Gray" => SANE_Frame_SANE_FRAME_GRAY,
+            _ => self.spec.format,
+        };
+        let channels = if format == SANE_Frame_SANE_FRAME_RGB {
+            3
+        } else {
+            1
+        };
+        let bits = self.spec.pixels_per_line * channels * self.spec.depth;
+        FrameParameters {
+            format,
+            last_frame: true,
+            bytes_per_line: (bits + 7) / 8,
+            pixels_per_line: self.spec.pixels_per_line,
+            lines: self.spec.lines,
+            depth: self.spec.depth,
+        }
+    }
+}
+
+impl ScannerDevice for MockDevice {
+    fn name(&self) -> &str {
+        &self.spec.name
+    }
+
+    fn options(&self) -> Result<Vec<OptionInfo>, BackendError> {
+        let state = self.state.borrow();
+        let mut options = state.options.clone();
+        if !self.spec.double_feeds.is_empty() {
+            options.push(OptionInfo {
+                name: "double-feed".to_owned(),
+                title: "Double feed".to_owned(),
+                desc: "Two sheets were fed together".to_owned(),
+                type_: SANE_Value_Type_SANE_TYPE_BOOL,
+                unit: SANE_Unit_SANE_UNIT_NONE,
+                cap: (SANE_CAP_HARD_SELECT | SANE_CAP_SOFT_DETECT) as SANE_Int,
+                constraint: Constraint::None,
+                value: Some(OptionValue::Bool(state.double_feed)),
+            });
+        }
+        Ok(options)
+    }
+
+    fn set_option(&self, name: &str, value: &OptionValue) -> Result<(), BackendError> {
+        let mut state = self.state.borrow_mut();
+        let option = state
+            .options
+            .iter_mut()
+            .find(|option| option.name == name)
+            .ok_or_else(|| format!("No option named {}", name))?;
+        #[allow(non_upper_case_globals)]
+        let value = match (option.type_, value) {
+            // Pressing a button has no lasting effect
+            (SANE_Value_Type_SANE_TYPE_BUTTON, _) => return Ok(()),
+            (SANE_Value_Type_SANE_TYPE_BOOL, OptionValue::Bool(_))
+            | (SANE_Value_Type_SANE_TYPE_INT, OptionValue::Int(_))
+            | (SANE_Value_Type_SANE_TYPE_FIXED, OptionValue::Fixed(_))
+            | (SANE_Value_Type_SANE_TYPE_STRING, OptionValue::String(_)) => value.clone(),
+            (SANE_Value_Type_SANE_TYPE_FIXED, &OptionValue::Int(i)) => {
+                OptionValue::Fixed(f64::from(i))
+            }
+            _ => return Err(crate::Error::WrongType.into()),
+        };
+        let valid = match (&option.constraint, &value) {
+            (Constraint::None, _) => true,
+            (Constraint::StringList(list), OptionValue::String(s)) => list.contains(s),
+            (Constraint::WordList(list), OptionValue::Int(i)) => list.contains(i),
+            (Constraint::WordList(list), &OptionValue::Fixed(f)) => list.contains(&SANE_FIX(f)),
+            (&Constraint::Range { min, max, .. }, &OptionValue::Int(i)) => (min..=max).contains(&i),
+            (&Constraint::Range { min, max, .. }, &OptionValue::Fixed(f)) => {
+                (min..=max).contains(&SANE_FIX(f))
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(crate::Error::Status(SANE_Status_SANE_STATUS_INVAL).into());
+        }
+        option.value = Some(value);
+        Ok(())
+    }
+
+    fn start(&self) -> Result<FrameParameters, BackendError> {
+        let mut state = self.state.borrow_mut();
+        let page = state.started;
+        state.started += 1;
+        state.frame = None;
+        state.double_feed = self.spec.double_feeds.contains(&page);
+
+        let fault = state
+            .faults
+            .iter()
+            .position(|fault| fault.page == page)
+            .map(|i| state.faults.remove(i));
+        if let Some(Fault {
+            after: None,
+            status,
+            ..
+        }) = fault
+        {
+            return Err(crate::Error::Status(status).into());
+        }
+        if matches!(self.spec.pages, Some(pages) if state.fed >= pages) {
+            return Err(crate::Error::Status(SANE_Status_SANE_STATUS_NO_DOCS).into());
+        }
+        state.fed += 1;
+
+        let mut parameters = self.parameters(&state);
+        state.frame = Some(Frame {
+            page,
+            len: (parameters.bytes_per_line * parameters.lines) as usize,
+            offset: 0,
+            fault: fault.and_then(|fault| fault.after.map(|after| (after, fault.status))),
+        });
+        if self.spec.unknown_lines {
+            parameters.lines = -1;
+        }
+        Ok(parameters)
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, BackendError> {
+        let mut state = self.state.borrow_mut();
+        let frame = state
+            .frame
+            .as_mut()
+            .ok_or(crate::Error::Status(SANE_Status_SANE_STATUS_INVAL))?;
+        let mut end = frame.len;
+        if let Some((after, status)) = frame.fault {
+            if frame.offset >= after {
+                frame.fault = None;
+                return Err(crate::Error::Status(status).into());
+            }
+            end = end.min(after);
+        }
+        let len = buffer
+            .len()
+            .min(self.spec.chunk.max(1))
+            .min(end - frame.offset);
+        for (i, sample) in buffer[..len].iter_mut().enumerate() {
+            *sample = pattern(frame.page, frame.offset + i);
+        }
+        frame.offset += len;
+        Ok(len)
+    }
+
+    fn cancel(&self) {
+        self.state.borrow_mut().frame = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(e: BackendError) -> SANE_Status {
+        match e.downcast_ref::<crate::Error>() {
+            Some(&crate::Error::Status(status)) => status,
+            _ => panic!("Unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "image")]
+    fn feeder_runs_empty() {
+        let device = MockDevice::new(DeviceSpec {
+            pages: Some(2),
+            ..DeviceSpec::default()
+        });
+        device
+            .set_option("mode", &OptionValue::String("Color".to_owned()))
+            .unwrap();
+        for _ in 0..2 {
+            match device.scan().unwrap() {
+                crate::Image::Rgb8(image) => assert_eq!(image.dimensions(), (32, 16)),
+                _ => panic!("Expected a color image"),
+            }
+        }
+        assert_eq!(
+            status(device.scan().err().unwrap()),
+            SANE_Status_SANE_STATUS_NO_DOCS
+        );
+    }
+
+    #[test]
+    fn faults() {
+        let device = MockDevice::new(DeviceSpec {
+            faults: vec![Fault::jammed(0), Fault::busy_while_reading(1, 100)],
+            ..DeviceSpec::default()
+        });
+        assert_eq!(
+            status(device.scan_frame().err().unwrap()),
+            SANE_Status_SANE_STATUS_JAMMED
+        );
+
+        device.start().unwrap();
+        let mut buffer = [0; 64];
+        let mut read = 0;
+        let e = loop {
+            match device.read(&mut buffer) {
+                Ok(n) => {
+                    assert_eq!(buffer[0], pattern(1, read));
+                    read += n;
+                }
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(read, 100);
+        assert_eq!(status(e), SANE_Status_SANE_STATUS_DEVICE_BUSY);
+        device.cancel();
+
+        assert!(device.scan_frame().is_ok());
+        assert_eq!(
+            status(
+                device
+                    .set_option("resolution", &OptionValue::Int(1200))
+                    .err()
+                    .unwrap()
+            ),
+            SANE_Status_SANE_STATUS_INVAL
+        );
+    }
+
+    #[test]
+    fn keeps_the_lines_read_before_an_io_error() {
+        let device = MockDevice::new(DeviceSpec {
+            faults: vec![Fault::io_error(0, 100), Fault::io_error(1, 20)],
+            ..DeviceSpec::default()
+        });
+        let e = device.scan_frame().err().unwrap();
+        let partial = e.downcast_ref::<crate::backend::PartialFrame>().unwrap();
+        assert_eq!((partial.frame.width(), partial.frame.height()), (32, 3));
+        assert_eq!(partial.frame.row(2)[0], pattern(0, 64));
+        assert_eq!(e.to_string(), "Device IO failed after 3 lines");
+
+        // Not even a line was read
+        assert_eq!(
+            status(device.scan_frame().err().unwrap()),
+            SANE_Status_SANE_STATUS_IO_ERROR
+        );
+    }
+
+    #[test]
+    fn cancels_a_page_at_a_time() {
+        use crate::backend::{is_cancelled, CancelToken, Cancellable};
+
+        let device = MockDevice::new(DeviceSpec {
+            chunk: 64,
+            ..DeviceSpec::default()
+        });
+        let token = CancelToken::new();
+        let cancellable = Cancellable::new(&device, token.clone());
+        // Cancelled between pages, which does not count
+        token.cancel();
+        cancellable.start().unwrap();
+        let mut buffer = [0; 64];
+        assert_eq!(cancellable.read(&mut buffer).unwrap(), 64);
+        token.cancel();
+        let e = cancellable.read(&mut buffer).unwrap_err();
+        assert!(is_cancelled(&e));
+        cancellable.cancel();
+
+        assert!(cancellable.scan_frame().is_ok());
+        let jammed: BackendError = crate::Error::Status(SANE_Status_SANE_STATUS_JAMMED).into();
+        assert!(!is_cancelled(&jammed));
+    }
+}
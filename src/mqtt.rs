@@ -0,0 +1,93 @@
+//! Publishing of scan events to an MQTT broker
+//!
+//! Events are published as JSON on `PREFIX/EVENT`, e.g. `skanny/page_scanned`.
+//! With thumbnails enabled, a small JPEG of every page is published
+//! on `PREFIX/thumbnail`.
+
+use crate::events::{Event, Sink};
+
+pub struct Publisher {
+    #[cfg(feature = "mqtt")]
+    client: rumqttc::Client,
+    prefix: String,
+    thumbnails: bool,
+}
+
+const THUMBNAIL_SIZE: u32 = 256;
+
+impl Publisher {
+    /// Connects to the broker at `HOST[:PORT]`
+    #[cfg(feature = "mqtt")]
+    pub fn connect(
+        broker: &str,
+        prefix: &str,
+        thumbnails: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut split = broker.rsplitn(2, ':');
+        let (host, port) = match (split.next(), split.next()) {
+            (Some(port), Some(host)) => (host, port.parse()?),
+            (Some(host), None) => (host, 1883),
+            _ => return Err(format!("Invalid broker address {}", broker).into()),
+        };
+
+        let id = format!("skanny-{}", std::process::id());
+        let mut options = rumqttc::MqttOptions::new(id, host, port);
+        options.set_keep_alive(std::time::Duration::from_secs(30));
+        let (client, mut connection) = rumqttc::Client::new(options, 16);
+        std::thread::spawn(move || {
+            // The connection must be polled for the client to make progress
+            for notification in connection.iter() {
+                if let Err(e) = notification {
+                    tracing::warn!("MQTT connection failed: {}", e);
+                    std::thread::sleep(std::time::Duration::from_secs(5));
+                }
+            }
+        });
+
+        Ok(Self {
+            client,
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            thumbnails,
+        })
+    }
+
+    #[cfg(not(feature = "mqtt"))]
+    pub fn connect(
+        _broker: &str,
+        _prefix: &str,
+        _thumbnails: bool,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Err("skanny was built without the mqtt feature".into())
+    }
+}
+
+impl Sink for Publisher {
+    #[cfg(feature = "mqtt")]
+    fn send(&self, event: &Event) {
+        use rumqttc::QoS;
+
+        let topic = format!("{}/{}", self.prefix, event.name());
+        let payload = serde_json::to_vec(event).unwrap();
+        if let Err(e) = self.client.publish(topic, QoS::AtLeastOnce, false, payload) {
+            tracing::warn!("Failed to publish {}: {}", event.name(), e);
+        }
+
+        if let Event::PageScanned { image, .. } = event {
+            if self.thumbnails {
+                let thumbnail = image.to_dynamic().thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+                let mut jpeg = Vec::new();
+                if let Err(e) = thumbnail.write_to(&mut jpeg, image::ImageOutputFormat::Jpeg(80)) {
+                    tracing::warn!("Failed to encode thumbnail: {}", e);
+                    return;
+                }
+                let topic = format!("{}/thumbnail", self.prefix);
+                if let Err(e) = self.client.publish(topic, QoS::AtMostOnce, false, jpeg) {
+                    tracing::warn!("Failed to publish thumbnail: {}", e);
+                }
+            }
+        }
+    }
+
+    #[cfg(not(feature = "mqtt"))]
+    fn send(&self, _event: &Event) {}
+}
@@ -0,0 +1,622 @@
+//! Native client for the SANE network protocol spoken by `saned`
+//!
+//! Talks to remote hosts directly, without going through the `net`
+//! backend of the local libsane. Only what is needed to list devices,
+//! read and set options and acquire single-pass images is implemented,
+//! hosts requiring authorization are rejected.
+
+use std::cell::RefCell;
+use std::io::{BufReader, BufWriter, Read};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use sane_sys::*;
+
+use crate::backend::{
+    BackendError, Constraint, DeviceInfo, FrameParameters, OptionInfo, ScannerBackend,
+    ScannerDevice,
+};
+use crate::OptionValue;
+
+pub mod wire;
+
+use wire::{procedure, Reader, Writer};
+
+/// Port `saned` listens on unless configured otherwise
+pub const DEFAULT_PORT: u16 = 6566;
+
+/// How long to wait for a reply before giving up on the host
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Byte orders announced by saned for 16 bit image data
+const LITTLE_ENDIAN: SANE_Word = 0x1234;
+const BIG_ENDIAN: SANE_Word = 0x4321;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Sane(crate::Error),
+    /// The other end sent something we could not make sense of
+    Protocol(String),
+    /// The host demands credentials for the named resource
+    AuthRequired(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e)
+                if e.kind() == std::io::ErrorKind::WouldBlock
+                    || e.kind() == std::io::ErrorKind::TimedOut =>
+            {
+                write!(f, "Timed out waiting for the other end")
+            }
+            Error::Io(e) => write!(f, "Connection failed: {}", e),
+            Error::Sane(e) => e.fmt(f),
+            Error::Protocol(message) => write!(f, "SANE network protocol violated: {}", message),
+            Error::AuthRequired(resource) => write!(
+                f,
+                "saned requires authorization for {}, which is not supported",
+                resource
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+fn checked(status: SANE_Word) -> Result<(), Error> {
+    if status as SANE_Status == SANE_Status_SANE_STATUS_GOOD {
+        Ok(())
+    } else {
+        Err(Error::Sane(crate::Error::Status(status as _)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OptionDescriptor {
+    pub name: String,
+    pub title: String,
+    pub desc: String,
+    pub type_: SANE_Value_Type,
+    pub unit: SANE_Unit,
+    pub size: SANE_Int,
+    pub cap: SANE_Int,
+    pub constraint: Constraint,
+}
+
+/// Connection to a `saned` host
+pub struct Client {
+    host: String,
+    reader: Reader<BufReader<TcpStream>>,
+    writer: Writer<BufWriter<TcpStream>>,
+}
+
+impl Client {
+    /// Connects to `saned` on `host`, which may be an IPv6 address
+    /// enclosed in brackets, and performs the handshake
+    pub fn connect(host: &str, port: u16) -> Result<Self, Error> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let stream = TcpStream::connect((host, port))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        stream.set_nodelay(true)?;
+
+        let mut client = Self {
+            host: host.to_owned(),
+            reader: Reader(BufReader::new(stream.try_clone()?)),
+            writer: Writer(BufWriter::new(stream)),
+        };
+
+        let user = std::env::var("USER").unwrap_or_default();
+        client
+            .writer
+            .word(procedure::INIT)?
+            .word(wire::VERSION_CODE)?
+            .string(&user)?
+            .flush()?;
+        checked(client.reader.word()?)?;
+        let version = sane_sys::Version::from_code(client.reader.word()?);
+        if version.major != 1 {
+            return Err(Error::Protocol(format!(
+                "Unsupported protocol version {}",
+                version
+            )));
+        }
+        Ok(client)
+    }
+
+    pub fn devices(&mut self) -> Result<Vec<DeviceInfo>, Error> {
+        self.writer.word(procedure::GET_DEVICES)?.flush()?;
+        checked(self.reader.word()?)?;
+        let len = self.reader.length()?;
+        let mut devices = Vec::with_capacity(len);
+        for _ in 0..len {
+            if self.reader.is_null()? {
+                continue;
+            }
+            devices.push(DeviceInfo {
+                name: self.reader.string()?,
+                vendor: self.reader.string()?,
+                model: self.reader.string()?,
+                type_: self.reader.string()?,
+            });
+        }
+        Ok(devices)
+    }
+
+    /// Opens the device called `name` on the host
+    pub fn open(mut self, name: &str) -> Result<RemoteHandle, Error> {
+        self.writer.word(procedure::OPEN)?.string(name)?.flush()?;
+        let status = self.reader.word()?;
+        let handle = self.reader.word()?;
+        let resource = self.reader.string()?;
+        if !resource.is_empty() {
+            return Err(Error::AuthRequired(resource));
+        }
+        checked(status)?;
+        Ok(RemoteHandle {
+            client: self,
+            handle,
+            data: None,
+        })
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        let _ = self
+            .writer
+            .word(procedure::EXIT)
+            .and_then(|writer| writer.flush());
+    }
+}
+
+/// An open device on a `saned` host
+pub struct RemoteHandle {
+    client: Client,
+    handle: SANE_Word,
+    /// Connection image data is received on while acquiring
+    data: Option<Data>,
+}
+
+struct Data {
+    stream: BufReader<TcpStream>,
+    /// Bytes left of the current record
+    remaining: usize,
+}
+
+impl RemoteHandle {
+    /// Descriptors of all options, including the option count at index 0
+    pub fn descriptors(&mut self) -> Result<Vec<OptionDescriptor>, Error> {
+        let Client { reader, writer, .. } = &mut self.client;
+        writer
+            .word(procedure::GET_OPTION_DESCRIPTORS)?
+            .word(self.handle)?
+            .flush()?;
+        let len = reader.length()?;
+        let mut descriptors = Vec::with_capacity(len);
+        for _ in 0..len {
+            if reader.is_null()? {
+                return Err(Error::Protocol("Missing option descriptor".to_owned()));
+            }
+            let name = reader.string()?;
+            let title = reader.string()?;
+            let desc = reader.string()?;
+            let type_ = reader.word()? as SANE_Value_Type;
+            let unit = reader.word()? as SANE_Unit;
+            let size = reader.word()?;
+            let cap = reader.word()?;
+            #[allow(non_upper_case_globals)]
+            let constraint = match reader.word()? as SANE_Constraint_Type {
+                SANE_Constraint_Type_SANE_CONSTRAINT_RANGE => {
+                    if reader.is_null()? {
+                        Constraint::None
+                    } else {
+                        Constraint::Range {
+                            min: reader.word()?,
+                            max: reader.word()?,
+                            quant: reader.word()?,
+                        }
+                    }
+                }
+                SANE_Constraint_Type_SANE_CONSTRAINT_WORD_LIST => {
+                    let len = reader.length()?;
+                    let mut list = Vec::with_capacity(len);
+                    for _ in 0..len {
+                        list.push(reader.word()?);
+                    }
+                    // The first word is the length of the list
+                    Constraint::WordList(list.into_iter().skip(1).collect())
+                }
+                SANE_Constraint_Type_SANE_CONSTRAINT_STRING_LIST => {
+                    let len = reader.length()?;
+                    let mut list = Vec::with_capacity(len);
+                    for _ in 0..len {
+                        if let Some(s) = reader.nullable_string()? {
+                            list.push(s);
+                        }
+                    }
+                    Constraint::StringList(list)
+                }
+                _ => Constraint::None,
+            };
+            descriptors.push(OptionDescriptor {
+                name,
+                title,
+                desc,
+                type_,
+                unit,
+                size,
+                cap,
+                constraint,
+            });
+        }
+        Ok(descriptors)
+    }
+
+    /// Sends a CONTROL_OPTION request, returning the value in the reply
+    fn control(
+        &mut self,
+        index: usize,
+        descriptor: &OptionDescriptor,
+        action: SANE_Action,
+        value: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let Client { reader, writer, .. } = &mut self.client;
+        let is_string = descriptor.type_ == SANE_Value_Type_SANE_TYPE_STRING;
+        let element_size = if is_string { 1 } else { 4 };
+        writer
+            .word(procedure::CONTROL_OPTION)?
+            .word(self.handle)?
+            .word(index as SANE_Word)?
+            .word(action as SANE_Word)?
+            .word(descriptor.type_ as SANE_Word)?
+            .word(value.len() as SANE_Word)?
+            .word((value.len() / element_size) as SANE_Word)?;
+        writer.bytes(value)?.flush()?;
+
+        let status = reader.word()?;
+        let _info = reader.word()?;
+        let _type = reader.word()?;
+        let _size = reader.word()?;
+        let len = reader.length()?;
+        let value = reader.bytes(len * element_size)?;
+        let resource = reader.string()?;
+        if !resource.is_empty() {
+            return Err(Error::AuthRequired(resource));
+        }
+        checked(status)?;
+        Ok(value)
+    }
+
+    /// Current value of the option at `index`, `None` for types without
+    /// a value
+    pub fn get_value(
+        &mut self,
+        index: usize,
+        descriptor: &OptionDescriptor,
+    ) -> Result<Option<OptionValue>, Error> {
+        let word = |bytes: &[u8]| {
+            bytes
+                .get(..4)
+                .map(|w| SANE_Word::from_be_bytes([w[0], w[1], w[2], w[3]]))
+                .ok_or_else(|| Error::Protocol("Option value is too short".to_owned()))
+        };
+        #[allow(non_upper_case_globals)]
+        match descriptor.type_ {
+            SANE_Value_Type_SANE_TYPE_BOOL
+            | SANE_Value_Type_SANE_TYPE_INT
+            | SANE_Value_Type_SANE_TYPE_FIXED
+            | SANE_Value_Type_SANE_TYPE_STRING => {}
+            _ => return Ok(None),
+        }
+        let value = self.control(
+            index,
+            descriptor,
+            SANE_Action_SANE_ACTION_GET_VALUE,
+            &vec![0; descriptor.size.max(0) as usize],
+        )?;
+        #[allow(non_upper_case_globals)]
+        Ok(Some(match descriptor.type_ {
+            SANE_Value_Type_SANE_TYPE_BOOL => OptionValue::Bool(word(&value)? != 0),
+            SANE_Value_Type_SANE_TYPE_INT => OptionValue::Int(word(&value)?),
+            SANE_Value_Type_SANE_TYPE_FIXED => OptionValue::Fixed(SANE_UNFIX(word(&value)?)),
+            _ => {
+                let end = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+                OptionValue::String(String::from_utf8_lossy(&value[..end]).into_owned())
+            }
+        }))
+    }
+
+    /// Sets the option at `index`, converting integers to fixed point
+    /// where the option requires it
+    pub fn set_value(
+        &mut self,
+        index: usize,
+        descriptor: &OptionDescriptor,
+        value: &OptionValue,
+    ) -> Result<(), Error> {
+        #[allow(non_upper_case_globals)]
+        let bytes = match (descriptor.type_, value) {
+            (SANE_Value_Type_SANE_TYPE_BOOL, &OptionValue::Bool(b)) => {
+                (b as SANE_Word).to_be_bytes().to_vec()
+            }
+            (SANE_Value_Type_SANE_TYPE_INT, &OptionValue::Int(i)) => i.to_be_bytes().to_vec(),
+            (SANE_Value_Type_SANE_TYPE_FIXED, &OptionValue::Int(i)) => {
+                SANE_FIX(i as f64).to_be_bytes().to_vec()
+            }
+            (SANE_Value_Type_SANE_TYPE_FIXED, &OptionValue::Fixed(f)) => {
+                SANE_FIX(f).to_be_bytes().to_vec()
+            }
+            (SANE_Value_Type_SANE_TYPE_STRING, OptionValue::String(s)) => {
+                let mut bytes = s.as_bytes().to_vec();
+                bytes.resize((descriptor.size.max(0) as usize).max(bytes.len() + 1), 0);
+                bytes
+            }
+            _ => return Err(Error::Sane(crate::Error::WrongType)),
+        };
+        self.control(index, descriptor, SANE_Action_SANE_ACTION_SET_VALUE, &bytes)?;
+        Ok(())
+    }
+
+    pub fn parameters(&mut self) -> Result<FrameParameters, Error> {
+        let Client { reader, writer, .. } = &mut self.client;
+        writer
+            .word(procedure::GET_PARAMETERS)?
+            .word(self.handle)?
+            .flush()?;
+        checked(reader.word()?)?;
+        Ok(FrameParameters {
+            format: reader.word()? as SANE_Frame,
+            last_frame: reader.word()? != 0,
+            bytes_per_line: reader.word()?,
+            pixels_per_line: reader.word()?,
+            lines: reader.word()?,
+            depth: reader.word()?,
+        })
+    }
+
+    /// Starts acquiring a frame, read it with [`RemoteHandle::read`]
+    pub fn start(&mut self) -> Result<FrameParameters, Error> {
+        let Client {
+            reader,
+            writer,
+            host,
+        } = &mut self.client;
+        writer.word(procedure::START)?.word(self.handle)?.flush()?;
+        let status = reader.word()?;
+        let port = reader.word()?;
+        let byte_order = reader.word()?;
+        let resource = reader.string()?;
+        if !resource.is_empty() {
+            return Err(Error::AuthRequired(resource));
+        }
+        checked(status)?;
+
+        let stream = TcpStream::connect((host.as_str(), port as u16))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        self.data = Some(Data {
+            stream: BufReader::new(stream),
+            remaining: 0,
+        });
+        let parameters = self.parameters()?;
+        let native = if cfg!(target_endian = "little") {
+            LITTLE_ENDIAN
+        } else {
+            BIG_ENDIAN
+        };
+        if parameters.depth == 16 && byte_order != native {
+            self.cancel()?;
+            return Err(Error::Protocol(
+                "16 bit samples in foreign byte order are not supported".to_owned(),
+            ));
+        }
+        Ok(parameters)
+    }
+
+    /// Reads the next chunk of the frame, returning 0 at its end
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let data = self
+            .data
+            .as_mut()
+            .ok_or_else(|| Error::Protocol("Not acquiring".to_owned()))?;
+        // Records are prefixed by their length, the end is marked by
+        // 0xffffffff and a status byte
+        while data.remaining == 0 {
+            let mut len = [0; 4];
+            data.stream.read_exact(&mut len)?;
+            let len = u32::from_be_bytes(len);
+            if len == u32::MAX {
+                let mut status = [0];
+                data.stream.read_exact(&mut status)?;
+                self.data = None;
+                if status[0] as SANE_Status != SANE_Status_SANE_STATUS_EOF {
+                    checked(status[0] as SANE_Word)?;
+                }
+                return Ok(0);
+            }
+            data.remaining = len as usize;
+        }
+        let len = buffer.len().min(data.remaining);
+        let n = data.stream.read(&mut buffer[..len])?;
+        if n == 0 {
+            return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        data.remaining -= n;
+        Ok(n)
+    }
+
+    /// Ends the acquisition
+    pub fn cancel(&mut self) -> Result<(), Error> {
+        self.data = None;
+        let Client { reader, writer, .. } = &mut self.client;
+        writer.word(procedure::CANCEL)?.word(self.handle)?.flush()?;
+        reader.word()?;
+        Ok(())
+    }
+}
+
+impl Drop for RemoteHandle {
+    fn drop(&mut self) {
+        let handle = self.handle;
+        let Client { reader, writer, .. } = &mut self.client;
+        let closed = writer
+            .word(procedure::CLOSE)
+            .and_then(|writer| writer.word(handle))
+            .and_then(|writer| writer.flush())
+            .and_then(|_| reader.word());
+        if let Err(e) = closed {
+            tracing::warn!("Closing remote device failed: {}", e);
+        }
+    }
+}
+
+/// A `saned` host as a [`ScannerBackend`], every opened device uses
+/// its own connection
+pub struct NetBackend {
+    pub host: String,
+    pub port: u16,
+}
+
+impl NetBackend {
+    pub fn new(host: &str) -> Self {
+        Self {
+            host: host.to_owned(),
+            port: DEFAULT_PORT,
+        }
+    }
+}
+
+impl ScannerBackend for NetBackend {
+    fn enumerate(&self) -> Result<Vec<DeviceInfo>, BackendError> {
+        Ok(Client::connect(&self.host, self.port)?.devices()?)
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn ScannerDevice>, BackendError> {
+        let handle = Client::connect(&self.host, self.port)?.open(name)?;
+        Ok(Box::new(NetDevice::new(
+            format!("net:{}:{}", self.host, name),
+            handle,
+        )?))
+    }
+}
+
+/// Device on a `saned` host, driven through [`ScannerDevice`]
+pub struct NetDevice {
+    name: String,
+    handle: RefCell<RemoteHandle>,
+    descriptors: RefCell<Vec<OptionDescriptor>>,
+}
+
+impl NetDevice {
+    pub fn new(name: String, mut handle: RemoteHandle) -> Result<Self, Error> {
+        let descriptors = handle.descriptors()?;
+        Ok(Self {
+            name,
+            handle: RefCell::new(handle),
+            descriptors: RefCell::new(descriptors),
+        })
+    }
+}
+
+impl ScannerDevice for NetDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn options(&self) -> Result<Vec<OptionInfo>, BackendError> {
+        let mut handle = self.handle.borrow_mut();
+        let descriptors = self.descriptors.borrow();
+        let mut options = Vec::with_capacity(descriptors.len());
+        for (index, descriptor) in descriptors.iter().enumerate().skip(1) {
+            let inactive = descriptor.cap & SANE_CAP_INACTIVE as SANE_Int != 0;
+            let value = if inactive {
+                None
+            } else {
+                handle.get_value(index, descriptor)?
+            };
+            options.push(OptionInfo {
+                name: descriptor.name.clone(),
+                title: match &descriptor.title[..] {
+                    "" => descriptor.name.clone(),
+                    title => title.to_owned(),
+                },
+                desc: descriptor.desc.clone(),
+                type_: descriptor.type_,
+                unit: descriptor.unit,
+                cap: descriptor.cap,
+                constraint: descriptor.constraint.clone(),
+                value,
+            });
+        }
+        Ok(options)
+    }
+
+    fn set_option(&self, name: &str, value: &OptionValue) -> Result<(), BackendError> {
+        let mut handle = self.handle.borrow_mut();
+        let mut descriptors = self.descriptors.borrow_mut();
+        let index = descriptors
+            .iter()
+            .skip(1)
+            .position(|descriptor| descriptor.name == name)
+            .ok_or_else(|| format!("No option named {}", name))?
+            + 1;
+        handle.set_value(index, &descriptors[index], value)?;
+        // Setting an option may change the others
+        *descriptors = handle.descriptors()?;
+        Ok(())
+    }
+
+    fn start(&self) -> Result<FrameParameters, BackendError> {
+        Ok(self.handle.borrow_mut().start()?)
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, BackendError> {
+        Ok(self.handle.borrow_mut().read(buffer)?)
+    }
+
+    fn cancel(&self) {
+        if let Err(e) = self.handle.borrow_mut().cancel() {
+            tracing::warn!("Cancelling remote scan failed: {}", e);
+        }
+    }
+}
+
+/// Splits a device name of the form `net:HOST:DEVICE`, where an IPv6
+/// host is enclosed in brackets
+pub fn split_device_name(name: &str) -> Option<(&str, &str)> {
+    let rest = name.strip_prefix("net:")?;
+    if rest.starts_with('[') {
+        let end = rest.find("]:")?;
+        Some((&rest[..=end], &rest[end + 2..]))
+    } else {
+        let mut split = rest.splitn(2, ':');
+        Some((split.next()?, split.next()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_device_name;
+
+    #[test]
+    fn device_names() {
+        assert_eq!(
+            split_device_name("net:scanhost:pixma:04A91912"),
+            Some(("scanhost", "pixma:04A91912"))
+        );
+        assert_eq!(
+            split_device_name("net:[::1]:test:0"),
+            Some(("[::1]", "test:0"))
+        );
+        assert_eq!(split_device_name("pixma:04A91912"), None);
+        assert_eq!(split_device_name("net:scanhost"), None);
+    }
+}
@@ -0,0 +1,106 @@
+//! Encoding shared by both ends of the SANE network protocol, see
+//! `sanei_wire.c`
+//!
+//! Words are big endian 32 bit integers, strings are prefixed by their
+//! length including the terminating NUL, with a length of 0 for NULL.
+//! Arrays are prefixed by their number of elements.
+
+use std::io::{Read, Write};
+
+use sane_sys::SANE_Word;
+
+use super::Error;
+
+/// SANE 1.0.3, the version spoken by saned
+pub const VERSION_CODE: SANE_Word = (1 << 24) | 3;
+
+/// Remote procedure numbers
+pub mod procedure {
+    use sane_sys::SANE_Word;
+
+    pub const INIT: SANE_Word = 0;
+    pub const GET_DEVICES: SANE_Word = 1;
+    pub const OPEN: SANE_Word = 2;
+    pub const CLOSE: SANE_Word = 3;
+    pub const GET_OPTION_DESCRIPTORS: SANE_Word = 4;
+    pub const CONTROL_OPTION: SANE_Word = 5;
+    pub const GET_PARAMETERS: SANE_Word = 6;
+    pub const START: SANE_Word = 7;
+    pub const CANCEL: SANE_Word = 8;
+    pub const AUTHORIZE: SANE_Word = 9;
+    pub const EXIT: SANE_Word = 10;
+}
+
+pub struct Writer<W>(pub W);
+
+impl<W: Write> Writer<W> {
+    pub fn word(&mut self, w: SANE_Word) -> Result<&mut Self, Error> {
+        self.0.write_all(&w.to_be_bytes())?;
+        Ok(self)
+    }
+    pub fn string(&mut self, s: &str) -> Result<&mut Self, Error> {
+        self.word(s.len() as SANE_Word + 1)?;
+        self.0.write_all(s.as_bytes())?;
+        self.0.write_all(&[0])?;
+        Ok(self)
+    }
+    pub fn nullable_string(&mut self, s: Option<&str>) -> Result<&mut Self, Error> {
+        match s {
+            Some(s) => self.string(s),
+            None => self.word(0),
+        }
+    }
+    /// Raw bytes, such as the elements of a character array
+    pub fn bytes(&mut self, bytes: &[u8]) -> Result<&mut Self, Error> {
+        self.0.write_all(bytes)?;
+        Ok(self)
+    }
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.0.flush()?;
+        Ok(())
+    }
+}
+
+pub struct Reader<R>(pub R);
+
+impl<R: Read> Reader<R> {
+    pub fn word(&mut self) -> Result<SANE_Word, Error> {
+        let mut bytes = [0; 4];
+        self.0.read_exact(&mut bytes)?;
+        Ok(SANE_Word::from_be_bytes(bytes))
+    }
+    /// The length of a string or array
+    pub fn length(&mut self) -> Result<usize, Error> {
+        let len = self.word()?;
+        // Guards against allocating absurd amounts on garbage input
+        if !(0..=1 << 24).contains(&len) {
+            return Err(Error::Protocol(format!("Invalid length {}", len)));
+        }
+        Ok(len as usize)
+    }
+    pub fn bytes(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![0; len];
+        self.0.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+    /// A string, where `None` is the NULL pointer
+    pub fn nullable_string(&mut self) -> Result<Option<String>, Error> {
+        let len = self.length()?;
+        if len == 0 {
+            return Ok(None);
+        }
+        let mut bytes = self.bytes(len)?;
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        bytes.truncate(end);
+        String::from_utf8(bytes)
+            .map(Some)
+            .map_err(|e| Error::Protocol(e.to_string()))
+    }
+    pub fn string(&mut self) -> Result<String, Error> {
+        self.nullable_string().map(Option::unwrap_or_default)
+    }
+    /// Whether the following pointer is NULL
+    pub fn is_null(&mut self) -> Result<bool, Error> {
+        Ok(self.word()? != 0)
+    }
+}
@@ -0,0 +1,438 @@
+//! Text recognition with Tesseract
+//!
+//! The `ocr` stage runs the `tesseract` command on each page and adds the
+//! text it finds to the metadata of the page as `text`. The languages, page
+//! segmentation mode and the command are given to the stage, or default to
+//! `--ocr-lang`, `--ocr-psm` and `--ocr-engine`:
+//!
+//! ```toml
+//! pipeline = [{ ocr = "deu+eng" }]
+//! pipeline = [{ ocr = { lang = "deu+eng", psm = 6, engine = "/opt/tesseract/bin/tesseract" } }]
+//! ```
+//!
+//! The languages are checked against those installed when the pipeline is
+//! set up, so a missing traineddata file fails the profile rather than every
+//! page.
+//!
+//! The stage recognizes the text itself by default, so the text is in the
+//! JSON file of the page before it is uploaded and `--on-page` runs.
+//! Recognizing a page takes longer than scanning it, so with
+//! `--ocr-workers N` the stage only keeps a copy of the page and the text
+//! is recognized on a pool of N threads while the next pages are scanned.
+//! The text is then added to the JSON file of the page once it is
+//! recognized, which may be after the page was uploaded and its hooks ran,
+//! and a failure is logged rather than failing the job. As many pages as
+//! there are workers may wait for one, after which the stage waits, so a
+//! fast feeder cannot fill the disk. Scans wait for the text of their pages
+//! before they exit.
+
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Condvar, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use skanny::pipeline::{Metadata, Page, ProcessingStage, StageError};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// Languages joined by `+`, as in `deu+eng`
+    pub lang: String,
+    /// Page segmentation mode, Tesseract picks if not given
+    pub psm: Option<u32>,
+    pub engine: PathBuf,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            lang: "eng".to_owned(),
+            psm: None,
+            engine: "tesseract".into(),
+        }
+    }
+}
+
+/// The settings of a stage, over the defaults
+#[derive(Debug, Default, Deserialize)]
+struct Overrides {
+    lang: Option<String>,
+    psm: Option<u32>,
+    engine: Option<PathBuf>,
+}
+
+static DEFAULTS: OnceLock<Settings> = OnceLock::new();
+static POOL: OnceLock<Pool> = OnceLock::new();
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/// The threads recognizing text while pages are scanned
+struct Pool {
+    sender: SyncSender<Task>,
+    /// Pages queued or being recognized
+    pending: Mutex<usize>,
+    done: Condvar,
+}
+
+thread_local! {
+    /// Pages the `ocr` stages on this thread left to the pool, with the
+    /// settings to recognize them with
+    static ASKED: RefCell<Vec<(Settings, PathBuf)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Sets the defaults of the `ocr` stage and starts its `workers`, before
+/// any pipeline is set up. The returned guard waits for the pages queued
+/// when it is dropped.
+pub fn init(settings: Settings, workers: usize) -> Result<Finish, Box<dyn std::error::Error>> {
+    DEFAULTS
+        .set(settings)
+        .map_err(|_| "The OCR settings are already set")?;
+    if workers > 0 {
+        let (sender, receiver) = sync_channel(workers);
+        let pool = Pool {
+            sender,
+            pending: Mutex::new(0),
+            done: Condvar::new(),
+        };
+        POOL.set(pool)
+            .map_err(|_| "The OCR workers are already started")?;
+        let receiver = Arc::new(Mutex::new(receiver));
+        for n in 0..workers {
+            let receiver = Arc::clone(&receiver);
+            std::thread::Builder::new()
+                .name(format!("ocr-{}", n))
+                .spawn(move || work(&receiver))?;
+        }
+    }
+    Ok(Finish)
+}
+
+fn work(receiver: &Mutex<Receiver<Task>>) {
+    loop {
+        let task = receiver
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .recv();
+        match task {
+            // Counted as done either way, or the scan would wait for it forever
+            Ok(task) => {
+                if std::panic::catch_unwind(std::panic::AssertUnwindSafe(task)).is_err() {
+                    tracing::error!("Recognizing the text of a page panicked");
+                }
+            }
+            Err(_) => return,
+        }
+        if let Some(pool) = POOL.get() {
+            *pool.pending.lock().unwrap_or_else(PoisonError::into_inner) -= 1;
+            pool.done.notify_all();
+        }
+    }
+}
+
+/// Waits for the text of the pages queued when dropped, for as long as
+/// stopping may take if the process is stopping
+pub struct Finish;
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        let timeout = crate::shutdown::stopping().then(crate::shutdown::timeout);
+        if !wait(timeout) {
+            tracing::warn!("Stopped with pages waiting for their text");
+        }
+    }
+}
+
+/// The number of pages queued or being recognized
+pub fn queued() -> usize {
+    POOL.get().map_or(0, |pool| {
+        *pool.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    })
+}
+
+/// Waits up to `timeout`, or for as long as it takes, for the pages queued
+/// to be recognized, returning whether they were
+pub fn wait(timeout: Option<Duration>) -> bool {
+    let pool = match POOL.get() {
+        Some(pool) => pool,
+        None => return true,
+    };
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut pending = pool.pending.lock().unwrap_or_else(PoisonError::into_inner);
+    if *pending > 0 {
+        tracing::info!("Waiting for the text of {} pages", *pending);
+    }
+    while *pending > 0 {
+        pending = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return false;
+                }
+                pool.done
+                    .wait_timeout(pending, left)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0
+            }
+            None => pool
+                .done
+                .wait(pending)
+                .unwrap_or_else(PoisonError::into_inner),
+        };
+    }
+    true
+}
+
+/// Calls `finish` with the metadata of a page once the text the `ocr`
+/// stages on this thread asked for is in it, on the pool if they asked for
+/// any. Waits while the pool is full.
+pub fn finish_page(
+    mut metadata: Metadata,
+    finish: impl FnOnce(Metadata) -> Result<(), Box<dyn std::error::Error>> + Send + 'static,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let asked = ASKED.with(|asked| std::mem::take(&mut *asked.borrow_mut()));
+    let pool = match POOL.get() {
+        Some(pool) if !asked.is_empty() => pool,
+        _ => return finish(metadata),
+    };
+    let span = tracing::Span::current();
+    let task: Task = Box::new(move || {
+        let _span = span.enter();
+        for (settings, path) in asked {
+            match settings.recognize(&path) {
+                Ok(text) => {
+                    metadata.insert("text".to_owned(), text);
+                }
+                Err(e) => tracing::error!("OCR failed: {}", e),
+            }
+            let _ = std::fs::remove_file(&path);
+        }
+        if let Err(e) = finish(metadata) {
+            tracing::error!("Storing the text failed: {}", e);
+        }
+    });
+    *pool.pending.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+    if pool.sender.send(task).is_err() {
+        *pool.pending.lock().unwrap_or_else(PoisonError::into_inner) -= 1;
+        return Err("The OCR workers stopped".into());
+    }
+    Ok(())
+}
+
+/// Forgets the pages left to the pool by stages whose pages are not stored
+pub fn forget() {
+    for (_, path) in ASKED.with(|asked| std::mem::take(&mut *asked.borrow_mut())) {
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+fn defaults() -> &'static Settings {
+    DEFAULTS.get_or_init(Settings::default)
+}
+
+impl Settings {
+    /// These settings with those given to a stage, the languages as a
+    /// string or all of them as a table
+    fn with(&self, setting: Option<&toml::Value>) -> Result<Self, StageError> {
+        let overrides = match setting {
+            None => Overrides::default(),
+            Some(toml::Value::String(lang)) => Overrides {
+                lang: Some(lang.clone()),
+                ..Overrides::default()
+            },
+            Some(setting) => setting.clone().try_into().map_err(|e| {
+                format!(
+                    "Expected the languages, or a table of lang, psm and engine: {}",
+                    e
+                )
+            })?,
+        };
+        Ok(Self {
+            lang: overrides.lang.unwrap_or_else(|| self.lang.clone()),
+            psm: overrides.psm.or(self.psm),
+            engine: overrides.engine.unwrap_or_else(|| self.engine.clone()),
+        })
+    }
+
+    fn command(&self) -> Command {
+        Command::new(&self.engine)
+    }
+
+    /// Fails unless every language has its traineddata installed
+    fn check_languages(&self) -> Result<(), StageError> {
+        let output = self.command().arg("--list-langs").output().map_err(|e| {
+            format!(
+                "Could not run the OCR engine {}: {}",
+                self.engine.display(),
+                e
+            )
+        })?;
+        // The languages follow a line naming the directory they are in
+        let listing = String::from_utf8_lossy(&output.stdout);
+        let mut lines = listing
+            .lines()
+            .skip_while(|line| !line.starts_with("List of available languages"));
+        let directory = lines.next().ok_or_else(|| {
+            format!(
+                "{} did not list its languages: {}",
+                self.engine.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+        })?;
+        let installed: Vec<&str> = lines.map(str::trim).collect();
+        for lang in self.lang.split('+') {
+            if !installed.contains(&lang) {
+                return Err(format!(
+                    "The OCR language {} is not installed, there is no {}.traineddata in {}. \
+                     Installed are {}",
+                    lang,
+                    lang,
+                    directory
+                        .split('"')
+                        .nth(1)
+                        .unwrap_or("the data directory of Tesseract"),
+                    installed.join(", ")
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The text of the image at `path`
+    fn recognize(&self, path: &Path) -> Result<String, StageError> {
+        let mut command = self.command();
+        command.arg(path).arg("stdout").arg("-l").arg(&self.lang);
+        if let Some(psm) = self.psm {
+            command.arg("--psm").arg(psm.to_string());
+        }
+        tracing::debug!("Running {:?}", command);
+        let output = command.output().map_err(|e| {
+            format!(
+                "Could not run the OCR engine {}: {}",
+                self.engine.display(),
+                e
+            )
+        })?;
+        if !output.status.success() {
+            return Err(format!(
+                "OCR failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// Tells apart the images of concurrent pages
+static PAGES: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates the `ocr` stage from its setting
+pub fn stage(setting: Option<&toml::Value>) -> Result<Box<dyn ProcessingStage>, StageError> {
+    let settings = defaults().with(setting)?;
+    settings.check_languages()?;
+    Ok(Box::new(
+        move |mut page: Page| -> Result<Page, StageError> {
+            let path = std::env::temp_dir().join(format!(
+                "skanny-ocr-{}-{}.png",
+                std::process::id(),
+                PAGES.fetch_add(1, Ordering::SeqCst)
+            ));
+            page.image.save(&path)?;
+            page.metadata
+                .insert("ocr-lang".to_owned(), settings.lang.clone());
+            if POOL.get().is_some() {
+                ASKED.with(|asked| asked.borrow_mut().push((settings.clone(), path)));
+                return Ok(page);
+            }
+            let text = settings.recognize(&path);
+            let _ = std::fs::remove_file(&path);
+            page.metadata.insert("text".to_owned(), text?);
+            Ok(page)
+        },
+    ))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_the_languages() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("skanny-ocr-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let engine = dir.join("tesseract");
+        std::fs::write(
+            &engine,
+            "#!/bin/sh\n\
+             if [ \"$1\" = --list-langs ]; then\n\
+             echo 'List of available languages in \"/usr/share/tessdata/\" (2):'\n\
+             echo eng; echo osd; exit\n\
+             fi\n\
+             echo \"$4 $6\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&engine, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let settings = Settings {
+            engine,
+            ..Settings::default()
+        };
+        let table: toml::Value = toml::from_str("lang = \"eng\"\npsm = 6").unwrap();
+        let eng = settings.with(Some(&table)).unwrap();
+        eng.check_languages().unwrap();
+        assert_eq!(eng.recognize(Path::new("page.png")).unwrap(), "eng 6\n");
+
+        let deu = settings
+            .with(Some(&toml::Value::String("deu+eng".to_owned())))
+            .unwrap();
+        let error = deu.check_languages().unwrap_err().to_string();
+        assert!(
+            error.starts_with("The OCR language deu is not installed, there is no deu.traineddata in /usr/share/tessdata/"),
+            "{}",
+            error
+        );
+        assert!(settings.with(Some(&toml::Value::Integer(6))).is_err());
+
+        // The text is recognized on the pool after the page is stored
+        let _finish = init(settings, 1).unwrap();
+        let stage = stage(Some(&table)).unwrap();
+        let image = skanny::Image::Gray8(image::ImageBuffer::from_pixel(2, 2, image::Luma([255])));
+        let page = stage.process(image.clone().into()).unwrap();
+        assert!(!page.metadata.contains_key("text"));
+        let (sender, receiver) = std::sync::mpsc::channel();
+        finish_page(page.metadata, move |metadata| {
+            sender.send(metadata).unwrap();
+            Ok(())
+        })
+        .unwrap();
+        assert!(wait(Some(Duration::from_secs(10))));
+        assert_eq!(queued(), 0);
+        let metadata = receiver.recv().unwrap();
+        assert_eq!(metadata["text"], "eng 6\n");
+        assert_eq!(metadata["ocr-lang"], "eng");
+
+        // A page which panics is counted as done, and the worker goes on
+        let page = stage.process(image.clone().into()).unwrap();
+        finish_page(page.metadata, |_| panic!("storing the text")).unwrap();
+        assert!(wait(Some(Duration::from_secs(10))));
+        assert_eq!(queued(), 0);
+        let page = stage.process(image.into()).unwrap();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        finish_page(page.metadata, move |metadata| {
+            sender.send(metadata).unwrap();
+            Ok(())
+        })
+        .unwrap();
+        assert!(wait(Some(Duration::from_secs(10))));
+        assert_eq!(receiver.recv().unwrap()["text"], "eng 6\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
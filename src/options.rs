@@ -0,0 +1,217 @@
+//! Listing of the options of a device
+//!
+//! With `--schema` the options are described by a JSON Schema for an object
+//! of option values, so settings forms can be generated for any backend.
+//! Everything JSON Schema has no keyword for, such as the unit, the group
+//! and the capabilities, is kept under `x-sane`. The properties are in the
+//! order the device lists the options. `export` saves their values for
+//! `--restore`, see [`crate::snapshot`].
+
+use gumdrop::Options;
+use sane_sys::*;
+use serde_json::{json, Map, Value};
+use skanny::backend::{OptionInfo, ScannerDevice};
+use skanny::{Handle, Opt, OptionValue};
+
+#[derive(Debug, Options)]
+pub struct OptionsOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "Print a JSON Schema describing the options")]
+    schema: bool,
+    #[options(command)]
+    command: Option<OptionsCommand>,
+}
+
+#[derive(Debug, Options)]
+enum OptionsCommand {
+    #[options(help = "Print the values of the options as TOML, for --restore")]
+    Export(ExportOptions),
+}
+
+#[derive(Debug, Options)]
+struct ExportOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+}
+
+pub fn run(handle: &Handle, opts: &OptionsOptions) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(OptionsCommand::Export(_)) = opts.command {
+        print!("{}", crate::snapshot::export(handle)?);
+        return Ok(());
+    }
+    if opts.schema {
+        println!("{}", serde_json::to_string_pretty(&schema(handle))?);
+        return Ok(());
+    }
+    print(&ScannerDevice::options(handle)?);
+    Ok(())
+}
+
+/// Prints the options as every command listing them does, with the values
+/// of the active ones
+pub fn print(options: &[OptionInfo]) {
+    for option in options {
+        if option.name.is_empty() {
+            continue;
+        }
+        // Recordings made before titles were kept have none
+        let title = match &option.title[..] {
+            "" => &option.name,
+            title => title,
+        };
+        println!("\t{}: {}", option.name, title);
+        for line in option.desc.lines() {
+            println!("\t\t{}", line);
+        }
+        let active = option.cap as u32 & SANE_CAP_INACTIVE == 0;
+        if let Some(value) = option.value.as_ref().filter(|_| active) {
+            println!("\t\tCurrent value: {}", value);
+        }
+    }
+}
+
+fn schema(handle: &Handle) -> Value {
+    let mut properties = Map::new();
+    let mut group = None;
+    for option in handle.options() {
+        let descriptor = option.descriptor();
+        if descriptor.type_() == SANE_Value_Type_SANE_TYPE_GROUP {
+            group = Some(descriptor.title().to_owned());
+            continue;
+        }
+        if option.name().is_empty() {
+            continue;
+        }
+        properties.insert(
+            option.name().to_owned(),
+            property(&option, group.as_deref()),
+        );
+    }
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": handle.name(),
+        "type": "object",
+        "properties": properties,
+    })
+}
+
+/// Schema of the value of `option`, where arrays describe their elements
+/// under `items`
+#[allow(non_upper_case_globals)]
+fn property(option: &Opt, group: Option<&str>) -> Value {
+    let descriptor = option.descriptor();
+    let mut property = Map::new();
+    property.insert("title".to_owned(), option.title().into());
+    property.insert("description".to_owned(), descriptor.desc().into());
+
+    let type_ = match descriptor.type_() {
+        SANE_Value_Type_SANE_TYPE_BOOL => "boolean",
+        SANE_Value_Type_SANE_TYPE_INT => "integer",
+        SANE_Value_Type_SANE_TYPE_FIXED => "number",
+        SANE_Value_Type_SANE_TYPE_STRING => "string",
+        _ => "null",
+    };
+    let word = |w: SANE_Word| match descriptor.type_() {
+        SANE_Value_Type_SANE_TYPE_FIXED => json!(SANE_UNFIX(w)),
+        _ => json!(w),
+    };
+    let mut element = Map::new();
+    element.insert("type".to_owned(), type_.into());
+    match descriptor.constraint_type() {
+        SANE_Constraint_Type_SANE_CONSTRAINT_RANGE => {
+            if let Ok(range) = option.get_range() {
+                element.insert("minimum".to_owned(), word(range.min()));
+                element.insert("maximum".to_owned(), word(range.max()));
+                if range.quant() != 0 {
+                    element.insert("multipleOf".to_owned(), word(range.quant()));
+                }
+            }
+        }
+        SANE_Constraint_Type_SANE_CONSTRAINT_WORD_LIST => {
+            if let Ok(list) = option.int_constraints() {
+                element.insert("enum".to_owned(), list.iter().map(|&w| word(w)).collect());
+            }
+        }
+        SANE_Constraint_Type_SANE_CONSTRAINT_STRING_LIST => {
+            if let Ok(list) = option.string_constraints() {
+                element.insert("enum".to_owned(), list.collect::<Vec<_>>().into());
+            }
+        }
+        _ => {}
+    }
+    let elements = match descriptor.type_() {
+        SANE_Value_Type_SANE_TYPE_INT | SANE_Value_Type_SANE_TYPE_FIXED => {
+            descriptor.size() as usize / std::mem::size_of::<SANE_Word>()
+        }
+        _ => 1,
+    };
+    if elements > 1 {
+        property.insert("type".to_owned(), "array".into());
+        property.insert("items".to_owned(), element.into());
+        property.insert("minItems".to_owned(), elements.into());
+        property.insert("maxItems".to_owned(), elements.into());
+    } else {
+        property.extend(element);
+    }
+
+    let cap = descriptor.cap() as u32;
+    if cap & SANE_CAP_SOFT_SELECT == 0 {
+        property.insert("readOnly".to_owned(), true.into());
+    }
+    if let Some(value) = value(option) {
+        property.insert("default".to_owned(), json!(value));
+    }
+
+    let capabilities: Vec<_> = [
+        (SANE_CAP_SOFT_SELECT, "soft-select"),
+        (SANE_CAP_HARD_SELECT, "hard-select"),
+        (SANE_CAP_SOFT_DETECT, "soft-detect"),
+        (SANE_CAP_EMULATED, "emulated"),
+        (SANE_CAP_AUTOMATIC, "automatic"),
+        (SANE_CAP_INACTIVE, "inactive"),
+        (SANE_CAP_ADVANCED, "advanced"),
+    ]
+    .iter()
+    .filter(|&&(flag, _)| cap & flag != 0)
+    .map(|&(_, name)| name)
+    .collect();
+    let unit = match descriptor.unit() {
+        SANE_Unit_SANE_UNIT_PIXEL => "pixel",
+        SANE_Unit_SANE_UNIT_BIT => "bit",
+        SANE_Unit_SANE_UNIT_MM => "mm",
+        SANE_Unit_SANE_UNIT_DPI => "dpi",
+        SANE_Unit_SANE_UNIT_PERCENT => "percent",
+        SANE_Unit_SANE_UNIT_MICROSECOND => "microsecond",
+        _ => "none",
+    };
+    let sane_type = match descriptor.type_() {
+        SANE_Value_Type_SANE_TYPE_BOOL => "bool",
+        SANE_Value_Type_SANE_TYPE_INT => "int",
+        SANE_Value_Type_SANE_TYPE_FIXED => "fixed",
+        SANE_Value_Type_SANE_TYPE_STRING => "string",
+        SANE_Value_Type_SANE_TYPE_BUTTON => "button",
+        _ => "unknown",
+    };
+    property.insert(
+        "x-sane".to_owned(),
+        json!({
+            "type": sane_type,
+            "unit": unit,
+            "size": descriptor.size(),
+            "group": group,
+            "capabilities": capabilities,
+        }),
+    );
+    Value::Object(property)
+}
+
+/// Current value of options which have a single one and can be read
+fn value(option: &Opt) -> Option<OptionValue> {
+    let descriptor = option.descriptor();
+    let cap = descriptor.cap() as u32;
+    if !descriptor.is_single() || cap & SANE_CAP_INACTIVE != 0 {
+        return None;
+    }
+    option.get_value().ok()
+}
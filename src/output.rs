@@ -0,0 +1,184 @@
+//! Writing of output files
+//!
+//! Files are written to a hidden file next to where they go and renamed
+//! once complete, so a crash or a full disk never leaves half a page under
+//! the name of a page. When a file of that name is already there,
+//! `--on-collision` decides:
+//!
+//! | Policy      | Result                                                    |
+//! |-------------|-----------------------------------------------------------|
+//! | `error`     | The page fails and the file is kept, the batch goes on    |
+//! | `overwrite` | The file is replaced                                      |
+//! | `suffix`    | The page is stored as `name-1.png`, `name-2.png` and so on, the default |
+//!
+//! Names are claimed by creating an empty file of the name, so two scans
+//! writing to the same directory never take the same one. The empty file
+//! is removed again if the page fails before it is written.
+
+use std::collections::BTreeSet;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock, PoisonError};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Collision {
+    Error,
+    Overwrite,
+    #[default]
+    Suffix,
+}
+
+impl FromStr for Collision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Collision::Error),
+            "overwrite" => Ok(Collision::Overwrite),
+            "suffix" => Ok(Collision::Suffix),
+            _ => Err(format!(
+                "Unknown policy {}, expected error, overwrite or suffix",
+                s
+            )),
+        }
+    }
+}
+
+static COLLISION: OnceLock<Collision> = OnceLock::new();
+
+/// Sets what happens when files exist, before any are written
+pub fn init(collision: Collision) -> Result<(), Box<dyn std::error::Error>> {
+    COLLISION
+        .set(collision)
+        .map_err(|_| "The collision policy is already set".into())
+}
+
+/// Where a new file meant for `path` goes
+pub fn claim(path: &Path) -> std::io::Result<PathBuf> {
+    resolve(path, *COLLISION.get_or_init(Collision::default))
+}
+
+/// Names claimed by empty files which were not written yet
+static CLAIMED: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
+fn claimed() -> std::sync::MutexGuard<'static, BTreeSet<PathBuf>> {
+    CLAIMED.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn resolve(path: &Path, collision: Collision) -> std::io::Result<PathBuf> {
+    match collision {
+        Collision::Error => create(path).map(|()| path.to_owned()).map_err(|e| {
+            if e.kind() == std::io::ErrorKind::AlreadyExists {
+                std::io::Error::new(e.kind(), format!("{} already exists", path.display()))
+            } else {
+                e
+            }
+        }),
+        Collision::Overwrite => Ok(path.to_owned()),
+        Collision::Suffix => {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let extension = path
+                .extension()
+                .map(|ext| format!(".{}", ext.to_string_lossy()))
+                .unwrap_or_default();
+            let numbered =
+                (1..).map(|n| path.with_file_name(format!("{}-{}{}", stem, n, extension)));
+            for candidate in std::iter::once(path.to_owned()).chain(numbered) {
+                match create(&candidate) {
+                    Ok(()) => return Ok(candidate),
+                    Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            unreachable!("the numbers ran out")
+        }
+    }
+}
+
+/// Claims the name by an empty file, failing if it exists
+fn create(path: &Path) -> std::io::Result<()> {
+    OpenOptions::new().write(true).create_new(true).open(path)?;
+    claimed().insert(path.to_owned());
+    Ok(())
+}
+
+/// Gives up a name claimed for a page which failed before it was written
+pub fn release(path: &Path) {
+    if claimed().remove(path) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Writes a file by `write`, which is given the temporary path to write
+/// to, and renames it to `path` when it succeeds
+///
+/// The temporary file keeps the extension, which decides the format of
+/// images.
+pub fn write<T>(
+    path: &Path,
+    write: impl FnOnce(&Path) -> Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    let name = path.file_name().ok_or("The path has no file name")?;
+    let temporary = path.with_file_name(format!(
+        ".part-{}-{}",
+        std::process::id(),
+        name.to_string_lossy()
+    ));
+    let written = write(&temporary).and_then(|value| {
+        std::fs::rename(&temporary, path)?;
+        Ok(value)
+    });
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+        release(path);
+    } else {
+        claimed().remove(path);
+    }
+    written
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renames_complete_files() {
+        let dir = std::env::temp_dir().join(format!("skanny-output-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("page.png");
+
+        write(&path, |temporary| {
+            assert_eq!(temporary.extension().unwrap(), "png");
+            Ok(std::fs::write(temporary, "first")?)
+        })
+        .unwrap();
+        let failed = write::<()>(&path, |temporary| {
+            std::fs::write(temporary, "half")?;
+            Err("The disk is full".into())
+        });
+        assert!(failed.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first");
+
+        assert!(resolve(&path, Collision::Error).is_err());
+        assert_eq!(resolve(&path, Collision::Overwrite).unwrap(), path);
+        std::fs::write(dir.join("page-1.png"), "").unwrap();
+        let suffixed = resolve(&path, Collision::Suffix).unwrap();
+        assert_eq!(suffixed, dir.join("page-2.png"));
+        // Claimed until written, so the next page takes another name
+        let next = resolve(&path, Collision::Suffix).unwrap();
+        assert_eq!(next, dir.join("page-3.png"));
+        assert!(write::<()>(&next, |_| Err("The scan failed".into())).is_err());
+        assert!(!next.exists());
+        write(&suffixed, |temporary| {
+            Ok(std::fs::write(temporary, "second")?)
+        })
+        .unwrap();
+        release(&suffixed);
+        let second = std::fs::read_to_string(&suffixed).unwrap();
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(second, "second");
+        assert_eq!(files, 3);
+    }
+}
@@ -0,0 +1,181 @@
+//! Paper sizes, including receipts and other paper longer than a page
+//!
+//! The scan area is set by `br-x` and `br-y`, measured from `tl-x` and
+//! `tl-y`. Sheet-fed scanners which can feed long paper, such as the Fujitsu
+//! ones, limit `br-y` to their `page-height` option, which is set first. A
+//! receipt is scanned at the longest length the device allows, and the frame
+//! ends where the paper does.
+
+use std::str::FromStr;
+
+use sane_sys::*;
+use skanny::backend::{BackendError, Constraint, OptionInfo, ScannerDevice};
+use skanny::OptionValue;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Paper {
+    A4,
+    Letter,
+    Legal,
+    /// The width of the device and as long as it can feed
+    Receipt,
+}
+
+impl Paper {
+    /// Width and height in mm, no width keeps the one of the device
+    fn size(self) -> (Option<f64>, Length) {
+        match self {
+            Paper::A4 => (Some(210.0), Length::Mm(297.0)),
+            Paper::Letter => (Some(215.9), Length::Mm(279.4)),
+            Paper::Legal => (Some(215.9), Length::Mm(355.6)),
+            Paper::Receipt => (None, Length::Longest),
+        }
+    }
+}
+
+impl FromStr for Paper {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "a4" => Ok(Paper::A4),
+            "letter" => Ok(Paper::Letter),
+            "legal" => Ok(Paper::Legal),
+            "receipt" => Ok(Paper::Receipt),
+            _ => Err(format!(
+                "Unknown paper {}, expected a4, letter, legal or receipt",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Length {
+    Mm(f64),
+    /// The largest value the option allows
+    Longest,
+}
+
+/// Sets the scan area to `paper`, with the height replaced by `height` in
+/// mm if given
+pub fn set(
+    device: &dyn ScannerDevice,
+    paper: Option<Paper>,
+    height: Option<f64>,
+) -> Result<(), BackendError> {
+    let (width, length) = paper.map_or((None, None), |paper| {
+        let (width, length) = paper.size();
+        (width, Some(length))
+    });
+    let length = height.map(Length::Mm).or(length);
+
+    // The page size bounds the scan area, so it goes first
+    let options = device.options()?;
+    if let Some(width) = width {
+        set_length(device, &options, "page-width", Length::Mm(width))?;
+    }
+    if let Some(length) = length {
+        set_length(device, &options, "page-height", length)?;
+    }
+
+    let options = device.options()?;
+    let start = |name: &str| match find(&options, name).and_then(|option| option.value.clone()) {
+        Some(OptionValue::Fixed(mm)) => mm,
+        Some(OptionValue::Int(mm)) => f64::from(mm),
+        _ => 0.0,
+    };
+    if let Some(width) = width {
+        set_length(device, &options, "br-x", Length::Mm(start("tl-x") + width))?;
+    }
+    match length {
+        Some(Length::Mm(mm)) => {
+            set_length(device, &options, "br-y", Length::Mm(start("tl-y") + mm))?
+        }
+        Some(Length::Longest) => set_length(device, &options, "br-y", Length::Longest)?,
+        None => {}
+    }
+    Ok(())
+}
+
+fn find<'a>(options: &'a [OptionInfo], name: &str) -> Option<&'a OptionInfo> {
+    options.iter().find(|option| option.name == name)
+}
+
+/// Sets an option in mm, if the device has it
+fn set_length(
+    device: &dyn ScannerDevice,
+    options: &[OptionInfo],
+    name: &str,
+    length: Length,
+) -> Result<(), BackendError> {
+    let option = match find(options, name) {
+        Some(option) => option,
+        None => return Ok(()),
+    };
+    let value = value(option, length).ok_or_else(|| format!("{} is not a length", name))?;
+    tracing::debug!("Setting {} to {}", name, value);
+    device.set_option(name, &value)
+}
+
+#[allow(non_upper_case_globals)]
+fn value(option: &OptionInfo, length: Length) -> Option<OptionValue> {
+    let mm = match (length, &option.constraint) {
+        (Length::Mm(mm), _) => mm,
+        (Length::Longest, Constraint::Range { max, .. }) => match option.type_ {
+            SANE_Value_Type_SANE_TYPE_FIXED => SANE_UNFIX(*max),
+            _ => f64::from(*max),
+        },
+        (Length::Longest, Constraint::WordList(words)) => {
+            let max = *words.iter().max()?;
+            match option.type_ {
+                SANE_Value_Type_SANE_TYPE_FIXED => SANE_UNFIX(max),
+                _ => f64::from(max),
+            }
+        }
+        (Length::Longest, _) => return None,
+    };
+    match option.type_ {
+        SANE_Value_Type_SANE_TYPE_FIXED => Some(OptionValue::Fixed(mm)),
+        SANE_Value_Type_SANE_TYPE_INT => Some(OptionValue::Int(mm.round() as SANE_Int)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option(type_: SANE_Value_Type, max: SANE_Word) -> OptionInfo {
+        OptionInfo {
+            name: "page-height".to_owned(),
+            title: "Page height".to_owned(),
+            desc: String::new(),
+            type_,
+            unit: SANE_Unit_SANE_UNIT_MM,
+            cap: SANE_CAP_SOFT_SELECT as SANE_Int,
+            constraint: Constraint::Range {
+                min: 0,
+                max,
+                quant: 0,
+            },
+            value: None,
+        }
+    }
+
+    #[test]
+    fn receipts_are_as_long_as_allowed() {
+        let fixed = option(SANE_Value_Type_SANE_TYPE_FIXED, SANE_FIX(5588.0));
+        assert_eq!(
+            value(&fixed, Length::Longest),
+            Some(OptionValue::Fixed(5588.0))
+        );
+        assert_eq!(
+            value(&fixed, Length::Mm(297.0)),
+            Some(OptionValue::Fixed(297.0))
+        );
+        let int = option(SANE_Value_Type_SANE_TYPE_INT, 863);
+        assert_eq!(value(&int, Length::Longest), Some(OptionValue::Int(863)));
+        assert_eq!(value(&int, Length::Mm(355.6)), Some(OptionValue::Int(356)));
+    }
+}
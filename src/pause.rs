@@ -0,0 +1,95 @@
+//! Pausing of batches between pages
+//!
+//! A paused batch finishes the page being scanned and waits before the next
+//! one, so paper can be reloaded or a document fixed without starting over
+//! and numbering the pages again. Batches are paused by `SIGUSR1` and
+//! resumed by `SIGUSR2`:
+//!
+//! ```text
+//! kill -USR1 $(pidof skanny)
+//! kill -USR2 $(pidof skanny)
+//! ```
+//!
+//! The server pauses and resumes its jobs by `POST /pause` and
+//! `POST /resume`.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// How often a paused batch checks whether it was resumed
+const POLL: Duration = Duration::from_millis(100);
+
+pub fn pause() {
+    PAUSED.store(true, Ordering::SeqCst);
+}
+
+pub fn resume() {
+    PAUSED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_paused() -> bool {
+    PAUSED.load(Ordering::SeqCst)
+}
+
+/// Waits until the batch is resumed, if it is paused
+pub fn wait() {
+    if !is_paused() {
+        return;
+    }
+    tracing::info!("Paused before the next page");
+    while is_paused() {
+        std::thread::sleep(POLL);
+    }
+    tracing::info!("Resumed");
+}
+
+/// Pauses on `SIGUSR1` and resumes on `SIGUSR2`
+#[cfg(unix)]
+pub fn on_signals() -> Result<(), Box<dyn std::error::Error>> {
+    extern "C" fn handle(signal: libc::c_int) {
+        // Storing to an atomic is all a signal handler may safely do here
+        PAUSED.store(signal == libc::SIGUSR1, Ordering::SeqCst);
+    }
+    for signal in [libc::SIGUSR1, libc::SIGUSR2] {
+        let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn on_signals() -> Result<(), Box<dyn std::error::Error>> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_until_resumed() {
+        #[cfg(unix)]
+        {
+            on_signals().unwrap();
+            unsafe { libc::raise(libc::SIGUSR1) };
+            assert!(is_paused());
+            unsafe { libc::raise(libc::SIGUSR2) };
+            assert!(!is_paused());
+        }
+
+        pause();
+        let resumer = std::thread::spawn(|| {
+            std::thread::sleep(3 * POLL);
+            resume();
+        });
+        let started = std::time::Instant::now();
+        wait();
+        assert!(started.elapsed() >= 3 * POLL);
+        assert!(!is_paused());
+        resumer.join().unwrap();
+    }
+}
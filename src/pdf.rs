@@ -0,0 +1,483 @@
+//! Pages saved as PDF files
+//!
+//! Images saved with a `.pdf` extension become single page PDF files. How
+//! the image is compressed decides the size of the file more than anything
+//! else, so `--pdf-compression` picks a strategy:
+//!
+//! | Strategy | Pages                                                       |
+//! |----------|-------------------------------------------------------------|
+//! | `auto`   | `g4` for line art, `flate` for 16 bit samples and pages of few colours, such as thresholded text and drawings, `jpeg` for photos and other scans |
+//! | `g4`     | CCITT Group 4, lossless for line art, other pages are thresholded |
+//! | `jpeg`   | JPEG at quality 85, in 8 bits                               |
+//! | `flate`  | Deflate, lossless                                           |
+//!
+//! Scans do not carry their resolution, the pages are sized for
+//! `--pdf-dpi`.
+//!
+//! With `--pdfa` the files are PDF/A-2b, for archives which keep documents
+//! for decades. They declare their colours as sRGB with an embedded
+//! profile and carry XMP metadata.
+//!
+//! With `--pdf-encrypt` the files need `--pdf-password` to be opened, and
+//! readers may only do what `--pdf-allow` allows unless they know
+//! `--pdf-owner-password`, see [`encrypt`]. PDF/A does not allow
+//! encryption.
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use skanny::{Bilevel, Image};
+
+use crate::template::DateTime;
+
+mod encrypt;
+mod g4;
+mod icc;
+
+pub use encrypt::{Encryption, Permissions};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    Auto,
+    G4,
+    Jpeg,
+    Flate,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Compression::Auto),
+            "g4" => Ok(Compression::G4),
+            "jpeg" => Ok(Compression::Jpeg),
+            "flate" => Ok(Compression::Flate),
+            _ => Err(format!(
+                "Unknown compression {}, expected auto, g4, jpeg or flate",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub compression: Compression,
+    pub dpi: u32,
+    /// Write PDF/A-2b
+    pub pdfa: bool,
+    pub encryption: Option<Encryption>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            compression: Compression::Auto,
+            dpi: 300,
+            pdfa: false,
+            encryption: None,
+        }
+    }
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Sets how PDF files are written, before any are
+pub fn init(settings: Settings) -> Result<(), Box<dyn std::error::Error>> {
+    if settings.pdfa && settings.encryption.is_some() {
+        return Err("PDF/A files cannot be encrypted".into());
+    }
+    SETTINGS
+        .set(settings)
+        .map_err(|_| "The PDF settings are already set".into())
+}
+
+fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+const JPEG_QUALITY: u8 = 85;
+
+/// More colours than this make a photo or a scan with its noise
+const FEW_COLOURS: usize = 256;
+
+/// The strategy for a page, from what is in it
+fn choose(image: &Image) -> Compression {
+    let colours = |pixels: &mut dyn Iterator<Item = u32>| {
+        let mut seen = HashSet::new();
+        for pixel in pixels {
+            if seen.insert(pixel) && seen.len() > FEW_COLOURS {
+                return Compression::Jpeg;
+            }
+        }
+        Compression::Flate
+    };
+    match image {
+        Image::Bilevel(_) => Compression::G4,
+        Image::Gray16(_) | Image::Rgb16(_) => Compression::Flate,
+        Image::Gray8(im) => colours(&mut im.as_raw().iter().map(|&sample| sample.into())),
+        Image::Rgb8(im) => colours(
+            &mut im
+                .as_raw()
+                .chunks_exact(3)
+                .map(|rgb| u32::from_be_bytes([0, rgb[0], rgb[1], rgb[2]])),
+        ),
+    }
+}
+
+/// Makes gray samples below the middle black
+fn threshold(image: &Image) -> Bilevel {
+    let gray = image.to_dynamic().into_luma8();
+    let (width, height) = gray.dimensions();
+    let row = (width as usize).div_ceil(8);
+    let mut packed = vec![0; row * height as usize];
+    if width > 0 {
+        for (line, pixels) in packed
+            .chunks_exact_mut(row)
+            .zip(gray.as_raw().chunks_exact(width as usize))
+        {
+            for (x, _) in pixels.iter().enumerate().filter(|(_, &pixel)| pixel < 128) {
+                line[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+    }
+    Bilevel::from_raw(width, height, packed).unwrap()
+}
+
+/// An image XObject, the dictionary entries besides the size and length
+struct Encoded {
+    entries: String,
+    data: Vec<u8>,
+}
+
+fn encode(image: &Image, compression: Compression) -> Result<Encoded, Box<dyn std::error::Error>> {
+    let color_space = match image {
+        Image::Rgb8(_) | Image::Rgb16(_) => "/DeviceRGB",
+        _ => "/DeviceGray",
+    };
+    let compression = match compression {
+        Compression::Auto => choose(image),
+        compression => compression,
+    };
+    Ok(match (compression, image) {
+        (Compression::G4, image) => {
+            let thresholded;
+            let bilevel = match image {
+                Image::Bilevel(bilevel) => bilevel,
+                image => {
+                    thresholded = threshold(image);
+                    &thresholded
+                }
+            };
+            Encoded {
+                entries: format!(
+                    "/ColorSpace /DeviceGray /BitsPerComponent 1 /Filter /CCITTFaxDecode \
+                     /DecodeParms << /K -1 /Columns {} /Rows {} >>",
+                    bilevel.width(),
+                    bilevel.height()
+                ),
+                data: g4::encode(bilevel),
+            }
+        }
+        (Compression::Jpeg, image) => {
+            let image = image.clone().into_8bit();
+            let mut data = Vec::new();
+            image.write_to(&mut data, image::ImageOutputFormat::Jpeg(JPEG_QUALITY))?;
+            Encoded {
+                entries: format!(
+                    "/ColorSpace {} /BitsPerComponent 8 /Filter /DCTDecode",
+                    color_space
+                ),
+                data,
+            }
+        }
+        (_, image) => {
+            let mut samples = image.as_bytes().to_vec();
+            let bits = match image {
+                Image::Gray16(_) | Image::Rgb16(_) => {
+                    skanny::pixels::to_big_endian16(&mut samples);
+                    16
+                }
+                Image::Bilevel(_) => 1,
+                _ => 8,
+            };
+            // 1 is black in line art
+            let decode = if bits == 1 { " /Decode [1 0]" } else { "" };
+            Encoded {
+                entries: format!(
+                    "/ColorSpace {} /BitsPerComponent {} /Filter /FlateDecode{}",
+                    color_space, bits, decode
+                ),
+                data: deflate::deflate_bytes_zlib(&samples),
+            }
+        }
+    })
+}
+
+/// Writes numbered objects, remembering where they start for the
+/// cross-reference table
+struct Writer<W> {
+    w: W,
+    written: usize,
+    offsets: Vec<usize>,
+    id: [u8; 16],
+    /// Encrypts the streams
+    keys: Option<encrypt::Keys>,
+}
+
+impl<W: Write> Writer<W> {
+    fn new(w: W, id: [u8; 16], keys: Option<encrypt::Keys>) -> std::io::Result<Self> {
+        // AES-256 came with version 1.7 at extension level 3
+        let version = if keys.is_some() { "1.7" } else { "1.5" };
+        let mut writer = Self {
+            w,
+            written: 0,
+            offsets: Vec::new(),
+            id,
+            keys,
+        };
+        // The binary comment tells transfers the file is not text
+        writer.write(format!("%PDF-{}\n", version).as_bytes())?;
+        writer.write(b"%\xe2\xe3\xcf\xd3\n")?;
+        Ok(writer)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.w.write_all(bytes)?;
+        self.written += bytes.len();
+        Ok(())
+    }
+
+    fn object(&mut self, dictionary: &str) -> std::io::Result<()> {
+        self.offsets.push(self.written);
+        let object = format!("{} 0 obj\n{}\nendobj\n", self.offsets.len(), dictionary);
+        self.write(object.as_bytes())
+    }
+
+    /// A stream, `entries` going in its dictionary besides the length
+    fn stream(&mut self, entries: &str, data: &[u8]) -> std::io::Result<()> {
+        let encrypted;
+        let data = match &self.keys {
+            Some(keys) => {
+                encrypted = keys.encrypt(data)?;
+                &encrypted[..]
+            }
+            None => data,
+        };
+        self.offsets.push(self.written);
+        let start = format!(
+            "{} 0 obj\n<< {} /Length {} >>\nstream\n",
+            self.offsets.len(),
+            entries,
+            data.len()
+        );
+        self.write(start.as_bytes())?;
+        self.write(data)?;
+        self.write(b"\nendstream\nendobj\n")
+    }
+
+    /// Ends the file with the catalog as object 1
+    fn finish(mut self) -> std::io::Result<W> {
+        // The encryption dictionary itself is not encrypted
+        let encrypt = match self.keys.take() {
+            Some(keys) => {
+                self.object(keys.dictionary())?;
+                format!(" /Encrypt {} 0 R", self.offsets.len())
+            }
+            None => String::new(),
+        };
+        let xref = self.written;
+        let mut table = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+        for offset in &self.offsets {
+            table.push_str(&format!("{:010} 00000 n \n", offset));
+        }
+        let id: String = self.id.iter().map(|byte| format!("{:02x}", byte)).collect();
+        table.push_str(&format!(
+            "trailer\n<< /Size {} /Root 1 0 R{} /ID [<{id}> <{id}>] >>\nstartxref\n{}\n%%EOF\n",
+            self.offsets.len() + 1,
+            encrypt,
+            xref,
+            id = id,
+        ));
+        self.write(table.as_bytes())?;
+        Ok(self.w)
+    }
+}
+
+/// An identifier for the file, from its image and when it is written
+fn file_id(data: &[u8]) -> [u8; 16] {
+    use std::hash::{Hash, Hasher};
+
+    let mut id = [0; 16];
+    let now = SystemTime::now();
+    for (i, half) in id.chunks_exact_mut(8).enumerate() {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        (i, data, now).hash(&mut hasher);
+        half.copy_from_slice(&hasher.finish().to_be_bytes());
+    }
+    id
+}
+
+/// The XMP metadata of PDF/A files
+fn xmp(created: &DateTime) -> String {
+    let date = created.to_string();
+    format!(
+        r#"<?xpacket begin="{2}" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+<rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+<rdf:Description rdf:about=""
+ xmlns:pdfaid="http://www.aiim.org/pdfa/ns/id/"
+ xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+ xmlns:pdf="http://ns.adobe.com/pdf/1.3/">
+<pdfaid:part>2</pdfaid:part>
+<pdfaid:conformance>B</pdfaid:conformance>
+<xmp:CreateDate>{0}</xmp:CreateDate>
+<xmp:ModifyDate>{0}</xmp:ModifyDate>
+<xmp:CreatorTool>skanny</xmp:CreatorTool>
+<pdf:Producer>skanny {1}</pdf:Producer>
+</rdf:Description>
+</rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#,
+        date,
+        env!("CARGO_PKG_VERSION"),
+        '\u{feff}'
+    )
+}
+
+/// Writes `image` as a page of `settings.dpi`
+fn write_page<W: Write>(
+    image: &Image,
+    settings: &Settings,
+    w: W,
+) -> Result<W, Box<dyn std::error::Error>> {
+    let encoded = encode(image, settings.compression)?;
+    let points = |pixels: u32| f64::from(pixels) * 72.0 / f64::from(settings.dpi.max(1));
+    let (width, height) = (points(image.width()), points(image.height()));
+
+    let keys = settings
+        .encryption
+        .as_ref()
+        .map(encrypt::Keys::new)
+        .transpose()?;
+    let mut writer = Writer::new(w, file_id(&encoded.data), keys)?;
+    if settings.pdfa {
+        writer
+            .object("<< /Type /Catalog /Pages 2 0 R /Metadata 6 0 R /OutputIntents [7 0 R] >>")?;
+    } else if settings.encryption.is_some() {
+        writer.object(
+            "<< /Type /Catalog /Pages 2 0 R \
+             /Extensions << /ADBE << /BaseVersion /1.7 /ExtensionLevel 3 >> >> >>",
+        )?;
+    } else {
+        writer.object("<< /Type /Catalog /Pages 2 0 R >>")?;
+    }
+    writer.object("<< /Type /Pages /Kids [3 0 R] /Count 1 >>")?;
+    writer.object(&format!(
+        "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.2} {:.2}] \
+         /Resources << /XObject << /Im0 4 0 R >> >> /Contents 5 0 R >>",
+        width, height
+    ))?;
+    writer.stream(
+        &format!(
+            "/Type /XObject /Subtype /Image /Width {} /Height {} {}",
+            image.width(),
+            image.height(),
+            encoded.entries
+        ),
+        &encoded.data,
+    )?;
+    let contents = format!("q {:.2} 0 0 {:.2} 0 0 cm /Im0 Do Q", width, height);
+    writer.stream("", contents.as_bytes())?;
+    if settings.pdfa {
+        let metadata = xmp(&DateTime::now());
+        writer.stream("/Type /Metadata /Subtype /XML", metadata.as_bytes())?;
+        writer.object(&format!(
+            "<< /Type /OutputIntent /S /GTS_PDFA1 /OutputConditionIdentifier ({0}) \
+             /Info ({0}) /DestOutputProfile 8 0 R >>",
+            icc::DESCRIPTION
+        ))?;
+        let profile = deflate::deflate_bytes_zlib(&icc::srgb());
+        writer.stream("/N 3 /Filter /FlateDecode", &profile)?;
+    }
+    Ok(writer.finish()?)
+}
+
+/// Saves `image` as a single page PDF file
+pub fn save(image: &Image, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let file = std::fs::File::create(path)?;
+    write_page(image, settings(), std::io::BufWriter::new(file))?
+        .flush()
+        .map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_compression_by_content() {
+        let text = Image::Gray8(image::ImageBuffer::from_fn(64, 64, |x, _| {
+            image::Luma([if x % 8 == 0 { 0 } else { 255 }])
+        }));
+        let photo = Image::Rgb8(image::ImageBuffer::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, 128])
+        }));
+        let line_art = Image::Bilevel(threshold(&text));
+        assert_eq!(choose(&text), Compression::Flate);
+        assert_eq!(choose(&photo), Compression::Jpeg);
+        assert_eq!(choose(&line_art), Compression::G4);
+
+        let settings = Settings {
+            compression: Compression::Auto,
+            dpi: 144,
+            pdfa: false,
+            encryption: None,
+        };
+        let pdf = write_page(&photo, &settings, Vec::new()).unwrap();
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("/MediaBox [0 0 32.00 32.00]"));
+        assert!(text.contains("/Filter /DCTDecode"));
+        // The trailer is text, unlike the JPEG
+        let trailer = std::str::from_utf8(&pdf[pdf.len() - 32..]).unwrap();
+        let xref: usize = trailer.rsplit("startxref\n").next().unwrap()[..]
+            .lines()
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(pdf[xref..].starts_with(b"xref"));
+
+        let forced = Settings {
+            compression: Compression::G4,
+            ..settings
+        };
+        let pdf = write_page(&photo, &forced, Vec::new()).unwrap();
+        assert!(String::from_utf8_lossy(&pdf).contains("/K -1 /Columns 64 /Rows 64"));
+    }
+
+    #[test]
+    fn writes_pdfa() {
+        let page = Image::Gray8(image::ImageBuffer::from_pixel(8, 8, image::Luma([200])));
+        let settings = Settings {
+            pdfa: true,
+            ..Settings::default()
+        };
+        let pdf = write_page(&page, &settings, Vec::new()).unwrap();
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(text.contains("/Metadata 6 0 R /OutputIntents [7 0 R]"));
+        assert!(text.contains("<pdfaid:part>2</pdfaid:part>"));
+        assert!(text.contains("begin=\"\u{feff}\""));
+        assert!(text.contains("/Size 9 "));
+        assert!(text.contains("/ID [<"));
+
+        let profile = icc::srgb();
+        assert_eq!(profile[..4], (profile.len() as u32).to_be_bytes());
+        assert_eq!(&profile[36..40], b"acsp");
+    }
+}
@@ -0,0 +1,257 @@
+//! Encryption with passwords, by AES-256 as the standard security handler
+//! does it from revision 6 on
+//!
+//! Every file gets a random key, which encrypts the streams. The key is
+//! stored encrypted with each password, and the permissions are stored
+//! encrypted with the key so readers notice when they are changed. Readers
+//! need the user password to open the file; those who know the owner
+//! password are not held to the permissions.
+//!
+//! Passwords are taken as UTF-8 without the SASLprep normalization, which
+//! only matters for passwords with unusual Unicode characters.
+
+use std::convert::TryInto;
+use std::str::FromStr;
+
+use aes::cipher::block_padding::{NoPadding, Pkcs7};
+use aes::cipher::{BlockEncrypt, BlockEncryptMut, KeyInit, KeyIvInit};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+/// What readers of an encrypted file may do without the owner password
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Permissions(u32);
+
+const PERMISSIONS: [(&str, u32); 6] = [
+    // Printing in full quality is bit 12 besides bit 3
+    ("print", 1 << 2 | 1 << 11),
+    ("modify", 1 << 3),
+    ("copy", 1 << 4),
+    ("annotate", 1 << 5),
+    ("fill-forms", 1 << 8),
+    ("assemble", 1 << 10),
+];
+
+impl Permissions {
+    /// The `/P` entry, with the bits which must be set
+    fn flags(self) -> i32 {
+        // Bits 7 and 8, and 13 up. Bit 10, extracting for accessibility,
+        // is always allowed from revision 6 on
+        let reserved = 0xffff_f0c0 | 1 << 9;
+        (self.0 | reserved) as i32
+    }
+}
+
+impl FromStr for Permissions {
+    type Err = String;
+
+    /// Permissions separated by commas, or `none`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bits = 0;
+        for name in s.split(',').map(str::trim).filter(|name| *name != "none") {
+            bits |= PERMISSIONS
+                .iter()
+                .find(|(permission, _)| *permission == name)
+                .ok_or_else(|| {
+                    format!(
+                        "Unknown permission {}, expected print, modify, copy, annotate, \
+                         fill-forms, assemble or none",
+                        name
+                    )
+                })?
+                .1;
+        }
+        Ok(Permissions(bits))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Encryption {
+    pub user_password: String,
+    /// A random password if not given, so that nobody may lift the
+    /// restrictions
+    pub owner_password: Option<String>,
+    pub permissions: Permissions,
+}
+
+/// The key of a file and the dictionary which tells readers how to get it
+pub struct Keys {
+    key: [u8; 32],
+    dictionary: String,
+}
+
+fn random<const N: usize>() -> Result<[u8; N], getrandom::Error> {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Passwords count up to 127 bytes
+fn password(password: &str) -> &[u8] {
+    let mut end = password.len().min(127);
+    while !password.is_char_boundary(end) {
+        end -= 1;
+    }
+    &password.as_bytes()[..end]
+}
+
+/// Hashes a password with a salt and, for the owner password, the user
+/// entry, by algorithm 2.B of ISO 32000-2
+fn hash(password: &[u8], salt: &[u8], user: &[u8]) -> [u8; 32] {
+    let mut k = Sha256::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(user)
+        .finalize()
+        .to_vec();
+    let mut round = 0;
+    loop {
+        let mut k1 = Vec::with_capacity(64 * (password.len() + k.len() + user.len()));
+        for _ in 0..64 {
+            k1.extend_from_slice(password);
+            k1.extend_from_slice(&k);
+            k1.extend_from_slice(user);
+        }
+        let e = cbc::Encryptor::<aes::Aes128>::new(k[..16].into(), k[16..32].into())
+            .encrypt_padded_vec_mut::<NoPadding>(&k1);
+        k = match e[..16].iter().map(|&byte| u32::from(byte)).sum::<u32>() % 3 {
+            0 => Sha256::digest(&e).to_vec(),
+            1 => Sha384::digest(&e).to_vec(),
+            _ => Sha512::digest(&e).to_vec(),
+        };
+        round += 1;
+        if round >= 64 && u32::from(*e.last().unwrap()) + 32 <= round {
+            break;
+        }
+    }
+    k[..32].try_into().unwrap()
+}
+
+/// The `U` or `O` entry and the `UE` or `OE` entry for a password
+fn entries(
+    key: &[u8; 32],
+    password: &[u8],
+    user: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), getrandom::Error> {
+    let salts: [u8; 16] = random()?;
+    let (validation, key_salt) = salts.split_at(8);
+    let mut entry = hash(password, validation, user).to_vec();
+    entry.extend_from_slice(&salts);
+    let encrypted_key =
+        cbc::Encryptor::<aes::Aes256>::new(&hash(password, key_salt, user).into(), &[0; 16].into())
+            .encrypt_padded_vec_mut::<NoPadding>(key);
+    Ok((entry, encrypted_key))
+}
+
+impl Keys {
+    pub fn new(encryption: &Encryption) -> Result<Self, getrandom::Error> {
+        let key: [u8; 32] = random()?;
+        let (user, user_key) = entries(&key, password(&encryption.user_password), &[])?;
+        let owner_password = match &encryption.owner_password {
+            Some(owner_password) => password(owner_password).to_vec(),
+            None => random::<32>()?.to_vec(),
+        };
+        let (owner, owner_key) = entries(&key, &owner_password, &user)?;
+
+        let flags = encryption.permissions.flags();
+        let mut perms = [0; 16];
+        perms[..4].copy_from_slice(&flags.to_le_bytes());
+        perms[4..8].copy_from_slice(&[0xff; 4]);
+        // The metadata is encrypted too
+        perms[8..12].copy_from_slice(b"Tadb");
+        perms[12..].copy_from_slice(&random::<4>()?);
+        aes::Aes256::new(&key.into()).encrypt_block((&mut perms).into());
+
+        let dictionary = format!(
+            "<< /Filter /Standard /V 5 /R 6 /Length 256 \
+             /CF << /StdCF << /Type /CryptFilter /CFM /AESV3 /AuthEvent /DocOpen /Length 32 >> >> \
+             /StmF /StdCF /StrF /StdCF /U <{}> /UE <{}> /O <{}> /OE <{}> /P {} /Perms <{}> >>",
+            hex(&user),
+            hex(&user_key),
+            hex(&owner),
+            hex(&owner_key),
+            flags,
+            hex(&perms)
+        );
+        Ok(Self { key, dictionary })
+    }
+
+    /// The `/Encrypt` dictionary of the trailer
+    pub fn dictionary(&self) -> &str {
+        &self.dictionary
+    }
+
+    /// Encrypts a stream or string, with the random initialization vector
+    /// in front
+    pub fn encrypt(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let iv: [u8; 16] = random()?;
+        let mut encrypted = iv.to_vec();
+        encrypted.extend(
+            cbc::Encryptor::<aes::Aes256>::new(&self.key.into(), &iv.into())
+                .encrypt_padded_vec_mut::<Pkcs7>(data),
+        );
+        Ok(encrypted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::{BlockDecrypt, BlockDecryptMut};
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn opens_with_the_passwords() {
+        let encryption = Encryption {
+            user_password: "user".to_owned(),
+            owner_password: Some("owner".to_owned()),
+            permissions: "print,copy".parse().unwrap(),
+        };
+        let keys = Keys::new(&encryption).unwrap();
+        let entry = |name: &str| {
+            let start = keys.dictionary.find(&format!("/{} <", name)).unwrap() + name.len() + 3;
+            let end = start + keys.dictionary[start..].find('>').unwrap();
+            unhex(&keys.dictionary[start..end])
+        };
+        let (u, ue, o, oe) = (entry("U"), entry("UE"), entry("O"), entry("OE"));
+        assert!(keys.dictionary.contains("/P -1324 "));
+
+        // Readers check a password against the validation salt, then take
+        // the key with the key salt
+        let open = |password: &[u8], entry: &[u8], encrypted: &[u8], user: &[u8]| {
+            assert_eq!(hash(password, &entry[32..40], user), entry[..32]);
+            cbc::Decryptor::<aes::Aes256>::new(
+                &hash(password, &entry[40..48], user).into(),
+                &[0; 16].into(),
+            )
+            .decrypt_padded_vec_mut::<NoPadding>(encrypted)
+            .unwrap()
+        };
+        let key = open(b"user", &u, &ue, &[]);
+        assert_eq!(key, keys.key);
+        assert_eq!(open(b"owner", &o, &oe, &u), key);
+        assert_ne!(hash(b"wrong", &u[32..40], &[]), u[..32]);
+
+        let mut perms: [u8; 16] = entry("Perms").try_into().unwrap();
+        aes::Aes256::new(key[..].into()).decrypt_block((&mut perms).into());
+        assert_eq!(perms[..4], (-1324i32).to_le_bytes());
+        assert_eq!(&perms[8..12], b"Tadb");
+
+        let stream = keys.encrypt(b"q 1 0 0 1 0 0 cm Q").unwrap();
+        assert_eq!(stream.len(), 16 + 32);
+        let plain = cbc::Decryptor::<aes::Aes256>::new(key[..].into(), stream[..16].into())
+            .decrypt_padded_vec_mut::<Pkcs7>(&stream[16..])
+            .unwrap();
+        assert_eq!(plain, b"q 1 0 0 1 0 0 cm Q");
+        assert!("print,scan".parse::<Permissions>().is_err());
+    }
+}
@@ -0,0 +1,102 @@
+//! The sRGB colour profile which PDF/A files declare their colours in
+//!
+//! The profile is built rather than shipped, as an ICC version 2 display
+//! profile with the primaries adapted to D50 and the sRGB curve sampled at
+//! 1024 points.
+
+/// Signed 15.16 fixed point, as ICC has its numbers
+fn fixed(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz(x: f64, y: f64, z: f64) -> Vec<u8> {
+    let mut tag = b"XYZ \0\0\0\0".to_vec();
+    for value in [x, y, z] {
+        tag.extend_from_slice(&fixed(value));
+    }
+    tag
+}
+
+fn text(text: &str) -> Vec<u8> {
+    let mut tag = b"text\0\0\0\0".to_vec();
+    tag.extend_from_slice(text.as_bytes());
+    tag.push(0);
+    tag
+}
+
+fn description(text: &str) -> Vec<u8> {
+    let mut tag = b"desc\0\0\0\0".to_vec();
+    tag.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+    tag.extend_from_slice(text.as_bytes());
+    tag.push(0);
+    // No Unicode or ScriptCode description
+    tag.extend_from_slice(&[0; 8]);
+    tag.extend_from_slice(&[0; 3]);
+    tag.extend_from_slice(&[0; 67]);
+    tag
+}
+
+fn curve() -> Vec<u8> {
+    const POINTS: u32 = 1024;
+    let mut tag = b"curv\0\0\0\0".to_vec();
+    tag.extend_from_slice(&POINTS.to_be_bytes());
+    for i in 0..POINTS {
+        let v = f64::from(i) / f64::from(POINTS - 1);
+        let linear = if v <= 0.04045 {
+            v / 12.92
+        } else {
+            ((v + 0.055) / 1.055).powf(2.4)
+        };
+        tag.extend_from_slice(&((linear * 65535.0).round() as u16).to_be_bytes());
+    }
+    tag
+}
+
+pub const DESCRIPTION: &str = "sRGB IEC61966-2.1";
+
+pub fn srgb() -> Vec<u8> {
+    let curve = curve();
+    let tags: [(&[u8; 4], Vec<u8>); 9] = [
+        (b"desc", description(DESCRIPTION)),
+        (b"cprt", text("No copyright, use freely")),
+        (b"wtpt", xyz(0.9505, 1.0, 1.0891)),
+        (b"rXYZ", xyz(0.4361, 0.2225, 0.0139)),
+        (b"gXYZ", xyz(0.3851, 0.7169, 0.0971)),
+        (b"bXYZ", xyz(0.1431, 0.0606, 0.7141)),
+        (b"rTRC", curve.clone()),
+        (b"gTRC", curve.clone()),
+        (b"bTRC", curve),
+    ];
+
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    let mut data = Vec::new();
+    let start = 128 + 4 + 12 * tags.len();
+    for (signature, tag) in &tags {
+        table.extend_from_slice(*signature);
+        table.extend_from_slice(&((start + data.len()) as u32).to_be_bytes());
+        table.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+        data.extend_from_slice(tag);
+        // Tags start on four bytes
+        data.resize(data.len().next_multiple_of(4), 0);
+    }
+
+    let mut profile = Vec::with_capacity(start + data.len());
+    profile.extend_from_slice(&((start + data.len()) as u32).to_be_bytes());
+    profile.extend_from_slice(&[0; 4]);
+    // Version 2.1
+    profile.extend_from_slice(&[2, 0x10, 0, 0]);
+    profile.extend_from_slice(b"mntrRGB XYZ ");
+    // 2024-01-01 00:00:00
+    for field in [2024u16, 1, 1, 0, 0, 0] {
+        profile.extend_from_slice(&field.to_be_bytes());
+    }
+    profile.extend_from_slice(b"acsp");
+    // Platform, flags, manufacturer, model, attributes and intent
+    profile.extend_from_slice(&[0; 28]);
+    // The D50 illuminant of the connection space
+    profile.extend_from_slice(&xyz(0.9642, 1.0, 0.8249)[8..]);
+    profile.resize(128, 0);
+    profile.extend_from_slice(&table);
+    profile.extend_from_slice(&data);
+    profile
+}
@@ -0,0 +1,269 @@
+//! Custom stages processing scanned pages
+//!
+//! A [`ProcessingStage`] takes a page and returns it changed, adding to the
+//! metadata of the page what it found, such as the text of OCR. Stages are
+//! registered by name in a [`Registry`], from which a [`Pipeline`] is put
+//! together from the stages in the configuration. A stage is given by name,
+//! or as a table with its setting:
+//!
+//! ```toml
+//! pipeline = ["deskew", { threshold = "otsu" }, "despeckle"]
+//! ```
+//!
+//! ```
+//! use skanny::pipeline::{Page, Registry, StageError};
+//!
+//! let mut registry = Registry::default();
+//! registry.register("checked", |_setting| {
+//!     Ok(Box::new(|mut page: Page| -> Result<Page, StageError> {
+//!         page.metadata.insert("checked".to_owned(), "yes".to_owned());
+//!         Ok(page)
+//!     }))
+//! });
+//! let pipeline = registry.pipeline(&["checked".parse().unwrap()]).unwrap();
+//! ```
+//!
+//! With the `plugins` feature, stages are also registered by shared
+//! libraries exporting
+//!
+//! ```ignore
+//! #[no_mangle]
+//! pub fn skanny_register(registry: &mut skanny::pipeline::Registry) {
+//!     registry.register("ocr", |language| Ok(Box::new(Ocr::new(language)?)));
+//! }
+//! ```
+//!
+//! There is no stable ABI for Rust, so plugins must be built with the same
+//! compiler and version of skanny.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+use crate::Image;
+
+/// What stages found out about a page, by key
+pub type Metadata = BTreeMap<String, String>;
+
+pub type StageError = Box<dyn std::error::Error>;
+
+pub struct Page {
+    pub image: Image,
+    pub metadata: Metadata,
+}
+
+impl From<Image> for Page {
+    fn from(image: Image) -> Self {
+        Self {
+            image,
+            metadata: Metadata::new(),
+        }
+    }
+}
+
+pub trait ProcessingStage: Send + Sync {
+    fn process(&self, page: Page) -> Result<Page, StageError>;
+}
+
+impl<F> ProcessingStage for F
+where
+    F: Fn(Page) -> Result<Page, StageError> + Send + Sync,
+{
+    fn process(&self, page: Page) -> Result<Page, StageError> {
+        self(page)
+    }
+}
+
+/// A stage in the configuration, by name or as `{ name = setting }`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum StageSpec {
+    Name(String),
+    Configured(BTreeMap<String, toml::Value>),
+}
+
+impl StageSpec {
+    /// The name of the stage and its setting, if it has one
+    fn parts(&self) -> Result<(&str, Option<&toml::Value>), StageError> {
+        match self {
+            StageSpec::Name(name) => Ok((name, None)),
+            StageSpec::Configured(table) if table.len() == 1 => {
+                let (name, setting) = table.iter().next().unwrap();
+                Ok((name, Some(setting)))
+            }
+            StageSpec::Configured(table) => Err(format!(
+                "A stage is given as {{ name = setting }}, not {}",
+                toml::Value::Table(table.clone().into_iter().collect())
+            )
+            .into()),
+        }
+    }
+}
+
+/// Parses `name` or `name=setting`, as in `threshold=128`
+impl std::str::FromStr for StageSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, setting) = match s.split_once('=') {
+            Some(parts) => parts,
+            None => return Ok(StageSpec::Name(s.to_owned())),
+        };
+        let setting = if let Ok(integer) = setting.parse() {
+            toml::Value::Integer(integer)
+        } else if let Ok(float) = setting.parse() {
+            toml::Value::Float(float)
+        } else if let Ok(boolean) = setting.parse() {
+            toml::Value::Boolean(boolean)
+        } else {
+            toml::Value::String(setting.to_owned())
+        };
+        Ok(StageSpec::Configured(
+            std::iter::once((name.to_owned(), setting)).collect(),
+        ))
+    }
+}
+
+/// Creates a stage from its setting
+type Factory =
+    Box<dyn Fn(Option<&toml::Value>) -> Result<Box<dyn ProcessingStage>, StageError> + Send + Sync>;
+
+/// Stages which can be used by name
+#[derive(Default)]
+pub struct Registry {
+    factories: BTreeMap<String, Factory>,
+}
+
+impl Registry {
+    /// Registers a stage, replacing any other of the same name
+    pub fn register(
+        &mut self,
+        name: &str,
+        factory: impl Fn(Option<&toml::Value>) -> Result<Box<dyn ProcessingStage>, StageError>
+            + Send
+            + Sync
+            + 'static,
+    ) {
+        self.factories.insert(name.to_owned(), Box::new(factory));
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+
+    /// Creates the stages of `specs`, in order
+    pub fn pipeline(&self, specs: &[StageSpec]) -> Result<Pipeline, StageError> {
+        let mut stages = Vec::new();
+        for spec in specs {
+            let (name, setting) = spec.parts()?;
+            let factory = self.factories.get(name).ok_or_else(|| {
+                format!(
+                    "Unknown stage {}, there are {}",
+                    name,
+                    self.names().collect::<Vec<_>>().join(", ")
+                )
+            })?;
+            let stage = factory(setting).map_err(|e| format!("Stage {}: {}", name, e))?;
+            stages.push((name.to_owned(), stage));
+        }
+        Ok(Pipeline(stages))
+    }
+
+    /// Lets the plugin at `path` register its stages
+    ///
+    /// # Safety
+    ///
+    /// The plugin runs arbitrary code when loaded and must be built against
+    /// this version of skanny with the same compiler. It stays loaded for
+    /// the rest of the process.
+    #[cfg(feature = "plugins")]
+    pub unsafe fn load(&mut self, path: &std::path::Path) -> Result<(), StageError> {
+        let library = libloading::Library::new(path)?;
+        let register: libloading::Symbol<fn(&mut Registry)> = library.get(b"skanny_register")?;
+        register(self);
+        // The stages are code of the library
+        std::mem::forget(library);
+        Ok(())
+    }
+}
+
+/// Stages run one after another on every page
+#[derive(Default)]
+pub struct Pipeline(Vec<(String, Box<dyn ProcessingStage>)>);
+
+impl Pipeline {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn run(&self, mut page: Page) -> Result<Page, StageError> {
+        for (name, stage) in &self.0 {
+            tracing::debug!("Running stage {}", name);
+            page = stage
+                .process(page)
+                .map_err(|e| format!("Stage {} failed: {}", name, e))?;
+        }
+        Ok(page)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_stages_in_order() {
+        let mut registry = Registry::default();
+        registry.register("invert", |_| {
+            Ok(Box::new(|mut page: Page| -> Result<Page, StageError> {
+                if let Image::Gray8(buffer) = &mut page.image {
+                    buffer.pixels_mut().for_each(|pixel| pixel[0] = !pixel[0]);
+                }
+                Ok(page)
+            }))
+        });
+        registry.register("add", |setting| {
+            let add = setting
+                .and_then(toml::Value::as_integer)
+                .ok_or("expected a number")? as u8;
+            Ok(Box::new(
+                move |mut page: Page| -> Result<Page, StageError> {
+                    if let Image::Gray8(buffer) = &mut page.image {
+                        buffer.pixels_mut().for_each(|pixel| pixel[0] += add);
+                        let value = buffer.get_pixel(0, 0)[0].to_string();
+                        page.metadata.insert("value".to_owned(), value);
+                    }
+                    Ok(page)
+                },
+            ))
+        });
+        registry.register("fail", |_| {
+            Ok(Box::new(|_| -> Result<Page, StageError> {
+                Err("no paper".into())
+            }))
+        });
+
+        let image = || Image::Gray8(image::ImageBuffer::from_pixel(2, 2, image::Luma([10])));
+        let specs: Vec<StageSpec> = toml::from_str::<BTreeMap<String, Vec<StageSpec>>>(
+            "pipeline = [\"invert\", { add = 5 }]",
+        )
+        .unwrap()
+        .remove("pipeline")
+        .unwrap();
+        assert_eq!(specs[1], "add=5".parse().unwrap());
+        let page = registry
+            .pipeline(&specs)
+            .unwrap()
+            .run(image().into())
+            .unwrap();
+        assert_eq!(page.metadata["value"], "250");
+        assert!(registry.pipeline(&["add".parse().unwrap()]).is_err());
+
+        let pipeline = registry.pipeline(&["fail".parse().unwrap()]).unwrap();
+        match pipeline.run(image().into()) {
+            Err(e) => assert_eq!(e.to_string(), "Stage fail failed: no paper"),
+            Ok(_) => panic!("the stage should fail"),
+        }
+        assert!(registry.pipeline(&["ocr".parse().unwrap()]).is_err());
+    }
+}
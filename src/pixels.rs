@@ -0,0 +1,311 @@
+//! Conversions of samples on the streaming path
+//!
+//! At 600 dpi and more, converting the samples SANE sends into those of PNG
+//! and [`crate::Image`] takes much of the time of a scan. The conversions
+//! use SSE2 on x86_64, AVX2 where the processor has it, and NEON on
+//! aarch64. Plain loops handle the remainder and other processors.
+
+/// Flips every bit, as SANE uses 1 for black and PNG for white
+pub fn invert(bytes: &mut [u8]) {
+    let done = simd::invert(bytes);
+    bytes[done..].iter_mut().for_each(|byte| *byte = !*byte);
+}
+
+/// Turns 16 bit samples in the byte order of the host, as SANE sends them,
+/// into big endian ones as PNG wants them
+pub fn to_big_endian16(bytes: &mut [u8]) {
+    if cfg!(target_endian = "big") {
+        return;
+    }
+    let done = simd::swap16(bytes);
+    bytes[done..]
+        .chunks_exact_mut(2)
+        .for_each(|sample| sample.swap(0, 1));
+}
+
+/// Unpacks a line of 1 bit samples into `out`, black as 0 and white as 255,
+/// with as many pixels as `out` is long
+///
+/// # Panics
+///
+/// If `packed` has fewer than `out.len()` bits.
+pub fn unpack_bits(packed: &[u8], out: &mut [u8]) {
+    assert!(packed.len() * 8 >= out.len(), "Too few bits for the line");
+    let done = simd::unpack_bits(packed, out);
+    for (i, pixel) in out.iter_mut().enumerate().skip(done) {
+        let bit = packed[i / 8] & (0x80 >> (i % 8));
+        *pixel = if bit == 0 { 255 } else { 0 };
+    }
+}
+
+/// Interleaves the planes of a three-pass scan into RGB samples
+///
+/// # Panics
+///
+/// If the planes differ in length, or `out` does not fit them.
+pub fn interleave(red: &[u8], green: &[u8], blue: &[u8], out: &mut [u8]) {
+    assert!(red.len() == green.len() && red.len() == blue.len());
+    assert_eq!(
+        out.len(),
+        red.len() * 3,
+        "The output does not fit the planes"
+    );
+    let done = simd::interleave(red, green, blue, out);
+    for (i, pixel) in out.chunks_exact_mut(3).enumerate().skip(done) {
+        pixel.copy_from_slice(&[red[i], green[i], blue[i]]);
+    }
+}
+
+/// Makes gray samples from `level` up white and the others black
+pub fn threshold(gray: &mut [u8], level: u8) {
+    let done = simd::threshold(gray, level);
+    gray[done..]
+        .iter_mut()
+        .for_each(|sample| *sample = if *sample >= level { 255 } else { 0 });
+}
+
+/// Each function converts as many samples as fill whole vectors and
+/// returns how many bytes or pixels it did
+#[cfg(target_arch = "x86_64")]
+mod simd {
+    use std::arch::x86_64::*;
+
+    pub fn invert(bytes: &mut [u8]) -> usize {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { invert_avx2(bytes) };
+        }
+        for chunk in bytes.chunks_exact_mut(16) {
+            unsafe {
+                let ptr = chunk.as_mut_ptr() as *mut __m128i;
+                let value = _mm_loadu_si128(ptr);
+                _mm_storeu_si128(ptr, _mm_xor_si128(value, _mm_set1_epi8(-1)));
+            }
+        }
+        bytes.len() / 16 * 16
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn invert_avx2(bytes: &mut [u8]) -> usize {
+        for chunk in bytes.chunks_exact_mut(32) {
+            let ptr = chunk.as_mut_ptr() as *mut __m256i;
+            let value = _mm256_loadu_si256(ptr);
+            _mm256_storeu_si256(ptr, _mm256_xor_si256(value, _mm256_set1_epi8(-1)));
+        }
+        bytes.len() / 32 * 32
+    }
+
+    pub fn swap16(bytes: &mut [u8]) -> usize {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { swap16_avx2(bytes) };
+        }
+        for chunk in bytes.chunks_exact_mut(16) {
+            unsafe {
+                let ptr = chunk.as_mut_ptr() as *mut __m128i;
+                let value = _mm_loadu_si128(ptr);
+                let swapped = _mm_or_si128(_mm_slli_epi16(value, 8), _mm_srli_epi16(value, 8));
+                _mm_storeu_si128(ptr, swapped);
+            }
+        }
+        bytes.len() / 16 * 16
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn swap16_avx2(bytes: &mut [u8]) -> usize {
+        for chunk in bytes.chunks_exact_mut(32) {
+            let ptr = chunk.as_mut_ptr() as *mut __m256i;
+            let value = _mm256_loadu_si256(ptr);
+            let swapped = _mm256_or_si256(_mm256_slli_epi16(value, 8), _mm256_srli_epi16(value, 8));
+            _mm256_storeu_si256(ptr, swapped);
+        }
+        bytes.len() / 32 * 32
+    }
+
+    /// Unpacks two bytes into 16 pixels at a time
+    pub fn unpack_bits(packed: &[u8], out: &mut [u8]) -> usize {
+        let mut done = 0;
+        for (bytes, pixels) in packed.chunks_exact(2).zip(out.chunks_exact_mut(16)) {
+            unsafe {
+                let spread = |byte: u8| (u64::from(byte) * 0x0101_0101_0101_0101) as i64;
+                let value = _mm_set_epi64x(spread(bytes[1]), spread(bytes[0]));
+                let bits = _mm_set_epi64x(0x0102_0408_1020_4080, 0x0102_0408_1020_4080);
+                // All ones where the bit is set, for black
+                let black = _mm_cmpeq_epi8(_mm_and_si128(value, bits), bits);
+                _mm_storeu_si128(
+                    pixels.as_mut_ptr() as *mut __m128i,
+                    _mm_xor_si128(black, _mm_set1_epi8(-1)),
+                );
+            }
+            done += 16;
+        }
+        done
+    }
+
+    /// Three planes have no simple shuffle in SSE2, the plain loop is as
+    /// fast
+    pub fn interleave(_red: &[u8], _green: &[u8], _blue: &[u8], _out: &mut [u8]) -> usize {
+        0
+    }
+
+    pub fn threshold(gray: &mut [u8], level: u8) -> usize {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { threshold_avx2(gray, level) };
+        }
+        for chunk in gray.chunks_exact_mut(16) {
+            unsafe {
+                let ptr = chunk.as_mut_ptr() as *mut __m128i;
+                let value = _mm_loadu_si128(ptr);
+                let level = _mm_set1_epi8(level as i8);
+                // Unsigned value >= level where the maximum is the value
+                _mm_storeu_si128(ptr, _mm_cmpeq_epi8(_mm_max_epu8(value, level), value));
+            }
+        }
+        gray.len() / 16 * 16
+    }
+
+    #[target_feature(enable = "avx2")]
+    unsafe fn threshold_avx2(gray: &mut [u8], level: u8) -> usize {
+        for chunk in gray.chunks_exact_mut(32) {
+            let ptr = chunk.as_mut_ptr() as *mut __m256i;
+            let value = _mm256_loadu_si256(ptr);
+            let level = _mm256_set1_epi8(level as i8);
+            _mm256_storeu_si256(ptr, _mm256_cmpeq_epi8(_mm256_max_epu8(value, level), value));
+        }
+        gray.len() / 32 * 32
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod simd {
+    use std::arch::aarch64::*;
+
+    pub fn invert(bytes: &mut [u8]) -> usize {
+        for chunk in bytes.chunks_exact_mut(16) {
+            unsafe { vst1q_u8(chunk.as_mut_ptr(), vmvnq_u8(vld1q_u8(chunk.as_ptr()))) };
+        }
+        bytes.len() / 16 * 16
+    }
+
+    pub fn swap16(bytes: &mut [u8]) -> usize {
+        for chunk in bytes.chunks_exact_mut(16) {
+            unsafe { vst1q_u8(chunk.as_mut_ptr(), vrev16q_u8(vld1q_u8(chunk.as_ptr()))) };
+        }
+        bytes.len() / 16 * 16
+    }
+
+    pub fn unpack_bits(packed: &[u8], out: &mut [u8]) -> usize {
+        const BITS: [u8; 16] = [
+            0x80, 0x40, 0x20, 0x10, 0x08, 0x04, 0x02, 0x01, 0x80, 0x40, 0x20, 0x10, 0x08, 0x04,
+            0x02, 0x01,
+        ];
+        let mut done = 0;
+        for (bytes, pixels) in packed.chunks_exact(2).zip(out.chunks_exact_mut(16)) {
+            unsafe {
+                let value = vcombine_u8(vdup_n_u8(bytes[0]), vdup_n_u8(bytes[1]));
+                // All ones where the bit is set, for black
+                let black = vtstq_u8(value, vld1q_u8(BITS.as_ptr()));
+                vst1q_u8(pixels.as_mut_ptr(), vmvnq_u8(black));
+            }
+            done += 16;
+        }
+        done
+    }
+
+    pub fn interleave(red: &[u8], green: &[u8], blue: &[u8], out: &mut [u8]) -> usize {
+        let mut done = 0;
+        let planes = red
+            .chunks_exact(16)
+            .zip(green.chunks_exact(16))
+            .zip(blue.chunks_exact(16));
+        for (((red, green), blue), pixels) in planes.zip(out.chunks_exact_mut(48)) {
+            unsafe {
+                let rgb = uint8x16x3_t(
+                    vld1q_u8(red.as_ptr()),
+                    vld1q_u8(green.as_ptr()),
+                    vld1q_u8(blue.as_ptr()),
+                );
+                vst3q_u8(pixels.as_mut_ptr(), rgb);
+            }
+            done += 16;
+        }
+        done
+    }
+
+    pub fn threshold(gray: &mut [u8], level: u8) -> usize {
+        for chunk in gray.chunks_exact_mut(16) {
+            unsafe {
+                let value = vld1q_u8(chunk.as_ptr());
+                vst1q_u8(chunk.as_mut_ptr(), vcgeq_u8(value, vdupq_n_u8(level)));
+            }
+        }
+        gray.len() / 16 * 16
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod simd {
+    pub fn invert(_bytes: &mut [u8]) -> usize {
+        0
+    }
+
+    pub fn swap16(_bytes: &mut [u8]) -> usize {
+        0
+    }
+
+    pub fn unpack_bits(_packed: &[u8], _out: &mut [u8]) -> usize {
+        0
+    }
+
+    pub fn interleave(_red: &[u8], _green: &[u8], _blue: &[u8], _out: &mut [u8]) -> usize {
+        0
+    }
+
+    pub fn threshold(_gray: &mut [u8], _level: u8) -> usize {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_plain_loops() {
+        // Not a multiple of any vector width, so the remainder is used too
+        let samples: Vec<u8> = (0..203u32).map(|i| (i * 37 % 251) as u8).collect();
+
+        let mut inverted = samples.clone();
+        invert(&mut inverted);
+        assert!(inverted.iter().zip(&samples).all(|(a, b)| *a == !*b));
+
+        let mut swapped = samples.clone();
+        to_big_endian16(&mut swapped[..202]);
+        for (sample, original) in swapped[..202].chunks(2).zip(samples.chunks(2)) {
+            let value = u16::from_ne_bytes([original[0], original[1]]);
+            assert_eq!(sample, value.to_be_bytes());
+        }
+
+        let mut pixels = vec![0; 203];
+        unpack_bits(&samples[..26], &mut pixels);
+        for (i, pixel) in pixels.iter().enumerate() {
+            let black = samples[i / 8] << (i % 8) & 0x80 != 0;
+            assert_eq!(*pixel, if black { 0 } else { 255 }, "pixel {}", i);
+        }
+
+        let mut rgb = vec![0; 3 * 67];
+        interleave(
+            &samples[..67],
+            &samples[67..134],
+            &samples[134..201],
+            &mut rgb,
+        );
+        for (i, pixel) in rgb.chunks(3).enumerate() {
+            assert_eq!(pixel, [samples[i], samples[67 + i], samples[134 + i]]);
+        }
+
+        let mut binary = samples.clone();
+        threshold(&mut binary, 128);
+        for (sample, original) in binary.iter().zip(&samples) {
+            assert_eq!(*sample, if *original >= 128 { 255 } else { 0 });
+        }
+    }
+}
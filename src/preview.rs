@@ -0,0 +1,314 @@
+//! Window showing pages as they are scanned
+//!
+//! `--preview` draws the lines of a page as they are read, so a misaligned
+//! page can be cancelled within seconds instead of after the whole scan.
+//! Closing the window or pressing Escape cancels the page, keeping the
+//! lines read as with other cancelled pages. The window opens again for the
+//! next page.
+//!
+//! Pages are drawn at most [`SIZE`] pixels high and wide. Frames of formats
+//! such as JPEG cannot be drawn before they are complete and leave the
+//! window blank. Needs the `gui` feature.
+
+use skanny::backend::{BackendError, ScannerDevice};
+
+#[cfg(feature = "gui")]
+use sane_sys::*;
+#[cfg(feature = "gui")]
+use skanny::backend::{FrameParameters, OptionInfo};
+#[cfg(feature = "gui")]
+use skanny::OptionValue;
+#[cfg(feature = "gui")]
+use std::cell::RefCell;
+#[cfg(feature = "gui")]
+use std::time::{Duration, Instant};
+
+/// Largest side of the drawn page in pixels
+#[cfg(feature = "gui")]
+const SIZE: usize = 800;
+/// How often the window is drawn while scanning
+#[cfg(feature = "gui")]
+const FRAME: Duration = Duration::from_millis(50);
+
+/// The page drawn from the lines read so far
+#[cfg(feature = "gui")]
+#[derive(Debug)]
+struct Canvas {
+    parameters: FrameParameters,
+    /// Lines and pixels of the page drawn for each pixel of the canvas
+    step: usize,
+    width: usize,
+    /// 0RGB pixels
+    pixels: Vec<u32>,
+    /// Samples of the line being read
+    line: Vec<u8>,
+    lines: usize,
+}
+
+#[cfg(feature = "gui")]
+impl Canvas {
+    /// A canvas for a page, or for the next channel of a page scanned one
+    /// channel at a time
+    fn new(parameters: FrameParameters, previous: Option<Canvas>) -> Self {
+        let pixels_per_line = parameters.pixels_per_line.max(1) as usize;
+        let lines = parameters.lines.max(0) as usize;
+        let step = pixels_per_line.max(lines).div_ceil(SIZE);
+        let width = pixels_per_line.div_ceil(step);
+        let pixels = match previous {
+            Some(canvas)
+                if canvas.width == width
+                    && parameters.format != SANE_Frame_SANE_FRAME_RED
+                    && is_channel(parameters.format) =>
+            {
+                canvas.pixels
+            }
+            _ => vec![0; width * lines.div_ceil(step).max(1)],
+        };
+        Self {
+            parameters,
+            step,
+            width,
+            pixels,
+            line: Vec::new(),
+            lines: 0,
+        }
+    }
+
+    /// Lines of the canvas
+    fn height(&self) -> usize {
+        self.pixels.len() / self.width
+    }
+
+    /// Draws the lines completed by `data`
+    fn extend(&mut self, mut data: &[u8]) {
+        let bytes_per_line = self.parameters.bytes_per_line.max(1) as usize;
+        while !data.is_empty() {
+            let taken = data.len().min(bytes_per_line - self.line.len());
+            self.line.extend_from_slice(&data[..taken]);
+            data = &data[taken..];
+            if self.line.len() == bytes_per_line {
+                if self.lines.is_multiple_of(self.step) {
+                    self.draw_line(self.lines / self.step);
+                }
+                self.line.clear();
+                self.lines += 1;
+            }
+        }
+    }
+
+    #[allow(non_upper_case_globals)]
+    fn draw_line(&mut self, y: usize) {
+        if self.pixels.len() < (y + 1) * self.width {
+            // Hand scanners send lines until the page ends
+            self.pixels.resize((y + 1) * self.width, 0);
+        }
+        let (line, depth, step) = (&self.line, self.parameters.depth, self.step);
+        let row = &mut self.pixels[y * self.width..(y + 1) * self.width];
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let index = x * step;
+            let value = |index| u32::from(sample(line, depth, index));
+            *pixel = match self.parameters.format {
+                SANE_Frame_SANE_FRAME_GRAY => value(index) * 0x010101,
+                SANE_Frame_SANE_FRAME_RGB => {
+                    value(3 * index) << 16 | value(3 * index + 1) << 8 | value(3 * index + 2)
+                }
+                SANE_Frame_SANE_FRAME_RED => *pixel & 0x00ffff | value(index) << 16,
+                SANE_Frame_SANE_FRAME_GREEN => *pixel & 0xff00ff | value(index) << 8,
+                SANE_Frame_SANE_FRAME_BLUE => *pixel & 0xffff00 | value(index),
+                _ => return,
+            };
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+#[allow(non_upper_case_globals)]
+fn is_channel(format: SANE_Frame) -> bool {
+    matches!(
+        format,
+        SANE_Frame_SANE_FRAME_RED | SANE_Frame_SANE_FRAME_GREEN | SANE_Frame_SANE_FRAME_BLUE
+    )
+}
+
+/// Sample `index` of a line, scaled to 8 bits
+#[cfg(feature = "gui")]
+fn sample(line: &[u8], depth: SANE_Int, index: usize) -> u8 {
+    match depth {
+        1 if line[index / 8] & (0x80 >> (index % 8)) != 0 => 0,
+        1 => 255,
+        16 => (u16::from_ne_bytes([line[2 * index], line[2 * index + 1]]) >> 8) as u8,
+        _ => line[index],
+    }
+}
+
+#[cfg(feature = "gui")]
+struct State {
+    window: Option<minifb::Window>,
+    canvas: Option<Canvas>,
+    drawn: Option<Instant>,
+}
+
+/// A device showing its pages in a window as they are read
+#[cfg(feature = "gui")]
+pub struct Preview {
+    inner: Box<dyn ScannerDevice>,
+    state: RefCell<State>,
+}
+
+#[cfg(feature = "gui")]
+impl Preview {
+    fn draw(&self, state: &mut State) {
+        state.drawn = Some(Instant::now());
+        if let (Some(window), Some(canvas)) = (&mut state.window, &state.canvas) {
+            if let Err(e) = window.update_with_buffer(&canvas.pixels, canvas.width, canvas.height())
+            {
+                tracing::warn!("Drawing the preview failed: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "gui")]
+impl ScannerDevice for Preview {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn options(&self) -> Result<Vec<OptionInfo>, BackendError> {
+        self.inner.options()
+    }
+
+    fn set_option(&self, name: &str, value: &OptionValue) -> Result<(), BackendError> {
+        self.inner.set_option(name, value)
+    }
+
+    fn start(&self) -> Result<FrameParameters, BackendError> {
+        let parameters = self.inner.start()?;
+        let mut state = self.state.borrow_mut();
+        let canvas = Canvas::new(parameters, state.canvas.take());
+        if !state.window.as_ref().is_some_and(minifb::Window::is_open) {
+            let options = minifb::WindowOptions {
+                resize: true,
+                scale_mode: minifb::ScaleMode::AspectRatioStretch,
+                ..minifb::WindowOptions::default()
+            };
+            // A page of unknown length is shown as tall as A4 until it ends
+            let height = match parameters.lines {
+                lines if lines > 0 => canvas.height(),
+                _ => canvas.width * 297 / 210,
+            };
+            let title = format!("{} - Escape cancels the page", self.inner.name());
+            let mut window = minifb::Window::new(&title, canvas.width, height, options)?;
+            window.set_target_fps(0);
+            state.window = Some(window);
+        }
+        state.canvas = Some(canvas);
+        self.draw(&mut state);
+        Ok(parameters)
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, BackendError> {
+        let mut state = self.state.borrow_mut();
+        let cancelled = state
+            .window
+            .as_ref()
+            .is_some_and(|window| !window.is_open() || window.is_key_down(minifb::Key::Escape));
+        if cancelled {
+            state.window = None;
+            return Err(skanny::Error::Status(SANE_Status_SANE_STATUS_CANCELLED).into());
+        }
+        let read = self.inner.read(buffer)?;
+        if let Some(canvas) = &mut state.canvas {
+            canvas.extend(&buffer[..read]);
+        }
+        if read == 0
+            || state
+                .drawn
+                .filter(|drawn| drawn.elapsed() < FRAME)
+                .is_none()
+        {
+            self.draw(&mut state);
+        }
+        Ok(read)
+    }
+
+    fn cancel(&self) {
+        self.inner.cancel()
+    }
+}
+
+/// Shows the pages of `device` in a window as they are read
+#[cfg(feature = "gui")]
+pub fn wrap(device: Box<dyn ScannerDevice>) -> Result<Box<dyn ScannerDevice>, BackendError> {
+    Ok(Box::new(Preview {
+        inner: device,
+        state: RefCell::new(State {
+            window: None,
+            canvas: None,
+            drawn: None,
+        }),
+    }))
+}
+
+#[cfg(not(feature = "gui"))]
+pub fn wrap(_device: Box<dyn ScannerDevice>) -> Result<Box<dyn ScannerDevice>, BackendError> {
+    Err("skanny was built without the gui feature".into())
+}
+
+#[cfg(all(test, feature = "gui"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_lines_as_they_are_read() {
+        let parameters = FrameParameters {
+            format: SANE_Frame_SANE_FRAME_RGB,
+            last_frame: true,
+            bytes_per_line: 3 * 1600,
+            pixels_per_line: 1600,
+            lines: 10,
+            depth: 8,
+        };
+        let mut canvas = Canvas::new(parameters, None);
+        // Every second pixel of every second line
+        assert_eq!((canvas.width, canvas.height()), (800, 5));
+        let line: Vec<u8> = (0..1600).flat_map(|x| [x as u8, 0x80, 0xff]).collect();
+        canvas.extend(&line[..1000]);
+        assert_eq!(canvas.pixels[0], 0);
+        canvas.extend(&line[1000..]);
+        canvas.extend(&line);
+        assert_eq!(canvas.pixels[1], 0x0280ff);
+        assert_eq!(canvas.pixels[800], 0);
+
+        let gray = FrameParameters {
+            format: SANE_Frame_SANE_FRAME_GRAY,
+            bytes_per_line: 1,
+            pixels_per_line: 8,
+            lines: -1,
+            depth: 1,
+            ..parameters
+        };
+        let mut canvas = Canvas::new(gray, None);
+        canvas.extend(&[0b1000_0000, 0, 0]);
+        assert_eq!(canvas.height(), 3);
+        assert_eq!(&canvas.pixels[..2], &[0, 0xffffff]);
+
+        let red = FrameParameters {
+            format: SANE_Frame_SANE_FRAME_RED,
+            bytes_per_line: 2,
+            pixels_per_line: 2,
+            lines: 1,
+            depth: 8,
+            ..parameters
+        };
+        let mut canvas = Canvas::new(red, None);
+        canvas.extend(&[0x10, 0x20]);
+        let blue = FrameParameters {
+            format: SANE_Frame_SANE_FRAME_BLUE,
+            ..red
+        };
+        let mut canvas = Canvas::new(blue, Some(canvas));
+        canvas.extend(&[0x30, 0x40]);
+        assert_eq!(canvas.pixels, [0x100030, 0x200040]);
+    }
+}
@@ -0,0 +1,119 @@
+//! Named scan settings loaded from a TOML file
+//!
+//! ```toml
+//! [color]
+//! dir = "scans/color"
+//! dest = ["s3://archive/scans/"]
+//! options = { mode = "Color", resolution = 300 }
+//! pipeline = ["deskew", { dropout = "red" }]
+//! ```
+//!
+//! The `pipeline` is run on every page, see [`crate::stages`].
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use skanny::backend::{BackendError, ScannerDevice};
+use skanny::pipeline::StageSpec;
+use skanny::{Image, OptionValue};
+
+/// Outcome of [`Profile::scan_image`]
+pub struct Scan {
+    /// Where the image was stored locally
+    pub path: PathBuf,
+    pub image: Image,
+    /// Locations returned by the destinations the image was uploaded to
+    pub locations: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Profile {
+    /// Options to set before scanning, keyed by option name
+    #[serde(default)]
+    pub options: BTreeMap<String, OptionValue>,
+    /// Directory to store images in, which may contain dates, see
+    /// [`crate::template`]
+    pub dir: Option<PathBuf>,
+    /// Destinations to upload the images to, see [`crate::destination`]
+    #[serde(default)]
+    pub dest: Vec<String>,
+    /// Processing stages to run on each page, in order
+    #[serde(default)]
+    pub pipeline: Vec<StageSpec>,
+    /// Options given with the request, which override the environment, see
+    /// [`crate::environment`]
+    #[serde(skip)]
+    pub requested: BTreeMap<String, OptionValue>,
+}
+
+impl Profile {
+    /// Applies the options in the order the device lists them,
+    /// as setting one option may change the constraints of the next
+    pub fn apply(&self, device: &dyn ScannerDevice) -> Result<(), BackendError> {
+        let environment = crate::environment::variables();
+        for option in device.options()? {
+            let value = match self.requested.get(&option.name) {
+                Some(value) => Some(value.clone()),
+                None => crate::environment::value(&environment, &option)?
+                    .or_else(|| self.options.get(&option.name).cloned()),
+            };
+            if let Some(value) = value {
+                device.set_option(&option.name, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the profile and stores a single scan in its directory
+    pub fn scan(&self, device: &dyn ScannerDevice) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        self.scan_image(device).map(|scan| scan.path)
+    }
+
+    /// As [`Profile::scan`], but also returns the image that was stored
+    /// and where it was uploaded to
+    pub fn scan_image(
+        &self,
+        device: &dyn ScannerDevice,
+    ) -> Result<Scan, Box<dyn std::error::Error>> {
+        self.apply(device)?;
+
+        let dir = self.dir.as_deref().unwrap_or_else(|| Path::new("."));
+        let image = device.scan()?;
+        let dir = crate::template::directory(dir, &crate::template::DateTime::now());
+        let imagepath = crate::timestamped_path(&dir);
+        let image = self.save(image, &imagepath)?;
+        let locations = crate::destination::store_all(&self.dest, &imagepath)?;
+        Ok(Scan {
+            path: imagepath,
+            image,
+            locations,
+        })
+    }
+
+    /// Runs the pipeline on a page and saves it, returning the processed
+    /// image
+    pub fn save(&self, image: Image, path: &Path) -> Result<Image, Box<dyn std::error::Error>> {
+        let pipeline = crate::stages::registry().pipeline(&self.pipeline)?;
+        crate::stages::save(&pipeline, image, path)
+    }
+}
+
+/// Saves an image, creating the directory it goes in
+pub fn save(image: &Image, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    crate::output::write(path, |temporary| {
+        if path.extension().and_then(|ext| ext.to_str()) == Some("pdf") {
+            return crate::pdf::save(image, temporary);
+        }
+        Ok(image.save(temporary)?)
+    })
+}
+
+pub fn load(path: &Path) -> Result<BTreeMap<String, Profile>, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(toml::from_str(&contents)?)
+}
@@ -0,0 +1,393 @@
+//! Recording of device sessions, and a backend replaying them
+//!
+//! [`Recorder`] wraps any [`ScannerDevice`] and writes every call and its
+//! result to a file, one JSON object per line. [`ReplayBackend`] serves such
+//! a file back, so a session with a real scanner can be turned into a test
+//! fixture. Replaying expects the calls in the recorded order, except that
+//! options may be listed and scans cancelled more often than recorded.
+//!
+//! ```text
+//! {"call":"open","name":"plustek:libusb:001:004"}
+//! {"call":"start","result":{"Ok":{"format":1,"last_frame":true,...}}}
+//! {"call":"read","result":{"Ok":"00ff10..."}}
+//! ```
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use sane_sys::SANE_Status;
+use serde::{Deserialize, Serialize};
+
+use crate::backend::{
+    BackendError, DeviceInfo, FrameParameters, OptionInfo, ScannerBackend, ScannerDevice,
+};
+use crate::OptionValue;
+
+/// A call made to the device and what it returned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+pub enum Entry {
+    Open {
+        name: String,
+    },
+    Options {
+        result: Result<Vec<OptionInfo>, RecordedError>,
+    },
+    SetOption {
+        name: String,
+        value: OptionValue,
+        result: Result<(), RecordedError>,
+    },
+    Start {
+        result: Result<FrameParameters, RecordedError>,
+    },
+    /// The data is hex encoded
+    Read {
+        result: Result<String, RecordedError>,
+    },
+    Cancel,
+}
+
+/// An error as recorded, keeping the SANE status if there was one
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedError {
+    pub status: Option<SANE_Status>,
+    pub message: String,
+}
+
+impl From<&BackendError> for RecordedError {
+    fn from(e: &BackendError) -> Self {
+        let status = match e.downcast_ref::<crate::Error>() {
+            Some(&crate::Error::Status(status)) => Some(status),
+            _ => None,
+        };
+        Self {
+            status,
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<RecordedError> for BackendError {
+    fn from(e: RecordedError) -> Self {
+        match e.status {
+            Some(status) => crate::Error::Status(status).into(),
+            None => e.message.into(),
+        }
+    }
+}
+
+/// Forwards to another device, recording every call
+pub struct Recorder {
+    inner: Box<dyn ScannerDevice>,
+    out: RefCell<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn new(inner: Box<dyn ScannerDevice>, path: &Path) -> Result<Self, BackendError> {
+        let recorder = Self {
+            out: RefCell::new(BufWriter::new(File::create(path)?)),
+            inner,
+        };
+        recorder.record(&Entry::Open {
+            name: recorder.inner.name().to_owned(),
+        });
+        Ok(recorder)
+    }
+
+    fn record(&self, entry: &Entry) {
+        let mut out = self.out.borrow_mut();
+        let written = serde_json::to_writer(&mut *out, entry)
+            .map_err(std::io::Error::from)
+            .and_then(|_| out.write_all(b"\n"));
+        if let Err(e) = written {
+            tracing::warn!("Recording {} failed: {}", self.inner.name(), e);
+        }
+    }
+}
+
+fn recorded<T, U>(
+    result: &Result<T, BackendError>,
+    f: impl FnOnce(&T) -> U,
+) -> Result<U, RecordedError> {
+    match result {
+        Ok(value) => Ok(f(value)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl ScannerDevice for Recorder {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn options(&self) -> Result<Vec<OptionInfo>, BackendError> {
+        let result = self.inner.options();
+        self.record(&Entry::Options {
+            result: recorded(&result, Vec::clone),
+        });
+        result
+    }
+
+    fn set_option(&self, name: &str, value: &OptionValue) -> Result<(), BackendError> {
+        let result = self.inner.set_option(name, value);
+        self.record(&Entry::SetOption {
+            name: name.to_owned(),
+            value: value.clone(),
+            result: recorded(&result, |_| ()),
+        });
+        result
+    }
+
+    fn start(&self) -> Result<FrameParameters, BackendError> {
+        let result = self.inner.start();
+        self.record(&Entry::Start {
+            result: recorded(&result, |parameters| *parameters),
+        });
+        result
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, BackendError> {
+        let result = self.inner.read(buffer);
+        self.record(&Entry::Read {
+            result: recorded(&result, |&len| hex(&buffer[..len])),
+        });
+        result
+    }
+
+    fn cancel(&self) {
+        self.inner.cancel();
+        self.record(&Entry::Cancel);
+        if let Err(e) = self.out.borrow_mut().flush() {
+            tracing::warn!("Recording {} failed: {}", self.inner.name(), e);
+        }
+    }
+}
+
+/// Serves the device of a recording
+pub struct ReplayBackend {
+    entries: Vec<Entry>,
+}
+
+impl ReplayBackend {
+    pub fn load(path: &Path) -> Result<Self, BackendError> {
+        let mut entries = Vec::new();
+        for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str(&line)
+                .map_err(|e| format!("{}:{}: {}", path.display(), number + 1, e))?;
+            entries.push(entry);
+        }
+        match entries.first() {
+            Some(Entry::Open { .. }) => Ok(Self { entries }),
+            _ => Err(format!("{} does not start with opening a device", path.display()).into()),
+        }
+    }
+
+    fn device_name(&self) -> &str {
+        match &self.entries[0] {
+            Entry::Open { name } => name,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl ScannerBackend for ReplayBackend {
+    fn enumerate(&self) -> Result<Vec<DeviceInfo>, BackendError> {
+        Ok(vec![DeviceInfo {
+            name: self.device_name().to_owned(),
+            vendor: "skanny".to_owned(),
+            model: "replay".to_owned(),
+            type_: "recorded device".to_owned(),
+        }])
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn ScannerDevice>, BackendError> {
+        if name != self.device_name() {
+            return Err(format!("The recording is of {}", self.device_name()).into());
+        }
+        Ok(Box::new(ReplayDevice {
+            name: name.to_owned(),
+            entries: self.entries[1..].to_vec(),
+            state: RefCell::new(ReplayState {
+                next: 0,
+                options: Vec::new(),
+                chunk: None,
+            }),
+        }))
+    }
+}
+
+pub struct ReplayDevice {
+    name: String,
+    entries: Vec<Entry>,
+    state: RefCell<ReplayState>,
+}
+
+struct ReplayState {
+    /// Index of the next entry
+    next: usize,
+    /// Last options returned, for listing them more often than recorded
+    options: Vec<OptionInfo>,
+    /// Part of a recorded chunk not returned yet, as it was larger than
+    /// the buffer of the read
+    chunk: Option<(Vec<u8>, usize)>,
+}
+
+impl ReplayDevice {
+    fn diverged(&self, state: &ReplayState, call: &str) -> BackendError {
+        match self.entries.get(state.next) {
+            Some(entry) => format!(
+                "Replay diverged at entry {}: expected {:?}, got {}",
+                state.next + 2,
+                entry,
+                call
+            )
+            .into(),
+            None => format!("Replay ended, got {}", call).into(),
+        }
+    }
+}
+
+impl ScannerDevice for ReplayDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn options(&self) -> Result<Vec<OptionInfo>, BackendError> {
+        let mut state = self.state.borrow_mut();
+        match self.entries.get(state.next) {
+            Some(Entry::Options { result }) => {
+                state.next += 1;
+                let options = result.clone()?;
+                state.options = options.clone();
+                Ok(options)
+            }
+            _ if !state.options.is_empty() => Ok(state.options.clone()),
+            _ => Err(self.diverged(&state, "options")),
+        }
+    }
+
+    fn set_option(&self, name: &str, value: &OptionValue) -> Result<(), BackendError> {
+        let mut state = self.state.borrow_mut();
+        match self.entries.get(state.next) {
+            Some(Entry::SetOption {
+                name: recorded_name,
+                value: recorded_value,
+                result,
+            }) if recorded_name == name && recorded_value == value => {
+                state.next += 1;
+                Ok(result.clone()?)
+            }
+            _ => Err(self.diverged(&state, &format!("set_option {} = {}", name, value))),
+        }
+    }
+
+    fn start(&self) -> Result<FrameParameters, BackendError> {
+        let mut state = self.state.borrow_mut();
+        // Skip cancels the caller did not repeat
+        while let Some(Entry::Cancel) = self.entries.get(state.next) {
+            state.next += 1;
+        }
+        state.chunk = None;
+        match self.entries.get(state.next) {
+            Some(Entry::Start { result }) => {
+                state.next += 1;
+                Ok(result.clone()?)
+            }
+            _ => Err(self.diverged(&state, "start")),
+        }
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, BackendError> {
+        let mut state = self.state.borrow_mut();
+        if state.chunk.is_none() {
+            let data = match self.entries.get(state.next) {
+                Some(Entry::Read { result }) => unhex(&result.clone()?)?,
+                _ => return Err(self.diverged(&state, "read")),
+            };
+            state.next += 1;
+            state.chunk = Some((data, 0));
+        }
+        let (data, offset) = state.chunk.as_mut().unwrap();
+        let len = buffer.len().min(data.len() - *offset);
+        buffer[..len].copy_from_slice(&data[*offset..*offset + len]);
+        *offset += len;
+        if *offset == data.len() {
+            state.chunk = None;
+        }
+        Ok(len)
+    }
+
+    fn cancel(&self) {
+        let mut state = self.state.borrow_mut();
+        state.chunk = None;
+        // Reads after the end of the frame may not have been repeated
+        while let Some(Entry::Read { .. }) = self.entries.get(state.next) {
+            state.next += 1;
+        }
+        if let Some(Entry::Cancel) = self.entries.get(state.next) {
+            state.next += 1;
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        s.push_str(&format!("{:02x}", byte));
+    }
+    s
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>, BackendError> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| "Invalid hex data".into())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{DeviceSpec, Fault, MockDevice};
+
+    #[test]
+    fn replays_recording() {
+        let path = std::env::temp_dir().join(format!("skanny-record-{}.jsonl", std::process::id()));
+        let spec = DeviceSpec {
+            pages: Some(2),
+            faults: vec![Fault::busy_while_reading(1, 10)],
+            ..DeviceSpec::default()
+        };
+        let scan = |device: &dyn ScannerDevice| {
+            device
+                .set_option("mode", &OptionValue::String("Color".to_owned()))
+                .unwrap();
+            let first = device.scan_frame().unwrap().into_raw();
+            let second = device.scan_frame().err().unwrap().to_string();
+            let third = device.scan_frame().err().unwrap().to_string();
+            (first, second, third)
+        };
+
+        let recorder = Recorder::new(Box::new(MockDevice::new(spec)), &path).unwrap();
+        let expected = scan(&recorder);
+        drop(recorder);
+
+        let backend = ReplayBackend::load(&path).unwrap();
+        let device = backend.open("mock").unwrap();
+        assert_eq!(scan(&*device), expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,650 @@
+//! Server side of the SANE network protocol, a replacement for saned
+//!
+//! Exports the open device to SANE clients on other hosts, which reach it
+//! through their `net` backend, e.g. with `scanimage -d net:HOST:DEVICE`.
+//! Clients are served one at a time. Connections from loopback addresses
+//! are always accepted, others only if allowed with `--allow`, see the
+//! [`listen`](crate::listen) module. SANE clients connect over TCP, so
+//! `--listen` takes no Unix sockets.
+//!
+//! Image data is sent over a second connection while the control
+//! connection keeps answering requests, as clients ask for the scan
+//! parameters before they start reading.
+
+use std::io::{BufReader, BufWriter, ErrorKind, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use gumdrop::Options;
+use sane_sys::*;
+use skanny::net::wire::{self, procedure, Reader, Writer};
+use skanny::net::Error;
+use skanny::{Context, Handle, OptionValue};
+
+use crate::audit::{Audit, Record};
+use crate::jobs::JobState;
+use crate::listen::{self, Address, Allow};
+
+#[derive(Debug, Options)]
+pub struct SanedOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(
+        help = "Address to listen on, may be given more than once (0.0.0.0:6566 if not given)",
+        meta = "ADDRESS"
+    )]
+    listen: Vec<String>,
+    #[options(
+        help = "Accept clients from this address or network, or * for any",
+        meta = "ADDRESS[/PREFIX]"
+    )]
+    allow: Vec<String>,
+    #[options(
+        no_short,
+        help = "Append a line about every finished scan to this file",
+        meta = "FILE"
+    )]
+    audit: Option<String>,
+}
+
+const DEFAULT_LISTEN: &str = "0.0.0.0:6566";
+/// How long a client may take to open the data connection
+const DATA_TIMEOUT: Duration = Duration::from_secs(10);
+/// Size of the records image data is sent in
+const RECORD_SIZE: usize = 32 * 1024;
+
+/// The exported device as listed to clients
+struct Device {
+    name: String,
+    vendor: String,
+    model: String,
+    type_: String,
+}
+
+pub fn run(
+    context: &Context,
+    handle: &Handle,
+    opts: &SanedOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let allowed = Allow::parse_all(&opts.allow)?;
+    let audit = match &opts.audit {
+        Some(path) => Some(Audit::open(std::path::Path::new(path), "saned")?),
+        None => None,
+    };
+
+    let device = context
+        .devices(false)?
+        .find(|device| device.name() == handle.name())
+        .map(|device| Device {
+            name: device.name().to_owned(),
+            vendor: device.vendor().to_owned(),
+            model: device.model().to_owned(),
+            type_: device.type_().to_owned(),
+        })
+        .unwrap_or_else(|| Device {
+            name: handle.name().to_owned(),
+            vendor: "skanny".to_owned(),
+            model: handle.name().to_owned(),
+            type_: "virtual device".to_owned(),
+        });
+
+    let mut listeners = Vec::new();
+    for address in Address::parse_all(&opts.listen, DEFAULT_LISTEN)? {
+        let addr = match address {
+            Address::Tcp(addr) => addr,
+            Address::Unix(_) => return Err("SANE clients cannot connect to Unix sockets".into()),
+        };
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        listeners.push(listener);
+    }
+    let addresses = listeners
+        .iter()
+        .map(|listener| listener.local_addr().map(|addr| addr.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let stop = crate::stop_on_ctrlc();
+    tracing::info!(
+        "Exporting {} on {}, interrupt with ctrl-c",
+        device.name,
+        addresses.join(", ")
+    );
+    while !stop.load(Ordering::SeqCst) {
+        let mut idle = true;
+        for listener in &listeners {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    idle = false;
+                    if !listen::allowed(&allowed, Some(peer.ip())) {
+                        tracing::warn!(%peer, "Connection refused, not allowed");
+                        continue;
+                    }
+                    let _connection = tracing::info_span!("connection", %peer).entered();
+                    tracing::info!("Connected");
+                    match serve(handle, &device, audit.as_ref(), stream) {
+                        Ok(()) => tracing::info!("Disconnected"),
+                        Err(e) => tracing::warn!("Connection failed: {}", e),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if idle {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+    Ok(())
+}
+
+fn serve(
+    handle: &Handle,
+    device: &Device,
+    audit: Option<&Audit>,
+    stream: TcpStream,
+) -> Result<(), Error> {
+    stream.set_nonblocking(false)?;
+    stream.set_nodelay(true)?;
+    let mut session = Session {
+        handle,
+        device,
+        audit,
+        peer: stream.peer_addr()?.ip().to_string(),
+        reader: Reader(BufReader::new(stream.try_clone()?)),
+        writer: Writer(BufWriter::new(stream.try_clone()?)),
+        stream,
+        acquiring: false,
+    };
+    loop {
+        let procedure = match session.reader.word() {
+            Ok(procedure) => procedure,
+            // The client hung up without saying goodbye
+            Err(Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if procedure == procedure::EXIT {
+            return Ok(());
+        }
+        session.dispatch(procedure)?;
+    }
+}
+
+struct Session<'a> {
+    handle: &'a Handle,
+    device: &'a Device,
+    audit: Option<&'a Audit>,
+    /// Address of the client, for the audit log
+    peer: String,
+    reader: Reader<BufReader<TcpStream>>,
+    writer: Writer<BufWriter<TcpStream>>,
+    stream: TcpStream,
+    /// Whether image data is being sent
+    acquiring: bool,
+}
+
+const GOOD: SANE_Word = SANE_Status_SANE_STATUS_GOOD as SANE_Word;
+
+fn status(e: skanny::Error) -> SANE_Word {
+    match e {
+        skanny::Error::Status(status) => status as SANE_Word,
+        skanny::Error::WrongType => SANE_Status_SANE_STATUS_INVAL as SANE_Word,
+        skanny::Error::NotInstalled => SANE_Status_SANE_STATUS_UNSUPPORTED as SANE_Word,
+        skanny::Error::TimedOut => SANE_Status_SANE_STATUS_IO_ERROR as SANE_Word,
+    }
+}
+
+impl Session<'_> {
+    fn dispatch(&mut self, procedure: SANE_Word) -> Result<(), Error> {
+        match procedure {
+            procedure::INIT => {
+                let _version = self.reader.word()?;
+                let user = self.reader.string()?;
+                tracing::info!("Session of user {}", user);
+                self.writer.word(GOOD)?.word(wire::VERSION_CODE)?.flush()
+            }
+            procedure::GET_DEVICES => {
+                let Device {
+                    name,
+                    vendor,
+                    model,
+                    type_,
+                } = self.device;
+                // The list is terminated by a NULL pointer
+                self.writer
+                    .word(GOOD)?
+                    .word(2)?
+                    .word(0)?
+                    .string(name)?
+                    .string(vendor)?
+                    .string(model)?
+                    .string(type_)?
+                    .word(1)?
+                    .flush()
+            }
+            procedure::OPEN => {
+                let name = self.reader.string()?;
+                let status = if name.is_empty() || name == self.device.name {
+                    tracing::info!("Opened {}", self.device.name);
+                    GOOD
+                } else {
+                    tracing::warn!("No device named {}", name);
+                    SANE_Status_SANE_STATUS_INVAL as SANE_Word
+                };
+                self.writer
+                    .word(status)?
+                    .word(0)?
+                    .nullable_string(None)?
+                    .flush()
+            }
+            procedure::CLOSE | procedure::CANCEL => {
+                let _handle = self.reader.word()?;
+                self.writer.word(0)?.flush()
+            }
+            procedure::GET_OPTION_DESCRIPTORS => {
+                let _handle = self.reader.word()?;
+                self.option_descriptors()
+            }
+            procedure::CONTROL_OPTION => self.control_option(),
+            procedure::GET_PARAMETERS => {
+                let _handle = self.reader.word()?;
+                match self.handle.parameters() {
+                    Ok(parameters) => self
+                        .writer
+                        .word(GOOD)?
+                        .word(parameters.format() as SANE_Word)?
+                        .word(parameters.last_frame())?
+                        .word(parameters.bytes_per_line())?
+                        .word(parameters.pixels_per_line())?
+                        .word(parameters.lines())?
+                        .word(parameters.depth())?
+                        .flush(),
+                    Err(e) => {
+                        self.writer.word(status(e))?;
+                        for _ in 0..6 {
+                            self.writer.word(0)?;
+                        }
+                        self.writer.flush()
+                    }
+                }
+            }
+            procedure::START => {
+                let _handle = self.reader.word()?;
+                self.start()
+            }
+            other => Err(Error::Protocol(format!("Unsupported procedure {}", other))),
+        }
+    }
+
+    fn option_descriptors(&mut self) -> Result<(), Error> {
+        let handle = self.handle;
+        let count = handle
+            .get_descriptor(0)
+            .ok_or_else(|| Error::Protocol("Device has no option count".to_owned()))?;
+        let options: Vec<_> = handle.options().collect();
+
+        self.writer.word(options.len() as SANE_Word + 1)?;
+        self.descriptor(&count)?;
+        self.writer
+            .word(SANE_Constraint_Type_SANE_CONSTRAINT_NONE as SANE_Word)?;
+        for opt in &options {
+            let descriptor = opt.descriptor();
+            self.descriptor(descriptor)?;
+            #[allow(non_upper_case_globals)]
+            match descriptor.constraint_type() {
+                SANE_Constraint_Type_SANE_CONSTRAINT_RANGE => match opt.get_range() {
+                    Ok(range) => self
+                        .writer
+                        .word(SANE_Constraint_Type_SANE_CONSTRAINT_RANGE as SANE_Word)?
+                        .word(0)?
+                        .word(range.min())?
+                        .word(range.max())?
+                        .word(range.quant())?,
+                    Err(_) => self
+                        .writer
+                        .word(SANE_Constraint_Type_SANE_CONSTRAINT_RANGE as SANE_Word)?
+                        .word(1)?,
+                },
+                SANE_Constraint_Type_SANE_CONSTRAINT_WORD_LIST => {
+                    let list = opt
+                        .int_constraints()
+                        .map_err(|e| Error::Protocol(e.to_string()))?;
+                    self.writer
+                        .word(SANE_Constraint_Type_SANE_CONSTRAINT_WORD_LIST as SANE_Word)?
+                        .word(list.len() as SANE_Word + 1)?
+                        .word(list.len() as SANE_Word)?;
+                    for &word in list {
+                        self.writer.word(word)?;
+                    }
+                    &mut self.writer
+                }
+                SANE_Constraint_Type_SANE_CONSTRAINT_STRING_LIST => {
+                    let list: Vec<&str> = opt
+                        .string_constraints()
+                        .map_err(|e| Error::Protocol(e.to_string()))?
+                        .collect();
+                    self.writer
+                        .word(SANE_Constraint_Type_SANE_CONSTRAINT_STRING_LIST as SANE_Word)?
+                        .word(list.len() as SANE_Word + 1)?;
+                    for s in list {
+                        self.writer.string(s)?;
+                    }
+                    self.writer.nullable_string(None)?
+                }
+                _ => self
+                    .writer
+                    .word(SANE_Constraint_Type_SANE_CONSTRAINT_NONE as SANE_Word)?,
+            };
+        }
+        self.writer.flush()
+    }
+
+    /// Writes a descriptor up to, but not including, its constraint
+    fn descriptor(&mut self, descriptor: &skanny::Descriptor) -> Result<(), Error> {
+        self.writer
+            .word(0)?
+            .string(descriptor.name())?
+            .string(descriptor.title())?
+            .string(descriptor.desc())?
+            .word(descriptor.type_() as SANE_Word)?
+            .word(descriptor.unit() as SANE_Word)?
+            .word(descriptor.size())?
+            .word(descriptor.cap())?;
+        Ok(())
+    }
+
+    fn control_option(&mut self) -> Result<(), Error> {
+        let _handle = self.reader.word()?;
+        let index = self.reader.word()?;
+        let action = self.reader.word()? as SANE_Action;
+        let type_ = self.reader.word()?;
+        let size = self.reader.word()?;
+        let len = self.reader.length()?;
+        let is_string = type_ as SANE_Value_Type == SANE_Value_Type_SANE_TYPE_STRING;
+        let element_size = if is_string {
+            1
+        } else if type_ as SANE_Value_Type == SANE_Value_Type_SANE_TYPE_BUTTON
+            || type_ as SANE_Value_Type == SANE_Value_Type_SANE_TYPE_GROUP
+        {
+            0
+        } else {
+            4
+        };
+        let request = self.reader.bytes(len * element_size)?;
+
+        let (status, info, value) = match self.execute(index, action, &request) {
+            Ok((info, value)) => (GOOD, info, value),
+            Err(status) => (status, 0, request),
+        };
+        let len = value.len().checked_div(element_size).unwrap_or(0);
+        self.writer
+            .word(status)?
+            .word(info)?
+            .word(type_)?
+            .word(size)?
+            .word(len as SANE_Word)?
+            .bytes(&value)?
+            .nullable_string(None)?
+            .flush()
+    }
+
+    /// Performs an option action, returning the info flags and the value
+    /// to reply with, or the status to fail with
+    fn execute(
+        &mut self,
+        index: SANE_Word,
+        action: SANE_Action,
+        request: &[u8],
+    ) -> Result<(SANE_Word, Vec<u8>), SANE_Word> {
+        let inval = SANE_Status_SANE_STATUS_INVAL as SANE_Word;
+        if index == 0 {
+            if action != SANE_Action_SANE_ACTION_GET_VALUE {
+                return Err(inval);
+            }
+            let count = self.handle.options().len() as SANE_Word + 1;
+            return Ok((0, count.to_be_bytes().to_vec()));
+        }
+        if index < 0 {
+            return Err(inval);
+        }
+        let opt = self.handle.options().nth(index as usize - 1).ok_or(inval)?;
+        let descriptor = opt.descriptor();
+        let type_ = descriptor.type_();
+        let scalar = type_ == SANE_Value_Type_SANE_TYPE_STRING
+            || type_ == SANE_Value_Type_SANE_TYPE_BUTTON
+            || descriptor.size() == std::mem::size_of::<SANE_Word>() as SANE_Int;
+        if !scalar {
+            // Arrays such as gamma tables are not supported by the device layer
+            return Err(SANE_Status_SANE_STATUS_UNSUPPORTED as SANE_Word);
+        }
+        let word = || {
+            request
+                .get(..4)
+                .map(|w| SANE_Word::from_be_bytes([w[0], w[1], w[2], w[3]]))
+                .ok_or(inval)
+        };
+
+        #[allow(non_upper_case_globals)]
+        match action {
+            SANE_Action_SANE_ACTION_GET_VALUE => {}
+            SANE_Action_SANE_ACTION_SET_VALUE => {
+                let value = match type_ {
+                    SANE_Value_Type_SANE_TYPE_BOOL => OptionValue::Bool(word()? != 0),
+                    SANE_Value_Type_SANE_TYPE_INT => OptionValue::Int(word()?),
+                    SANE_Value_Type_SANE_TYPE_FIXED => OptionValue::Fixed(SANE_UNFIX(word()?)),
+                    SANE_Value_Type_SANE_TYPE_STRING => {
+                        let end = request
+                            .iter()
+                            .position(|&b| b == 0)
+                            .unwrap_or(request.len());
+                        OptionValue::String(String::from_utf8_lossy(&request[..end]).into_owned())
+                    }
+                    // Buttons are pressed whatever the value
+                    _ => OptionValue::Bool(true),
+                };
+                opt.set_value(&value).map_err(status)?;
+            }
+            _ => return Err(SANE_Status_SANE_STATUS_UNSUPPORTED as SANE_Word),
+        }
+
+        // Setting one option may change others, have the client reload
+        let info = if action == SANE_Action_SANE_ACTION_SET_VALUE {
+            (SANE_INFO_RELOAD_OPTIONS | SANE_INFO_RELOAD_PARAMS) as SANE_Word
+        } else {
+            0
+        };
+        let value = match opt.get_value() {
+            Ok(OptionValue::Bool(b)) => (b as SANE_Word).to_be_bytes().to_vec(),
+            Ok(OptionValue::Int(i)) => i.to_be_bytes().to_vec(),
+            Ok(OptionValue::Fixed(f)) => SANE_FIX(f).to_be_bytes().to_vec(),
+            Ok(OptionValue::String(s)) => {
+                let mut bytes = s.into_bytes();
+                bytes.resize(descriptor.size().max(0) as usize, 0);
+                bytes
+            }
+            // Buttons and groups carry no value
+            Err(skanny::Error::WrongType) => Vec::new(),
+            Err(e) => return Err(status(e)),
+        };
+        Ok((info, value))
+    }
+
+    fn start(&mut self) -> Result<(), Error> {
+        let byte_order = if cfg!(target_endian = "little") {
+            0x1234
+        } else {
+            0x4321
+        };
+        if self.acquiring {
+            return self
+                .writer
+                .word(SANE_Status_SANE_STATUS_DEVICE_BUSY as SANE_Word)?
+                .word(0)?
+                .word(byte_order)?
+                .nullable_string(None)?
+                .flush();
+        }
+        let acquisition = match self.handle.start() {
+            Ok(acquisition) => acquisition,
+            Err(e) => {
+                tracing::warn!("Starting scan failed: {}", e);
+                self.audit(Err(&e.to_string()));
+                return self
+                    .writer
+                    .word(status(e))?
+                    .word(0)?
+                    .word(byte_order)?
+                    .nullable_string(None)?
+                    .flush();
+            }
+        };
+
+        let listener = TcpListener::bind((self.stream.local_addr()?.ip(), 0))?;
+        let port = listener.local_addr()?.port();
+        self.writer
+            .word(GOOD)?
+            .word(SANE_Word::from(port))?
+            .word(byte_order)?
+            .nullable_string(None)?
+            .flush()?;
+
+        tracing::info!("Scanning");
+        self.acquiring = true;
+        let result = self.send_frame(&acquisition, listener);
+        self.acquiring = false;
+        match &result {
+            Ok(()) => self.audit(Ok(())),
+            Err(e) => self.audit(Err(&e.to_string())),
+        }
+        result
+    }
+
+    /// Records a frame sent, or not, in the audit log
+    fn audit(&self, result: Result<(), &str>) {
+        if let Some(audit) = self.audit {
+            audit.record(&Record {
+                client: &self.peer,
+                device: &self.device.name,
+                profile: "SANE",
+                state: if result.is_ok() {
+                    JobState::Done
+                } else {
+                    JobState::Failed
+                },
+                error: result.err(),
+                pages: usize::from(result.is_ok()),
+                outputs: result.iter().map(|()| "client".to_owned()).collect(),
+            });
+        }
+    }
+
+    /// Waits for the data connection, answering requests meanwhile as
+    /// clients may ask for the parameters before connecting
+    fn accept_data(&mut self, listener: TcpListener) -> Result<TcpStream, Error> {
+        listener.set_nonblocking(true)?;
+        let started = Instant::now();
+        loop {
+            match listener.accept() {
+                Ok((data, _)) => return Ok(data),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if started.elapsed() > DATA_TIMEOUT {
+                        return Err(Error::Protocol(
+                            "Client did not open the data connection".to_owned(),
+                        ));
+                    }
+                    if self.request_pending()? {
+                        let procedure = self.reader.word()?;
+                        self.dispatch(procedure)?;
+                    } else {
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Streams the frame over the data connection while answering
+    /// requests on the control connection
+    fn send_frame(
+        &mut self,
+        acquisition: &skanny::Acquisition<'_>,
+        listener: TcpListener,
+    ) -> Result<(), Error> {
+        let mut data = self.accept_data(listener)?;
+        data.set_nonblocking(true)?;
+        let mut pending: Vec<u8> = Vec::new();
+        let mut written = 0;
+        let mut finished = false;
+        let mut chunk = vec![0; RECORD_SIZE];
+        loop {
+            if self.request_pending()? {
+                let procedure = self.reader.word()?;
+                if procedure == procedure::CANCEL && !finished {
+                    let _handle = self.reader.word()?;
+                    self.writer.word(0)?.flush()?;
+                    tracing::info!("Scan cancelled");
+                    pending.truncate(written);
+                    pending.extend_from_slice(&u32::MAX.to_be_bytes());
+                    pending.push(SANE_Status_SANE_STATUS_CANCELLED as u8);
+                    finished = true;
+                } else {
+                    self.dispatch(procedure)?;
+                }
+                continue;
+            }
+
+            if written < pending.len() {
+                match data.write(&pending[written..]) {
+                    Ok(n) => written += n,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        std::thread::sleep(Duration::from_millis(5));
+                    }
+                    // The client went away, it will tell us on the control connection
+                    Err(_) => return Ok(()),
+                }
+                continue;
+            }
+            if finished {
+                return Ok(());
+            }
+
+            pending.clear();
+            written = 0;
+            match acquisition.read(&mut chunk) {
+                Ok(0) => {
+                    pending.extend_from_slice(&u32::MAX.to_be_bytes());
+                    pending.push(SANE_Status_SANE_STATUS_EOF as u8);
+                    finished = true;
+                }
+                Ok(n) => {
+                    pending.extend_from_slice(&(n as u32).to_be_bytes());
+                    pending.extend_from_slice(&chunk[..n]);
+                }
+                Err(e) => {
+                    tracing::warn!("Reading from the device failed: {}", e);
+                    pending.extend_from_slice(&u32::MAX.to_be_bytes());
+                    pending.push(status(e) as u8);
+                    finished = true;
+                }
+            }
+        }
+    }
+
+    /// Whether a request can be read without blocking
+    fn request_pending(&mut self) -> Result<bool, Error> {
+        if !self.reader.0.buffer().is_empty() {
+            return Ok(true);
+        }
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let pending = match self.stream.peek(&mut byte) {
+            Ok(0) => Err(Error::Io(ErrorKind::UnexpectedEof.into())),
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        };
+        self.stream.set_nonblocking(false)?;
+        pending
+    }
+}
@@ -0,0 +1,94 @@
+//! Polling of read-only hardware options such as buttons and paper sensors
+//!
+//! SANE has no notification mechanism, so the state of the sensors must be
+//! read periodically. Changes are reported as [`SensorEvent`]s through a
+//! channel, which decouples the consumer from the polling loop.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use sane_sys::*;
+
+use crate::{Error, Handle, Opt, OptionValue};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SensorEvent {
+    /// A boolean sensor (e.g. a button) became active
+    Activated(String),
+    /// A boolean sensor (e.g. a button) became inactive
+    Deactivated(String),
+    /// A non-boolean sensor changed value
+    Changed { name: String, value: OptionValue },
+}
+
+/// The sensors of a device and their last known state
+pub struct Sensors<'a> {
+    sensors: Vec<(Opt<'a>, OptionValue)>,
+}
+
+impl<'a> Sensors<'a> {
+    /// Finds all sensors on the device and reads their initial state
+    pub fn new(handle: &'a Handle) -> Result<Self, Error> {
+        let sensors = handle
+            .options()
+            .filter(|opt| opt.descriptor().is_sensor())
+            .filter(|opt| {
+                let type_ = opt.descriptor().type_();
+                type_ != SANE_Value_Type_SANE_TYPE_BUTTON
+                    && type_ != SANE_Value_Type_SANE_TYPE_GROUP
+            })
+            .map(|opt| {
+                let value = opt.get_value()?;
+                Ok((opt, value))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self { sensors })
+    }
+
+    pub fn names(&self) -> impl ExactSizeIterator<Item = &str> {
+        self.sensors.iter().map(|(opt, _)| opt.name())
+    }
+
+    /// Reads every sensor once, sending an event for each that changed
+    ///
+    /// Returns `false` when the receiver has hung up
+    pub fn poll(&mut self, events: &Sender<SensorEvent>) -> Result<bool, Error> {
+        for (opt, last) in &mut self.sensors {
+            let value = opt.get_value()?;
+            if value == *last {
+                continue;
+            }
+            let name = opt.name().to_owned();
+            let event = match value {
+                OptionValue::Bool(true) => SensorEvent::Activated(name),
+                OptionValue::Bool(false) => SensorEvent::Deactivated(name),
+                ref value => SensorEvent::Changed {
+                    name,
+                    value: value.clone(),
+                },
+            };
+            *last = value;
+            if events.send(event).is_err() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Polls with a fixed interval until `stop` is raised or the receiver hangs up
+    pub fn watch(
+        &mut self,
+        interval: Duration,
+        events: &Sender<SensorEvent>,
+        stop: &AtomicBool,
+    ) -> Result<(), Error> {
+        while !stop.load(Ordering::SeqCst) {
+            if !self.poll(events)? {
+                break;
+            }
+            std::thread::sleep(interval);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+//! Splitting of feeder batches into documents at separator sheets
+//!
+//! A separator sheet carries a QR code starting with the separator prefix,
+//! say `SKANNY:`. The pages after it go into a directory of their own,
+//! named after the rest of the code if there is any and numbered otherwise.
+//! Separator sheets themselves are not stored. When the feeder jams or
+//! pulls in two sheets at once, the sheet is scanned again if `on_misfeed`
+//! says so. A sheet whose page is cancelled, see
+//! [`skanny::backend::Cancellable`], is skipped and the batch goes on.
+//! The batch may be paused between sheets, see [`crate::pause`]. Pages are
+//! processed and saved by [`crate::workers`] while the
+//! next sheets are scanned. The [`Progress`] after every sheet lets a batch
+//! which was interrupted go on where it stopped, see [`crate::store`].
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use skanny::backend::{is_cancelled, BackendError, ScannerDevice};
+use skanny::pipeline::Pipeline;
+use skanny::Image;
+
+use crate::jam::{self, Misfeed, Recovery};
+use crate::manifest::Scan;
+use crate::template::Numbering;
+
+/// Contents of the QR codes on a page
+#[cfg(feature = "barcode")]
+pub fn codes(image: &Image) -> Result<Vec<String>, BackendError> {
+    let gray = image.to_dynamic().to_luma8();
+    let mut prepared = rqrr::PreparedImage::prepare_from_greyscale(
+        gray.width() as usize,
+        gray.height() as usize,
+        |x, y| gray.get_pixel(x as u32, y as u32)[0],
+    );
+    Ok(prepared
+        .detect_grids()
+        .into_iter()
+        .filter_map(|grid| grid.decode().ok())
+        .map(|(_, content)| content)
+        .collect())
+}
+
+#[cfg(not(feature = "barcode"))]
+pub fn codes(_image: &Image) -> Result<Vec<String>, BackendError> {
+    Err("skanny was built without the barcode feature".into())
+}
+
+/// A stored page of a batch
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Page {
+    pub path: PathBuf,
+    /// The device reported a double feed, and the page was kept anyway
+    pub double_feed: bool,
+    pub scan: Scan,
+    /// Size in pixels after processing
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Where a batch stands after a sheet
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    /// Sheets taken from the feeder
+    pub sheets: usize,
+    /// Documents started
+    pub documents: usize,
+    /// Directory the next pages go to
+    pub document: Option<PathBuf>,
+}
+
+/// Scans pages from the feeder until it is empty, storing them in a
+/// directory in `dir` per document after running them through `pipeline`.
+/// `on_misfeed` is told the number of the sheet which was misfed. The batch
+/// goes on `from` an earlier one, and `on_sheet` is told the progress after
+/// every sheet, along with its page once that is stored. No more sheets are
+/// scanned once `stopping` returns true.
+#[allow(clippy::too_many_arguments)]
+pub fn scan_batch(
+    device: &dyn ScannerDevice,
+    dir: &Path,
+    prefix: &str,
+    codes: impl Fn(&Image) -> Result<Vec<String>, BackendError>,
+    pipeline: &Pipeline,
+    on_misfeed: impl FnMut(usize, Misfeed) -> Recovery,
+    stopping: impl Fn() -> bool,
+    from: Progress,
+    on_sheet: impl Fn(&Progress, Option<&Page>) + Sync,
+) -> Result<Vec<Page>, BackendError> {
+    let on_sheet = &on_sheet;
+    crate::workers::overlap(
+        crate::workers::threads(),
+        |queue| {
+            scan_sheets(
+                device, dir, prefix, codes, on_misfeed, stopping, from, on_sheet, queue,
+            )
+        },
+        |(image, mut page, progress): (Image, Page, Progress)| {
+            let image =
+                crate::stages::save(pipeline, image, &page.path).map_err(|e| e.to_string())?;
+            page.width = image.width();
+            page.height = image.height();
+            on_sheet(&progress, Some(&page));
+            Ok(page)
+        },
+    )
+}
+
+/// Queues the pages of a batch with where they go
+#[allow(clippy::too_many_arguments)]
+fn scan_sheets(
+    device: &dyn ScannerDevice,
+    dir: &Path,
+    prefix: &str,
+    codes: impl Fn(&Image) -> Result<Vec<String>, BackendError>,
+    mut on_misfeed: impl FnMut(usize, Misfeed) -> Recovery,
+    stopping: impl Fn() -> bool,
+    mut progress: Progress,
+    on_sheet: &impl Fn(&Progress, Option<&Page>),
+    queue: &mut crate::workers::Queue<'_, (Image, Page, Progress)>,
+) -> Result<(), BackendError> {
+    let mut document = progress.document.as_deref().map(open).transpose()?;
+    loop {
+        if stopping() {
+            tracing::warn!("Stopped after sheet {}", progress.sheets);
+            break;
+        }
+        crate::pause::wait();
+        let sheet = progress.sheets + 1;
+        let (image, scan) = Scan::time(sheet, || device.scan());
+        let image = match image {
+            Ok(image) => image,
+            Err(e) if is_cancelled(&e) => {
+                progress.sheets = sheet;
+                tracing::warn!("Skipped sheet {}", sheet);
+                on_sheet(&progress, None);
+                continue;
+            }
+            Err(e) if is_no_docs(&e) && progress.documents > 0 => break,
+            Err(e) if jam::is_jam(&e) => match on_misfeed(sheet, Misfeed::Jam(&e)) {
+                Recovery::Rescan => continue,
+                Recovery::Keep | Recovery::Abort => return Err(e),
+            },
+            Err(e) => return Err(e),
+        };
+        let double_feed = jam::is_double_feed(device);
+        if double_feed {
+            match on_misfeed(sheet, Misfeed::DoubleFeed) {
+                Recovery::Rescan => continue,
+                Recovery::Keep => tracing::warn!("Keeping sheet {}, a double feed", sheet),
+                Recovery::Abort => return Err(format!("Sheet {} was a double feed", sheet).into()),
+            }
+        }
+        progress.sheets = sheet;
+        let separator = codes(&image)?
+            .into_iter()
+            .find_map(|code| code.strip_prefix(prefix).map(str::to_owned));
+        if let Some(name) = separator {
+            progress.documents += 1;
+            let name = match crate::template::safe_name(name.trim()) {
+                name if name.is_empty() => format!("document_{:04}", progress.documents),
+                name => name,
+            };
+            tracing::info!("Starting document {}", name);
+            let path = dir.join(name);
+            document = Some(open(&path)?);
+            progress.document = Some(path);
+            on_sheet(&progress, None);
+            continue;
+        }
+        let numbering = match &mut document {
+            Some(numbering) => numbering,
+            None => {
+                // Pages before the first separator
+                progress.documents += 1;
+                let path = dir.join(format!("document_{:04}", progress.documents));
+                let numbering = document.insert(open(&path)?);
+                progress.document = Some(path);
+                numbering
+            }
+        };
+        let path = numbering.next_path();
+        let page = Page {
+            path,
+            double_feed,
+            scan,
+            width: 0,
+            height: 0,
+        };
+        queue.push((image, page, progress.clone()))?;
+    }
+    Ok(())
+}
+
+fn open(dir: &Path) -> Result<Numbering, BackendError> {
+    std::fs::create_dir_all(dir)?;
+    Ok(Numbering::resume(dir, "page_{page}.png")?)
+}
+
+fn is_no_docs(e: &BackendError) -> bool {
+    matches!(e.downcast_ref::<skanny::Error>(), Some(e) if e.is_no_docs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use skanny::mock::{pattern, DeviceSpec, Fault, MockDevice};
+
+    #[test]
+    fn splits_at_separators() {
+        let dir = std::env::temp_dir().join(format!("skanny-separate-{}", std::process::id()));
+        let device = MockDevice::new(DeviceSpec {
+            pages: Some(6),
+            ..DeviceSpec::default()
+        });
+        // Pages 2 and 4 are separators
+        let codes = |image: &Image| {
+            let first = image.to_dynamic().to_luma8().get_pixel(0, 0)[0];
+            Ok(if first == pattern(2, 0) {
+                vec!["SKANNY:invoices/2020".to_owned()]
+            } else if first == pattern(4, 0) {
+                vec!["other".to_owned(), "SKANNY:".to_owned()]
+            } else {
+                vec![]
+            })
+        };
+        let pipeline = Pipeline::default();
+        let pages = scan_batch(
+            &device,
+            &dir,
+            "SKANNY:",
+            codes,
+            &pipeline,
+            |_, misfeed| panic!("Unexpected {:?}", misfeed),
+            || false,
+            Progress::default(),
+            |_, _| {},
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        let pages: Vec<_> = pages
+            .unwrap()
+            .into_iter()
+            .map(|page| page.path.strip_prefix(&dir).unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            pages,
+            [
+                "document_0001/page_0001.png",
+                "document_0001/page_0002.png",
+                "invoices_2020/page_0001.png",
+                "document_0003/page_0001.png",
+            ]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn recovers_from_misfeeds() {
+        let dir = std::env::temp_dir().join(format!("skanny-jam-{}", std::process::id()));
+        let device = MockDevice::new(DeviceSpec {
+            pages: Some(5),
+            faults: vec![Fault::jammed(1), Fault::jammed(2)],
+            double_feeds: vec![4, 6],
+            ..DeviceSpec::default()
+        });
+        let mut misfeeds = Vec::new();
+        let pages = scan_batch(
+            &device,
+            &dir,
+            "SKANNY:",
+            |_| Ok(vec![]),
+            &Pipeline::default(),
+            |sheet, misfeed| {
+                misfeeds.push((sheet, matches!(misfeed, Misfeed::Jam(_))));
+                match misfeed {
+                    Misfeed::Jam(_) => Recovery::Rescan,
+                    // The first double feed is scanned again, the second kept
+                    Misfeed::DoubleFeed if sheet == 3 => Recovery::Rescan,
+                    Misfeed::DoubleFeed => Recovery::Keep,
+                }
+            },
+            || false,
+            Progress::default(),
+            |_, _| {},
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(misfeeds, [(2, true), (2, true), (3, false), (4, false)]);
+        let pages: Vec<_> = pages
+            .unwrap()
+            .into_iter()
+            .map(|page| {
+                let path = page.path.strip_prefix(&dir).unwrap().to_owned();
+                (path.to_str().unwrap().to_owned(), page.double_feed)
+            })
+            .collect();
+        assert_eq!(
+            pages,
+            [
+                ("document_0001/page_0001.png".to_owned(), false),
+                ("document_0001/page_0002.png".to_owned(), false),
+                ("document_0001/page_0003.png".to_owned(), false),
+                ("document_0001/page_0004.png".to_owned(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn goes_on_from_an_interrupted_batch() {
+        let dir = std::env::temp_dir().join(format!("skanny-resume-{}", std::process::id()));
+        let scan = |pages, from| {
+            let device = MockDevice::new(DeviceSpec {
+                pages: Some(pages),
+                ..DeviceSpec::default()
+            });
+            let seen = std::sync::Mutex::new(Vec::new());
+            let pages = scan_batch(
+                &device,
+                &dir,
+                "SKANNY:",
+                |_| Ok(vec![]),
+                &Pipeline::default(),
+                |_, misfeed| panic!("Unexpected {:?}", misfeed),
+                || false,
+                from,
+                |progress: &Progress, page: Option<&Page>| {
+                    assert!(page.is_some());
+                    seen.lock().unwrap().push(progress.clone());
+                },
+            )
+            .unwrap();
+            let mut progress = seen.into_inner().unwrap();
+            progress.sort_by_key(|progress| progress.sheets);
+            (pages, progress)
+        };
+        let (pages, progress) = scan(3, Progress::default());
+        assert_eq!(progress[1].sheets, 2);
+        assert_eq!(progress[1].document, Some(dir.join("document_0001")));
+
+        // The third page was lost
+        std::fs::remove_file(&pages[2].path).unwrap();
+        let (resumed, _) = scan(2, progress[1].clone());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let resumed: Vec<_> = resumed
+            .iter()
+            .map(|page| (page.path.strip_prefix(&dir).unwrap(), page.scan.number))
+            .collect();
+        assert_eq!(
+            resumed,
+            [
+                (Path::new("document_0001/page_0003.png"), 3),
+                (Path::new("document_0001/page_0004.png"), 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops_when_asked() {
+        let dir = std::env::temp_dir().join(format!("skanny-stop-{}", std::process::id()));
+        let device = MockDevice::new(DeviceSpec {
+            pages: Some(5),
+            ..DeviceSpec::default()
+        });
+        let asked = std::sync::atomic::AtomicUsize::new(0);
+        let pages = scan_batch(
+            &device,
+            &dir,
+            "SKANNY:",
+            |_| Ok(vec![]),
+            &Pipeline::default(),
+            |_, misfeed| panic!("Unexpected {:?}", misfeed),
+            // Before every sheet, so two are scanned
+            || asked.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 2,
+            Progress::default(),
+            |_, _| {},
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(pages.unwrap().len(), 2);
+    }
+}
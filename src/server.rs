@@ -0,0 +1,868 @@
+//! HTTP API for remote control of the scanner
+//!
+//! | Method | Path                    | Description                       |
+//! |--------|-------------------------|-----------------------------------|
+//! | GET    | `/`                     | Web interface for scanning        |
+//! | GET    | `/devices`              | Devices known to SANE, `?refresh` to look again |
+//! | GET    | `/options`              | Options of the open device        |
+//! | PUT    | `/options/NAME`         | Set an option from a JSON value   |
+//! | POST   | `/jobs`                 | Start a scan, optionally `{"profile": NAME}` |
+//! | GET    | `/jobs/ID`              | State of a job, and its `position` while queued |
+//! | POST   | `/jobs/ID/skip`         | Skip the page of a job being scanned |
+//! | GET    | `/jobs/ID/files/N`      | Download an image produced by a job |
+//! | GET    | `/jobs/ID/files/N/thumbnail` | Its thumbnail, with `--thumbnails` |
+//! | POST   | `/pause`                | Hold the jobs after the one being scanned |
+//! | POST   | `/resume`               | Go on with the held jobs          |
+//! | GET    | `/metrics`              | Scan counters for Prometheus, see [`crate::stats`] |
+//! | GET    | `/usage`                | Pages and jobs of the client this month, see the [`quota`] module |
+//! | GET    | `/usage/clients`        | Those of every client             |
+//! | GET    | `/openapi.json`         | Description of this API, see the [`openapi`] module |
+//!
+//! With `--escl` the device is additionally exposed through the eSCL
+//! protocol under `/eSCL`, see the [`escl`] module.
+//!
+//! The server listens on `127.0.0.1:8080` unless given other addresses or
+//! Unix sockets with `--listen`, and only answers clients on other hosts
+//! allowed with `--allow`, see the [`listen`](crate::listen) module. eSCL
+//! clients find the server through mDNS, so it has to listen on TCP.
+//!
+//! `--cert` and `--key` serve everything over HTTPS, see the [`tls`]
+//! module, and `--auth` lets only known clients in, see the [`auth`]
+//! module, with the quotas of the [`quota`] module.
+//!
+//! The device scans one job at a time. `--max-queued` refuses new jobs with
+//! 429 while as many wait for it, and `--queue-timeout` fails the jobs which
+//! waited longer.
+//!
+//! SANE handles may not be shared between threads, so the device is driven
+//! from the thread calling [`run`], while requests are answered by a fixed
+//! number of threads per address and forwarded as tasks. Request bodies
+//! larger than 64 KiB are refused with 413.
+
+use std::path::PathBuf;
+
+use gumdrop::Options;
+use skanny::{Context, Handle};
+
+#[derive(Debug, Options)]
+pub struct ServerOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(
+        help = "Address or unix:PATH to listen on, may be given more than once (127.0.0.1:8080 if not given)",
+        meta = "ADDRESS"
+    )]
+    listen: Vec<String>,
+    #[options(
+        help = "Accept clients from this address or network, or * for any",
+        meta = "ADDRESS[/PREFIX]"
+    )]
+    allow: Vec<String>,
+    #[options(help = "TOML file with scan profiles")]
+    profiles: Option<String>,
+    #[options(help = "Directory to store images", default = ".")]
+    dir: String,
+    #[options(help = "Serve the eSCL (AirScan) protocol and announce it over mDNS")]
+    escl: bool,
+    #[options(no_short, help = "File to keep the job history in", meta = "FILE")]
+    jobs: Option<String>,
+    #[options(
+        no_short,
+        help = "SQLite database to keep the jobs in, see skanny jobs",
+        meta = "FILE"
+    )]
+    store: Option<String>,
+    #[options(
+        no_short,
+        help = "Number of jobs stored and uploaded at once",
+        default = "2"
+    )]
+    workers: usize,
+    #[options(no_short, help = "Refuse jobs while this many are queued", meta = "N")]
+    max_queued: Option<usize>,
+    #[options(
+        no_short,
+        help = "Fail jobs which waited this long for the device",
+        meta = "SECS"
+    )]
+    queue_timeout: Option<u64>,
+    #[options(no_short, help = "PEM certificate to serve HTTPS with", meta = "FILE")]
+    cert: Option<String>,
+    #[options(no_short, help = "PEM private key of the certificate", meta = "FILE")]
+    key: Option<String>,
+    #[options(
+        no_short,
+        help = "Create a self-signed certificate and key if neither exists"
+    )]
+    self_signed: bool,
+    #[options(
+        no_short,
+        help = "TOML file with the tokens and users allowed in",
+        meta = "FILE"
+    )]
+    auth: Option<String>,
+    #[options(
+        no_short,
+        help = "Append a line about every finished job to this file",
+        meta = "FILE"
+    )]
+    audit: Option<String>,
+    #[options(
+        no_short,
+        help = "File to keep the pages scanned by every client in",
+        meta = "FILE"
+    )]
+    usage: Option<String>,
+    #[options(
+        no_short,
+        help = "Look for the devices again once the listed ones are this old",
+        meta = "SECS",
+        default = "300"
+    )]
+    device_cache_ttl: u64,
+}
+
+/// Where the server listens without `--listen`
+const DEFAULT_LISTEN: &str = "127.0.0.1:8080";
+
+#[cfg(feature = "server")]
+mod auth;
+#[cfg(feature = "escl")]
+mod escl;
+#[cfg(feature = "server")]
+mod openapi;
+#[cfg(feature = "server")]
+mod quota;
+#[cfg(feature = "tls")]
+mod tls;
+
+#[cfg(not(feature = "server"))]
+pub fn run(
+    _context: &Context,
+    _handle: &Handle,
+    _opts: &ServerOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    Err("skanny was built without the server feature".into())
+}
+
+#[cfg(feature = "server")]
+pub use imp::run;
+
+#[cfg(feature = "server")]
+mod imp {
+    use std::collections::BTreeMap;
+    use std::fs::{File, OpenOptions};
+    use std::io::{BufRead, BufReader, Write};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::Ordering;
+    use std::sync::mpsc::{channel, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use sane_sys::*;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use skanny::{Context, Handle, Opt, OptionValue};
+    use tiny_http::{
+        ConfigListenAddr, Header, Method, Request, Response, Server, ServerConfig, SslConfig,
+    };
+    use utoipa::ToSchema;
+
+    use super::auth::{Auth, Client, Role};
+    use super::quota::Usage;
+    use super::ServerOptions;
+    use crate::audit::Audit;
+    use crate::device_cache::DeviceCache;
+    use crate::jobs::{Job, JobObserver, JobQueue, JobState};
+    use crate::listen::{self, Address, Allow};
+    use crate::profile::{self, Profile};
+
+    pub(super) use crate::device_thread::{call as device_call, Task};
+
+    /// Single page web interface driving the API below
+    const INDEX: &str = include_str!("server/index.html");
+    /// Requests answered at once on every address
+    const WORKERS: usize = 16;
+    /// Largest request body read, in bytes
+    const MAX_BODY: u64 = 64 * 1024;
+
+    #[derive(Debug, Default, Deserialize, ToSchema)]
+    pub(super) struct JobRequest {
+        /// Scan profile to use, the defaults if not given
+        profile: Option<String>,
+        /// Options to set for the job, on top of the profile
+        #[serde(default)]
+        #[schema(value_type = BTreeMap<String, Object>)]
+        options: BTreeMap<String, OptionValue>,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub(super) struct Device {
+        name: String,
+        vendor: String,
+        model: String,
+        #[serde(rename = "type")]
+        type_: String,
+        /// Whether it is the device the server drives
+        open: bool,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub(super) struct DeviceOption {
+        name: String,
+        title: String,
+        desc: String,
+        /// `bool`, `int`, `fixed`, `string`, `button` or `group`
+        #[serde(rename = "type")]
+        type_: &'static str,
+        /// A `range` of `min`, `max` and `quant`, or a `list` of values
+        #[schema(value_type = Option<Object>)]
+        constraint: Option<serde_json::Value>,
+        settable: bool,
+        #[schema(value_type = Option<Object>)]
+        value: Option<OptionValue>,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub(super) struct OptionSet {
+        name: String,
+        #[schema(value_type = Option<Object>)]
+        value: Option<OptionValue>,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub(super) struct JobId {
+        id: usize,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub(super) struct JobStatus {
+        id: usize,
+        device: String,
+        profile: String,
+        state: JobState,
+        error: Option<String>,
+        /// URLs of the images
+        files: Vec<String>,
+        /// URLs of their thumbnails, where written
+        thumbnails: Vec<Option<String>>,
+        /// Where the images were uploaded to
+        locations: Vec<String>,
+        /// Place among the jobs waiting for the device, 1 if next
+        position: Option<usize>,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub(super) struct Paused {
+        paused: bool,
+    }
+
+    #[derive(Debug, Serialize, ToSchema)]
+    pub(super) struct Error {
+        error: String,
+    }
+
+    pub(super) struct State {
+        jobs: Arc<JobQueue>,
+        /// Name of the open device
+        pub(super) device: String,
+        profiles: BTreeMap<String, Profile>,
+        dir: PathBuf,
+        /// `https` if served with TLS, `http` otherwise
+        pub(super) scheme: &'static str,
+        /// Who is let in, anyone if `None`
+        auth: Option<Auth>,
+        pub(super) audit: Option<Arc<Audit>>,
+        pub(super) usage: Arc<Usage>,
+        devices: Arc<DeviceCache>,
+        #[cfg(feature = "escl")]
+        pub(super) escl: super::escl::Jobs,
+    }
+
+    pub fn run(
+        context: &Context,
+        handle: &Handle,
+        opts: &ServerOptions,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let profiles = match &opts.profiles {
+            Some(path) => profile::load(Path::new(path))?,
+            None => BTreeMap::new(),
+        };
+        let mut jobs = JobQueue::new(opts.workers);
+        jobs.bound(opts.max_queued, opts.queue_timeout.map(Duration::from_secs));
+        if let Some(path) = &opts.jobs {
+            let path = Path::new(path);
+            let previous = load_jobs(path)?;
+            jobs.observe(JobLog(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )));
+            jobs.restore(previous);
+        }
+        if let Some(path) = &opts.store {
+            crate::store::attach(&mut jobs, Path::new(path))?;
+        }
+        let audit = match &opts.audit {
+            Some(path) => {
+                let audit = Arc::new(Audit::open(Path::new(path), "server")?);
+                jobs.observe(Arc::clone(&audit));
+                Some(audit)
+            }
+            None => None,
+        };
+        let usage = Arc::new(Usage::load(opts.usage.as_deref().map(Path::new))?);
+        jobs.observe(Arc::clone(&usage));
+        let allowed = Arc::new(Allow::parse_all(&opts.allow)?);
+        let ssl = ssl_config(opts)?;
+        let auth = opts
+            .auth
+            .as_ref()
+            .map(|path| Auth::load(Path::new(path)))
+            .transpose()?;
+        let scheme = if ssl.is_some() { "https" } else { "http" };
+        let mut servers = Vec::new();
+        for address in Address::parse_all(&opts.listen, super::DEFAULT_LISTEN)? {
+            let addr = match &address {
+                Address::Tcp(addr) => ConfigListenAddr::from_socket_addrs(addr)?,
+                Address::Unix(path) => {
+                    if path.exists() {
+                        std::fs::remove_file(path)?;
+                    }
+                    ConfigListenAddr::unix_from_path(path)
+                }
+            };
+            let server = Server::new(ServerConfig {
+                addr,
+                ssl: ssl.clone(),
+            })
+            .map_err(|e| format!("Cannot listen on {}: {}", address, e))?;
+            servers.push((address, server));
+        }
+        let state = Arc::new(State {
+            jobs: Arc::new(jobs),
+            device: handle.name().to_owned(),
+            profiles,
+            dir: PathBuf::from(&opts.dir),
+            scheme,
+            auth,
+            audit,
+            usage,
+            devices: Arc::new(DeviceCache::new(
+                None,
+                Duration::from_secs(opts.device_cache_ttl),
+                false,
+            )),
+            #[cfg(feature = "escl")]
+            escl: Default::default(),
+        });
+
+        #[cfg(feature = "escl")]
+        let _advertisement = if opts.escl {
+            let port = servers
+                .iter()
+                .find_map(|(_, server)| server.server_addr().to_ip())
+                .map(|addr| addr.port())
+                .ok_or("eSCL must be served over TCP")?;
+            Some(super::escl::advertise(port, ssl.is_some())?)
+        } else {
+            None
+        };
+        #[cfg(not(feature = "escl"))]
+        {
+            if opts.escl {
+                return Err("skanny was built without the escl feature".into());
+            }
+        }
+
+        let (tasks, rx) = channel::<Task>();
+        let mut addresses = Vec::new();
+        for (address, server) in servers {
+            addresses.push(match address {
+                Address::Tcp(_) => format!("{}://{}", scheme, server.server_addr()),
+                Address::Unix(_) => address.to_string(),
+            });
+            // Scans take a long time, answer other requests meanwhile
+            let server = Arc::new(server);
+            for _ in 0..WORKERS {
+                let server = Arc::clone(&server);
+                let state = Arc::clone(&state);
+                let allowed = Arc::clone(&allowed);
+                let tasks = tasks.clone();
+                std::thread::spawn(move || {
+                    for request in server.incoming_requests() {
+                        let peer = request.remote_addr().map(|addr| addr.ip());
+                        let answered = if listen::allowed(&allowed, peer) {
+                            route(request, &state, &tasks)
+                        } else {
+                            tracing::warn!(peer = ?peer, "Request refused, not allowed");
+                            request.respond(error_response(403, "Not allowed"))
+                        };
+                        if let Err(e) = answered {
+                            tracing::warn!("Failed to respond: {}", e);
+                        }
+                    }
+                });
+            }
+        }
+
+        crate::pause::on_signals()?;
+        let stop = crate::stop_on_ctrlc();
+        let scanning = Arc::clone(&state.jobs);
+        crate::shutdown::cancel_after_drain(Arc::clone(&stop), move || scanning.cancel_scan());
+        tracing::info!(
+            "Listening on {}, interrupt with ctrl-c",
+            addresses.join(", ")
+        );
+        crate::device_thread::serve(context, handle, &rx, &stop);
+        if !state.jobs.drain(crate::shutdown::timeout()) {
+            tracing::warn!("Stopped with pages not stored or uploaded");
+        }
+        Ok(())
+    }
+
+    /// The certificate to serve HTTPS with, if one is given
+    #[cfg(feature = "tls")]
+    fn ssl_config(opts: &ServerOptions) -> Result<Option<SslConfig>, Box<dyn std::error::Error>> {
+        match (&opts.cert, &opts.key) {
+            (Some(cert), Some(key)) => {
+                super::tls::config(Path::new(cert), Path::new(key), opts.self_signed).map(Some)
+            }
+            (None, None) if !opts.self_signed => Ok(None),
+            _ => Err("HTTPS needs both --cert and --key".into()),
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn ssl_config(opts: &ServerOptions) -> Result<Option<SslConfig>, Box<dyn std::error::Error>> {
+        if opts.cert.is_some() || opts.key.is_some() || opts.self_signed {
+            return Err("skanny was built without the tls feature".into());
+        }
+        Ok(None)
+    }
+
+    pub(super) fn hostname() -> String {
+        std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|host| host.trim().to_owned())
+            .unwrap_or_else(|_| "skanny".to_owned())
+    }
+
+    /// Appends every change of a job to a file, one JSON object per line
+    struct JobLog(Mutex<File>);
+
+    impl JobObserver for JobLog {
+        fn changed(&self, job: &Job) {
+            let mut line = serde_json::to_string(job).unwrap();
+            line.push('\n');
+            let mut file = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                tracing::warn!("Saving job {} failed: {}", job.id, e);
+            }
+        }
+    }
+
+    /// Reads the last state of every job in a file written by [`JobLog`]
+    fn load_jobs(path: &Path) -> Result<Vec<Job>, Box<dyn std::error::Error>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut jobs = BTreeMap::new();
+        for line in BufReader::new(file).lines() {
+            let job: Job = serde_json::from_str(&line?)?;
+            jobs.insert(job.id, job);
+        }
+        Ok(jobs.into_values().collect())
+    }
+
+    pub(super) fn json_response(
+        status: u16,
+        body: &serde_json::Value,
+    ) -> Response<std::io::Cursor<Vec<u8>>> {
+        Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(
+                Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap(),
+            )
+    }
+
+    pub(super) fn error_response(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+        json_response(
+            status,
+            &json!(Error {
+                error: message.to_owned()
+            }),
+        )
+    }
+
+    /// The body of the request, `None` if it is larger than [`MAX_BODY`]
+    pub(super) fn read_body(request: &mut Request) -> std::io::Result<Option<String>> {
+        let mut body = String::new();
+        std::io::Read::read_to_string(
+            &mut std::io::Read::take(request.as_reader(), MAX_BODY + 1),
+            &mut body,
+        )?;
+        Ok((body.len() as u64 <= MAX_BODY).then_some(body))
+    }
+
+    pub(super) fn too_large() -> Response<std::io::Cursor<Vec<u8>>> {
+        error_response(413, &format!("The body is larger than {} bytes", MAX_BODY))
+    }
+
+    fn route(
+        mut request: Request,
+        state: &Arc<State>,
+        tasks: &Sender<Task>,
+    ) -> std::io::Result<()> {
+        let url = request.url().to_owned();
+        let path: Vec<&str> = url
+            .split('?')
+            .next()
+            .unwrap_or("")
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        // Named after how it authenticated, or its address
+        let client: Client = match &state.auth {
+            Some(auth) => {
+                let authorization = request
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv("Authorization"))
+                    .map(|h| h.value.as_str());
+                match auth.client(authorization) {
+                    None => return request.respond(super::auth::challenge()),
+                    Some(client)
+                        if client.role < super::auth::required(request.method(), &path) =>
+                    {
+                        return request.respond(error_response(403, "Not allowed"))
+                    }
+                    Some(client) => client,
+                }
+            }
+            // Anyone may do anything
+            None => Client {
+                name: request
+                    .remote_addr()
+                    .map_or_else(|| "local".to_owned(), |addr| addr.ip().to_string()),
+                role: Role::Admin,
+                quota: Default::default(),
+            },
+        };
+
+        match (request.method(), path.as_slice()) {
+            (Method::Get, []) => request.respond(Response::from_string(INDEX).with_header(
+                Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap(),
+            )),
+            (Method::Get, ["devices"]) => {
+                let cache = Arc::clone(&state.devices);
+                let refresh = url.split_once('?').is_some_and(|(_, query)| {
+                    query
+                        .split('&')
+                        .any(|param| matches!(param, "refresh" | "refresh=true"))
+                });
+                on_device(request, tasks, move |context, handle| {
+                    if refresh {
+                        cache.forget();
+                    }
+                    let devices = cache.devices(context, None).map_err(|e| e.to_string())?;
+                    Ok(devices
+                        .into_iter()
+                        .map(|device| {
+                            json!(Device {
+                                open: device.name == handle.name(),
+                                name: device.name,
+                                vendor: device.vendor,
+                                model: device.model,
+                                type_: device.type_,
+                            })
+                        })
+                        .collect())
+                })
+            }
+            (Method::Get, ["options"]) => on_device(request, tasks, |_, handle| {
+                Ok(handle
+                    .options()
+                    .filter(|opt| !opt.name().is_empty())
+                    .map(|opt| json!(describe(&opt)))
+                    .collect())
+            }),
+            (Method::Put, ["options", name]) => {
+                let name = (*name).to_owned();
+                let body = match read_body(&mut request)? {
+                    Some(body) => body,
+                    None => return request.respond(too_large()),
+                };
+                let value: OptionValue = match serde_json::from_str(&body) {
+                    Ok(value) => value,
+                    Err(e) => return request.respond(error_response(400, &e.to_string())),
+                };
+                on_device(request, tasks, move |_, handle| {
+                    let opt = handle
+                        .option(&name)
+                        .ok_or_else(|| format!("No option named {}", name))?;
+                    opt.set_value(&value).map_err(|e| e.to_string())?;
+                    Ok(json!(OptionSet {
+                        value: opt.get_value().ok(),
+                        name,
+                    }))
+                })
+            }
+            (Method::Post, ["jobs"]) => {
+                let body = match read_body(&mut request)? {
+                    Some(body) => body,
+                    None => return request.respond(too_large()),
+                };
+                let job: JobRequest = if body.trim().is_empty() {
+                    JobRequest::default()
+                } else {
+                    match serde_json::from_str(&body) {
+                        Ok(job) => job,
+                        Err(e) => return request.respond(error_response(400, &e.to_string())),
+                    }
+                };
+                let mut profile = match &job.profile {
+                    Some(name) => match state.profiles.get(name) {
+                        Some(profile) => profile.clone(),
+                        None => {
+                            return request.respond(error_response(
+                                404,
+                                &format!("No profile named {}", name),
+                            ))
+                        }
+                    },
+                    None => Profile::default(),
+                };
+                profile.requested.extend(job.options);
+                if profile.dir.is_none() {
+                    profile.dir = Some(state.dir.clone());
+                }
+
+                let name = job.profile.as_deref().unwrap_or("default");
+                let submitted = state
+                    .usage
+                    .admit(&client.name, &client.quota)
+                    .and_then(|()| {
+                        state.jobs.submit(
+                            &state.device,
+                            name,
+                            &profile.requested,
+                            Some(&client.name),
+                        )
+                    });
+                let id = match submitted {
+                    Ok(id) => id,
+                    Err(e) => return request.respond(error_response(429, &e)),
+                };
+                let jobs = Arc::clone(&state.jobs);
+                let task: Task = Box::new(move |_, handle| {
+                    // Progress is followed through the job
+                    let _ = jobs.run(id, &profile, handle);
+                });
+                if tasks.send(task).is_err() {
+                    return request.respond(error_response(503, "Device is shutting down"));
+                }
+                request.respond(json_response(202, &json!(JobId { id })))
+            }
+            (Method::Get, ["jobs", id]) => match job_of(state, &client, id) {
+                Some(job) => {
+                    let files: Vec<String> = (0..job.files.len())
+                        .map(|n| format!("/jobs/{}/files/{}", job.id, n))
+                        .collect();
+                    let thumbnails: Vec<Option<String>> = job
+                        .files
+                        .iter()
+                        .zip(&files)
+                        .map(|(path, file)| {
+                            crate::thumbnail::path(path)
+                                .exists()
+                                .then(|| format!("{}/thumbnail", file))
+                        })
+                        .collect();
+                    let body = JobStatus {
+                        position: state.jobs.position(job.id),
+                        id: job.id,
+                        device: job.device,
+                        profile: job.profile,
+                        state: job.state,
+                        error: job.error,
+                        files,
+                        thumbnails,
+                        locations: job.locations,
+                    };
+                    request.respond(json_response(200, &json!(body)))
+                }
+                None => request.respond(error_response(404, "No such job")),
+            },
+            (Method::Post, ["jobs", id, "skip"]) => match job_of(state, &client, id) {
+                Some(job) if state.jobs.skip(job.id) => {
+                    request.respond(json_response(202, &json!(JobId { id: job.id })))
+                }
+                Some(_) => request.respond(error_response(409, "The job is not scanning")),
+                None => request.respond(error_response(404, "No such job")),
+            },
+            (Method::Get, ["jobs", id, "files", n, rest @ ..]) if rest.len() <= 1 => {
+                let file = {
+                    let n = n.parse::<usize>().ok();
+                    job_of(state, &client, id)
+                        .and_then(|job| n.and_then(|n| job.files.get(n).cloned()))
+                };
+                let file = match rest {
+                    ["thumbnail"] => file.map(|file| crate::thumbnail::path(&file)),
+                    _ => file,
+                };
+                let path = match file {
+                    Some(path) => path,
+                    None => return request.respond(error_response(404, "No such file")),
+                };
+                let content_type = crate::destination::content_type(&path);
+                match std::fs::File::open(&path) {
+                    Ok(file) => request.respond(Response::from_file(file).with_header(
+                        Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap(),
+                    )),
+                    Err(e) => request.respond(error_response(500, &e.to_string())),
+                }
+            }
+            (Method::Post, ["pause"]) => {
+                crate::pause::pause();
+                request.respond(json_response(200, &json!(Paused { paused: true })))
+            }
+            (Method::Post, ["resume"]) => {
+                crate::pause::resume();
+                request.respond(json_response(200, &json!(Paused { paused: false })))
+            }
+            (Method::Get, ["metrics"]) => {
+                let stats = match crate::stats::default_path() {
+                    Some(path) => crate::stats::load(&path),
+                    None => Ok(Default::default()),
+                };
+                match stats {
+                    Ok(stats) => request.respond(
+                        Response::from_string(crate::stats::metrics(&stats)).with_header(
+                            Header::from_bytes(
+                                &b"Content-Type"[..],
+                                &b"text/plain; version=0.0.4"[..],
+                            )
+                            .unwrap(),
+                        ),
+                    ),
+                    Err(e) => request.respond(error_response(500, &e.to_string())),
+                }
+            }
+            (Method::Get, ["usage"]) => request.respond(json_response(
+                200,
+                &json!(state.usage.of(&client.name, client.quota)),
+            )),
+            (Method::Get, ["usage", "clients"]) => {
+                let quotas = state.auth.as_ref();
+                let usage: Vec<_> = state
+                    .usage
+                    .clients()
+                    .into_iter()
+                    .map(|name| {
+                        let quota = quotas.map_or_else(Default::default, |auth| auth.quota(&name));
+                        state.usage.of(&name, quota)
+                    })
+                    .collect();
+                request.respond(json_response(200, &json!(usage)))
+            }
+            (Method::Get, ["openapi.json"]) => {
+                request.respond(json_response(200, &super::openapi::spec()))
+            }
+            #[cfg(feature = "escl")]
+            (_, ["eSCL", rest @ ..]) => super::escl::route(request, rest, state, tasks, &client),
+            _ => request.respond(error_response(404, "Not found")),
+        }
+    }
+
+    /// The job with the id given, if the client may see it, as if it did
+    /// not exist otherwise
+    fn job_of(state: &State, client: &Client, id: &str) -> Option<Job> {
+        id.parse()
+            .ok()
+            .and_then(|id| state.jobs.get(id))
+            .filter(|job| client.may_see(job.client.as_deref()))
+    }
+
+    /// Describes an option with enough detail to generate a form for it
+    fn describe(opt: &Opt) -> DeviceOption {
+        let descriptor = opt.descriptor();
+        let fixed = descriptor.type_() == SANE_Value_Type_SANE_TYPE_FIXED;
+        let word = |w: SANE_Word| {
+            if fixed {
+                json!(SANE_UNFIX(w))
+            } else {
+                json!(w)
+            }
+        };
+        #[allow(non_upper_case_globals)]
+        let type_ = match descriptor.type_() {
+            SANE_Value_Type_SANE_TYPE_BOOL => "bool",
+            SANE_Value_Type_SANE_TYPE_INT => "int",
+            SANE_Value_Type_SANE_TYPE_FIXED => "fixed",
+            SANE_Value_Type_SANE_TYPE_STRING => "string",
+            SANE_Value_Type_SANE_TYPE_BUTTON => "button",
+            _ => "group",
+        };
+        #[allow(non_upper_case_globals)]
+        let constraint = match descriptor.constraint_type() {
+            SANE_Constraint_Type_SANE_CONSTRAINT_RANGE => opt.get_range().ok().map(|range| {
+                json!({ "range": {
+                    "min": word(range.min()),
+                    "max": word(range.max()),
+                    "quant": word(range.quant()),
+                } })
+            }),
+            SANE_Constraint_Type_SANE_CONSTRAINT_WORD_LIST => opt
+                .int_constraints()
+                .ok()
+                .map(|list| json!({ "list": list.iter().map(|&w| word(w)).collect::<Vec<_>>() })),
+            SANE_Constraint_Type_SANE_CONSTRAINT_STRING_LIST => opt
+                .string_constraints()
+                .ok()
+                .map(|list| json!({ "list": list.collect::<Vec<_>>() })),
+            _ => None,
+        };
+        let cap = descriptor.cap() as u32;
+        DeviceOption {
+            name: opt.name().to_owned(),
+            title: opt.title().to_owned(),
+            desc: opt.desc().to_owned(),
+            type_,
+            constraint,
+            settable: cap & SANE_CAP_SOFT_SELECT != 0 && cap & SANE_CAP_INACTIVE == 0,
+            value: opt.get_value().ok(),
+        }
+    }
+
+    /// Runs `f` on the device thread and responds with its result
+    fn on_device<F>(request: Request, tasks: &Sender<Task>, f: F) -> std::io::Result<()>
+    where
+        F: FnOnce(&Context, &Handle) -> Result<serde_json::Value, String> + Send + 'static,
+    {
+        match device_call(tasks, f) {
+            Some(Ok(body)) => request.respond(json_response(200, &body)),
+            Some(Err(e)) => request.respond(error_response(400, &e)),
+            None => request.respond(error_response(503, "Device is shutting down")),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use tiny_http::TestRequest;
+
+        #[test]
+        fn refuses_large_bodies() {
+            let body = |body: &'static str| {
+                let mut request = Request::from(TestRequest::new().with_body(body));
+                read_body(&mut request).unwrap()
+            };
+            assert_eq!(body("{}").as_deref(), Some("{}"));
+            let limit = "x".repeat(MAX_BODY as usize);
+            assert_eq!(body(Box::leak(limit.clone().into_boxed_str())), Some(limit));
+            let larger = "x".repeat(MAX_BODY as usize + 1);
+            assert_eq!(body(Box::leak(larger.into_boxed_str())), None);
+        }
+    }
+}
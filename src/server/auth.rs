@@ -0,0 +1,381 @@
+//! Authentication of the clients of the server
+//!
+//! Without `--auth FILE` anyone reaching the server may scan. With it every
+//! request has to carry a token as `Authorization: Bearer TOKEN`, or the
+//! name and password of a user through HTTP basic authentication, which
+//! browsers ask for when the web interface is opened:
+//!
+//! ```toml
+//! [[tokens]]
+//! token = "c2a8f5e0b1d94c7e9a13"
+//! name = "scanner-kiosk"
+//! role = "admin"
+//!
+//! [[users]]
+//! name = "office"
+//! # printf %s PASSWORD | argon2 "$(head -c 16 /dev/urandom | base64)" -id -e
+//! password_hash = "$argon2id$v=19$m=19456,t=2,p=1$c2thbm55LW9mZmljZS0wMQ$S8WhOJiHRPKAx0wLSlVvCaxI/LfoNrfYHHCdY2/V0JM"
+//!
+//! [oidc]
+//! userinfo = "https://id.example.com/userinfo"
+//! admins = ["alice@example.com"]
+//! ```
+//!
+//! Passwords are kept as salted Argon2 hashes in the PHC string format, as
+//! printed by the `argon2` tool above. Hashing is slow on purpose, so
+//! credentials which matched are remembered for a minute rather than hashed
+//! again for every request of a browser.
+//!
+//! The `scan` role, the default, may scan, change the options of the device
+//! and fetch the results of its own jobs. `admin` may also see the jobs of
+//! other clients, pause and resume the jobs and read the metrics. With `[oidc]` other bearer tokens are checked against
+//! the userinfo endpoint of an OpenID Connect provider, which needs the
+//! `oidc` feature. Users whose `email` or `sub` is in `admins` are admins.
+//!
+//! The audit log names clients by their user name, token name, or `email`
+//! or `sub`. Tokens without a name are named after the start of their
+//! SHA-256 hash, see [`crate::audit`].
+//!
+//! Tokens and users may be given a `quota`, and `[quota]` sets one for all
+//! other clients, see [`super::quota`].
+//!
+//! eSCL clients have to support basic authentication to scan.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::Argon2;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tiny_http::{Header, Method, Response};
+
+use super::quota::Quota;
+
+/// What a client may do
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Scan,
+    Admin,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Token {
+    token: String,
+    name: Option<String>,
+    #[serde(default)]
+    role: Role,
+    quota: Option<Quota>,
+}
+
+impl Token {
+    /// Its name, or the start of its hash
+    fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("token {}", &sha256_hex(&self.token)[..8]),
+        }
+    }
+}
+
+/// A client which authenticated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Client {
+    pub name: String,
+    pub role: Role,
+    pub quota: Quota,
+}
+
+impl Client {
+    /// Whether the client may see the job submitted by `owner`, only its
+    /// own ones unless it is an admin
+    pub fn may_see(&self, owner: Option<&str>) -> bool {
+        self.role == Role::Admin || owner == Some(self.name.as_str())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct User {
+    name: String,
+    /// Argon2 hash of the password in the PHC string format
+    password_hash: String,
+    #[serde(default)]
+    role: Role,
+    quota: Option<Quota>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Oidc {
+    userinfo: String,
+    #[serde(default)]
+    admins: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Auth {
+    #[serde(default)]
+    tokens: Vec<Token>,
+    #[serde(default)]
+    users: Vec<User>,
+    oidc: Option<Oidc>,
+    /// Quota of the clients without their own
+    quota: Option<Quota>,
+    /// Clients the provider knew by bearer tokens, `None` for rejected ones
+    #[serde(skip)]
+    checked: Mutex<HashMap<String, (Instant, Option<Client>)>>,
+    /// When the SHA-256 of basic credentials last matched a password hash
+    #[serde(skip)]
+    verified: Mutex<HashMap<String, Instant>>,
+}
+
+/// How long the checked credentials are remembered
+const REMEMBERED: std::time::Duration = std::time::Duration::from_secs(60);
+
+fn sha256_hex(s: &str) -> String {
+    Sha256::digest(s.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Compares the digests, which takes as long wherever the secrets differ
+fn same_secret(a: &str, b: &str) -> bool {
+    Sha256::digest(a.as_bytes()) == Sha256::digest(b.as_bytes())
+}
+
+impl Auth {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let contents = std::fs::read_to_string(path)?;
+        let auth: Auth = toml::from_str(&contents)?;
+        for user in &auth.users {
+            PasswordHash::new(&user.password_hash)
+                .map_err(|e| format!("The password hash of {}: {}", user.name, e))?;
+        }
+        if auth.oidc.is_some() && !cfg!(feature = "oidc") {
+            return Err("skanny was built without the oidc feature".into());
+        }
+        Ok(auth)
+    }
+
+    /// The client sending the `Authorization` header given, if it is known
+    pub fn client(&self, authorization: Option<&str>) -> Option<Client> {
+        let (scheme, credentials) = authorization?.trim().split_once(' ')?;
+        let credentials = credentials.trim();
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            let known = self
+                .tokens
+                .iter()
+                .find(|token| same_secret(&token.token, credentials))
+                .map(|token| Client {
+                    name: token.name(),
+                    role: token.role,
+                    quota: token.quota.or(self.quota).unwrap_or_default(),
+                });
+            return known.or_else(|| self.check(credentials));
+        }
+        if scheme.eq_ignore_ascii_case("Basic") {
+            use base64::Engine;
+            let decoded = base64::engine::general_purpose::STANDARD
+                .decode(credentials)
+                .ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (name, password) = decoded.split_once(':')?;
+            let user = self.users.iter().find(|user| user.name == name)?;
+            if !self.verify(user, password, &decoded) {
+                return None;
+            }
+            return Some(Client {
+                name: user.name.clone(),
+                role: user.role,
+                quota: user.quota.or(self.quota).unwrap_or_default(),
+            });
+        }
+        None
+    }
+
+    /// Whether `password` is the one of `user`, remembering the matching
+    /// `credentials` by their SHA-256 so the slow hash is not computed again
+    fn verify(&self, user: &User, password: &str, credentials: &str) -> bool {
+        let key = sha256_hex(credentials);
+        if let Some(at) = self.verified.lock().unwrap().get(&key) {
+            if at.elapsed() < REMEMBERED {
+                return true;
+            }
+        }
+        let matches = PasswordHash::new(&user.password_hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        });
+        if matches {
+            let mut verified = self.verified.lock().unwrap();
+            verified.retain(|_, at| at.elapsed() < REMEMBERED);
+            verified.insert(key, Instant::now());
+        }
+        matches
+    }
+
+    /// The quota of the client with the name given
+    pub fn quota(&self, name: &str) -> Quota {
+        let own = match self.tokens.iter().find(|token| token.name() == name) {
+            Some(token) => token.quota,
+            None => self
+                .users
+                .iter()
+                .find(|user| user.name == name)
+                .and_then(|user| user.quota),
+        };
+        own.or(self.quota).unwrap_or_default()
+    }
+
+    /// Asks the OpenID Connect provider about a bearer token, remembering
+    /// the answer for a minute
+    #[cfg(feature = "oidc")]
+    fn check(&self, token: &str) -> Option<Client> {
+        let oidc = self.oidc.as_ref()?;
+        if let Some((at, client)) = self.checked.lock().unwrap().get(token) {
+            if at.elapsed() < REMEMBERED {
+                return client.clone();
+            }
+        }
+        let client = match ureq::get(&oidc.userinfo)
+            .set("Authorization", &format!("Bearer {}", token))
+            .call()
+        {
+            Ok(response) => {
+                let info: serde_json::Value = response
+                    .into_string()
+                    .ok()
+                    .and_then(|body| serde_json::from_str(&body).ok())?;
+                let admin = ["email", "sub"].iter().any(|claim| {
+                    info[claim]
+                        .as_str()
+                        .is_some_and(|id| oidc.admins.iter().any(|admin| admin == id))
+                });
+                let name = info["email"].as_str().or(info["sub"].as_str());
+                Some(Client {
+                    name: name.unwrap_or("unknown").to_owned(),
+                    role: if admin { Role::Admin } else { Role::Scan },
+                    quota: self.quota.unwrap_or_default(),
+                })
+            }
+            Err(ureq::Error::Status(_, _)) => None,
+            Err(e) => {
+                // Not remembered, the provider may be back for the next request
+                tracing::warn!("Checking a token with {} failed: {}", oidc.userinfo, e);
+                return None;
+            }
+        };
+        let mut checked = self.checked.lock().unwrap();
+        checked.retain(|_, (at, _)| at.elapsed() < REMEMBERED);
+        checked.insert(token.to_owned(), (Instant::now(), client.clone()));
+        client
+    }
+
+    #[cfg(not(feature = "oidc"))]
+    fn check(&self, _token: &str) -> Option<Client> {
+        None
+    }
+}
+
+/// The role needed for a request
+pub fn required(method: &Method, path: &[&str]) -> Role {
+    match (method, path) {
+        (Method::Post, ["pause"])
+        | (Method::Post, ["resume"])
+        | (Method::Get, ["metrics"])
+        | (Method::Get, ["usage", "clients"]) => Role::Admin,
+        _ => Role::Scan,
+    }
+}
+
+/// Asks the client to authenticate
+pub fn challenge() -> Response<std::io::Cursor<Vec<u8>>> {
+    super::imp::error_response(401, "Authentication required").with_header(
+        Header::from_bytes(&b"WWW-Authenticate"[..], &br#"Basic realm="skanny""#[..]).unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn knows_tokens_and_users() {
+        let auth: Auth = toml::from_str(
+            r#"
+            [[tokens]]
+            token = "c2a8f5e0b1d94c7e9a13"
+            role = "admin"
+
+            [[tokens]]
+            token = "5b0d2e7f"
+            name = "kiosk"
+            quota = { jobs_per_minute = 2 }
+
+            [[users]]
+            name = "office"
+            password_hash = '$argon2id$v=19$m=19456,t=2,p=1$c2thbm55LW9mZmljZS0wMQ$S8WhOJiHRPKAx0wLSlVvCaxI/LfoNrfYHHCdY2/V0JM'
+
+            [quota]
+            pages_per_month = 500
+            "#,
+        )
+        .unwrap();
+        let role = |authorization| auth.client(authorization).map(|client| client.role);
+        assert_eq!(role(Some("Bearer c2a8f5e0b1d94c7e9a13")), Some(Role::Admin));
+        assert_eq!(
+            auth.client(Some("Bearer c2a8f5e0b1d94c7e9a13"))
+                .unwrap()
+                .name,
+            format!("token {}", &sha256_hex("c2a8f5e0b1d94c7e9a13")[..8])
+        );
+        let kiosk = auth.client(Some("Bearer 5b0d2e7f")).unwrap();
+        assert_eq!(kiosk.name, "kiosk");
+        assert_eq!(kiosk.quota.jobs_per_minute, Some(2));
+        assert_eq!(kiosk.quota.pages_per_month, None);
+        assert_eq!(auth.quota("kiosk"), kiosk.quota);
+        assert_eq!(auth.quota("alice@example.com").pages_per_month, Some(500));
+        assert_eq!(role(Some("Bearer c2a8f5e0b1d94c7e9a14")), None);
+        // office:secret
+        assert_eq!(
+            auth.client(Some("Basic b2ZmaWNlOnNlY3JldA==")),
+            Some(Client {
+                name: "office".to_owned(),
+                role: Role::Scan,
+                quota: Quota {
+                    pages_per_month: Some(500),
+                    jobs_per_minute: None
+                }
+            })
+        );
+        // office:wrong
+        assert_eq!(role(Some("Basic b2ZmaWNlOndyb25n")), None);
+        assert_eq!(role(Some("Digest x")), None);
+        assert_eq!(role(None), None);
+
+        // Jobs are only shown to whoever submitted them, and the admins
+        let office = auth.client(Some("Basic b2ZmaWNlOnNlY3JldA==")).unwrap();
+        assert!(office.may_see(Some("office")));
+        assert!(!kiosk.may_see(Some("office")));
+        assert!(!kiosk.may_see(None));
+        let admin = auth.client(Some("Bearer c2a8f5e0b1d94c7e9a13")).unwrap();
+        assert!(admin.may_see(Some("office")));
+        assert!(admin.may_see(None));
+
+        assert_eq!(required(&Method::Post, &["jobs"]), Role::Scan);
+        assert_eq!(required(&Method::Put, &["options", "mode"]), Role::Scan);
+        assert_eq!(required(&Method::Post, &["pause"]), Role::Admin);
+        assert_eq!(required(&Method::Get, &["usage"]), Role::Scan);
+        assert_eq!(required(&Method::Get, &["usage", "clients"]), Role::Admin);
+        assert!(Role::Admin > Role::Scan);
+    }
+}
@@ -0,0 +1,538 @@
+//! Server side of the eSCL (AirScan) protocol
+//!
+//! Implements the subset of the protocol used by the stock clients in
+//! macOS, Windows, Android and sane-airscan:
+//!
+//! * `GET /eSCL/ScannerCapabilities`
+//! * `GET /eSCL/ScannerStatus`
+//! * `POST /eSCL/ScanJobs` with a `ScanSettings` document
+//! * `GET /eSCL/ScanJobs/ID/NextDocument`
+//! * `DELETE /eSCL/ScanJobs/ID`
+//!
+//! Pages are only acquired once the client asks for the next document.
+//! Jobs which ended are listed in the status for a minute and then
+//! forgotten. Deleting a job which has not ended counts the pages the
+//! client fetched of it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use sane_sys::*;
+use skanny::escl::{element, NAMESPACES, UNITS_PER_MM, VERSION};
+use skanny::{Handle, OptionValue};
+use tiny_http::{Header, Method, Request, Response};
+
+use super::auth::Client;
+use super::imp::{device_call, error_response, hostname, read_body, too_large, State, Task};
+use crate::audit::Record;
+use crate::jobs::JobState;
+use crate::profile::Profile;
+
+#[derive(Default)]
+pub struct Jobs {
+    jobs: Mutex<BTreeMap<String, Job>>,
+    counter: AtomicUsize,
+}
+
+struct Job {
+    settings: Profile,
+    format: String,
+    feeder: bool,
+    pages: usize,
+    /// When the job ended, if it did
+    ended: Option<Instant>,
+    /// Who created the job, for the audit log
+    client: String,
+}
+
+impl Job {
+    fn done(&self) -> bool {
+        self.ended.is_some()
+    }
+}
+
+/// How long jobs which ended stay in the status
+const KEEP_ENDED: Duration = Duration::from_secs(60);
+
+/// Forgets the jobs which ended a while ago
+fn forget_ended(jobs: &mut BTreeMap<String, Job>, now: Instant) {
+    jobs.retain(|_, job| {
+        job.ended
+            .is_none_or(|ended| now.duration_since(ended) < KEEP_ENDED)
+    });
+}
+
+/// What the device can do, in the terms of eSCL
+struct Capabilities {
+    resolutions: Vec<SANE_Int>,
+    color_modes: Vec<&'static str>,
+    platen: bool,
+    feeder: bool,
+    max_width: u32,
+    max_height: u32,
+}
+
+/// Announces the service as `_uscan._tcp` until the returned daemon is dropped
+pub fn advertise(
+    port: u16,
+    tls: bool,
+) -> Result<mdns_sd::ServiceDaemon, Box<dyn std::error::Error>> {
+    let host = hostname();
+    let txt: HashMap<String, String> = [
+        ("txtvers", "1"),
+        ("vers", VERSION),
+        ("rs", "eSCL"),
+        ("ty", "skanny"),
+        ("pdl", "image/jpeg,image/png"),
+        ("cs", "color,grayscale"),
+        ("is", "platen,adf"),
+        ("uuid", &uuid(&host, 0)),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+
+    let mdns = mdns_sd::ServiceDaemon::new()?;
+    let info = mdns_sd::ServiceInfo::new(
+        // Clients look for scanners with TLS under their own service type
+        if tls {
+            "_uscans._tcp.local."
+        } else {
+            "_uscan._tcp.local."
+        },
+        &format!("skanny on {}", host),
+        &format!("{}.local.", host),
+        (),
+        port,
+        txt,
+    )?
+    .enable_addr_auto();
+    mdns.register(info)?;
+    Ok(mdns)
+}
+
+pub fn route(
+    mut request: Request,
+    path: &[&str],
+    state: &Arc<State>,
+    tasks: &Sender<Task>,
+    client: &Client,
+) -> std::io::Result<()> {
+    match (request.method(), path) {
+        (Method::Get, ["ScannerCapabilities"]) => {
+            match device_call(tasks, |_, handle| capabilities(handle)) {
+                Some(caps) => request.respond(xml_response(200, capabilities_xml(&caps))),
+                None => request.respond(error_response(503, "Device is shutting down")),
+            }
+        }
+        (Method::Get, ["ScannerStatus"]) => {
+            let mut jobs = state.escl.jobs.lock().unwrap();
+            forget_ended(&mut jobs, Instant::now());
+            let mut xml = String::new();
+            let busy = jobs.values().any(|job| !job.done());
+            let _ = write!(
+                xml,
+                r#"<?xml version="1.0" encoding="UTF-8"?><scan:ScannerStatus {}><pwg:Version>{}</pwg:Version><pwg:State>{}</pwg:State><scan:Jobs>"#,
+                NAMESPACES,
+                VERSION,
+                if busy { "Processing" } else { "Idle" }
+            );
+            for (id, job) in jobs.iter() {
+                let _ = write!(
+                    xml,
+                    "<scan:JobInfo><pwg:JobUri>/eSCL/ScanJobs/{id}</pwg:JobUri><pwg:JobUuid>{id}</pwg:JobUuid><pwg:JobState>{}</pwg:JobState><pwg:ImagesCompleted>{}</pwg:ImagesCompleted></scan:JobInfo>",
+                    if job.done() { "Completed" } else { "Processing" },
+                    job.pages,
+                    id = id,
+                );
+            }
+            xml.push_str("</scan:Jobs></scan:ScannerStatus>");
+            request.respond(xml_response(200, xml))
+        }
+        (Method::Post, ["ScanJobs"]) => {
+            let body = match read_body(&mut request)? {
+                Some(body) => body,
+                None => return request.respond(too_large()),
+            };
+            let caps = match device_call(tasks, |_, handle| capabilities(handle)) {
+                Some(caps) => caps,
+                None => return request.respond(error_response(503, "Device is shutting down")),
+            };
+            let mut job = match parse_settings(&body, &caps) {
+                Some(job) => job,
+                None => return request.respond(error_response(409, "Unsupported scan settings")),
+            };
+            if let Err(e) = state.usage.admit(&client.name, &client.quota) {
+                return request.respond(error_response(429, &e));
+            }
+            job.client = client.name.clone();
+
+            let counter = state.escl.counter.fetch_add(1, Ordering::SeqCst) + 1;
+            let id = uuid(&hostname(), counter);
+            let mut jobs = state.escl.jobs.lock().unwrap();
+            forget_ended(&mut jobs, Instant::now());
+            jobs.insert(id.clone(), job);
+            drop(jobs);
+
+            let location = match request.headers().iter().find(|h| h.field.equiv("Host")) {
+                Some(host) => format!("{}://{}/eSCL/ScanJobs/{}", state.scheme, host.value, id),
+                None => format!("/eSCL/ScanJobs/{}", id),
+            };
+            request.respond(
+                Response::empty(201).with_header(
+                    Header::from_bytes(&b"Location"[..], location.as_bytes()).unwrap(),
+                ),
+            )
+        }
+        (Method::Get, ["ScanJobs", id, "NextDocument"]) => {
+            let (settings, format, first) = {
+                let jobs = state.escl.jobs.lock().unwrap();
+                match jobs.get(*id) {
+                    Some(job) if !job.done() && (job.feeder || job.pages == 0) => {
+                        (job.settings.clone(), job.format.clone(), job.pages == 0)
+                    }
+                    _ => return request.respond(Response::empty(404)),
+                }
+            };
+
+            let output = if format == "image/png" {
+                image::ImageOutputFormat::Png
+            } else {
+                image::ImageOutputFormat::Jpeg(90)
+            };
+            let page = device_call(tasks, move |_, handle| -> Result<Option<Vec<u8>>, String> {
+                if first {
+                    settings.apply(handle).map_err(|e| e.to_string())?;
+                }
+                let acquisition = match handle.start() {
+                    Ok(acquisition) => acquisition,
+                    Err(e) if e.is_no_docs() && !first => return Ok(None),
+                    Err(e) => return Err(e.to_string()),
+                };
+                let image = acquisition.get_image().map_err(|e| e.to_string())?;
+                let mut bytes = Vec::new();
+                image
+                    .write_to(&mut bytes, output)
+                    .map_err(|e| e.to_string())?;
+                Ok(Some(bytes))
+            });
+
+            let mut jobs = state.escl.jobs.lock().unwrap();
+            let job = match jobs.get_mut(*id) {
+                Some(job) => job,
+                None => return request.respond(Response::empty(404)),
+            };
+            match page {
+                Some(Ok(Some(bytes))) => {
+                    job.pages += 1;
+                    if !job.feeder {
+                        ended(state, job, None);
+                    }
+                    request.respond(Response::from_data(bytes).with_header(
+                        Header::from_bytes(&b"Content-Type"[..], format.as_bytes()).unwrap(),
+                    ))
+                }
+                Some(Ok(None)) => {
+                    ended(state, job, None);
+                    request.respond(Response::empty(404))
+                }
+                Some(Err(e)) => {
+                    ended(state, job, Some(e.as_str()));
+                    request.respond(error_response(503, &e))
+                }
+                None => request.respond(error_response(503, "Device is shutting down")),
+            }
+        }
+        (Method::Delete, ["ScanJobs", id]) => match state.escl.jobs.lock().unwrap().remove(*id) {
+            Some(mut job) => {
+                // The pages fetched so far count against the quota
+                if !job.done() {
+                    ended(state, &mut job, Some("Cancelled by the client"));
+                }
+                request.respond(Response::empty(200))
+            }
+            None => request.respond(Response::empty(404)),
+        },
+        _ => request.respond(error_response(404, "Not found")),
+    }
+}
+
+/// Ends a job, counts it and logs it to the audit log, if there is one.
+/// The pages only went to the client.
+fn ended(state: &State, job: &mut Job, error: Option<&str>) {
+    job.ended = Some(Instant::now());
+    state.usage.record(&job.client, job.pages);
+    if let Some(audit) = &state.audit {
+        audit.record(&Record {
+            client: &job.client,
+            device: &state.device,
+            profile: "eSCL",
+            state: if error.is_some() {
+                JobState::Failed
+            } else {
+                JobState::Done
+            },
+            error,
+            pages: job.pages,
+            outputs: vec!["client".to_owned()],
+        });
+    }
+}
+
+fn xml_response(status: u16, body: String) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(body)
+        .with_status_code(status)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/xml"[..]).unwrap())
+}
+
+fn capabilities(handle: &Handle) -> Capabilities {
+    let mut caps = Capabilities {
+        resolutions: Vec::new(),
+        color_modes: Vec::new(),
+        platen: true,
+        feeder: false,
+        max_width: (215.9 * UNITS_PER_MM) as u32,
+        max_height: (297.0 * UNITS_PER_MM) as u32,
+    };
+
+    for opt in handle.options() {
+        #[allow(non_upper_case_globals)]
+        match (opt.name(), opt.descriptor().constraint_type()) {
+            ("resolution", SANE_Constraint_Type_SANE_CONSTRAINT_RANGE) => {
+                let range = opt.get_range().unwrap();
+                let (min, max) = if opt.descriptor().type_() == SANE_Value_Type_SANE_TYPE_FIXED {
+                    (
+                        SANE_UNFIX(range.min()) as SANE_Int,
+                        SANE_UNFIX(range.max()) as SANE_Int,
+                    )
+                } else {
+                    (range.min(), range.max())
+                };
+                caps.resolutions = [75, 100, 150, 200, 300, 600, 1200]
+                    .iter()
+                    .copied()
+                    .filter(|res| (min..=max).contains(res))
+                    .collect();
+            }
+            ("resolution", SANE_Constraint_Type_SANE_CONSTRAINT_WORD_LIST) => {
+                let list = opt.int_constraints().unwrap();
+                caps.resolutions = if opt.descriptor().type_() == SANE_Value_Type_SANE_TYPE_FIXED {
+                    list.iter()
+                        .map(|&res| SANE_UNFIX(res) as SANE_Int)
+                        .collect()
+                } else {
+                    list.to_vec()
+                };
+            }
+            ("mode", SANE_Constraint_Type_SANE_CONSTRAINT_STRING_LIST) => {
+                for mode in opt.string_constraints().unwrap() {
+                    if let Some(escl) = escl_color_mode(mode) {
+                        caps.color_modes.push(escl);
+                    }
+                }
+            }
+            ("source", SANE_Constraint_Type_SANE_CONSTRAINT_STRING_LIST) => {
+                let (feeders, platens): (Vec<_>, Vec<_>) = opt
+                    .string_constraints()
+                    .unwrap()
+                    .partition(|s| is_feeder(s));
+                caps.feeder = !feeders.is_empty();
+                caps.platen = !platens.is_empty();
+            }
+            (axis @ "br-x", SANE_Constraint_Type_SANE_CONSTRAINT_RANGE)
+            | (axis @ "br-y", SANE_Constraint_Type_SANE_CONSTRAINT_RANGE) => {
+                if opt.descriptor().unit() != SANE_Unit_SANE_UNIT_MM {
+                    continue;
+                }
+                let max = opt.get_range().unwrap().max();
+                let max = if opt.descriptor().type_() == SANE_Value_Type_SANE_TYPE_FIXED {
+                    SANE_UNFIX(max)
+                } else {
+                    f64::from(max)
+                };
+                let max = (max * UNITS_PER_MM) as u32;
+                if axis == "br-x" {
+                    caps.max_width = max;
+                } else {
+                    caps.max_height = max;
+                }
+            }
+            _ => {}
+        }
+    }
+    if caps.resolutions.is_empty() {
+        caps.resolutions.push(300);
+    }
+    if caps.color_modes.is_empty() {
+        caps.color_modes.push("RGB24");
+    }
+    caps
+}
+
+fn escl_color_mode(mode: &str) -> Option<&'static str> {
+    let mode = mode.to_lowercase();
+    if mode.contains("color") {
+        Some("RGB24")
+    } else if mode.contains("gray") {
+        Some("Grayscale8")
+    } else {
+        None
+    }
+}
+
+fn is_feeder(source: &str) -> bool {
+    let source = source.to_lowercase();
+    source.contains("adf") || source.contains("feeder")
+}
+
+fn capabilities_xml(caps: &Capabilities) -> String {
+    let mut profile = String::new();
+    profile.push_str("<scan:SettingProfiles><scan:SettingProfile><scan:ColorModes>");
+    for mode in &caps.color_modes {
+        let _ = write!(profile, "<scan:ColorMode>{}</scan:ColorMode>", mode);
+    }
+    profile.push_str("</scan:ColorModes><scan:DocumentFormats>");
+    for format in &["image/jpeg", "image/png"] {
+        let _ = write!(
+            profile,
+            "<pwg:DocumentFormat>{0}</pwg:DocumentFormat><scan:DocumentFormatExt>{0}</scan:DocumentFormatExt>",
+            format
+        );
+    }
+    profile
+        .push_str("</scan:DocumentFormats><scan:SupportedResolutions><scan:DiscreteResolutions>");
+    for res in &caps.resolutions {
+        let _ = write!(
+            profile,
+            "<scan:DiscreteResolution><scan:XResolution>{0}</scan:XResolution><scan:YResolution>{0}</scan:YResolution></scan:DiscreteResolution>",
+            res
+        );
+    }
+    profile.push_str("</scan:DiscreteResolutions></scan:SupportedResolutions></scan:SettingProfile></scan:SettingProfiles>");
+
+    let input_caps = format!(
+        "<scan:MinWidth>16</scan:MinWidth><scan:MaxWidth>{}</scan:MaxWidth><scan:MinHeight>16</scan:MinHeight><scan:MaxHeight>{}</scan:MaxHeight><scan:MaxScanRegions>1</scan:MaxScanRegions>{}",
+        caps.max_width, caps.max_height, profile
+    );
+
+    let host = hostname();
+    let mut xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><scan:ScannerCapabilities {}><pwg:Version>{}</pwg:Version><pwg:MakeAndModel>skanny</pwg:MakeAndModel><pwg:SerialNumber>{}</pwg:SerialNumber><scan:UUID>{}</scan:UUID>"#,
+        NAMESPACES,
+        VERSION,
+        host,
+        uuid(&host, 0)
+    );
+    if caps.platen {
+        let _ = write!(
+            xml,
+            "<scan:Platen><scan:PlatenInputCaps>{}</scan:PlatenInputCaps></scan:Platen>",
+            input_caps
+        );
+    }
+    if caps.feeder {
+        let _ = write!(
+            xml,
+            "<scan:Adf><scan:AdfSimplexInputCaps>{}</scan:AdfSimplexInputCaps></scan:Adf>",
+            input_caps
+        );
+    }
+    xml.push_str("</scan:ScannerCapabilities>");
+    xml
+}
+
+/// Translates a `ScanSettings` document into a job
+fn parse_settings(xml: &str, caps: &Capabilities) -> Option<Job> {
+    let mut settings = Profile::default();
+    let options = &mut settings.requested;
+
+    if let Some(res) = element(xml, "XResolution") {
+        options.insert("resolution".to_owned(), OptionValue::Int(res.parse().ok()?));
+    }
+    let feeder = element(xml, "InputSource") == Some("Feeder");
+    if feeder && !caps.feeder {
+        return None;
+    }
+    if let Some(mode) = element(xml, "ColorMode") {
+        if !caps.color_modes.contains(&mode) {
+            return None;
+        }
+        options.insert(
+            "mode".to_owned(),
+            OptionValue::String(if mode == "RGB24" { "Color" } else { "Gray" }.to_owned()),
+        );
+    }
+    if let (Some(x), Some(y), Some(w), Some(h)) = (
+        element(xml, "XOffset"),
+        element(xml, "YOffset"),
+        element(xml, "Width"),
+        element(xml, "Height"),
+    ) {
+        let mm = |v: &str| v.parse::<f64>().ok().map(|v| v / UNITS_PER_MM);
+        let (x, y, w, h) = (mm(x)?, mm(y)?, mm(w)?, mm(h)?);
+        options.insert("tl-x".to_owned(), OptionValue::Fixed(x));
+        options.insert("tl-y".to_owned(), OptionValue::Fixed(y));
+        options.insert("br-x".to_owned(), OptionValue::Fixed(x + w));
+        options.insert("br-y".to_owned(), OptionValue::Fixed(y + h));
+    }
+
+    let format = element(xml, "DocumentFormatExt")
+        .or_else(|| element(xml, "DocumentFormat"))
+        .unwrap_or("image/jpeg");
+    if format != "image/jpeg" && format != "image/png" {
+        return None;
+    }
+
+    Some(Job {
+        settings,
+        format: format.to_owned(),
+        feeder,
+        pages: 0,
+        ended: None,
+        client: String::new(),
+    })
+}
+
+/// Stable identifier in the UUID format expected by clients
+fn uuid(seed: &str, counter: usize) -> String {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    seed.hash(&mut hasher);
+    let hash = hasher.finish();
+    format!(
+        "{:08x}-{:04x}-4000-8000-{:012x}",
+        hash >> 32,
+        hash & 0xffff,
+        counter
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forgets_jobs_a_while_after_they_ended() {
+        let now = Instant::now();
+        let job = |ended: Option<Instant>| Job {
+            settings: Profile::default(),
+            format: "image/png".to_owned(),
+            feeder: true,
+            pages: 2,
+            ended,
+            client: "office".to_owned(),
+        };
+        let mut jobs = BTreeMap::new();
+        jobs.insert("running".to_owned(), job(None));
+        jobs.insert("ended".to_owned(), job(Some(now + KEEP_ENDED)));
+        jobs.insert("long-ended".to_owned(), job(Some(now)));
+        forget_ended(&mut jobs, now + KEEP_ENDED / 2);
+        assert_eq!(jobs.len(), 3);
+        forget_ended(&mut jobs, now + KEEP_ENDED);
+        assert_eq!(jobs.keys().collect::<Vec<_>>(), ["ended", "running"]);
+    }
+}
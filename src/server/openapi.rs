@@ -0,0 +1,212 @@
+//! OpenAPI description of the HTTP API, served at `/openapi.json`
+//!
+//! Clients for the API can be generated from it with the usual OpenAPI
+//! tools. The requests are routed by one `match` in the server, so the
+//! operations are declared here on functions standing in for its arms,
+//! with the types the arms send and receive.
+
+// The functions only carry the description of their operation
+#![allow(dead_code)]
+
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use super::imp::{Device, DeviceOption, Error, JobId, JobRequest, JobStatus, OptionSet, Paused};
+use super::quota::{ClientUsage, Quota};
+use crate::jobs::JobState;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "skanny", description = "Remote control of a scanner"),
+    paths(
+        devices, options, set_option, submit, job, skip, file, thumbnail, pause, resume, metrics,
+        usage, clients
+    ),
+    components(schemas(
+        ClientUsage,
+        Device,
+        DeviceOption,
+        Error,
+        JobId,
+        JobRequest,
+        JobState,
+        JobStatus,
+        OptionSet,
+        Paused,
+        Quota
+    )),
+    modifiers(&Authentication),
+    // Only needed when the server is started with --auth
+    security((), ("bearer" = []), ("basic" = []))
+)]
+struct Api;
+
+/// The ways to authenticate, see [`super::auth`]
+struct Authentication;
+
+impl Modify for Authentication {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+        components.add_security_scheme(
+            "basic",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)),
+        );
+    }
+}
+
+/// The description as JSON
+pub fn spec() -> serde_json::Value {
+    serde_json::to_value(Api::openapi()).expect("the description is valid JSON")
+}
+
+#[utoipa::path(
+    get,
+    path = "/devices",
+    params(("refresh" = Option<bool>, Query, description = "Look for the devices again instead of using the cached list")),
+    responses((status = 200, description = "Devices known to SANE, looked for again after --device-cache-ttl", body = [Device]))
+)]
+fn devices() {}
+
+#[utoipa::path(
+    get,
+    path = "/options",
+    responses((status = 200, description = "Options of the open device", body = [DeviceOption]))
+)]
+fn options() {}
+
+#[utoipa::path(
+    put,
+    path = "/options/{name}",
+    params(("name" = String, Path, description = "Name of the option")),
+    request_body(content = Object, description = "The new value"),
+    responses(
+        (status = 200, description = "The value the device took", body = OptionSet),
+        (status = 400, description = "The value was refused", body = Error)
+    )
+)]
+fn set_option() {}
+
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    request_body = JobRequest,
+    responses(
+        (status = 202, description = "The job was queued", body = JobId),
+        (status = 404, description = "No such profile", body = Error),
+        (status = 429, description = "Too many jobs are queued, or the quota is used up", body = Error)
+    )
+)]
+fn submit() {}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(("id" = usize, Path, description = "Id of the job")),
+    responses(
+        (status = 200, description = "State of the job", body = JobStatus),
+        (status = 404, description = "No such job", body = Error)
+    )
+)]
+fn job() {}
+
+#[utoipa::path(
+    post,
+    path = "/jobs/{id}/skip",
+    params(("id" = usize, Path, description = "Id of the job")),
+    responses(
+        (status = 202, description = "The page is skipped", body = JobId),
+        (status = 409, description = "The job is not scanning", body = Error),
+        (status = 404, description = "No such job", body = Error)
+    )
+)]
+fn skip() {}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}/files/{n}",
+    params(
+        ("id" = usize, Path, description = "Id of the job"),
+        ("n" = usize, Path, description = "Index of the image")
+    ),
+    responses(
+        (status = 200, description = "The image, of the type its extension names", content(
+            (Vec<u8> = "image/png"),
+            (Vec<u8> = "image/jpeg"),
+            (Vec<u8> = "image/tiff"),
+            (Vec<u8> = "application/pdf")
+        )),
+        (status = 404, description = "No such file", body = Error)
+    )
+)]
+fn file() {}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}/files/{n}/thumbnail",
+    params(
+        ("id" = usize, Path, description = "Id of the job"),
+        ("n" = usize, Path, description = "Index of the image")
+    ),
+    responses(
+        (status = 200, description = "Its thumbnail", content_type = "image/jpeg", body = Vec<u8>),
+        (status = 404, description = "No such file", body = Error)
+    )
+)]
+fn thumbnail() {}
+
+#[utoipa::path(
+    post,
+    path = "/pause",
+    responses((status = 200, description = "Jobs are held", body = Paused))
+)]
+fn pause() {}
+
+#[utoipa::path(
+    post,
+    path = "/resume",
+    responses((status = 200, description = "Jobs go on", body = Paused))
+)]
+fn resume() {}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses((status = 200, description = "Scan counters for Prometheus", content_type = "text/plain", body = String))
+)]
+fn metrics() {}
+
+#[utoipa::path(
+    get,
+    path = "/usage",
+    responses((status = 200, description = "Pages and jobs of the client this month", body = ClientUsage))
+)]
+fn usage() {}
+
+#[utoipa::path(
+    get,
+    path = "/usage/clients",
+    responses((status = 200, description = "Pages and jobs of every client this month", body = [ClientUsage]))
+)]
+fn clients() {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_every_operation() {
+        let spec = spec();
+        assert_eq!(spec["openapi"], "3.1.0");
+        let paths = spec["paths"].as_object().unwrap();
+        assert_eq!(paths.len(), 13);
+        assert!(paths["/jobs/{id}"]["get"]["responses"]["200"].is_object());
+        let schemas = &spec["components"]["schemas"];
+        assert!(schemas["JobStatus"]["properties"]["position"].is_object());
+        assert_eq!(schemas["JobState"]["enum"][0], "queued");
+        assert!(spec["components"]["securitySchemes"]["bearer"].is_object());
+    }
+}
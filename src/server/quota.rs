@@ -0,0 +1,253 @@
+//! Quotas and usage of the clients of the server
+//!
+//! Servers shared between departments can limit what every client scans.
+//! Quotas are set in the `--auth` file, for all clients with `[quota]` and
+//! for single tokens and users with their own:
+//!
+//! ```toml
+//! [quota]
+//! pages_per_month = 500
+//!
+//! [[users]]
+//! name = "archive"
+//! password_hash = "$argon2id$v=19$m=19456,t=2,p=1$c2thbm55LW9mZmljZS0wMQ$S8WhOJiHRPKAx0wLSlVvCaxI/LfoNrfYHHCdY2/V0JM"
+//! quota = { pages_per_month = 5000, jobs_per_minute = 4 }
+//! ```
+//!
+//! Jobs, eSCL ones included, are refused with 429 once the client scanned
+//! its pages of the month or submitted its jobs of the last minute. A job
+//! which runs over the quota is finished, so a month may end a little
+//! above it.
+//!
+//! The pages and jobs of every client, named as in the audit log, are
+//! counted when its jobs end. `GET /usage` tells a client its own usage
+//! and `GET /usage/clients` tells admins that of everyone. The counts start
+//! over every month, and are kept in the file given with `--usage`, or
+//! only in memory without it.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::jobs::{Job, JobObserver, JobState};
+use crate::template::DateTime;
+
+/// What a client may scan, without limits by default
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    /// Pages which may be scanned in a calendar month
+    pub pages_per_month: Option<u64>,
+    /// Jobs which may be submitted in a minute
+    pub jobs_per_minute: Option<u32>,
+}
+
+/// What a client scanned in a month
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Used {
+    pub pages: u64,
+    pub jobs: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClientUsage {
+    client: String,
+    /// `YEAR-MONTH` counted in
+    month: String,
+    pages: u64,
+    jobs: u64,
+    quota: Quota,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Counts {
+    month: String,
+    clients: BTreeMap<String, Used>,
+}
+
+impl Counts {
+    /// Starts over in a new month
+    fn roll(&mut self, month: &str) {
+        if self.month != month {
+            self.month = month.to_owned();
+            self.clients.clear();
+        }
+    }
+}
+
+pub struct Usage {
+    /// Where the counts are kept, if anywhere
+    path: Option<PathBuf>,
+    counts: Mutex<Counts>,
+    /// When every client submitted its jobs of the last minute
+    recent: Mutex<HashMap<String, VecDeque<Instant>>>,
+}
+
+const MINUTE: Duration = Duration::from_secs(60);
+
+fn this_month() -> String {
+    let now = DateTime::now();
+    format!("{:04}-{:02}", now.year, now.month)
+}
+
+impl Usage {
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        let counts = match path.map(std::fs::read) {
+            Some(Ok(data)) => serde_json::from_slice(&data)?,
+            Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => Counts::default(),
+        };
+        Ok(Self {
+            path: path.map(Path::to_owned),
+            counts: Mutex::new(counts),
+            recent: Default::default(),
+        })
+    }
+
+    /// Lets a client submit a job if its quota allows
+    pub fn admit(&self, client: &str, quota: &Quota) -> Result<(), String> {
+        self.admit_at(client, quota, &this_month(), Instant::now())
+    }
+
+    fn admit_at(
+        &self,
+        client: &str,
+        quota: &Quota,
+        month: &str,
+        now: Instant,
+    ) -> Result<(), String> {
+        if let Some(max) = quota.pages_per_month {
+            let mut counts = self.counts.lock().unwrap();
+            counts.roll(month);
+            let pages = counts.clients.get(client).map_or(0, |used| used.pages);
+            if pages >= max {
+                return Err(format!(
+                    "{} scanned its {} pages of the month already",
+                    client, max
+                ));
+            }
+        }
+        if let Some(max) = quota.jobs_per_minute {
+            let mut recent = self.recent.lock().unwrap();
+            recent.retain(|_, times| {
+                while times
+                    .front()
+                    .is_some_and(|time| now.duration_since(*time) >= MINUTE)
+                {
+                    times.pop_front();
+                }
+                !times.is_empty()
+            });
+            let times = recent.entry(client.to_owned()).or_default();
+            if times.len() >= max as usize {
+                return Err(format!(
+                    "{} submitted {} jobs in the last minute already",
+                    client, max
+                ));
+            }
+            times.push_back(now);
+        }
+        Ok(())
+    }
+
+    /// Counts a job which ended with the pages it scanned
+    pub fn record(&self, client: &str, pages: usize) {
+        self.record_in(client, pages, &this_month());
+    }
+
+    fn record_in(&self, client: &str, pages: usize, month: &str) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.roll(month);
+        let used = counts.clients.entry(client.to_owned()).or_default();
+        used.pages += pages as u64;
+        used.jobs += 1;
+        if let Some(path) = &self.path {
+            if let Err(e) = save(path, &counts) {
+                tracing::warn!("Saving the usage to {} failed: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// The usage of a client this month
+    pub fn of(&self, client: &str, quota: Quota) -> ClientUsage {
+        let mut counts = self.counts.lock().unwrap();
+        counts.roll(&this_month());
+        let used = counts.clients.get(client).copied().unwrap_or_default();
+        ClientUsage {
+            client: client.to_owned(),
+            month: counts.month.clone(),
+            pages: used.pages,
+            jobs: used.jobs,
+            quota,
+        }
+    }
+
+    /// The clients which scanned this month
+    pub fn clients(&self) -> Vec<String> {
+        let mut counts = self.counts.lock().unwrap();
+        counts.roll(&this_month());
+        counts.clients.keys().cloned().collect()
+    }
+}
+
+fn save(path: &Path, counts: &Counts) -> Result<(), Box<dyn std::error::Error>> {
+    let json = serde_json::to_vec_pretty(counts)?;
+    crate::output::write(path, |temporary| Ok(std::fs::write(temporary, json)?))
+}
+
+/// Counts the jobs of a [`crate::jobs::JobQueue`] as they end
+impl JobObserver for Usage {
+    fn changed(&self, job: &Job) {
+        if matches!(
+            job.state,
+            JobState::Done | JobState::Failed | JobState::Skipped
+        ) {
+            self.record(job.client.as_deref().unwrap_or("unknown"), job.files.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_jobs_over_the_quota() {
+        let path = std::env::temp_dir().join(format!("skanny-usage-{}.json", std::process::id()));
+        let usage = Usage::load(Some(&path)).unwrap();
+        let quota = Quota {
+            pages_per_month: Some(3),
+            jobs_per_minute: Some(2),
+        };
+        let start = Instant::now();
+        assert!(usage.admit_at("office", &quota, "2024-01", start).is_ok());
+        assert!(usage.admit_at("office", &quota, "2024-01", start).is_ok());
+        // Two jobs in the minute, and others may still scan
+        assert!(usage.admit_at("office", &quota, "2024-01", start).is_err());
+        assert!(usage.admit_at("lab", &quota, "2024-01", start).is_ok());
+        let later = start + MINUTE;
+        assert!(usage.admit_at("office", &quota, "2024-01", later).is_ok());
+
+        usage.record_in("office", 2, "2024-01");
+        usage.record_in("office", 1, "2024-01");
+        let later = later + MINUTE;
+        assert!(usage.admit_at("office", &quota, "2024-01", later).is_err());
+        assert!(usage
+            .admit_at("office", &Quota::default(), "2024-01", later)
+            .is_ok());
+
+        // Kept across restarts, and started over in the next month
+        let usage = Usage::load(Some(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            usage.counts.lock().unwrap().clients["office"],
+            Used { pages: 3, jobs: 2 }
+        );
+        assert!(usage.admit_at("office", &quota, "2024-02", later).is_ok());
+    }
+}
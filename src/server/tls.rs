@@ -0,0 +1,95 @@
+//! HTTPS for the server
+//!
+//! Scans are often of documents nobody else should read, so `--cert FILE`
+//! and `--key FILE` serve the API, the web interface and eSCL over HTTPS
+//! with a certificate and private key in PEM. With `--self-signed` the two
+//! files are created on the first run, with a certificate for the host name
+//! and `localhost` which browsers warn about until it is trusted.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use tiny_http::SslConfig;
+
+/// Writes a new certificate and key to `cert` and `key`
+fn generate(cert: &Path, key: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let host = super::imp::hostname();
+    let names = vec![
+        host.clone(),
+        format!("{}.local", host),
+        "localhost".to_owned(),
+    ];
+    let generated = rcgen::generate_simple_self_signed(names)?;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        // Only the server may read the key
+        options.mode(0o600);
+    }
+    options
+        .open(key)?
+        .write_all(generated.serialize_private_key_pem().as_bytes())?;
+    std::fs::write(cert, generated.serialize_pem()?)?;
+    tracing::info!(
+        "Created the self-signed certificate {} for {}",
+        cert.display(),
+        host
+    );
+    Ok(())
+}
+
+/// Reads the certificate and key, created first with `self_signed` if
+/// neither exists
+pub fn config(
+    cert: &Path,
+    key: &Path,
+    self_signed: bool,
+) -> Result<SslConfig, Box<dyn std::error::Error>> {
+    if self_signed && !cert.exists() && !key.exists() {
+        generate(cert, key)?;
+    }
+    let read = |path: &Path| {
+        std::fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))
+    };
+    let certificate = read(cert)?;
+    let private_key = read(key)?;
+    // tiny_http panics on keys it cannot find
+    if !String::from_utf8_lossy(&private_key).contains("PRIVATE KEY-----") {
+        return Err(format!("{} holds no private key in PEM", key.display()).into());
+    }
+    Ok(SslConfig {
+        certificate,
+        private_key,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_a_certificate_once() {
+        let dir = std::env::temp_dir().join(format!("skanny-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        assert!(config(&cert, &key, false).is_err());
+
+        let created = config(&cert, &key, true).unwrap();
+        assert!(String::from_utf8_lossy(&created.certificate).contains("BEGIN CERTIFICATE"));
+        let again = config(&cert, &key, true).unwrap();
+        assert_eq!(again.private_key, created.private_key);
+        tiny_http::Server::new(tiny_http::ServerConfig {
+            addr: tiny_http::ConfigListenAddr::from_socket_addrs("127.0.0.1:0").unwrap(),
+            ssl: Some(created),
+        })
+        .unwrap();
+
+        std::fs::write(&key, "not a key").unwrap();
+        assert!(config(&cert, &key, true).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+//! Stopping gracefully
+//!
+//! The long running services stop on ctrl-c and on `SIGTERM`, which service
+//! managers send. The page in the scanner is finished, or cancelled if that
+//! takes longer than `--drain-timeout SECS`, 30 seconds by default. The pages
+//! being stored and uploaded, and those waiting for their text, then get as
+//! long again to finish, and the jobs which had not started scanning fail.
+//!
+//! Single scans finish what they are doing on `SIGTERM` and exit when done,
+//! or once the drain timeout is up. Batches split with `--separator` stop
+//! scanning sheets, and deliver those scanned, on `SIGTERM` or when ctrl-c
+//! is pressed twice quickly.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+static TIMEOUT: OnceLock<Duration> = OnceLock::new();
+/// Raised on `SIGTERM`
+static STOP: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL: Duration = Duration::from_millis(100);
+
+/// Sets how long stopping may take, in seconds
+pub fn init(secs: Option<u64>) -> Result<(), Box<dyn std::error::Error>> {
+    TIMEOUT
+        .set(secs.map_or(DEFAULT_TIMEOUT, Duration::from_secs))
+        .map_err(|_| "The shutdown settings are already set".into())
+}
+
+/// How long stopping may take
+pub fn timeout() -> Duration {
+    TIMEOUT.get().copied().unwrap_or(DEFAULT_TIMEOUT)
+}
+
+/// Raises `stop` on `SIGTERM`
+#[cfg(unix)]
+pub fn on_sigterm(stop: Arc<AtomicBool>) -> Result<(), Box<dyn std::error::Error>> {
+    extern "C" fn handle(_signal: libc::c_int) {
+        // Set before the handler is installed, so this only loads atomics
+        if let Some(stop) = STOP.get() {
+            stop.store(true, Ordering::SeqCst);
+        }
+    }
+    STOP.set(stop)
+        .map_err(|_| "Stopping on SIGTERM is already set up")?;
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    if unsafe { libc::signal(libc::SIGTERM, handler) } == libc::SIG_ERR {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn on_sigterm(stop: Arc<AtomicBool>) -> Result<(), Box<dyn std::error::Error>> {
+    STOP.set(stop)
+        .map_err(|_| "Stopping on SIGTERM is already set up")?;
+    Ok(())
+}
+
+/// Stops as on `SIGTERM`, as asked for by the user
+pub fn stop() {
+    if let Some(stop) = STOP.get() {
+        stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Whether the process is stopping, on `SIGTERM` or, for the services, on
+/// ctrl-c
+pub fn stopping() -> bool {
+    STOP.get().is_some_and(|stop| stop.load(Ordering::SeqCst))
+}
+
+fn wait_for(stop: &AtomicBool) {
+    while !stop.load(Ordering::SeqCst) {
+        std::thread::sleep(POLL);
+    }
+}
+
+/// Calls `cancel` once the drain timeout is up after `stop` is raised, to
+/// cancel the page in the scanner
+pub fn cancel_after_drain(stop: Arc<AtomicBool>, cancel: impl FnOnce() + Send + 'static) {
+    std::thread::spawn(move || {
+        wait_for(&stop);
+        let timeout = timeout();
+        tracing::info!(
+            "Stopping, finishing the page in the scanner within {:?}",
+            timeout
+        );
+        std::thread::sleep(timeout);
+        cancel();
+    });
+}
+
+/// Lets a single scan finish on `SIGTERM`, exiting once the drain timeout
+/// is up if it has not
+pub fn finish_on_sigterm() -> Result<(), Box<dyn std::error::Error>> {
+    let stop = Arc::new(AtomicBool::new(false));
+    on_sigterm(Arc::clone(&stop))?;
+    std::thread::spawn(move || {
+        wait_for(&stop);
+        let timeout = timeout();
+        tracing::warn!("Stopping, finishing the scan within {:?}", timeout);
+        std::thread::sleep(timeout);
+        tracing::error!("The scan did not finish in time");
+        std::process::exit(143);
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raises_the_stop_flag_on_sigterm() {
+        let stop = Arc::new(AtomicBool::new(false));
+        on_sigterm(Arc::clone(&stop)).unwrap();
+        assert!(on_sigterm(Arc::clone(&stop)).is_err());
+        #[cfg(unix)]
+        {
+            unsafe { libc::raise(libc::SIGTERM) };
+            assert!(stop.load(Ordering::SeqCst));
+            assert!(stopping());
+        }
+        assert_eq!(timeout(), DEFAULT_TIMEOUT);
+    }
+}
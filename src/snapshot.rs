@@ -0,0 +1,116 @@
+//! Saving and restoring the settings of a device
+//!
+//! `skanny options export` writes the value of every option which can be
+//! set as TOML, in the order the device lists them:
+//!
+//! ```toml
+//! # Options of fujitsu:fi-7160:1234
+//! source = "ADF Duplex"
+//! mode = "Color"
+//! resolution = 300
+//! ```
+//!
+//! `--restore` sets them again before scanning. Setting an option may make
+//! the backend reload the others, such as the mode changing which
+//! resolutions there are, so the options are listed again after each one
+//! and set in the order of the device.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use sane_sys::*;
+use skanny::backend::{BackendError, OptionInfo, ScannerDevice};
+use skanny::OptionValue;
+
+/// Option values keyed by option name
+pub type Snapshot = BTreeMap<String, OptionValue>;
+
+fn is_settable(option: &OptionInfo) -> bool {
+    let cap = option.cap as u32;
+    cap & SANE_CAP_SOFT_SELECT != 0
+        && cap & SANE_CAP_INACTIVE == 0
+        && option.type_ != SANE_Value_Type_SANE_TYPE_BUTTON
+        && option.type_ != SANE_Value_Type_SANE_TYPE_GROUP
+        && !option.name.is_empty()
+}
+
+/// The values of the options which can be set, as TOML
+pub fn export(device: &dyn ScannerDevice) -> Result<String, Box<dyn std::error::Error>> {
+    let mut toml = format!("# Options of {}\n", device.name());
+    for option in device.options()? {
+        if !is_settable(&option) {
+            continue;
+        }
+        if let Some(value) = &option.value {
+            toml.push_str(&format!(
+                "{} = {}\n",
+                option.name,
+                toml::Value::try_from(value)?
+            ));
+        }
+    }
+    Ok(toml)
+}
+
+pub fn load(path: &Path) -> Result<Snapshot, Box<dyn std::error::Error>> {
+    let contents = std::fs::read_to_string(path)?;
+    Ok(toml::from_str(&contents)?)
+}
+
+/// Sets the options of `snapshot`, warning about those the device does
+/// not have or which are inactive
+pub fn restore(device: &dyn ScannerDevice, snapshot: &Snapshot) -> Result<(), BackendError> {
+    let mut restored = BTreeSet::new();
+    // Setting an option may reload the others, so they are listed again
+    // every time
+    while let Some(option) = device.options()?.into_iter().find(|option| {
+        is_settable(option)
+            && snapshot.contains_key(&option.name)
+            && !restored.contains(&option.name)
+    }) {
+        let value = &snapshot[&option.name];
+        if option.value.as_ref() != Some(value) {
+            tracing::debug!("Setting {} to {}", option.name, value);
+            device.set_option(&option.name, value)?;
+        }
+        restored.insert(option.name);
+    }
+    for name in snapshot.keys().filter(|&name| !restored.contains(name)) {
+        tracing::warn!(
+            "Not restoring {}, the device has no such option or it is inactive",
+            name
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use skanny::mock::{DeviceSpec, MockDevice};
+
+    #[test]
+    fn restores_exported_options() {
+        let device = MockDevice::new(DeviceSpec::default());
+        device
+            .set_option("mode", &OptionValue::String("Color".to_owned()))
+            .unwrap();
+        device
+            .set_option("resolution", &OptionValue::Int(150))
+            .unwrap();
+        let exported = export(&device).unwrap();
+        assert_eq!(
+            exported,
+            "# Options of mock\nmode = \"Color\"\nresolution = 150\n"
+        );
+
+        let device = MockDevice::new(DeviceSpec::default());
+        restore(&device, &toml::from_str(&exported).unwrap()).unwrap();
+        let options = device.options().unwrap();
+        assert_eq!(
+            options[0].value,
+            Some(OptionValue::String("Color".to_owned()))
+        );
+        assert_eq!(options[1].value, Some(OptionValue::Int(150)));
+    }
+}
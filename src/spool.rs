@@ -0,0 +1,242 @@
+//! Acquisition of frames too large to keep in memory
+//!
+//! A 1200 dpi A3 scan in 16 bit color takes several gigabytes. [`Spool`]
+//! keeps the frame in memory up to a threshold and moves it to a temporary
+//! file beyond that, and [`Spool::write_png`] encodes it a line at a time.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use sane_sys::*;
+
+use crate::backend::{BackendError, FrameParameters, ScannerDevice};
+use crate::frame::{Frame, Layout};
+#[cfg(feature = "image")]
+use crate::Image;
+
+pub struct Spool {
+    parameters: FrameParameters,
+    storage: Storage,
+    len: u64,
+}
+
+enum Storage {
+    Memory(Vec<u8>),
+    File {
+        file: BufWriter<File>,
+        /// Removed when the spool is dropped
+        path: PathBuf,
+    },
+}
+
+/// Tells apart the spool files of a process
+static SPOOLS: AtomicUsize = AtomicUsize::new(0);
+
+impl Spool {
+    /// Acquires a frame, moving it to a temporary file once it exceeds
+    /// `threshold` bytes
+    pub fn acquire(device: &dyn ScannerDevice, threshold: usize) -> Result<Self, BackendError> {
+        let parameters = device.start()?;
+        let mut spool = Spool {
+            parameters,
+            storage: Storage::Memory(Vec::new()),
+            len: 0,
+        };
+        let mut buffer = vec![0; 64 * 1024];
+        let result = loop {
+            match device.read(&mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(n) => {
+                    if let Err(e) = spool.append(&buffer[..n], threshold) {
+                        break Err(e.into());
+                    }
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        device.cancel();
+        result?;
+
+        if !parameters.last_frame {
+            return Err("Multi-pass frames are not supported".into());
+        }
+        Ok(spool)
+    }
+
+    fn append(&mut self, data: &[u8], threshold: usize) -> std::io::Result<()> {
+        if let Storage::Memory(memory) = &mut self.storage {
+            if memory.len() + data.len() <= threshold {
+                memory.extend_from_slice(data);
+                self.len += data.len() as u64;
+                return Ok(());
+            }
+            let path = std::env::temp_dir().join(format!(
+                "skanny-spool-{}-{}.raw",
+                std::process::id(),
+                SPOOLS.fetch_add(1, Ordering::SeqCst)
+            ));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)?;
+            let mut file = BufWriter::new(file);
+            file.write_all(memory)?;
+            self.storage = Storage::File { file, path };
+        }
+        if let Storage::File { file, .. } = &mut self.storage {
+            file.write_all(data)?;
+        }
+        self.len += data.len() as u64;
+        Ok(())
+    }
+
+    pub fn parameters(&self) -> &FrameParameters {
+        &self.parameters
+    }
+
+    /// Whether the frame was moved to disk
+    pub fn is_spooled(&self) -> bool {
+        matches!(self.storage, Storage::File { .. })
+    }
+
+    /// Number of complete lines, which is only known in advance for some
+    /// devices
+    pub fn lines(&self) -> u32 {
+        match self.parameters.bytes_per_line {
+            0 => 0,
+            bytes_per_line => (self.len / bytes_per_line as u64) as u32,
+        }
+    }
+
+    /// Reads the frame back from the start
+    fn reader(&mut self) -> Result<Box<dyn Read + '_>, BackendError> {
+        Ok(match &mut self.storage {
+            Storage::Memory(memory) => Box::new(&memory[..]),
+            Storage::File { file, .. } => {
+                file.flush()?;
+                let file = file.get_mut();
+                file.seek(SeekFrom::Start(0))?;
+                Box::new(BufReader::new(file))
+            }
+        })
+    }
+
+    /// Loads the frame into memory
+    pub fn into_frame(mut self) -> Result<Frame, BackendError> {
+        let mut data = Vec::with_capacity(self.len as usize);
+        self.reader()?.read_to_end(&mut data)?;
+        Ok(Frame::decode(&self.parameters, data)?)
+    }
+
+    /// Loads the frame into an image, which needs it all in memory
+    #[cfg(feature = "image")]
+    pub fn into_image(self) -> Result<Image, BackendError> {
+        let frame = self.into_frame()?;
+        Ok(frame.into_image().ok_or("The frame is no image")?)
+    }
+
+    /// Encodes the frame as PNG, which unlike [`Image`] supports depths of
+    /// 1 and 16 bits
+    pub fn write_png<W: Write>(&mut self, w: W) -> Result<(), BackendError> {
+        let parameters = self.parameters;
+        let width = parameters.pixels_per_line.max(0) as usize;
+        #[allow(non_upper_case_globals)]
+        let (color, channels) = match parameters.format {
+            SANE_Frame_SANE_FRAME_GRAY => (png::ColorType::Grayscale, 1),
+            SANE_Frame_SANE_FRAME_RGB => (png::ColorType::RGB, 3),
+            // Such as JPEG, which is decoded whole
+            _ => return self.write_decoded_png(w),
+        };
+        let (depth, row) = match (parameters.depth, channels) {
+            (1, 1) => (png::BitDepth::One, width.div_ceil(8)),
+            (8, _) => (png::BitDepth::Eight, width * channels),
+            (16, _) => (png::BitDepth::Sixteen, width * channels * 2),
+            (depth, _) => return Err(format!("Unsupported depth {}", depth).into()),
+        };
+        let bytes_per_line = parameters.bytes_per_line.max(0) as usize;
+        if bytes_per_line < row {
+            return Err("Lines are shorter than their pixels".into());
+        }
+
+        let mut encoder = png::Encoder::new(w, width as u32, self.lines());
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        let mut writer = encoder.write_header()?;
+        let mut stream = writer.stream_writer();
+        let lines = self.lines();
+        let mut reader = self.reader()?;
+        let mut line = vec![0; bytes_per_line];
+        for _ in 0..lines {
+            reader.read_exact(&mut line)?;
+            let row = &mut line[..row];
+            match parameters.depth {
+                1 => crate::pixels::invert(row),
+                16 => crate::pixels::to_big_endian16(row),
+                _ => {}
+            }
+            stream.write_all(row)?;
+        }
+        stream.finish()?;
+        Ok(())
+    }
+
+    /// Encodes a frame which is not read a line at a time, into 8 bit
+    /// samples
+    fn write_decoded_png<W: Write>(&mut self, w: W) -> Result<(), BackendError> {
+        let mut data = Vec::with_capacity(self.len as usize);
+        self.reader()?.read_to_end(&mut data)?;
+        let frame = Frame::decode(&self.parameters, data)?;
+        let mut encoder = png::Encoder::new(w, frame.width(), frame.height());
+        encoder.set_color(match frame.layout() {
+            Layout::Gray => png::ColorType::Grayscale,
+            Layout::Rgb => png::ColorType::RGB,
+        });
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        let mut stream = writer.stream_writer();
+        for y in 0..frame.height() {
+            stream.write_all(frame.row(y))?;
+        }
+        stream.finish()?;
+        Ok(())
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        if let Storage::File { path, .. } = &self.storage {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "image"))]
+mod tests {
+    use super::*;
+    use crate::mock::{DeviceSpec, MockDevice};
+
+    #[test]
+    fn spools_to_disk() {
+        // The pattern depends on the page, so each device scans a single one
+        let expected = MockDevice::new(DeviceSpec::default())
+            .scan()
+            .unwrap()
+            .to_dynamic()
+            .to_luma8();
+
+        let device = MockDevice::new(DeviceSpec::default());
+        let mut spool = Spool::acquire(&device, 100).unwrap();
+        assert!(spool.is_spooled());
+        let mut png = Vec::new();
+        spool.write_png(&mut png).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap().to_luma8();
+        assert_eq!(decoded, expected);
+        assert_eq!(
+            spool.into_image().unwrap().to_dynamic().to_luma8(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,160 @@
+//! The processing stages of skanny
+//!
+//! Profiles run their `pipeline` on every page, plain scans the stages of
+//! `--stage`. Besides those of `--plugin`, there are
+//!
+//! | Stage              | Setting                        |
+//! |--------------------|--------------------------------|
+//! | `clean-background` |                                |
+//! | `deskew`           |                                |
+//! | `dewarp`           |                                |
+//! | `dropout`          | `"red"`, `"green"` or `"blue"` |
+//! | `ocr`              | Languages, or a table of `lang`, `psm` and `engine`, see [`crate::ocr`] |
+//! | `threshold`        | Gray level from 0 to 255, white from there on, 128 if not given |
+
+use std::convert::TryFrom;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use skanny::pipeline::{Page, Pipeline, ProcessingStage, Registry, StageError};
+use skanny::Image;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// A stage without settings which only changes the image
+fn image_stage(
+    change: fn(Image) -> Image,
+) -> impl Fn(Option<&toml::Value>) -> Result<Box<dyn ProcessingStage>, StageError> {
+    move |_| {
+        Ok(Box::new(move |page: Page| -> Result<Page, StageError> {
+            Ok(Page {
+                image: change(page.image),
+                ..page
+            })
+        }))
+    }
+}
+
+fn builtin() -> Registry {
+    let mut registry = Registry::default();
+    registry.register(
+        "clean-background",
+        image_stage(crate::clean::clean_background),
+    );
+    registry.register("deskew", image_stage(|image| crate::book::deskew(&image)));
+    registry.register("dewarp", image_stage(|image| crate::book::dewarp(&image)));
+    registry.register("dropout", |setting| {
+        let channel: crate::dropout::Channel = setting
+            .and_then(toml::Value::as_str)
+            .ok_or("Expected the colour to drop")?
+            .parse()?;
+        Ok(Box::new(move |page: Page| -> Result<Page, StageError> {
+            Ok(Page {
+                image: crate::dropout::apply(page.image, channel),
+                ..page
+            })
+        }))
+    });
+    registry.register("ocr", crate::ocr::stage);
+    registry.register("threshold", |setting| {
+        let level = match setting {
+            None => 128,
+            Some(setting) => setting
+                .as_integer()
+                .and_then(|level| u8::try_from(level).ok())
+                .ok_or("Expected a gray level from 0 to 255")?,
+        };
+        Ok(Box::new(move |page: Page| -> Result<Page, StageError> {
+            let mut gray = page.image.to_dynamic().into_luma8();
+            skanny::pixels::threshold(&mut gray, level);
+            Ok(Page {
+                image: Image::Gray8(gray),
+                ..page
+            })
+        }))
+    });
+    registry
+}
+
+/// Sets up the stages with those of the plugins, before any are used
+pub fn init(plugins: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut registry = builtin();
+    for path in plugins {
+        load_plugin(&mut registry, path)?;
+    }
+    REGISTRY
+        .set(registry)
+        .map_err(|_| "The processing stages are already set up".into())
+}
+
+pub fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(builtin)
+}
+
+#[cfg(feature = "plugins")]
+fn load_plugin(registry: &mut Registry, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    // Plugins are trusted like the rest of the configuration
+    unsafe { registry.load(path.as_ref()) }
+        .map_err(|e| format!("Could not load the plugin {}: {}", path, e).into())
+}
+
+#[cfg(not(feature = "plugins"))]
+fn load_plugin(_registry: &mut Registry, _path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Err("skanny was built without the plugins feature".into())
+}
+
+/// Runs a page through `pipeline` and saves it, with what the stages found
+/// in a JSON file of the same name and its thumbnail. The JSON file is
+/// written once the text of the page is recognized, see [`crate::ocr`].
+pub fn save(
+    pipeline: &Pipeline,
+    image: Image,
+    path: &Path,
+) -> Result<Image, Box<dyn std::error::Error>> {
+    // Left by a pipeline whose page was not saved
+    crate::ocr::forget();
+    let page = match pipeline.run(image.into()) {
+        Ok(page) => page,
+        Err(e) => {
+            crate::output::release(path);
+            return Err(e);
+        }
+    };
+    crate::profile::save(&page.image, path)?;
+    crate::thumbnail::write(&page.image, path)?;
+    let json_path = path.with_extension("json");
+    crate::ocr::finish_page(page.metadata, move |metadata| {
+        if !metadata.is_empty() {
+            let json = serde_json::to_string_pretty(&metadata)?;
+            crate::output::write(&json_path, |temporary| Ok(std::fs::write(temporary, json)?))?;
+        }
+        Ok(())
+    })?;
+    Ok(page.image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_the_configured_colour() {
+        let registry = builtin();
+        let pipeline = registry
+            .pipeline(&["dropout=green".parse().unwrap()])
+            .unwrap();
+        let image = Image::Rgb8(image::ImageBuffer::from_pixel(
+            2,
+            2,
+            image::Rgb([10, 20, 30]),
+        ));
+        match pipeline.run(image.into()).unwrap().image {
+            Image::Gray8(gray) => assert_eq!(gray.get_pixel(0, 0)[0], 20),
+            _ => panic!("the colour should be dropped"),
+        }
+        assert!(registry.pipeline(&["dropout".parse().unwrap()]).is_err());
+        assert!(registry
+            .pipeline(&["dropout=purple".parse().unwrap()])
+            .is_err());
+    }
+}
@@ -0,0 +1,306 @@
+//! Scan counters per device, kept across runs
+//!
+//! The counters are kept as JSON in `$XDG_STATE_HOME/skanny/stats.json`,
+//! or `~/.local/state/skanny/stats.json`, keyed by device name. Every scan
+//! updates the file, which is replaced as a whole so it is never left half
+//! written. They are printed by `skanny stats` and served by the HTTP server
+//! under `/metrics` for Prometheus.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use gumdrop::Options;
+use sane_sys::*;
+use serde::{Deserialize, Serialize};
+use skanny::backend::{BackendError, FrameParameters, OptionInfo, ScannerDevice};
+use skanny::frame::Frame;
+use skanny::{Image, OptionValue};
+
+#[derive(Debug, Options)]
+pub struct StatsOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "Read the counters from this file")]
+    file: Option<String>,
+    #[options(help = "Print the counters as JSON")]
+    json: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Counters {
+    pub pages: u64,
+    pub jams: u64,
+    pub jobs: u64,
+    /// Time spent acquiring pages
+    pub scan_seconds: f64,
+}
+
+pub type Stats = BTreeMap<String, Counters>;
+
+/// Name, description and value of a metric
+type Metric = (&'static str, &'static str, fn(&Counters) -> f64);
+
+/// Serializes updates between the threads of the process
+static LOCK: Mutex<()> = Mutex::new(());
+
+pub fn default_path() -> Option<PathBuf> {
+    let state = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => Path::new(&std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state.join("skanny").join("stats.json"))
+}
+
+pub fn load(path: &Path) -> Result<Stats, Box<dyn std::error::Error>> {
+    match std::fs::read(path) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Stats::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn update_file(
+    path: &Path,
+    device: &str,
+    f: impl FnOnce(&mut Counters),
+) -> Result<(), Box<dyn std::error::Error>> {
+    let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut stats = load(path)?;
+    f(stats.entry(device.to_owned()).or_default());
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let temporary = path.with_extension(format!("json.{}", std::process::id()));
+    std::fs::write(&temporary, serde_json::to_vec_pretty(&stats)?)?;
+    std::fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+/// Updates the counters of `device` in `path`, failures are only logged as
+/// counting must not fail scans
+fn update(path: Option<&Path>, device: &str, f: impl FnOnce(&mut Counters)) {
+    if let Some(path) = path {
+        if let Err(e) = update_file(path, device, f) {
+            tracing::warn!(
+                "Updating the statistics in {} failed: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Counts a job, such as a scan requested from the server
+pub fn record_job(device: &str) {
+    update(default_path().as_deref(), device, |counters| {
+        counters.jobs += 1
+    });
+}
+
+/// Counts an attempt at scanning a page, which took `took`
+pub fn record_scan(device: &str, took: Duration, error: Option<&skanny::Error>) {
+    record_scan_in(default_path().as_deref(), device, took, error)
+}
+
+#[allow(non_upper_case_globals)]
+fn record_scan_in(
+    path: Option<&Path>,
+    device: &str,
+    took: Duration,
+    error: Option<&skanny::Error>,
+) {
+    update(path, device, |counters| {
+        match error {
+            None => counters.pages += 1,
+            Some(&skanny::Error::Status(SANE_Status_SANE_STATUS_JAMMED)) => counters.jams += 1,
+            Some(_) => {}
+        }
+        counters.scan_seconds += took.as_secs_f64();
+    });
+}
+
+/// Counts the pages a device scans
+pub struct Counting<'a> {
+    inner: &'a dyn ScannerDevice,
+    path: Option<PathBuf>,
+    /// When the frame being read was started
+    started: Cell<Option<Instant>>,
+}
+
+impl<'a> Counting<'a> {
+    pub fn new(inner: &'a dyn ScannerDevice) -> Self {
+        Self {
+            inner,
+            path: default_path(),
+            started: Cell::new(None),
+        }
+    }
+
+    fn record<T>(&self, started: Instant, result: &Result<T, BackendError>) {
+        let error = match result {
+            Ok(_) => None,
+            Err(e) => match e.downcast_ref::<skanny::Error>() {
+                Some(e) => Some(e),
+                // Not an error of the device
+                None => return,
+            },
+        };
+        record_scan_in(
+            self.path.as_deref(),
+            self.inner.name(),
+            started.elapsed(),
+            error,
+        );
+    }
+}
+
+impl ScannerDevice for Counting<'_> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn options(&self) -> Result<Vec<OptionInfo>, BackendError> {
+        self.inner.options()
+    }
+
+    fn set_option(&self, name: &str, value: &OptionValue) -> Result<(), BackendError> {
+        self.inner.set_option(name, value)
+    }
+
+    fn start(&self) -> Result<FrameParameters, BackendError> {
+        let started = Instant::now();
+        let result = self.inner.start();
+        match result {
+            Ok(_) => self.started.set(Some(started)),
+            Err(_) => self.record(started, &result),
+        }
+        result
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, BackendError> {
+        let result = self.inner.read(buffer);
+        if let Some(started) = self.started.get() {
+            if matches!(result, Ok(0) | Err(_)) {
+                self.started.set(None);
+                self.record(started, &result);
+            }
+        }
+        result
+    }
+
+    fn cancel(&self) {
+        self.started.set(None);
+        self.inner.cancel()
+    }
+
+    /// Delegates, so the device may scan the page its own way
+    fn scan_frame(&self) -> Result<Frame, BackendError> {
+        let started = Instant::now();
+        let result = self.inner.scan_frame();
+        self.record(started, &result);
+        result
+    }
+
+    fn scan(&self) -> Result<Image, BackendError> {
+        let started = Instant::now();
+        let result = self.inner.scan();
+        self.record(started, &result);
+        result
+    }
+}
+
+/// The counters in the text format of Prometheus
+pub fn metrics(stats: &Stats) -> String {
+    let mut text = String::new();
+    let metrics: [Metric; 4] = [
+        ("pages", "Pages scanned", |c| c.pages as f64),
+        ("jams", "Paper jams", |c| c.jams as f64),
+        ("jobs", "Scan jobs", |c| c.jobs as f64),
+        ("scan_seconds", "Time spent scanning", |c| c.scan_seconds),
+    ];
+    for (name, help, value) in &metrics {
+        text.push_str(&format!("# HELP skanny_{}_total {}\n", name, help));
+        text.push_str(&format!("# TYPE skanny_{}_total counter\n", name));
+        for (device, counters) in stats {
+            let device = device
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            text.push_str(&format!(
+                "skanny_{}_total{{device=\"{}\"}} {}\n",
+                name,
+                device,
+                value(counters)
+            ));
+        }
+    }
+    text
+}
+
+pub fn run(opts: &StatsOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let path = match &opts.file {
+        Some(path) => PathBuf::from(path),
+        None => default_path().ok_or("No place for the statistics, set HOME")?,
+    };
+    let stats = load(&path)?;
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+        return Ok(());
+    }
+    println!(
+        "{:<40} {:>8} {:>6} {:>6} {:>10}",
+        "device", "pages", "jams", "jobs", "scan time"
+    );
+    for (device, counters) in &stats {
+        let seconds = counters.scan_seconds.round() as u64;
+        println!(
+            "{:<40} {:>8} {:>6} {:>6} {:>4}:{:02}:{:02}",
+            device,
+            counters.pages,
+            counters.jams,
+            counters.jobs,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use skanny::mock::{DeviceSpec, Fault, MockDevice};
+
+    #[test]
+    fn counts_pages_and_jams() {
+        let path = std::env::temp_dir().join(format!("skanny-stats-{}.json", std::process::id()));
+        let device = MockDevice::new(DeviceSpec {
+            faults: vec![Fault::jammed(1)],
+            ..DeviceSpec::default()
+        });
+        let counting = Counting {
+            inner: &device,
+            path: Some(path.clone()),
+            started: Cell::new(None),
+        };
+        counting.scan().unwrap();
+        assert!(counting.scan().is_err());
+        // Reading the frame in chunks counts as well
+        counting.start().unwrap();
+        let mut buffer = [0; 4096];
+        while counting.read(&mut buffer).unwrap() > 0 {}
+        counting.cancel();
+        update(Some(&path), "mock", |counters| counters.jobs += 1);
+
+        let stats = load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let counters = stats["mock"];
+        assert_eq!((counters.pages, counters.jams, counters.jobs), (2, 1, 1));
+        assert!(metrics(&stats).contains("skanny_pages_total{device=\"mock\"} 2\n"));
+    }
+}
@@ -0,0 +1,694 @@
+//! Job history in SQLite
+//!
+//! With `--store FILE` the server and the daemon keep their jobs in an
+//! SQLite database: the device, profile and options of every job, its state
+//! and error, and the files and upload locations of its page. The jobs
+//! survive restarts of the service, which fails those it did not finish and
+//! numbers new jobs after them. The database may be read while the service
+//! runs:
+//!
+//! ```text
+//! skanny jobs --store jobs.sqlite list --state failed
+//! skanny jobs --store jobs.sqlite show 12
+//! ```
+//!
+//! A `--separator` batch scanned with `--store FILE` is kept as a job too,
+//! with the sheets it took from the feeder. When it is interrupted by a jam
+//! or a crash, `skanny jobs --store FILE resume ID` runs it again with the
+//! same arguments, from the first sheet which was not stored. Its pages go
+//! on in the same document and are numbered after the earlier ones, which
+//! are listed in the manifest and uploaded along with the new pages. Pages
+//! stored after that sheet are scanned again. A batch should not share its
+//! store with a running service. Other feeder batches are not kept, so
+//! `--store` and `--resume` on the command line need `--separator`.
+//!
+//! This needs the `sqlite` feature.
+
+use std::path::{Path, PathBuf};
+#[cfg(feature = "sqlite")]
+use std::sync::Mutex;
+
+use gumdrop::Options;
+
+#[cfg(feature = "sqlite")]
+use crate::jobs::Job;
+use crate::jobs::JobQueue;
+use crate::separate::{Page, Progress};
+
+#[derive(Debug, Options)]
+pub struct JobsOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "SQLite database the service keeps its jobs in", required)]
+    store: String,
+    #[options(command)]
+    command: Option<JobsCommand>,
+}
+
+#[derive(Debug, Options)]
+enum JobsCommand {
+    #[options(help = "List the jobs, the latest last")]
+    List(ListOptions),
+    #[options(help = "Show a job with its files and upload locations")]
+    Show(ShowOptions),
+    #[options(help = "Go on with a batch which was interrupted")]
+    Resume(ResumeOptions),
+}
+
+#[derive(Debug, Options)]
+struct ListOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "Only list the jobs in this state, like failed")]
+    state: Option<String>,
+    #[options(help = "Print the jobs as JSON")]
+    json: bool,
+}
+
+#[derive(Debug, Options)]
+struct ShowOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "Print the job as JSON")]
+    json: bool,
+    #[options(free, help = "Id of the job")]
+    id: Option<usize>,
+}
+
+#[derive(Debug, Options)]
+struct ResumeOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(free, help = "Id of the batch")]
+    id: Option<usize>,
+}
+
+/// A `--separator` batch kept in the store
+pub struct Batch {
+    #[cfg(feature = "sqlite")]
+    store: imp::JobStore,
+    #[cfg(feature = "sqlite")]
+    path: PathBuf,
+    #[cfg(feature = "sqlite")]
+    job: Mutex<Job>,
+}
+
+/// What an interrupted batch had done
+pub struct Resumed {
+    /// Where the documents of the batch go
+    pub dir: PathBuf,
+    pub progress: Progress,
+    /// The pages stored before the first missing sheet
+    pub pages: Vec<Page>,
+}
+
+#[cfg(not(feature = "sqlite"))]
+impl Batch {
+    pub fn start(
+        _path: &Path,
+        _device: &str,
+        _dir: &Path,
+        _args: Vec<String>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Err("skanny was built without the sqlite feature".into())
+    }
+
+    pub fn resume(_path: &Path, _id: usize) -> Result<(Self, Resumed), Box<dyn std::error::Error>> {
+        Err("skanny was built without the sqlite feature".into())
+    }
+
+    pub fn sheet(&self, _progress: &Progress, _page: Option<&Page>) {}
+
+    pub fn finish(&self, _error: Option<String>) {}
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub fn attach(_queue: &mut JobQueue, _path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    Err("skanny was built without the sqlite feature".into())
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub fn run(_opts: &JobsOptions) -> Result<(), Box<dyn std::error::Error>> {
+    Err("skanny was built without the sqlite feature".into())
+}
+
+#[cfg(feature = "sqlite")]
+pub use imp::{attach, run};
+
+#[cfg(feature = "sqlite")]
+mod imp {
+    use std::path::{Path, PathBuf};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use rusqlite::{params, Connection};
+    use serde::Serialize;
+
+    use super::{Batch, JobsCommand, JobsOptions, Page, Progress, Resumed};
+    use crate::jobs::{Job, JobObserver, JobQueue, JobState};
+
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS jobs (
+            id INTEGER PRIMARY KEY,
+            device TEXT NOT NULL,
+            profile TEXT NOT NULL,
+            options TEXT NOT NULL,
+            client TEXT,
+            state TEXT NOT NULL,
+            error TEXT,
+            submitted TEXT NOT NULL DEFAULT (datetime('now')),
+            updated TEXT NOT NULL DEFAULT (datetime('now'))
+        );
+        CREATE TABLE IF NOT EXISTS files (
+            job INTEGER NOT NULL REFERENCES jobs (id),
+            n INTEGER NOT NULL,
+            path TEXT NOT NULL,
+            PRIMARY KEY (job, n)
+        );
+        CREATE TABLE IF NOT EXISTS locations (
+            job INTEGER NOT NULL REFERENCES jobs (id),
+            n INTEGER NOT NULL,
+            location TEXT NOT NULL,
+            PRIMARY KEY (job, n)
+        );
+        CREATE TABLE IF NOT EXISTS batches (
+            job INTEGER PRIMARY KEY REFERENCES jobs (id),
+            args TEXT NOT NULL,
+            dir TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS sheets (
+            job INTEGER NOT NULL REFERENCES jobs (id),
+            sheet INTEGER NOT NULL,
+            progress TEXT NOT NULL,
+            page TEXT,
+            PRIMARY KEY (job, sheet)
+        );
+    ";
+
+    /// A job as stored, with when it was submitted and last changed in UTC
+    #[derive(Debug, Serialize)]
+    struct Record {
+        #[serde(flatten)]
+        job: Job,
+        submitted: String,
+        updated: String,
+    }
+
+    pub struct JobStore(Mutex<Connection>);
+
+    fn state_name(state: JobState) -> String {
+        match serde_json::to_value(state) {
+            Ok(serde_json::Value::String(name)) => name,
+            _ => unreachable!("states serialize to their names"),
+        }
+    }
+
+    impl JobStore {
+        pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+            let db = Connection::open(path)?;
+            // Lets `skanny jobs` read while the service writes
+            db.busy_timeout(Duration::from_secs(5))?;
+            db.pragma_update(None, "journal_mode", "wal")?;
+            db.execute_batch(SCHEMA)?;
+            Ok(Self(Mutex::new(db)))
+        }
+
+        fn save(&self, job: &Job) -> Result<(), Box<dyn std::error::Error>> {
+            let mut db = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            let tx = db.transaction()?;
+            tx.execute(
+                "INSERT INTO jobs (id, device, profile, options, client, state, error)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT (id) DO UPDATE SET
+                     state = excluded.state,
+                     error = excluded.error,
+                     updated = datetime('now')",
+                params![
+                    job.id as i64,
+                    job.device,
+                    job.profile,
+                    serde_json::to_string(&job.options)?,
+                    job.client,
+                    state_name(job.state),
+                    job.error,
+                ],
+            )?;
+            tx.execute("DELETE FROM files WHERE job = ?1", [job.id as i64])?;
+            for (n, path) in job.files.iter().enumerate() {
+                tx.execute(
+                    "INSERT INTO files (job, n, path) VALUES (?1, ?2, ?3)",
+                    params![job.id as i64, n as i64, path.to_string_lossy()],
+                )?;
+            }
+            tx.execute("DELETE FROM locations WHERE job = ?1", [job.id as i64])?;
+            for (n, location) in job.locations.iter().enumerate() {
+                tx.execute(
+                    "INSERT INTO locations (job, n, location) VALUES (?1, ?2, ?3)",
+                    params![job.id as i64, n as i64, location],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        }
+
+        /// The job `id`, or all of them
+        fn records(&self, id: Option<usize>) -> Result<Vec<Record>, Box<dyn std::error::Error>> {
+            let db = self.0.lock().unwrap_or_else(|e| e.into_inner());
+            let mut jobs = db.prepare(
+                "SELECT id, device, profile, options, client, state, error, submitted, updated
+                 FROM jobs WHERE ?1 IS NULL OR id = ?1 ORDER BY id",
+            )?;
+            let mut files = db.prepare("SELECT path FROM files WHERE job = ?1 ORDER BY n")?;
+            let mut locations =
+                db.prepare("SELECT location FROM locations WHERE job = ?1 ORDER BY n")?;
+
+            let mut records = Vec::new();
+            let mut rows = jobs.query([id.map(|id| id as i64)])?;
+            while let Some(row) = rows.next()? {
+                let id: i64 = row.get(0)?;
+                let options: String = row.get(3)?;
+                let state: String = row.get(5)?;
+                let job = Job {
+                    id: id as usize,
+                    device: row.get(1)?,
+                    profile: row.get(2)?,
+                    options: serde_json::from_str(&options)?,
+                    client: row.get(4)?,
+                    state: serde_json::from_value(serde_json::Value::String(state))?,
+                    error: row.get(6)?,
+                    files: files
+                        .query_map([id], |row| row.get::<_, String>(0))?
+                        .map(|path| path.map(PathBuf::from))
+                        .collect::<Result<_, _>>()?,
+                    locations: locations
+                        .query_map([id], |row| row.get(0))?
+                        .collect::<Result<_, _>>()?,
+                };
+                records.push(Record {
+                    job,
+                    submitted: row.get(7)?,
+                    updated: row.get(8)?,
+                });
+            }
+            Ok(records)
+        }
+    }
+
+    impl Batch {
+        /// Keeps a new batch of `device` into `dir`, started with the
+        /// command line `args`
+        pub fn start(
+            path: &Path,
+            device: &str,
+            dir: &Path,
+            args: Vec<String>,
+        ) -> Result<Self, Box<dyn std::error::Error>> {
+            let store = JobStore::open(path)?;
+            let id = {
+                let db = store.0.lock().unwrap_or_else(|e| e.into_inner());
+                let id: i64 =
+                    db.query_row("SELECT coalesce(max(id) + 1, 0) FROM jobs", [], |row| {
+                        row.get(0)
+                    })?;
+                id as usize
+            };
+            let job = Job {
+                id,
+                device: device.to_owned(),
+                profile: "batch".to_owned(),
+                options: Default::default(),
+                client: None,
+                state: JobState::Scanning,
+                error: None,
+                files: Vec::new(),
+                locations: Vec::new(),
+            };
+            store.save(&job)?;
+            store.0.lock().unwrap_or_else(|e| e.into_inner()).execute(
+                "INSERT INTO batches (job, args, dir) VALUES (?1, ?2, ?3)",
+                params![
+                    id as i64,
+                    serde_json::to_string(&args)?,
+                    dir.to_string_lossy()
+                ],
+            )?;
+            tracing::info!("Keeping the batch as job {}", id);
+            Ok(Self {
+                store,
+                path: path.to_owned(),
+                job: Mutex::new(job),
+            })
+        }
+
+        /// Takes up the batch `id` again, from the first sheet it did not
+        /// store
+        pub fn resume(
+            path: &Path,
+            id: usize,
+        ) -> Result<(Self, Resumed), Box<dyn std::error::Error>> {
+            let store = JobStore::open(path)?;
+            let mut job = store
+                .records(Some(id))?
+                .pop()
+                .ok_or_else(|| format!("No job {}", id))?
+                .job;
+            if job.state == JobState::Done {
+                return Err(format!("Job {} is done", id).into());
+            }
+            let (dir, sheets) = {
+                let db = store.0.lock().unwrap_or_else(|e| e.into_inner());
+                let dir: String = db
+                    .query_row(
+                        "SELECT dir FROM batches WHERE job = ?1",
+                        [id as i64],
+                        |row| row.get(0),
+                    )
+                    .map_err(|_| format!("Job {} is not a batch", id))?;
+                let mut sheets = db.prepare(
+                    "SELECT sheet, progress, page FROM sheets WHERE job = ?1 ORDER BY sheet",
+                )?;
+                let sheets = sheets
+                    .query_map([id as i64], |row| {
+                        Ok((
+                            row.get::<_, i64>(0)? as usize,
+                            row.get::<_, String>(1)?,
+                            row.get::<_, Option<String>>(2)?,
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                (PathBuf::from(dir), sheets)
+            };
+
+            // Pages are stored out of order, those after a missing sheet
+            // are scanned again
+            let missing = sheets
+                .iter()
+                .zip(1..)
+                .find(|((sheet, _, _), expected)| sheet != expected)
+                .map_or(sheets.len() + 1, |(_, expected)| expected);
+            let mut progress = Progress::default();
+            let mut pages = Vec::new();
+            for (sheet, stored, page) in sheets {
+                let page: Option<Page> = page.as_deref().map(serde_json::from_str).transpose()?;
+                if sheet < missing {
+                    progress = serde_json::from_str(&stored)?;
+                    pages.extend(page);
+                } else if let Some(page) = page {
+                    match std::fs::remove_file(&page.path) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                        _ => {}
+                    }
+                }
+            }
+            store.0.lock().unwrap_or_else(|e| e.into_inner()).execute(
+                "DELETE FROM sheets WHERE job = ?1 AND sheet >= ?2",
+                params![id as i64, missing as i64],
+            )?;
+            job.state = JobState::Scanning;
+            job.error = None;
+            job.files = pages.iter().map(|page| page.path.clone()).collect();
+            store.save(&job)?;
+            tracing::info!("Going on with batch {} from sheet {}", id, missing);
+            let batch = Self {
+                store,
+                path: path.to_owned(),
+                job: Mutex::new(job),
+            };
+            Ok((
+                batch,
+                Resumed {
+                    dir,
+                    progress,
+                    pages,
+                },
+            ))
+        }
+
+        /// Keeps a sheet taken from the feeder, with its page if it had one
+        pub fn sheet(&self, progress: &Progress, page: Option<&Page>) {
+            let mut job = self.job.lock().unwrap_or_else(|e| e.into_inner());
+            let saved = (|| -> Result<(), Box<dyn std::error::Error>> {
+                let page = page.map(serde_json::to_string).transpose()?;
+                self.store
+                    .0
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .execute(
+                        "INSERT OR REPLACE INTO sheets (job, sheet, progress, page)
+                         VALUES (?1, ?2, ?3, ?4)",
+                        params![
+                            job.id as i64,
+                            progress.sheets as i64,
+                            serde_json::to_string(progress)?,
+                            page
+                        ],
+                    )?;
+                Ok(())
+            })();
+            if let Some(page) = page {
+                job.files.push(page.path.clone());
+                self.store.changed(&job);
+            }
+            if let Err(e) = saved {
+                tracing::warn!("Keeping sheet {} failed: {}", progress.sheets, e);
+            }
+        }
+
+        /// Marks the batch as done, or as failed with `error`
+        pub fn finish(&self, error: Option<String>) {
+            let mut job = self.job.lock().unwrap_or_else(|e| e.into_inner());
+            if error.is_some() {
+                job.state = JobState::Failed;
+                tracing::info!(
+                    "Go on with the batch by skanny jobs --store {} resume {}",
+                    self.path.display(),
+                    job.id
+                );
+            } else {
+                job.state = JobState::Done;
+            }
+            job.error = error;
+            self.store.changed(&job);
+        }
+    }
+
+    impl JobObserver for JobStore {
+        fn changed(&self, job: &Job) {
+            if let Err(e) = self.save(job) {
+                tracing::warn!("Storing job {} failed: {}", job.id, e);
+            }
+        }
+    }
+
+    /// Keeps the jobs of `queue` in the database at `path`, along with those
+    /// of earlier runs
+    pub fn attach(queue: &mut JobQueue, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let store = JobStore::open(path)?;
+        let previous: Vec<Job> = store
+            .records(None)?
+            .into_iter()
+            .map(|record| record.job)
+            .collect();
+        queue.observe(store);
+        queue.restore(previous);
+        Ok(())
+    }
+
+    pub fn run(opts: &JobsOptions) -> Result<(), Box<dyn std::error::Error>> {
+        let path = Path::new(&opts.store);
+        if !path.exists() {
+            return Err(format!("{} does not exist", path.display()).into());
+        }
+        let store = JobStore::open(path)?;
+        match &opts.command {
+            Some(JobsCommand::List(list)) => {
+                let mut records = store.records(None)?;
+                if let Some(state) = &list.state {
+                    records.retain(|record| state_name(record.job.state) == *state);
+                }
+                if list.json {
+                    println!("{}", serde_json::to_string_pretty(&records)?);
+                    return Ok(());
+                }
+                println!(
+                    "{:>6} {:<10} {:<20} {:<19} result",
+                    "id", "state", "profile", "updated"
+                );
+                for Record { job, updated, .. } in &records {
+                    let result = match (&job.error, job.locations.first(), job.files.first()) {
+                        (Some(error), _, _) => error.clone(),
+                        (None, Some(location), _) => location.clone(),
+                        (None, None, Some(file)) => file.display().to_string(),
+                        (None, None, None) => String::new(),
+                    };
+                    println!(
+                        "{:>6} {:<10} {:<20} {:<19} {}",
+                        job.id,
+                        state_name(job.state),
+                        job.profile,
+                        updated,
+                        result
+                    );
+                }
+            }
+            Some(JobsCommand::Show(show)) => {
+                let id = show.id.ok_or("Expected the id of a job")?;
+                let record = store
+                    .records(Some(id))?
+                    .pop()
+                    .ok_or_else(|| format!("No job {}", id))?;
+                if show.json {
+                    println!("{}", serde_json::to_string_pretty(&record)?);
+                    return Ok(());
+                }
+                let job = &record.job;
+                println!("job {}", job.id);
+                println!("  state: {}", state_name(job.state));
+                if let Some(error) = &job.error {
+                    println!("  error: {}", error);
+                }
+                println!("  device: {}", job.device);
+                println!("  profile: {}", job.profile);
+                if let Some(client) = &job.client {
+                    println!("  client: {}", client);
+                }
+                for (name, value) in &job.options {
+                    println!("  option {}: {}", name, serde_json::to_string(value)?);
+                }
+                println!("  submitted: {}", record.submitted);
+                println!("  updated: {}", record.updated);
+                for file in &job.files {
+                    println!("  file: {}", file.display());
+                }
+                for location in &job.locations {
+                    println!("  uploaded to: {}", location);
+                }
+            }
+            Some(JobsCommand::Resume(resume)) => {
+                let id = resume.id.ok_or("Expected the id of a batch")?;
+                let args: String = store
+                    .0
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .query_row(
+                        "SELECT args FROM batches WHERE job = ?1",
+                        [id as i64],
+                        |row| row.get(0),
+                    )
+                    .map_err(|_| format!("Job {} is not a batch", id))?;
+                let args: Vec<String> = serde_json::from_str(&args)?;
+                let status = std::process::Command::new(std::env::current_exe()?)
+                    .args(args)
+                    .arg("--resume")
+                    .arg(id.to_string())
+                    .status()?;
+                if !status.success() {
+                    return Err(format!("The batch stopped with {}", status).into());
+                }
+            }
+            None => return Err("Expected a command, see skanny jobs --help".into()),
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::collections::BTreeMap;
+
+        #[test]
+        fn keeps_jobs_across_restarts() {
+            let path =
+                std::env::temp_dir().join(format!("skanny-jobs-{}.sqlite", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+
+            let store = JobStore::open(&path).unwrap();
+            let mut options = BTreeMap::new();
+            options.insert("resolution".to_owned(), skanny::OptionValue::Int(300));
+            let mut job = Job {
+                id: 0,
+                device: "mock:demo".to_owned(),
+                profile: "letter".to_owned(),
+                options,
+                client: Some("office".to_owned()),
+                state: JobState::Uploading,
+                error: None,
+                files: vec![PathBuf::from("scan.png")],
+                locations: Vec::new(),
+            };
+            store.changed(&job);
+            job.state = JobState::Done;
+            job.locations.push("s3://bucket/scan.png".to_owned());
+            store.changed(&job);
+            store.changed(&Job {
+                id: 1,
+                state: JobState::Scanning,
+                files: Vec::new(),
+                locations: Vec::new(),
+                ..job.clone()
+            });
+            drop(store);
+
+            let mut queue = JobQueue::new(1);
+            attach(&mut queue, &path).unwrap();
+            let done = queue.get(0).unwrap();
+            assert_eq!(done.state, JobState::Done);
+            assert_eq!(done.options, job.options);
+            assert_eq!(done.locations, job.locations);
+            assert_eq!(queue.get(1).unwrap().state, JobState::Failed);
+            assert_eq!(
+                queue.submit("mock:demo", "letter", &BTreeMap::new(), None),
+                Ok(2)
+            );
+
+            let records = JobStore::open(&path).unwrap().records(None).unwrap();
+            assert_eq!(records.len(), 3);
+            assert_eq!(records[1].job.state, JobState::Failed);
+            assert_eq!(records[2].job.state, JobState::Queued);
+            drop(queue);
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn resumes_a_batch_at_the_first_missing_sheet() {
+            let dir = std::env::temp_dir().join(format!("skanny-batch-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("jobs.sqlite");
+            let batch = Batch::start(&path, "mock:demo", &dir, vec!["--separator".into()]).unwrap();
+            let stored = |sheet: usize| {
+                let progress = Progress {
+                    sheets: sheet,
+                    documents: 1,
+                    document: Some(dir.join("document_0001")),
+                };
+                let page = Page {
+                    path: dir.join(format!("page_{:04}.png", sheet)),
+                    double_feed: false,
+                    scan: crate::manifest::Scan::time(sheet, || ()).1,
+                    width: 10,
+                    height: 10,
+                };
+                std::fs::write(&page.path, b"png").unwrap();
+                (progress, page)
+            };
+            // The page of the second sheet was being saved
+            for sheet in [1, 3] {
+                let (progress, page) = stored(sheet);
+                batch.sheet(&progress, Some(&page));
+            }
+            batch.finish(Some("Stopped".to_owned()));
+            drop(batch);
+
+            let (batch, resumed) = Batch::resume(&path, 0).unwrap();
+            assert_eq!(resumed.dir, dir);
+            assert_eq!(resumed.progress, stored(1).0);
+            assert_eq!(resumed.pages.len(), 1);
+            assert_eq!(resumed.pages[0].scan.number, 1);
+            assert!(!dir.join("page_0003.png").exists());
+            batch.finish(None);
+            drop(batch);
+            assert!(Batch::resume(&path, 0).is_err());
+            std::fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
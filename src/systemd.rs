@@ -0,0 +1,105 @@
+//! Integration of the daemon with systemd
+//!
+//! Under a `Type=notify` service the daemon tells systemd when it takes
+//! connections and when it stops, and with `WatchdogSec=` it pings the
+//! watchdog between connections, so a hung daemon is restarted. Scans are
+//! served one at a time, so the watchdog has to allow for the longest one.
+//! With a socket unit the daemon takes the socket bound by systemd instead
+//! of binding its own, and is started by the first connection:
+//!
+//! ```ini
+//! # skanny.socket
+//! [Socket]
+//! ListenStream=%t/skanny.sock
+//!
+//! # skanny.service
+//! [Service]
+//! Type=notify
+//! WatchdogSec=120
+//! ExecStart=/usr/bin/skanny --device pixma daemon --profiles /etc/skanny.toml
+//! ```
+//!
+//! Outside of systemd all of this does nothing.
+
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixListener;
+use std::time::{Duration, Instant};
+
+use sd_notify::NotifyState;
+
+fn notify(states: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, states) {
+        tracing::warn!("Notifying systemd failed: {}", e);
+    }
+}
+
+/// Tells systemd the daemon takes connections
+pub fn ready(status: &str) {
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+/// Tells systemd the daemon is stopping
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// The listening socket passed by systemd, if the daemon was started by a
+/// socket unit
+pub fn listener() -> std::io::Result<Option<UnixListener>> {
+    let mut fds = sd_notify::listen_fds()?;
+    let listener = fds.next().map(|fd| {
+        // systemd hands over the descriptor, nobody else owns it
+        unsafe { UnixListener::from_raw_fd(fd) }
+    });
+    if fds.next().is_some() {
+        tracing::warn!("systemd passed several sockets, listening on the first");
+    }
+    Ok(listener)
+}
+
+/// Pings of the watchdog of the service
+pub struct Watchdog {
+    interval: Option<Duration>,
+    last: Instant,
+}
+
+/// How often to ping a watchdog firing after `usec`, twice as often as
+/// needed to allow for delays
+fn interval(usec: u64) -> Duration {
+    Duration::from_micros(usec) / 2
+}
+
+impl Watchdog {
+    /// The watchdog set up by systemd, if any
+    pub fn from_env() -> Self {
+        let mut usec = 0;
+        let interval = sd_notify::watchdog_enabled(false, &mut usec).then(|| interval(usec));
+        Self {
+            interval,
+            last: Instant::now(),
+        }
+    }
+
+    /// Pings the watchdog if it is due
+    pub fn ping(&mut self) {
+        if let Some(interval) = self.interval {
+            if self.last.elapsed() >= interval {
+                notify(&[NotifyState::Watchdog]);
+                self.last = Instant::now();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pings_twice_per_watchdog_period() {
+        assert_eq!(interval(120_000_000), Duration::from_secs(60));
+        // Not started by systemd
+        assert!(listener().unwrap().is_none());
+        assert!(Watchdog::from_env().interval.is_none());
+    }
+}
@@ -0,0 +1,394 @@
+//! Expansion of placeholders in paths and names
+//!
+//! | Placeholder | Expands to                       |
+//! |-------------|----------------------------------|
+//! | `{name}`    | File name of the scan            |
+//! | `{year}`    | Four digit year                  |
+//! | `{month}`   | Two digit month                  |
+//! | `{day}`     | Two digit day of the month       |
+//! | `{date}`    | `{year}-{month}-{day}`           |
+//!
+//! Times are in UTC. File names of batches may also contain `{page}`, see
+//! [`Numbering`].
+//!
+//! Output directories may contain the placeholders of dates, as in
+//! `--dir scans/{year}/{month}`, and `--organize by-date` stores pages in
+//! `{year}/{month}/{day}` below the directory, see [`directory`].
+//!
+//! Names made from templates and QR codes are made safe for Windows and SMB
+//! shares before files are created, see [`safe_name`]: characters those do
+//! not allow become `--name-replacement`, names are cut to
+//! `--name-max-length` bytes keeping the extension, and names of devices
+//! such as `CON` or `LPT1` get the replacement in front.
+
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    pub fn now() -> Self {
+        Self::from(SystemTime::now())
+    }
+}
+
+/// As in ISO 8601, `2024-01-31T12:00:00Z`
+impl std::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+impl From<SystemTime> for DateTime {
+    fn from(time: SystemTime) -> Self {
+        let secs = match time.duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(since) => since.as_secs() as i64,
+            Err(before) => -(before.duration().as_secs() as i64),
+        };
+        let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+        // Civil date from days since epoch, by Howard Hinnant
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        Self {
+            year,
+            month: month as u32,
+            day: day as u32,
+            hour: (rem / 3600) as u32,
+            minute: (rem % 3600 / 60) as u32,
+            second: (rem % 60) as u32,
+        }
+    }
+}
+
+/// Replaces the placeholders in `template`, leaving unknown ones untouched
+pub fn expand(template: &str, name: &str, time: &DateTime) -> String {
+    template
+        .replace("{name}", name)
+        .replace(
+            "{date}",
+            &format!("{:04}-{:02}-{:02}", time.year, time.month, time.day),
+        )
+        .replace("{year}", &format!("{:04}", time.year))
+        .replace("{month}", &format!("{:02}", time.month))
+        .replace("{day}", &format!("{:02}", time.day))
+}
+
+/// How pages are laid out below the output directory
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Organize {
+    /// All in the directory
+    #[default]
+    Flat,
+    /// In a directory per day, as `2024/01/31`
+    ByDate,
+}
+
+impl FromStr for Organize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(Organize::Flat),
+            "by-date" => Ok(Organize::ByDate),
+            _ => Err(format!("Unknown layout {}, expected flat or by-date", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub organize: Organize,
+    /// Replaces characters which are not allowed in names
+    pub replacement: char,
+    /// Bytes of a name at most
+    pub max_length: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            organize: Organize::Flat,
+            replacement: '_',
+            max_length: 255,
+        }
+    }
+}
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Sets how pages are laid out and named, before any are stored
+pub fn init(settings: Settings) -> Result<(), Box<dyn std::error::Error>> {
+    if !is_allowed(settings.replacement) {
+        return Err(format!(
+            "{:?} is not allowed in names, and cannot replace what is not",
+            settings.replacement
+        )
+        .into());
+    }
+    // Room for an extension and a page number
+    if settings.max_length < 16 {
+        return Err("Names must be allowed at least 16 bytes".into());
+    }
+    SETTINGS
+        .set(settings)
+        .map_err(|_| "The layout of the output directory is already set".into())
+}
+
+fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+/// The directory pages scanned at `time` go in, `dir` with its placeholders
+/// expanded and the directory of the day below it when organized by date
+pub fn directory(dir: &Path, time: &DateTime) -> PathBuf {
+    organized(dir, settings().organize, time)
+}
+
+fn organized(dir: &Path, organize: Organize, time: &DateTime) -> PathBuf {
+    // Paths which are not UTF-8 cannot have placeholders
+    let dir = match dir.to_str() {
+        Some(template) => PathBuf::from(expand(template, "", time)),
+        None => dir.to_owned(),
+    };
+    match organize {
+        Organize::Flat => dir,
+        Organize::ByDate => dir
+            .join(format!("{:04}", time.year))
+            .join(format!("{:02}", time.month))
+            .join(format!("{:02}", time.day)),
+    }
+}
+
+/// Whether Windows allows `c` in names
+fn is_allowed(c: char) -> bool {
+    !c.is_control() && !matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*')
+}
+
+/// `name` with the characters which are not allowed replaced
+fn replace_invalid(name: &str, replacement: char) -> String {
+    name.chars()
+        .map(|c| if is_allowed(c) { c } else { replacement })
+        .collect()
+}
+
+/// The longest start of `s` of at most `max` bytes
+fn truncate(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Names Windows keeps for devices, whatever their extension
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    let upper = stem.to_ascii_uppercase();
+    match upper.as_bytes() {
+        b"CON" | b"PRN" | b"AUX" | b"NUL" => true,
+        [b'C', b'O', b'M', digit] | [b'L', b'P', b'T', digit] => (b'1'..=b'9').contains(digit),
+        _ => false,
+    }
+}
+
+/// Makes `name` safe as a file or directory name
+///
+/// Names which are nothing but dots and spaces, such as `..`, come back
+/// empty.
+pub fn safe_name(name: &str) -> String {
+    let settings = settings();
+    safe(name, settings.replacement, settings.max_length)
+}
+
+fn safe(name: &str, replacement: char, max_length: usize) -> String {
+    // Windows drops dots and spaces at the end
+    let name = replace_invalid(name, replacement)
+        .trim_end_matches(['.', ' '])
+        .to_owned();
+    let name = if is_reserved(&name) {
+        format!("{}{}", replacement, name)
+    } else {
+        name
+    };
+    if name.len() <= max_length {
+        return name;
+    }
+    // Cut the stem, unless the extension takes most of the room
+    match name.rfind('.') {
+        Some(dot) if dot > 0 && name.len() - dot <= max_length / 2 => {
+            let extension = &name[dot..];
+            format!(
+                "{}{}",
+                truncate(&name[..dot], max_length - extension.len()),
+                extension
+            )
+        }
+        _ => truncate(&name, max_length).to_owned(),
+    }
+}
+
+/// Numbers the pages of a batch, continuing after those already in the
+/// directory
+///
+/// `{page}` in the file name is replaced by a four digit counter, the other
+/// placeholders by the time the batch started.
+#[derive(Debug, Clone)]
+pub struct Numbering {
+    dir: PathBuf,
+    prefix: String,
+    suffix: String,
+    next: u32,
+}
+
+impl Numbering {
+    pub fn resume(dir: &Path, template: &str) -> std::io::Result<Self> {
+        let settings = settings();
+        let template = expand(template, "", &DateTime::now());
+        let (prefix, suffix) = template.split_once("{page}").ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} has no {{page}} placeholder", template),
+            )
+        })?;
+        // The counter is safe, and leaves the rest of the name its room
+        let suffix = safe(suffix, settings.replacement, settings.max_length / 2);
+        let prefix = replace_invalid(prefix, settings.replacement);
+        let room = settings.max_length - suffix.len() - 4;
+        let mut numbering = Self {
+            dir: dir.to_owned(),
+            prefix: truncate(&prefix, room).to_owned(),
+            suffix,
+            next: 1,
+        };
+        for entry in std::fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            if let Some(page) = name.to_str().and_then(|name| numbering.page(name)) {
+                numbering.next = numbering.next.max(page + 1);
+            }
+        }
+        Ok(numbering)
+    }
+
+    /// The counter in `name` if it follows the template
+    fn page(&self, name: &str) -> Option<u32> {
+        let digits = name
+            .strip_prefix(&self.prefix)?
+            .strip_suffix(&self.suffix)?;
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    }
+
+    /// Path of the next page, skipping pages created meanwhile
+    pub fn next_path(&mut self) -> PathBuf {
+        loop {
+            let path = self
+                .dir
+                .join(format!("{}{:04}{}", self.prefix, self.next, self.suffix));
+            self.next += 1;
+            if !path.exists() {
+                return path;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn civil_date() {
+        let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_582_979_696);
+        assert_eq!(
+            DateTime::from(time),
+            DateTime {
+                year: 2020,
+                month: 2,
+                day: 29,
+                hour: 12,
+                minute: 34,
+                second: 56,
+            }
+        );
+    }
+
+    #[test]
+    fn placeholders() {
+        let time = DateTime::from(SystemTime::UNIX_EPOCH);
+        assert_eq!(
+            expand("{year}/{month}/{date}_{name}", "a.png", &time),
+            "1970/01/1970-01-01_a.png"
+        );
+    }
+
+    #[test]
+    fn organizes_by_date() {
+        let time = DateTime::from(SystemTime::UNIX_EPOCH);
+        let dir = Path::new("scans/{year}");
+        assert_eq!(
+            organized(dir, Organize::Flat, &time),
+            Path::new("scans/1970")
+        );
+        assert_eq!(
+            organized(dir, Organize::ByDate, &time),
+            Path::new("scans/1970/1970/01/01")
+        );
+    }
+
+    #[test]
+    fn safe_names() {
+        assert_eq!(
+            safe("2024-01-31 12:00:00.png", '_', 255),
+            "2024-01-31 12_00_00.png"
+        );
+        assert_eq!(safe("con.png", '_', 255), "_con.png");
+        assert_eq!(safe("COM1", '-', 255), "-COM1");
+        assert_eq!(safe("COM10", '_', 255), "COM10");
+        assert_eq!(safe("invoices/2020. ", '_', 255), "invoices_2020");
+        assert_eq!(safe("..", '_', 255), "");
+        assert_eq!(safe("æøå.png", '_', 9), "æø.png");
+        assert_eq!(safe("page.verylongextension", '_', 16), "page.verylongext");
+    }
+
+    #[test]
+    fn numbering_resumes() {
+        let dir = std::env::temp_dir().join(format!("skanny-numbering-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in &[
+            "page_0002.png",
+            "page_0010.png",
+            "page_x.png",
+            "other_0042.png",
+        ] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+        let mut numbering = Numbering::resume(&dir, "page_{page}.png").unwrap();
+        let next = numbering.next_path();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(next, dir.join("page_0011.png"));
+        assert_eq!(numbering.next_path(), dir.join("page_0012.png"));
+    }
+}
@@ -0,0 +1,189 @@
+//! Controls of the `test` backend of sane-backends
+//!
+//! `--testdevice` scans with the backend, and the `--test-*` flags set its
+//! options to reproduce a frame format or a failure on demand:
+//!
+//! ```text
+//! skanny --testdevice --test-mode Color --test-depth 16 --test-three-pass
+//! skanny --testdevice --test-picture Grid --test-width 210 --test-height 297
+//! skanny --testdevice --test-error io-error
+//! ```
+//!
+//! Errors are named as SANE statuses without the prefix, such as
+//! `io-error`, `jammed` or `no-docs`, and are returned by every read.
+
+use skanny::backend::{BackendError, ScannerDevice};
+use skanny::OptionValue;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// `Solid black`, `Solid white`, `Color pattern` or `Grid`
+    pub picture: String,
+    /// `Lineart`, `Gray` or `Color`
+    pub mode: Option<String>,
+    pub depth: Option<i32>,
+    /// Size of the scan area in mm, from the top left corner
+    pub width: Option<f64>,
+    pub height: Option<f64>,
+    /// Send colour scans as a frame per channel
+    pub three_pass: bool,
+    /// Leave the number of lines unknown until the end of the frame
+    pub hand_scanner: bool,
+    /// Turn on the options of the backend which test frontends
+    pub test_options: bool,
+    /// Status which reads return
+    pub error: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            picture: "Color pattern".to_owned(),
+            mode: None,
+            depth: None,
+            width: None,
+            height: None,
+            three_pass: false,
+            hand_scanner: false,
+            test_options: false,
+            error: None,
+        }
+    }
+}
+
+/// The value of `read-return-value` for an error such as `io-error`
+fn status(error: &str) -> String {
+    format!(
+        "SANE_STATUS_{}",
+        error.to_ascii_uppercase().replace('-', "_")
+    )
+}
+
+/// Sets the options of the test device, those which others depend on
+/// first
+pub fn apply(device: &dyn ScannerDevice, settings: &Settings) -> Result<(), BackendError> {
+    let set = |name: &str, value: OptionValue| {
+        device
+            .set_option(name, &value)
+            .map_err(|e| format!("Setting {} of the test device to {}: {}", name, value, e))
+    };
+    set(
+        "enable-test-options",
+        OptionValue::Bool(settings.test_options),
+    )?;
+    if let Some(mode) = &settings.mode {
+        set("mode", OptionValue::String(mode.clone()))?;
+    }
+    if let Some(depth) = settings.depth {
+        set("depth", OptionValue::Int(depth))?;
+    }
+    set(
+        "test-picture",
+        OptionValue::String(settings.picture.clone()),
+    )?;
+    if settings.three_pass {
+        set("three-pass", OptionValue::Bool(true))?;
+    }
+    if settings.hand_scanner {
+        set("hand-scanner", OptionValue::Bool(true))?;
+    }
+    if let Some(width) = settings.width {
+        set("tl-x", OptionValue::Fixed(0.0))?;
+        set("br-x", OptionValue::Fixed(width))?;
+    }
+    if let Some(height) = settings.height {
+        set("tl-y", OptionValue::Fixed(0.0))?;
+        set("br-y", OptionValue::Fixed(height))?;
+    }
+    if let Some(error) = &settings.error {
+        set("read-return-value", OptionValue::String(status(error)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sane_sys::*;
+    use skanny::backend::{Constraint, OptionInfo};
+    use skanny::mock::{DeviceSpec, MockDevice};
+
+    #[test]
+    fn sets_the_backend_options() {
+        let mut spec = DeviceSpec::default();
+        let option = |name: &str, type_, value| OptionInfo {
+            name: name.to_owned(),
+            title: String::new(),
+            desc: String::new(),
+            type_,
+            unit: SANE_Unit_SANE_UNIT_NONE,
+            cap: (SANE_CAP_SOFT_SELECT | SANE_CAP_SOFT_DETECT) as SANE_Int,
+            constraint: Constraint::None,
+            value: Some(value),
+        };
+        let string = |s: &str| OptionValue::String(s.to_owned());
+        spec.options.extend(vec![
+            option(
+                "enable-test-options",
+                SANE_Value_Type_SANE_TYPE_BOOL,
+                OptionValue::Bool(false),
+            ),
+            option("depth", SANE_Value_Type_SANE_TYPE_INT, OptionValue::Int(8)),
+            option(
+                "test-picture",
+                SANE_Value_Type_SANE_TYPE_STRING,
+                string("Solid black"),
+            ),
+            option(
+                "tl-y",
+                SANE_Value_Type_SANE_TYPE_FIXED,
+                OptionValue::Fixed(0.0),
+            ),
+            option(
+                "br-y",
+                SANE_Value_Type_SANE_TYPE_FIXED,
+                OptionValue::Fixed(100.0),
+            ),
+            option(
+                "read-return-value",
+                SANE_Value_Type_SANE_TYPE_STRING,
+                string("Default"),
+            ),
+        ]);
+        let device = MockDevice::new(spec);
+        let settings = Settings {
+            depth: Some(16),
+            height: Some(297.0),
+            error: Some("io-error".to_owned()),
+            ..Settings::default()
+        };
+        apply(&device, &settings).unwrap();
+
+        let value = |name: &str| {
+            device
+                .options()
+                .unwrap()
+                .into_iter()
+                .find(|option| option.name == name)
+                .and_then(|option| option.value)
+        };
+        assert_eq!(value("depth"), Some(OptionValue::Int(16)));
+        assert_eq!(value("test-picture"), Some(string("Color pattern")));
+        assert_eq!(value("br-y"), Some(OptionValue::Fixed(297.0)));
+        assert_eq!(
+            value("read-return-value"),
+            Some(string("SANE_STATUS_IO_ERROR"))
+        );
+
+        let three_pass = Settings {
+            three_pass: true,
+            ..Settings::default()
+        };
+        let error = apply(&device, &three_pass).unwrap_err().to_string();
+        assert!(
+            error.starts_with("Setting three-pass of the test device"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,71 @@
+//! Small copies of the pages saved
+//!
+//! `--thumbnails 256` writes a JPEG of at most 256 by 256 pixels next to
+//! every page, `page.thumb.jpg` for `page.png`, for galleries and document
+//! management systems which show pages before fetching them. Thumbnails
+//! are made from the processed page before it is dropped, so it is not read
+//! back from the file.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use skanny::Image;
+
+static SIZE: OnceLock<Option<u32>> = OnceLock::new();
+
+/// Sets the largest side of thumbnails, none are written without it
+pub fn init(size: Option<u32>) -> Result<(), Box<dyn std::error::Error>> {
+    if size == Some(0) {
+        return Err("Thumbnails need a size of at least one pixel".into());
+    }
+    SIZE.set(size)
+        .map_err(|_| "The thumbnail settings are already set".into())
+}
+
+/// Where the thumbnail of the page at `path` goes
+pub fn path(page: &Path) -> PathBuf {
+    page.with_extension("thumb.jpg")
+}
+
+/// Writes the thumbnail of a page saved at `path`, if thumbnails are
+/// written
+pub fn write(image: &Image, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    match SIZE.get().copied().flatten() {
+        Some(size) => save(image, size, &self::path(path)),
+        None => Ok(()),
+    }
+}
+
+fn save(image: &Image, size: u32, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let small = image.to_dynamic().thumbnail(size, size);
+    // JPEG has 8 bit samples only
+    let small = match image {
+        Image::Rgb8(_) | Image::Rgb16(_) => image::DynamicImage::ImageRgb8(small.into_rgb8()),
+        _ => image::DynamicImage::ImageLuma8(small.into_luma8()),
+    };
+    crate::output::write(path, |temporary| Ok(small.save(temporary)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_small_jpegs() {
+        let dir = std::env::temp_dir().join(format!("skanny-thumbnail-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let page = dir.join("page.png");
+        assert_eq!(path(&page), dir.join("page.thumb.jpg"));
+
+        let image = Image::Rgb16(image::ImageBuffer::from_pixel(
+            1000,
+            500,
+            image::Rgb([0xffff, 0, 0]),
+        ));
+        save(&image, 256, &path(&page)).unwrap();
+        let thumbnail = image::open(path(&page)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(image::GenericImageView::dimensions(&thumbnail), (256, 128));
+        assert!(matches!(thumbnail, image::DynamicImage::ImageRgb8(_)));
+    }
+}
@@ -0,0 +1,522 @@
+//! Dashboard for scanning from the terminal
+//!
+//! `--tui` shows the device and its options, the progress of the page being
+//! scanned, thumbnails of the last pages and the latest log lines, and
+//! scans on these keys:
+//!
+//! | Key      | Action                                                  |
+//! |----------|---------------------------------------------------------|
+//! | `s`      | Scan, until the feeder is empty when scanning from one  |
+//! | `p`      | Pause before the next page, or resume                   |
+//! | `c`      | Cancel the page being scanned                           |
+//! | `q`      | Quit after the page being scanned, ctrl-c cancels it too |
+//!
+//! The dashboard takes the alternate screen of the terminal and is drawn
+//! with ANSI escape sequences, the thumbnails with half blocks in 24 bit
+//! colour. Log lines go to the dashboard unless `--log-file` is given.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use skanny::backend::{
+    is_cancelled, BackendError, CancelToken, Cancellable, FrameParameters, OptionInfo,
+    ScannerDevice,
+};
+use skanny::{Image, OptionValue};
+
+/// Log lines shown
+const LOG_LINES: usize = 8;
+/// Thumbnails shown, and their size in characters
+const THUMBNAILS: usize = 6;
+const THUMBNAIL_WIDTH: u32 = 16;
+const THUMBNAIL_HEIGHT: u32 = 10;
+/// How often the dashboard is drawn while scanning
+const FRAME: Duration = Duration::from_millis(100);
+
+/// The latest log lines, the last one possibly still being written
+struct Log {
+    lines: VecDeque<String>,
+    complete: bool,
+}
+
+static LOG: Mutex<Log> = Mutex::new(Log {
+    lines: VecDeque::new(),
+    complete: true,
+});
+
+/// Keeps log lines for the dashboard, which may be written in parts
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        let mut log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+        for part in String::from_utf8_lossy(buffer).split_inclusive('\n') {
+            if log.complete {
+                if log.lines.len() == LOG_LINES {
+                    log.lines.pop_front();
+                }
+                log.lines.push_back(String::new());
+            }
+            log.complete = part.ends_with('\n');
+            if let Some(line) = log.lines.back_mut() {
+                line.push_str(part.trim_end_matches('\n'));
+            }
+        }
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The terminal in the alternate screen, reading keys as they are pressed
+struct Terminal {
+    #[cfg(unix)]
+    saved: libc::termios,
+}
+
+impl Terminal {
+    fn enter() -> std::io::Result<Self> {
+        #[cfg(unix)]
+        let saved = unsafe {
+            let mut saved = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let mut raw = saved;
+            // ctrl-c arrives as a key, so the terminal is restored on quitting
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            saved
+        };
+        print!("\x1b[?1049h\x1b[?25l");
+        std::io::stdout().flush()?;
+        Ok(Self {
+            #[cfg(unix)]
+            saved,
+        })
+    }
+
+    /// Columns and rows
+    fn size() -> (usize, usize) {
+        #[cfg(unix)]
+        unsafe {
+            let mut size = std::mem::zeroed::<libc::winsize>();
+            if libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) == 0 && size.ws_col > 0
+            {
+                return (size.ws_col.into(), size.ws_row.into());
+            }
+        }
+        (80, 24)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = std::io::stdout().flush();
+        #[cfg(unix)]
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved);
+        }
+    }
+}
+
+/// A scanned page
+struct Thumbnail {
+    caption: String,
+    /// Lines of half blocks
+    lines: Vec<String>,
+}
+
+/// Draws `image` in at most `width` by `height` characters, two pixels to
+/// a character
+fn thumbnail(image: &Image, width: u32, height: u32) -> Vec<String> {
+    let small = image.to_dynamic().thumbnail(width, height * 2).to_rgb8();
+    (0..small.height())
+        .step_by(2)
+        .map(|y| {
+            let mut line = String::new();
+            for x in 0..small.width() {
+                let top = small.get_pixel(x, y);
+                line += &format!("\x1b[38;2;{};{};{}m", top[0], top[1], top[2]);
+                if y + 1 < small.height() {
+                    let bottom = small.get_pixel(x, y + 1);
+                    line += &format!("\x1b[48;2;{};{};{}m", bottom[0], bottom[1], bottom[2]);
+                }
+                line += "▀\x1b[0m";
+            }
+            line
+        })
+        .collect()
+}
+
+/// A progress bar of `width` characters
+fn bar(done: f64, width: usize) -> String {
+    let filled = ((done.clamp(0.0, 1.0) * width as f64).round() as usize).min(width);
+    "█".repeat(filled) + &"░".repeat(width - filled)
+}
+
+/// Cuts `text` to `width` characters
+fn fit(text: &str, width: usize) -> String {
+    text.chars().take(width).collect()
+}
+
+struct Dashboard<'a> {
+    device: &'a dyn ScannerDevice,
+    keys: Receiver<u8>,
+    token: CancelToken,
+    options: RefCell<Vec<OptionInfo>>,
+    thumbnails: RefCell<VecDeque<Thumbnail>>,
+    /// Number of the page being scanned and its progress
+    page: Cell<Option<usize>>,
+    read: Cell<u64>,
+    expected: Cell<Option<u64>>,
+    status: RefCell<String>,
+    scan: Cell<bool>,
+    quit: Cell<bool>,
+    drawn: Cell<Option<Instant>>,
+}
+
+impl Dashboard<'_> {
+    /// Acts on the keys pressed, waiting up to `timeout` for one
+    fn poll(&self, timeout: Duration) {
+        let mut key = match self.keys.recv_timeout(timeout) {
+            Ok(key) => Some(key),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => {
+                self.quit.set(true);
+                None
+            }
+        };
+        while let Some(pressed) = key {
+            match pressed {
+                b's' => self.scan.set(true),
+                b'p' if crate::pause::is_paused() => {
+                    crate::pause::resume();
+                    tracing::info!("Resumed");
+                }
+                b'p' => {
+                    crate::pause::pause();
+                    tracing::info!("Pausing before the next page");
+                }
+                b'c' => self.token.cancel(),
+                b'q' => self.quit.set(true),
+                // ctrl-c
+                3 => {
+                    self.token.cancel();
+                    self.quit.set(true);
+                }
+                _ => {}
+            }
+            key = self.keys.try_recv().ok();
+        }
+        self.draw();
+    }
+
+    fn draw(&self) {
+        self.drawn.set(Some(Instant::now()));
+        let (width, height) = Terminal::size();
+        let mut lines = Vec::new();
+        let state = if crate::pause::is_paused() {
+            "paused"
+        } else if self.page.get().is_some() {
+            "scanning"
+        } else {
+            "idle"
+        };
+        lines.push(format!(
+            "\x1b[1m{}\x1b[0m  {}",
+            fit(self.device.name(), width.saturating_sub(12)),
+            state
+        ));
+        let options: Vec<String> = self
+            .options
+            .borrow()
+            .iter()
+            .filter_map(|option| Some(format!("{}={}", option.name, option.value.as_ref()?)))
+            .collect();
+        let mut line = String::new();
+        let mut option_lines = 0;
+        for option in options {
+            if !line.is_empty() && line.chars().count() + option.chars().count() + 2 > width {
+                lines.push(std::mem::take(&mut line));
+                option_lines += 1;
+                if option_lines == 3 {
+                    break;
+                }
+            }
+            if !line.is_empty() {
+                line += "  ";
+            }
+            line += &option;
+        }
+        if !line.is_empty() && option_lines < 3 {
+            lines.push(fit(&line, width));
+        }
+        lines.push(String::new());
+
+        lines.push(match (self.page.get(), self.expected.get()) {
+            (Some(page), Some(expected)) if expected > 0 => {
+                let done = self.read.get() as f64 / expected as f64;
+                format!(
+                    "Page {} {} {:3.0}%",
+                    page,
+                    bar(done, width.saturating_sub(16).min(50)),
+                    done * 100.0
+                )
+            }
+            (Some(page), _) => format!("Page {} {} KiB", page, self.read.get() / 1024),
+            (None, _) => fit(&self.status.borrow(), width),
+        });
+        lines.push(String::new());
+
+        let thumbnails = self.thumbnails.borrow();
+        let shown = (width / (THUMBNAIL_WIDTH as usize + 2)).min(thumbnails.len());
+        let shown: Vec<&Thumbnail> = thumbnails.iter().skip(thumbnails.len() - shown).collect();
+        for row in 0..THUMBNAIL_HEIGHT as usize {
+            let mut line = String::new();
+            for thumbnail in &shown {
+                let cell = thumbnail.lines.get(row).map_or("", String::as_str);
+                let cells = thumbnail
+                    .lines
+                    .first()
+                    .map_or(0, |line| line.matches('▀').count());
+                let used = if cell.is_empty() { 0 } else { cells };
+                line += cell;
+                line += &" ".repeat(THUMBNAIL_WIDTH as usize + 2 - used);
+            }
+            lines.push(line);
+        }
+        lines.push(
+            shown
+                .iter()
+                .map(|thumbnail| {
+                    format!(
+                        "{:width$}",
+                        fit(&thumbnail.caption, THUMBNAIL_WIDTH as usize + 1),
+                        width = THUMBNAIL_WIDTH as usize + 2
+                    )
+                })
+                .collect(),
+        );
+        lines.push(String::new());
+
+        let log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+        let room = height.saturating_sub(lines.len() + 2);
+        lines.extend(
+            log.lines
+                .iter()
+                .skip(log.lines.len().saturating_sub(room))
+                .map(|line| fit(line, width)),
+        );
+        drop(log);
+
+        let mut screen = String::from("\x1b[H\x1b[2J");
+        for line in lines.iter().take(height.saturating_sub(1)) {
+            screen += line;
+            screen += "\r\n";
+        }
+        screen += &format!(
+            "\x1b[{};1H\x1b[7m s scan  p pause  c cancel page  q quit \x1b[0m",
+            height
+        );
+        let mut stdout = std::io::stdout().lock();
+        let _ = stdout.write_all(screen.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    /// Scans pages until the feeder is empty, or one from other sources
+    fn scan_batch(
+        &self,
+        device: &dyn ScannerDevice,
+        save: &mut dyn FnMut(Image) -> Result<(PathBuf, Image), BackendError>,
+    ) {
+        let feeder = self.options.borrow().iter().any(|option| {
+            option.name == "source"
+                && matches!(&option.value, Some(OptionValue::String(source))
+                    if ["adf", "feeder", "duplex"].iter().any(|name| source.to_lowercase().contains(name)))
+        });
+        let mut pages = 0;
+        while !self.quit.get() {
+            while crate::pause::is_paused() && !self.quit.get() {
+                self.poll(FRAME);
+            }
+            if self.quit.get() {
+                break;
+            }
+            pages += 1;
+            self.page.set(Some(pages));
+            self.read.set(0);
+            self.expected.set(None);
+            self.draw();
+            let scanned = device.scan();
+            self.page.set(None);
+            match scanned.and_then(&mut *save) {
+                Ok((path, image)) => {
+                    tracing::info!("Stored {}", path.display());
+                    let mut thumbnails = self.thumbnails.borrow_mut();
+                    if thumbnails.len() == THUMBNAILS {
+                        thumbnails.pop_front();
+                    }
+                    thumbnails.push_back(Thumbnail {
+                        caption: path
+                            .file_name()
+                            .unwrap_or_default()
+                            .to_string_lossy()
+                            .into_owned(),
+                        lines: thumbnail(&image, THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT),
+                    });
+                }
+                Err(e) if is_cancelled(&e) => tracing::warn!("Cancelled page {}", pages),
+                Err(e) if matches!(e.downcast_ref::<skanny::Error>(), Some(e) if e.is_no_docs()) => {
+                    tracing::info!("The feeder is empty");
+                    break;
+                }
+                Err(e) => {
+                    tracing::error!("Scanning page {} failed: {}", pages, e);
+                    break;
+                }
+            }
+            if !feeder {
+                break;
+            }
+        }
+        *self.status.borrow_mut() = format!("Scanned {} pages, press s to scan more", pages);
+        if let Ok(options) = self.device.options() {
+            *self.options.borrow_mut() = options;
+        }
+    }
+}
+
+/// Follows the progress of pages for the dashboard
+struct Watched<'a> {
+    inner: &'a dyn ScannerDevice,
+    dashboard: &'a Dashboard<'a>,
+}
+
+impl ScannerDevice for Watched<'_> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn options(&self) -> Result<Vec<OptionInfo>, BackendError> {
+        self.inner.options()
+    }
+
+    fn set_option(&self, name: &str, value: &OptionValue) -> Result<(), BackendError> {
+        self.inner.set_option(name, value)
+    }
+
+    fn start(&self) -> Result<FrameParameters, BackendError> {
+        let parameters = self.inner.start()?;
+        let expected = match parameters.lines {
+            lines if lines > 0 => Some(parameters.bytes_per_line.max(0) as u64 * lines as u64),
+            _ => None,
+        };
+        self.dashboard.expected.set(expected);
+        Ok(parameters)
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, BackendError> {
+        let read = self.inner.read(buffer)?;
+        let dashboard = self.dashboard;
+        dashboard.read.set(dashboard.read.get() + read as u64);
+        if dashboard
+            .drawn
+            .get()
+            .filter(|drawn| drawn.elapsed() < FRAME)
+            .is_none()
+        {
+            dashboard.poll(Duration::ZERO);
+        }
+        Ok(read)
+    }
+
+    fn cancel(&self) {
+        self.inner.cancel()
+    }
+}
+
+/// Shows the dashboard for `device` until quit, storing pages by `save`
+pub fn run(
+    device: &dyn ScannerDevice,
+    mut save: impl FnMut(Image) -> Result<(PathBuf, Image), BackendError>,
+) -> Result<(), BackendError> {
+    let (tx, keys) = channel();
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut key = [0];
+        while let Ok(1) = stdin.read(&mut key) {
+            if tx.send(key[0]).is_err() {
+                break;
+            }
+        }
+    });
+    let token = CancelToken::new();
+    let cancellable = Cancellable::new(device, token.clone());
+    let dashboard = Dashboard {
+        device,
+        keys,
+        token,
+        options: RefCell::new(device.options()?),
+        thumbnails: RefCell::new(VecDeque::new()),
+        page: Cell::new(None),
+        read: Cell::new(0),
+        expected: Cell::new(None),
+        status: RefCell::new("Press s to scan".to_owned()),
+        scan: Cell::new(false),
+        quit: Cell::new(false),
+        drawn: Cell::new(None),
+    };
+    let watched = Watched {
+        inner: &cancellable,
+        dashboard: &dashboard,
+    };
+    let _terminal = Terminal::enter()?;
+    while !dashboard.quit.get() {
+        dashboard.poll(FRAME * 5);
+        if dashboard.scan.replace(false) {
+            dashboard.scan_batch(&watched, &mut save);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_pages_and_progress() {
+        assert_eq!(bar(0.5, 8), "████░░░░");
+        assert_eq!(bar(1.5, 4), "████");
+
+        let image = Image::Gray8(image::ImageBuffer::from_pixel(40, 60, image::Luma([255])));
+        let lines = thumbnail(&image, 16, 10);
+        // Two rows of pixels to a line, the width as the aspect ratio allows
+        assert_eq!(lines.len(), 10);
+        assert!(lines
+            .iter()
+            .all(|line| line.matches("\x1b[38;2;255;255;255m").count() == 13));
+
+        let mut writer = LogWriter;
+        for n in 0..LOG_LINES + 2 {
+            writeln!(writer, "line {}", n).unwrap();
+        }
+        let log = LOG.lock().unwrap();
+        assert_eq!(log.lines.len(), LOG_LINES);
+        assert_eq!(
+            log.lines.back().unwrap(),
+            &format!("line {}", LOG_LINES + 1)
+        );
+    }
+}
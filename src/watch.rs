@@ -0,0 +1,187 @@
+//! Scanning triggered by the buttons on the device
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::time::Instant;
+
+use gumdrop::Options;
+use skanny::sensors::{SensorEvent, Sensors};
+use skanny::{Context, Handle};
+
+use crate::events::{Event, Sinks};
+use crate::hotplug::{Attached, Change, Monitor};
+use crate::profile::{self, Profile};
+
+#[derive(Debug, Options)]
+pub struct WatchOptions {
+    #[options(help = "Print this help message")]
+    help: bool,
+    #[options(help = "TOML file with scan profiles", required)]
+    profiles: String,
+    #[options(help = "Bind a button to a profile", meta = "BUTTON=PROFILE")]
+    bind: Vec<String>,
+    #[options(help = "Polling interval in milliseconds", default = "100")]
+    interval: u64,
+    #[options(
+        no_short,
+        help = "MQTT broker to publish events to",
+        meta = "HOST[:PORT]"
+    )]
+    mqtt: Option<String>,
+    #[options(no_short, help = "Topic prefix for MQTT events", default = "skanny")]
+    mqtt_topic: String,
+    #[options(no_short, help = "Publish thumbnails of scanned pages over MQTT")]
+    mqtt_thumbnails: bool,
+    #[options(no_short, help = "URL to POST finished jobs to", meta = "URL")]
+    webhook: Vec<String>,
+    #[options(
+        no_short,
+        help = "Check for the device being disconnected and connected again this often",
+        meta = "SECS"
+    )]
+    hotplug: Option<u64>,
+}
+
+pub fn run(
+    context: &Context,
+    handle: Handle,
+    opts: &WatchOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let profiles = profile::load(Path::new(&opts.profiles))?;
+
+    let mut bindings: BTreeMap<&str, (&str, &Profile)> = BTreeMap::new();
+    for bind in &opts.bind {
+        let mut split = bind.splitn(2, '=');
+        let (button, name) = match (split.next(), split.next()) {
+            (Some(button), Some(name)) => (button, name),
+            _ => return Err(format!("Binding {} is not of the form BUTTON=PROFILE", bind).into()),
+        };
+        let profile = profiles
+            .get(name)
+            .ok_or_else(|| format!("No profile named {}", name))?;
+        bindings.insert(button, (name, profile));
+    }
+
+    let mut sinks = Sinks::default();
+    if let Some(broker) = &opts.mqtt {
+        sinks.push(crate::mqtt::Publisher::connect(
+            broker,
+            &opts.mqtt_topic,
+            opts.mqtt_thumbnails,
+        )?);
+    }
+    for url in &opts.webhook {
+        sinks.push(crate::webhook::Webhook::new(url)?);
+    }
+
+    {
+        let sensors = Sensors::new(&handle)?;
+        for button in bindings.keys() {
+            if !sensors.names().any(|name| name == *button) {
+                return Err(format!("Device has no button named {}", button).into());
+            }
+        }
+    }
+    let mut monitor = Monitor::new(
+        context,
+        opts.hotplug.map(std::time::Duration::from_secs),
+        handle.name(),
+    )?;
+
+    let stop = crate::stop_on_ctrlc();
+    let interval = std::time::Duration::from_millis(opts.interval);
+    let mut attached = Attached::Open(handle);
+
+    tracing::info!("Waiting for buttons, interrupt with ctrl-c");
+    while !stop.load(Ordering::SeqCst) {
+        let changes = match attached.handle() {
+            Some(handle) => match watch(handle, &bindings, &sinks, &mut monitor, &stop, interval) {
+                Ok(changes) => changes,
+                Err(e) if monitor.is_enabled() => {
+                    tracing::warn!("Watching {} failed: {}", handle.name(), e);
+                    monitor.poll_now()
+                }
+                Err(e) => return Err(e),
+            },
+            None => {
+                std::thread::sleep(interval);
+                monitor.poll()
+            }
+        };
+        attached = attached.update(&monitor, &changes, &sinks);
+    }
+    Ok(())
+}
+
+/// Scans on the bound buttons until stopped or the connected devices
+/// change, which are returned
+fn watch(
+    handle: &Handle,
+    bindings: &BTreeMap<&str, (&str, &Profile)>,
+    sinks: &Sinks,
+    monitor: &mut Monitor,
+    stop: &AtomicBool,
+    interval: std::time::Duration,
+) -> Result<Vec<Change>, Box<dyn std::error::Error>> {
+    let mut sensors = Sensors::new(handle)?;
+    let (tx, rx) = channel();
+    while !stop.load(Ordering::SeqCst) {
+        sensors.poll(&tx)?;
+
+        for event in rx.try_iter() {
+            let button = match event {
+                SensorEvent::Activated(button) => button,
+                _ => continue,
+            };
+            let (name, profile) = match bindings.get(button.as_str()) {
+                Some(binding) => binding,
+                None => continue,
+            };
+
+            tracing::info!("{} pressed, scanning", button);
+            sinks.send(&Event::ButtonPressed { button: &button });
+            let started = Instant::now();
+            match profile.scan_image(handle) {
+                Ok(scan) => {
+                    println!("SAVED IMAGE {}", scan.path.display());
+                    crate::print_ocr_queue();
+                    sinks.send(&Event::PageScanned {
+                        path: &scan.path,
+                        image: &scan.image,
+                    });
+                    sinks.send(&Event::JobCompleted {
+                        device: handle.name(),
+                        profile: name,
+                        pages: 1,
+                        files: std::slice::from_ref(&scan.path),
+                        locations: &scan.locations,
+                        duration_secs: started.elapsed().as_secs_f64(),
+                    });
+                }
+                Err(e) => {
+                    tracing::warn!("Scanning failed: {}", e);
+                    sinks.send(&Event::JobFailed {
+                        device: handle.name(),
+                        profile: name,
+                        message: e.to_string(),
+                        duration_secs: started.elapsed().as_secs_f64(),
+                    });
+                    // The device may have been disconnected while scanning
+                    let changes = monitor.poll_now();
+                    if !changes.is_empty() {
+                        return Ok(changes);
+                    }
+                }
+            }
+        }
+
+        let changes = monitor.poll();
+        if !changes.is_empty() {
+            return Ok(changes);
+        }
+        std::thread::sleep(interval);
+    }
+    Ok(Vec::new())
+}
@@ -0,0 +1,92 @@
+//! Cancelling of scans which stopped delivering data
+//!
+//! `sane_read` blocks until data arrives, which is forever when a network
+//! backend loses its connection at the wrong moment. SANE allows
+//! `sane_cancel` to be called at any time, so a thread watching the progress
+//! cancels a stalled acquisition, which makes the blocked read return.
+
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use sane_sys::*;
+
+struct State {
+    last_progress: Instant,
+    stopped: bool,
+    fired: bool,
+}
+
+/// The handle the watchdog cancels, which outlives it
+struct RawHandle(SANE_Handle);
+unsafe impl Send for RawHandle {}
+
+pub(crate) struct Watchdog {
+    state: Arc<(Mutex<State>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl Watchdog {
+    /// Cancels `raw` when no progress is made for `timeout`
+    ///
+    /// # Safety
+    /// `raw` must stay open until the watchdog is dropped
+    pub(crate) unsafe fn start(raw: SANE_Handle, timeout: Duration) -> Self {
+        let state = Arc::new((
+            Mutex::new(State {
+                last_progress: Instant::now(),
+                stopped: false,
+                fired: false,
+            }),
+            Condvar::new(),
+        ));
+        let raw = RawHandle(raw);
+        let watched = Arc::clone(&state);
+        let thread = std::thread::spawn(move || {
+            let raw = raw;
+            let (mutex, condvar) = &*watched;
+            let mut state = lock(mutex);
+            while !state.stopped {
+                let deadline = state.last_progress + timeout;
+                let now = Instant::now();
+                if now >= deadline {
+                    state.fired = true;
+                    tracing::warn!("No data for {:?}, cancelling the scan", timeout);
+                    unsafe { sane_cancel(raw.0) };
+                    return;
+                }
+                state = condvar
+                    .wait_timeout(state, deadline - now)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+        });
+        Self {
+            state,
+            thread: Some(thread),
+        }
+    }
+
+    pub(crate) fn progress(&self) {
+        lock(&self.state.0).last_progress = Instant::now();
+    }
+
+    /// Whether the acquisition was cancelled for lack of progress
+    pub(crate) fn fired(&self) -> bool {
+        lock(&self.state.0).fired
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        lock(&self.state.0).stopped = true;
+        self.state.1.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Notification of external systems over HTTP
+//!
+//! Completed and failed jobs are POSTed as JSON to every configured URL,
+//! e.g.
+//!
+//! ```json
+//! {"event": "job_completed", "device": "test", "profile": "color", "pages": 1,
+//!  "files": ["scans/plate_1600000000_0.png"], "locations": [], "duration_secs": 4.2}
+//! ```
+
+use crate::events::{Event, Sink};
+
+pub struct Webhook {
+    #[cfg_attr(not(feature = "webhook"), allow(dead_code))]
+    url: String,
+}
+
+impl Webhook {
+    #[cfg(feature = "webhook")]
+    pub fn new(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!("Webhook {} is not an http(s) url", url).into());
+        }
+        Ok(Self {
+            url: url.to_owned(),
+        })
+    }
+
+    #[cfg(not(feature = "webhook"))]
+    pub fn new(_url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Err("skanny was built without the webhook feature".into())
+    }
+}
+
+impl Sink for Webhook {
+    #[cfg(feature = "webhook")]
+    fn send(&self, event: &Event) {
+        match event {
+            Event::JobCompleted { .. } | Event::JobFailed { .. } => {}
+            _ => return,
+        }
+        let payload = serde_json::to_string(event).unwrap();
+        let result = ureq::post(&self.url)
+            .timeout(std::time::Duration::from_secs(10))
+            .set("Content-Type", "application/json")
+            .send_string(&payload);
+        if let Err(e) = result {
+            tracing::warn!("Webhook {} failed: {}", self.url, e);
+        }
+    }
+
+    #[cfg(not(feature = "webhook"))]
+    fn send(&self, _event: &Event) {}
+}
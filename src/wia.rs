@@ -0,0 +1,529 @@
+//! Windows Image Acquisition (WIA 2.0) backend
+//!
+//! Devices are named `wia:ID` after their WIA device id. The flatbed and
+//! feeder items of a device are presented with the same SANE style options
+//! as [`crate::escl`]: `source`, `mode`, `resolution` and the scan area in
+//! mm. Pages are transferred in the format the driver prefers (usually BMP)
+//! and decoded, so frames are always complete 8 bit images.
+
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use sane_sys::*;
+use windows::core::{implement, Interface, BSTR, PROPVARIANT};
+use windows::Win32::Devices::ImageAcquisition::*;
+use windows::Win32::Foundation::HGLOBAL;
+use windows::Win32::System::Com::StructuredStorage::{
+    CreateStreamOnHGlobal, PropVariantGetElementCount, PropVariantGetInt32Elem, PROPSPEC,
+    PROPSPEC_0, PRSPEC_PROPID,
+};
+use windows::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, IStream, CLSCTX_LOCAL_SERVER, COINIT_APARTMENTTHREADED,
+    STREAM_SEEK_SET,
+};
+
+use crate::backend::{
+    BackendError, Constraint, DeviceInfo, FrameParameters, OptionInfo, ScannerBackend,
+    ScannerDevice,
+};
+use crate::OptionValue;
+
+const MM_PER_INCH: f64 = 25.4;
+
+/// Devices known to the WIA device manager
+pub struct WiaBackend {
+    manager: IWiaDevMgr2,
+}
+
+impl WiaBackend {
+    /// Initializes COM for the calling thread and connects to the WIA service
+    pub fn new() -> Result<Self, BackendError> {
+        unsafe {
+            CoInitializeEx(None, COINIT_APARTMENTTHREADED).ok()?;
+            let manager = CoCreateInstance(&WiaDevMgr2, None, CLSCTX_LOCAL_SERVER)?;
+            Ok(Self { manager })
+        }
+    }
+}
+
+impl ScannerBackend for WiaBackend {
+    fn enumerate(&self) -> Result<Vec<DeviceInfo>, BackendError> {
+        let mut devices = Vec::new();
+        unsafe {
+            let infos = self.manager.EnumDeviceInfo(WIA_DEVINFO_ENUM_LOCAL as i32)?;
+            loop {
+                let mut storage = [None];
+                let mut fetched = 0;
+                infos.Next(1, storage.as_mut_ptr(), &mut fetched)?;
+                let storage = match (fetched, storage[0].take()) {
+                    (1, Some(storage)) => storage,
+                    _ => break,
+                };
+                let string = |id| {
+                    read(&storage, id)
+                        .ok()
+                        .and_then(|value| BSTR::try_from(&value).ok())
+                        .map(|value| value.to_string())
+                        .unwrap_or_default()
+                };
+                devices.push(DeviceInfo {
+                    name: format!("wia:{}", string(WIA_DIP_DEV_ID)),
+                    vendor: string(WIA_DIP_VEND_DESC),
+                    model: string(WIA_DIP_DEV_NAME),
+                    type_: "WIA scanner".to_owned(),
+                });
+            }
+        }
+        Ok(devices)
+    }
+
+    fn open(&self, name: &str) -> Result<Box<dyn ScannerDevice>, BackendError> {
+        let id = name.strip_prefix("wia:").unwrap_or(name);
+        let root = unsafe { self.manager.CreateDevice(0, &BSTR::from(id))? };
+        Ok(Box::new(WiaDevice::new(format!("wia:{}", id), root)?))
+    }
+}
+
+/// A scanner opened through WIA, see the module documentation
+pub struct WiaDevice {
+    name: String,
+    /// Scan items by source name
+    items: Vec<(&'static str, IWiaItem2)>,
+    /// Largest scan area in mm
+    max_size: (f64, f64),
+    state: RefCell<State>,
+}
+
+struct State {
+    /// Index into `items`
+    source: usize,
+    /// Samples of the current frame and how far they have been read
+    frame: Option<(Vec<u8>, usize)>,
+}
+
+const MODES: [(&str, u32); 2] = [("Color", WIA_DATA_COLOR), ("Gray", WIA_DATA_GRAYSCALE)];
+const AREA: [&str; 4] = ["tl-x", "tl-y", "br-x", "br-y"];
+
+impl WiaDevice {
+    fn new(name: String, root: IWiaItem2) -> Result<Self, BackendError> {
+        let mut items = Vec::new();
+        unsafe {
+            let children = root.EnumChildItems(None)?;
+            loop {
+                let mut item = [None];
+                let mut fetched = 0;
+                children.Next(1, item.as_mut_ptr(), &mut fetched)?;
+                let item = match (fetched, item[0].take()) {
+                    (1, Some(item)) => item,
+                    _ => break,
+                };
+                let source = match item.GetItemCategory()? {
+                    category if category == WIA_CATEGORY_FLATBED => "Flatbed",
+                    category if category == WIA_CATEGORY_FEEDER => "ADF",
+                    _ => continue,
+                };
+                if !items.iter().any(|(s, _)| *s == source) {
+                    items.push((source, item));
+                }
+            }
+        }
+        if items.is_empty() {
+            return Err("Device has no flatbed or feeder".into());
+        }
+
+        // Sizes are given in 1/1000 inch
+        let root: IWiaPropertyStorage = root.cast()?;
+        let size = |id| {
+            read_int(&root, id)
+                .map(|size| f64::from(size) * MM_PER_INCH / 1000.0)
+                .unwrap_or(0.0)
+        };
+        let max_size = (
+            size(WIA_IPS_MAX_HORIZONTAL_SIZE),
+            size(WIA_IPS_MAX_VERTICAL_SIZE),
+        );
+
+        Ok(Self {
+            name,
+            items,
+            max_size,
+            state: RefCell::new(State {
+                source: 0,
+                frame: None,
+            }),
+        })
+    }
+
+    fn storage(&self) -> Result<IWiaPropertyStorage, BackendError> {
+        let state = self.state.borrow();
+        Ok(self.items[state.source].1.cast()?)
+    }
+}
+
+impl ScannerDevice for WiaDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn options(&self) -> Result<Vec<OptionInfo>, BackendError> {
+        let storage = self.storage()?;
+        let cap = (SANE_CAP_SOFT_SELECT | SANE_CAP_SOFT_DETECT) as SANE_Int;
+        let option =
+            |name: &str, title: &str, desc: &str, type_, unit, constraint, value| OptionInfo {
+                name: name.to_owned(),
+                title: title.to_owned(),
+                desc: desc.to_owned(),
+                type_,
+                unit,
+                cap,
+                constraint,
+                value: Some(value),
+            };
+
+        let mut options = Vec::new();
+        let source = self.items[self.state.borrow().source].0;
+        options.push(option(
+            "source",
+            "Scan source",
+            "Selects the scan source, such as the document feeder",
+            SANE_Value_Type_SANE_TYPE_STRING,
+            SANE_Unit_SANE_UNIT_NONE,
+            Constraint::StringList(self.items.iter().map(|(s, _)| s.to_string()).collect()),
+            OptionValue::String(source.to_owned()),
+        ));
+
+        let data_type = read_int(&storage, WIA_IPA_DATATYPE)? as u32;
+        let modes = match valid_values(&storage, WIA_IPA_DATATYPE)? {
+            Constraint::WordList(list) => MODES
+                .iter()
+                .filter(|(_, wia)| list.contains(&(*wia as SANE_Int)))
+                .map(|(mode, _)| mode.to_string())
+                .collect(),
+            _ => MODES.iter().map(|(mode, _)| mode.to_string()).collect(),
+        };
+        let mode = MODES
+            .iter()
+            .find(|(_, wia)| *wia == data_type)
+            .map_or("Color", |(mode, _)| mode);
+        options.push(option(
+            "mode",
+            "Scan mode",
+            "Selects the scan mode",
+            SANE_Value_Type_SANE_TYPE_STRING,
+            SANE_Unit_SANE_UNIT_NONE,
+            Constraint::StringList(modes),
+            OptionValue::String(mode.to_owned()),
+        ));
+
+        let resolution = read_int(&storage, WIA_IPS_XRES)?;
+        options.push(option(
+            "resolution",
+            "Scan resolution",
+            "Sets the resolution of the scanned image",
+            SANE_Value_Type_SANE_TYPE_INT,
+            SANE_Unit_SANE_UNIT_DPI,
+            valid_values(&storage, WIA_IPS_XRES)?,
+            OptionValue::Int(resolution),
+        ));
+
+        // The scan area is given in pixels at the current resolution
+        let area = area(&storage, resolution)?;
+        for (i, (&name, &value)) in AREA.iter().zip(area.iter()).enumerate() {
+            let max = if i % 2 == 0 {
+                self.max_size.0
+            } else {
+                self.max_size.1
+            };
+            let corner = if i < 2 { "Top-left" } else { "Bottom-right" };
+            let axis = if i % 2 == 0 { "x" } else { "y" };
+            options.push(option(
+                name,
+                &format!("{} {}", corner, axis),
+                &format!("{} {} of the scan area", corner, axis),
+                SANE_Value_Type_SANE_TYPE_FIXED,
+                SANE_Unit_SANE_UNIT_MM,
+                Constraint::Range {
+                    min: 0,
+                    max: SANE_FIX(max),
+                    quant: 0,
+                },
+                OptionValue::Fixed(value),
+            ));
+        }
+        Ok(options)
+    }
+
+    fn set_option(&self, name: &str, value: &OptionValue) -> Result<(), BackendError> {
+        let invalid = || crate::Error::Status(SANE_Status_SANE_STATUS_INVAL);
+        match (name, value) {
+            ("source", OptionValue::String(source)) => {
+                let index = self
+                    .items
+                    .iter()
+                    .position(|(s, _)| s == source)
+                    .ok_or_else(invalid)?;
+                self.state.borrow_mut().source = index;
+            }
+            ("mode", OptionValue::String(mode)) => {
+                let (_, data_type) = MODES.iter().find(|(m, _)| m == mode).ok_or_else(invalid)?;
+                write(
+                    &self.storage()?,
+                    &[
+                        (WIA_IPA_DATATYPE, *data_type as i32),
+                        (
+                            WIA_IPA_DEPTH,
+                            if *data_type == WIA_DATA_COLOR { 24 } else { 8 },
+                        ),
+                    ],
+                )?;
+            }
+            ("resolution", &OptionValue::Int(resolution)) => {
+                let storage = self.storage()?;
+                // Keep the scan area when the resolution changes
+                let area = area(&storage, read_int(&storage, WIA_IPS_XRES)?)?;
+                write(
+                    &storage,
+                    &[(WIA_IPS_XRES, resolution), (WIA_IPS_YRES, resolution)],
+                )?;
+                write_area(&storage, resolution, area)?;
+            }
+            (name, &OptionValue::Int(_)) | (name, &OptionValue::Fixed(_)) => {
+                let index = AREA
+                    .iter()
+                    .position(|&area| area == name)
+                    .ok_or_else(|| format!("No option named {}", name))?;
+                let mm = match *value {
+                    OptionValue::Int(i) => f64::from(i),
+                    OptionValue::Fixed(f) => f,
+                    _ => unreachable!(),
+                };
+                let storage = self.storage()?;
+                let resolution = read_int(&storage, WIA_IPS_XRES)?;
+                let mut area = area(&storage, resolution)?;
+                area[index] = mm.max(0.0);
+                write_area(&storage, resolution, area)?;
+            }
+            (name, _) if name == "source" || name == "mode" || name == "resolution" => {
+                return Err(crate::Error::WrongType.into())
+            }
+            (name, _) => return Err(format!("No option named {}", name).into()),
+        }
+        Ok(())
+    }
+
+    fn start(&self) -> Result<FrameParameters, BackendError> {
+        let (item, gray) = {
+            let mut state = self.state.borrow_mut();
+            state.frame = None;
+            let item = self.items[state.source].1.clone();
+            let storage: IWiaPropertyStorage = item.cast()?;
+            let gray = read_int(&storage, WIA_IPA_DATATYPE)? as u32 != WIA_DATA_COLOR;
+            (item, gray)
+        };
+
+        let stream = Rc::new(RefCell::new(None));
+        let sink: IWiaTransferCallback = TransferSink {
+            stream: stream.clone(),
+        }
+        .into();
+        unsafe {
+            let transfer: IWiaTransfer = item.cast()?;
+            transfer.Download(0, &sink)?;
+        }
+        let stream = stream
+            .borrow_mut()
+            .take()
+            .ok_or(crate::Error::Status(SANE_Status_SANE_STATUS_NO_DOCS))?;
+        let bytes = read_stream(&stream)?;
+
+        let image = image::load_from_memory(&bytes)?;
+        let (format, channels, width, height, data) = if gray {
+            let image = image.to_luma8();
+            let (width, height) = image.dimensions();
+            (
+                SANE_Frame_SANE_FRAME_GRAY,
+                1,
+                width,
+                height,
+                image.into_raw(),
+            )
+        } else {
+            let image = image.to_rgb8();
+            let (width, height) = image.dimensions();
+            (
+                SANE_Frame_SANE_FRAME_RGB,
+                3,
+                width,
+                height,
+                image.into_raw(),
+            )
+        };
+        self.state.borrow_mut().frame = Some((data, 0));
+        Ok(FrameParameters {
+            format,
+            last_frame: true,
+            bytes_per_line: (width * channels) as SANE_Int,
+            pixels_per_line: width as SANE_Int,
+            lines: height as SANE_Int,
+            depth: 8,
+        })
+    }
+
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, BackendError> {
+        let mut state = self.state.borrow_mut();
+        let (data, offset) = state
+            .frame
+            .as_mut()
+            .ok_or(crate::Error::Status(SANE_Status_SANE_STATUS_INVAL))?;
+        let len = buffer.len().min(data.len() - *offset);
+        buffer[..len].copy_from_slice(&data[*offset..*offset + len]);
+        *offset += len;
+        Ok(len)
+    }
+
+    fn cancel(&self) {
+        self.state.borrow_mut().frame = None;
+    }
+}
+
+/// Collects the image of a download into a memory stream
+#[implement(IWiaTransferCallback)]
+struct TransferSink {
+    stream: Rc<RefCell<Option<IStream>>>,
+}
+
+impl IWiaTransferCallback_Impl for TransferSink_Impl {
+    fn TransferCallback(
+        &self,
+        _flags: i32,
+        _params: *const WiaTransferParams,
+    ) -> windows::core::Result<()> {
+        Ok(())
+    }
+
+    fn GetNextStream(
+        &self,
+        _flags: i32,
+        _item_name: &BSTR,
+        _full_item_name: &BSTR,
+    ) -> windows::core::Result<IStream> {
+        // Only the first page is kept, a feeder is read one page per scan
+        let mut current = self.stream.borrow_mut();
+        if current.is_some() {
+            return Err(windows::Win32::Foundation::E_ABORT.into());
+        }
+        let stream = unsafe { CreateStreamOnHGlobal(HGLOBAL::default(), true)? };
+        *current = Some(stream.clone());
+        Ok(stream)
+    }
+}
+
+fn read_stream(stream: &IStream) -> Result<Vec<u8>, BackendError> {
+    let mut bytes = Vec::new();
+    let mut buffer = vec![0_u8; 64 * 1024];
+    unsafe {
+        stream.Seek(0, STREAM_SEEK_SET, None)?;
+        loop {
+            let mut read = 0;
+            stream
+                .Read(
+                    buffer.as_mut_ptr().cast(),
+                    buffer.len() as u32,
+                    Some(&mut read),
+                )
+                .ok()?;
+            if read == 0 {
+                break;
+            }
+            bytes.extend_from_slice(&buffer[..read as usize]);
+        }
+    }
+    Ok(bytes)
+}
+
+fn propspec(id: u32) -> PROPSPEC {
+    PROPSPEC {
+        ulKind: PRSPEC_PROPID,
+        Anonymous: PROPSPEC_0 { propid: id },
+    }
+}
+
+fn read(storage: &IWiaPropertyStorage, id: u32) -> windows::core::Result<PROPVARIANT> {
+    let mut value = PROPVARIANT::default();
+    unsafe { storage.ReadMultiple(1, &propspec(id), &mut value)? };
+    Ok(value)
+}
+
+fn read_int(storage: &IWiaPropertyStorage, id: u32) -> Result<i32, BackendError> {
+    Ok(i32::try_from(&read(storage, id)?)?)
+}
+
+fn write(storage: &IWiaPropertyStorage, values: &[(u32, i32)]) -> Result<(), BackendError> {
+    let specs: Vec<_> = values.iter().map(|&(id, _)| propspec(id)).collect();
+    let values: Vec<_> = values
+        .iter()
+        .map(|&(_, value)| PROPVARIANT::from(value))
+        .collect();
+    unsafe {
+        storage.WriteMultiple(
+            specs.len() as u32,
+            specs.as_ptr(),
+            values.as_ptr(),
+            WIA_IPA_FIRST,
+        )?
+    };
+    Ok(())
+}
+
+/// Values the property accepts, as a SANE constraint
+fn valid_values(storage: &IWiaPropertyStorage, id: u32) -> Result<Constraint, BackendError> {
+    let mut flags = 0;
+    let mut values = PROPVARIANT::default();
+    unsafe {
+        storage.GetPropertyAttributes(1, &propspec(id), &mut flags, &mut values)?;
+        let element = |i| PropVariantGetInt32Elem(&values, i);
+        if flags & WIA_PROP_RANGE != 0 {
+            Ok(Constraint::Range {
+                min: element(WIA_RANGE_MIN)?,
+                max: element(WIA_RANGE_MAX)?,
+                quant: element(WIA_RANGE_STEP)?,
+            })
+        } else if flags & WIA_PROP_LIST != 0 {
+            let count = PropVariantGetElementCount(&values);
+            let list = (WIA_LIST_VALUES..count)
+                .map(element)
+                .collect::<Result<_, _>>()?;
+            Ok(Constraint::WordList(list))
+        } else {
+            Ok(Constraint::None)
+        }
+    }
+}
+
+/// tl-x, tl-y, br-x and br-y in mm
+fn area(storage: &IWiaPropertyStorage, resolution: i32) -> Result<[f64; 4], BackendError> {
+    let mm = |px: i32| f64::from(px) * MM_PER_INCH / f64::from(resolution.max(1));
+    let x = read_int(storage, WIA_IPS_XPOS)?;
+    let y = read_int(storage, WIA_IPS_YPOS)?;
+    let width = read_int(storage, WIA_IPS_XEXTENT)?;
+    let height = read_int(storage, WIA_IPS_YEXTENT)?;
+    Ok([mm(x), mm(y), mm(x + width), mm(y + height)])
+}
+
+fn write_area(
+    storage: &IWiaPropertyStorage,
+    resolution: i32,
+    [tl_x, tl_y, br_x, br_y]: [f64; 4],
+) -> Result<(), BackendError> {
+    let px = |mm: f64| (mm * f64::from(resolution) / MM_PER_INCH).round() as i32;
+    write(
+        storage,
+        &[
+            (WIA_IPS_XPOS, px(tl_x)),
+            (WIA_IPS_YPOS, px(tl_y)),
+            (WIA_IPS_XEXTENT, px(br_x - tl_x).max(1)),
+            (WIA_IPS_YEXTENT, px(br_y - tl_y).max(1)),
+        ],
+    )
+}
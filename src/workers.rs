@@ -0,0 +1,129 @@
+//! Processing pages while the next ones are scanned
+//!
+//! Processing and encoding a page at 600 dpi takes longer than the feeder
+//! needs for the next sheet. [`overlap`] hands each page to a pool of
+//! workers as soon as it is acquired, so the feeder keeps running. At most
+//! as many pages as there are workers wait for one, which bounds the memory
+//! a fast feeder can fill.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel, SyncSender};
+use std::sync::{Mutex, PoisonError};
+
+use skanny::backend::BackendError;
+
+/// Hands items to the workers of [`overlap`]
+pub struct Queue<'a, T> {
+    sender: SyncSender<(usize, T)>,
+    sent: usize,
+    failed: &'a AtomicBool,
+}
+
+impl<T> Queue<'_, T> {
+    /// Waits until a worker can take `item`, failing once a worker has
+    /// failed so no more pages are scanned in vain
+    pub fn push(&mut self, item: T) -> Result<(), BackendError> {
+        if self.failed.load(Ordering::SeqCst) {
+            return Err("Processing a page failed".into());
+        }
+        self.sender
+            .send((self.sent, item))
+            .map_err(|_| "The workers stopped")?;
+        self.sent += 1;
+        Ok(())
+    }
+}
+
+/// The number of workers, one per processor
+pub fn threads() -> usize {
+    std::thread::available_parallelism().map_or(1, usize::from)
+}
+
+/// Runs `process` on the items `produce` queues, on `threads` workers,
+/// and returns the results in the order the items were queued
+pub fn overlap<T: Send, R: Send>(
+    threads: usize,
+    produce: impl FnOnce(&mut Queue<'_, T>) -> Result<(), BackendError>,
+    process: impl Fn(T) -> Result<R, String> + Sync,
+) -> Result<Vec<R>, BackendError> {
+    let failed = AtomicBool::new(false);
+    let (sender, receiver) = sync_channel::<(usize, T)>(threads);
+    let receiver = Mutex::new(receiver);
+    let (done, results) = channel();
+    let span = tracing::Span::current();
+    let produced = std::thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            let (receiver, process, failed) = (&receiver, &process, &failed);
+            let (done, span) = (done.clone(), span.clone());
+            scope.spawn(move || {
+                let _span = span.enter();
+                loop {
+                    let next = receiver
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .recv();
+                    let (index, item) = match next {
+                        Ok(next) => next,
+                        // The producer is done
+                        Err(_) => break,
+                    };
+                    let result = process(item);
+                    if result.is_err() {
+                        failed.store(true, Ordering::SeqCst);
+                    }
+                    let _ = done.send((index, result));
+                }
+            });
+        }
+        drop(done);
+        // Dropping the queue at the end lets the workers finish
+        let mut queue = Queue {
+            sender,
+            sent: 0,
+            failed: &failed,
+        };
+        produce(&mut queue)
+    });
+    let mut results: Vec<(usize, Result<R, String>)> = results.iter().collect();
+    results.sort_by_key(|(index, _)| *index);
+    // An error of a worker is why the producer stopped
+    let results: Vec<R> = results
+        .into_iter()
+        .map(|(_, result)| result)
+        .collect::<Result<_, _>>()?;
+    produced?;
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_order_of_items() {
+        let results = overlap(
+            3,
+            |queue| (0..20u64).try_for_each(|i| queue.push(i)),
+            |i| {
+                std::thread::sleep(std::time::Duration::from_millis(20 - i));
+                Ok(i * 2)
+            },
+        )
+        .unwrap();
+        assert_eq!(results, (0..20).map(|i| i * 2).collect::<Vec<_>>());
+
+        let error = overlap(
+            2,
+            |queue| (0..100).try_for_each(|i| queue.push(i)),
+            |i| {
+                if i == 3 {
+                    Err("bad page".to_owned())
+                } else {
+                    Ok(i)
+                }
+            },
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "bad page");
+    }
+}
@@ -0,0 +1,128 @@
+//! Finding scanners through WS-Discovery
+//!
+//! Windows finds network scanners through WSD, and many which do not
+//! announce eSCL answer a WS-Discovery `Probe` for `ScanDeviceType`, sent
+//! to 239.255.255.250 port 3702, with the URLs of their WSD services.
+//! skanny cannot scan through WSD, the scanners are listed to show what is
+//! on the network. They are named `wsd:URL`.
+
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::backend::{BackendError, DeviceInfo};
+use crate::xml::{element, elements};
+
+/// Where probes are sent
+pub const MULTICAST: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 255, 250), 3702);
+
+/// A probe for scanners, identified by `id`
+fn probe(id: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<soap:Envelope xmlns:soap="http://www.w3.org/2003/05/soap-envelope" xmlns:wsa="http://schemas.xmlsoap.org/ws/2004/08/addressing" xmlns:wsd="http://schemas.xmlsoap.org/ws/2005/04/discovery" xmlns:wscn="http://schemas.microsoft.com/windows/2006/08/wdp/scan">
+<soap:Header>
+<wsa:To>urn:schemas-xmlsoap-org:ws:2005:04:discovery</wsa:To>
+<wsa:Action>http://schemas.xmlsoap.org/ws/2005/04/discovery/Probe</wsa:Action>
+<wsa:MessageID>urn:uuid:{}</wsa:MessageID>
+</soap:Header>
+<soap:Body><wsd:Probe><wsd:Types>wscn:ScanDeviceType</wsd:Types></wsd:Probe></soap:Body>
+</soap:Envelope>"#,
+        id
+    )
+}
+
+/// A random UUID for the message ID of a probe
+fn uuid() -> Result<String, BackendError> {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).map_err(|e| format!("No random numbers: {}", e))?;
+    // Version 4, variant 1
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    ))
+}
+
+/// The scanners in a `ProbeMatches` message
+fn matches(xml: &str) -> Vec<DeviceInfo> {
+    elements(xml, "ProbeMatch")
+        .into_iter()
+        .filter(|found| {
+            element(found, "Types").is_some_and(|types| {
+                types
+                    .split_whitespace()
+                    .any(|type_| type_.rsplit(':').next() == Some("ScanDeviceType"))
+            })
+        })
+        .filter_map(|found| {
+            let url = element(found, "XAddrs")?.split_whitespace().next()?;
+            let host = url.split("://").nth(1)?.split('/').next()?;
+            Some(DeviceInfo {
+                name: format!("wsd:{}", url),
+                vendor: String::new(),
+                model: host.to_owned(),
+                type_: "WSD scanner".to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Probes for scanners and collects the answers for `time`
+pub fn discover(time: Duration) -> Result<Vec<DeviceInfo>, BackendError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.send_to(probe(&uuid()?).as_bytes(), MULTICAST)?;
+
+    let deadline = Instant::now() + time;
+    let mut buffer = vec![0; 64 * 1024];
+    let mut devices = Vec::<DeviceInfo>::new();
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(left))?;
+        let read = match socket.recv_from(&mut buffer) {
+            Ok((read, _)) => read,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                break
+            }
+            Err(e) => return Err(e.into()),
+        };
+        for device in matches(&String::from_utf8_lossy(&buffer[..read])) {
+            if !devices.iter().any(|known| known.name == device.name) {
+                devices.push(device);
+            }
+        }
+    }
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_the_scanners_which_answer() {
+        let id = uuid().unwrap();
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert!(probe(&id).contains(&format!("urn:uuid:{}", id)));
+
+        let answer = r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://www.w3.org/2003/05/soap-envelope" xmlns:d="http://schemas.xmlsoap.org/ws/2005/04/discovery"><s:Body><d:ProbeMatches><d:ProbeMatch><a:EndpointReference><a:Address>urn:uuid:1</a:Address></a:EndpointReference><d:Types>wprt:PrintDeviceType sca:ScanDeviceType</d:Types><d:XAddrs>http://192.168.1.30:5358/wsd http://[fe80::1]:5358/wsd</d:XAddrs></d:ProbeMatch><d:ProbeMatch><d:Types>wprt:PrintDeviceType</d:Types><d:XAddrs>http://192.168.1.31:5358/wsd</d:XAddrs></d:ProbeMatch></d:ProbeMatches></s:Body></s:Envelope>"#;
+        let devices = matches(answer);
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "wsd:http://192.168.1.30:5358/wsd");
+        assert_eq!(devices[0].model, "192.168.1.30:5358");
+    }
+}
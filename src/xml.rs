@@ -0,0 +1,36 @@
+//! Reading the XML of network scanning protocols
+//!
+//! eSCL and WS-Discovery messages are small and their elements are found
+//! by their local names, whatever namespace prefixes the device uses.
+
+/// Text of the first element with the local name `name`, ignoring namespaces
+pub fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    elements(xml, name).into_iter().next()
+}
+
+/// Text of all elements with the local name `name`, ignoring namespaces.
+/// Elements of the same name are not expected to be nested.
+pub fn elements<'a>(xml: &'a str, name: &str) -> Vec<&'a str> {
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let end = match rest.find('>') {
+            Some(end) => end,
+            None => break,
+        };
+        let tag = rest[..end].split_whitespace().next().unwrap_or("");
+        let opening = !tag.starts_with('/') && !rest[..end].ends_with('/');
+        rest = &rest[end + 1..];
+        if !opening || tag.rsplit(':').next() != Some(name) {
+            continue;
+        }
+        let close = match rest.find(&format!("</{}>", tag)) {
+            Some(close) => close,
+            None => break,
+        };
+        found.push(rest[..close].trim());
+        rest = &rest[close..];
+    }
+    found
+}
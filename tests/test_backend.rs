@@ -0,0 +1,301 @@
+//! Scans with the `test` backend of sane-backends, which has to be
+//! installed and enabled in `dll.conf`. Run with
+//!
+//! ```text
+//! cargo test --features test-backend --test test_backend
+//! ```
+//!
+//! At the default geometry of 80x100 mm and 50 dpi the backend produces
+//! frames of 157x196 pixels.
+
+use std::sync::Mutex;
+
+use image::GenericImageView;
+use sane_sys::*;
+use skanny::backend::{FrameParameters, ScannerDevice};
+use skanny::{Context, Handle, Image, OptionValue};
+
+const WIDTH: SANE_Int = 157;
+const LINES: SANE_Int = 196;
+
+/// The tests share the one test device, so it is used by one at a time
+static SANE: Mutex<()> = Mutex::new(());
+
+fn with_device(options: &[(&str, OptionValue)], f: impl FnOnce(&Handle)) {
+    let _guard = SANE.lock().unwrap_or_else(|e| e.into_inner());
+    let (_context, _) = Context::init().unwrap();
+    let handle = Handle::from_name("test").unwrap();
+    for (name, value) in options {
+        handle
+            .set_option(name, value)
+            .unwrap_or_else(|e| panic!("Setting {} to {}: {}", name, value, e));
+    }
+    f(&handle);
+}
+
+fn string(s: &str) -> OptionValue {
+    OptionValue::String(s.to_owned())
+}
+
+/// Reads the current frame completely
+fn read_frame(handle: &Handle) -> (FrameParameters, Vec<u8>) {
+    let parameters = ScannerDevice::start(handle).unwrap();
+    let mut data = Vec::new();
+    let mut buffer = vec![0; 4096];
+    loop {
+        match ScannerDevice::read(handle, &mut buffer).unwrap() {
+            0 => break,
+            n => data.extend_from_slice(&buffer[..n]),
+        }
+    }
+    (parameters, data)
+}
+
+/// FNV-1a, to compare frames without keeping them around
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn assert_frame(
+    parameters: &FrameParameters,
+    data: &[u8],
+    format: SANE_Frame,
+    depth: SANE_Int,
+    bytes_per_line: SANE_Int,
+) {
+    assert_eq!(parameters.format, format);
+    assert_eq!(parameters.depth, depth);
+    assert_eq!(parameters.pixels_per_line, WIDTH);
+    assert_eq!(parameters.bytes_per_line, bytes_per_line);
+    assert_eq!(parameters.lines, LINES);
+    assert_eq!(data.len(), (bytes_per_line * LINES) as usize);
+}
+
+#[test]
+fn solid_gray() {
+    for (picture, sample) in &[("Solid black", 0x00), ("Solid white", 0xff)] {
+        with_device(&[("test-picture", string(picture))], |handle| {
+            let image = handle.scan().unwrap();
+            let image = match image {
+                Image::Gray8(image) => image,
+                _ => panic!("Expected a gray image"),
+            };
+            assert_eq!(image.dimensions(), (WIDTH as u32, LINES as u32));
+            assert!(image.as_raw().iter().all(|s| s == sample));
+
+            // The image survives encoding
+            let mut png = Vec::new();
+            Image::Gray8(image)
+                .write_to(&mut png, image::ImageOutputFormat::Png)
+                .unwrap();
+            let decoded = image::load_from_memory(&png).unwrap().to_luma8();
+            assert_eq!(decoded.dimensions(), (WIDTH as u32, LINES as u32));
+        });
+    }
+}
+
+#[test]
+fn color_pictures_are_deterministic() {
+    for picture in &["Color pattern", "Grid"] {
+        let mut checksums = Vec::new();
+        for _ in 0..2 {
+            with_device(
+                &[("mode", string("Color")), ("test-picture", string(picture))],
+                |handle| {
+                    let (parameters, data) = read_frame(handle);
+                    handle.cancel();
+                    assert_frame(&parameters, &data, SANE_Frame_SANE_FRAME_RGB, 8, WIDTH * 3);
+                    assert!(data.iter().any(|&s| s != data[0]), "{} is uniform", picture);
+                    checksums.push(checksum(&data));
+                },
+            );
+        }
+        assert_eq!(
+            checksums[0], checksums[1],
+            "{} differs between scans",
+            picture
+        );
+    }
+}
+
+#[test]
+fn read_to_streams_the_frame() {
+    let options = [("mode", string("Color")), ("test-picture", string("Grid"))];
+    let mut expected = None;
+    with_device(&options, |handle| {
+        let (_, data) = read_frame(handle);
+        handle.cancel();
+        expected = Some(checksum(&data));
+    });
+    with_device(&options, |handle| {
+        let acquisition = handle.start().unwrap();
+        let mut data = Vec::new();
+        let written = acquisition.read_to(&mut data).unwrap();
+        assert_eq!(written, (WIDTH * 3 * LINES) as u64);
+        assert_eq!(Some(checksum(&data)), expected);
+    });
+}
+
+#[test]
+fn depths() {
+    // Lineart stores black as 1
+    let cases = [
+        (1, (WIDTH + 7) / 8, "Solid black", 0xff),
+        (8, WIDTH, "Solid white", 0xff),
+        (16, WIDTH * 2, "Solid white", 0xff),
+    ];
+    for &(depth, bytes_per_line, picture, sample) in &cases {
+        with_device(
+            &[
+                ("depth", OptionValue::Int(depth)),
+                ("test-picture", string(picture)),
+            ],
+            |handle| {
+                let (parameters, data) = read_frame(handle);
+                handle.cancel();
+                assert_frame(
+                    &parameters,
+                    &data,
+                    SANE_Frame_SANE_FRAME_GRAY,
+                    depth,
+                    bytes_per_line,
+                );
+                let row = (WIDTH * depth / 8) as usize;
+                for line in data.chunks(bytes_per_line as usize) {
+                    assert!(line[..row].iter().all(|&s| s == sample));
+                }
+            },
+        );
+    }
+}
+
+#[test]
+fn three_pass() {
+    let options = [
+        ("mode", string("Color")),
+        ("three-pass", OptionValue::Bool(true)),
+        ("test-picture", string("Color pattern")),
+    ];
+    with_device(&options, |handle| {
+        let mut frames = Vec::new();
+        loop {
+            let (parameters, data) = read_frame(handle);
+            assert_frame(&parameters, &data, parameters.format, 8, WIDTH);
+            frames.push(parameters.format);
+            if parameters.last_frame {
+                break;
+            }
+        }
+        handle.cancel();
+        assert_eq!(
+            frames,
+            [
+                SANE_Frame_SANE_FRAME_RED,
+                SANE_Frame_SANE_FRAME_GREEN,
+                SANE_Frame_SANE_FRAME_BLUE
+            ]
+        );
+        // Single pass consumers refuse the frames
+        assert!(handle.scan().is_err());
+    });
+}
+
+#[test]
+fn hand_scanner() {
+    with_device(&[("hand-scanner", OptionValue::Bool(true))], |handle| {
+        let (parameters, data) = read_frame(handle);
+        handle.cancel();
+        assert_eq!(parameters.lines, -1);
+        assert!(!data.is_empty());
+        assert_eq!(data.len() % parameters.bytes_per_line as usize, 0);
+
+        let image = handle.scan().unwrap();
+        assert_eq!(image.to_dynamic().width(), WIDTH as u32);
+    });
+}
+
+#[test]
+fn read_errors() {
+    let statuses = [
+        ("SANE_STATUS_JAMMED", SANE_Status_SANE_STATUS_JAMMED),
+        ("SANE_STATUS_NO_DOCS", SANE_Status_SANE_STATUS_NO_DOCS),
+        (
+            "SANE_STATUS_DEVICE_BUSY",
+            SANE_Status_SANE_STATUS_DEVICE_BUSY,
+        ),
+        ("SANE_STATUS_IO_ERROR", SANE_Status_SANE_STATUS_IO_ERROR),
+    ];
+    for &(name, status) in &statuses {
+        with_device(&[("read-return-value", string(name))], |handle| {
+            let e = handle.scan().err().unwrap();
+            assert_eq!(
+                e.downcast_ref::<skanny::Error>(),
+                Some(&skanny::Error::Status(status)),
+                "{}",
+                name
+            );
+        });
+    }
+}
+
+/// Collects what is logged on the thread running a closure
+#[derive(Clone, Default)]
+struct Log(std::sync::Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for Log {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn stalled_reads_time_out_and_are_retried() {
+    use std::time::Duration;
+
+    let _guard = SANE.lock().unwrap_or_else(|e| e.into_inner());
+    let (_context, _) = Context::init().unwrap();
+    let mut handle = Handle::from_name("test").unwrap();
+    // Every read waits 200 ms, longer than the watchdog lets it
+    handle
+        .set_option("read-delay", &OptionValue::Bool(true))
+        .unwrap();
+    handle
+        .set_option("read-delay-duration", &OptionValue::Int(200_000))
+        .unwrap();
+    handle.set_read_timeout(Some(Duration::from_millis(50)));
+
+    let read = || -> Result<(), skanny::backend::BackendError> {
+        ScannerDevice::start(&handle)?;
+        let mut buffer = vec![0; 4096];
+        while ScannerDevice::read(&handle, &mut buffer)? > 0 {}
+        Ok(())
+    };
+    let e = read().unwrap_err();
+    ScannerDevice::cancel(&handle);
+    assert_eq!(
+        e.downcast_ref::<skanny::Error>(),
+        Some(&skanny::Error::TimedOut)
+    );
+
+    handle.set_page_retries(1);
+    let log = Log::default();
+    let writer = log.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let e = tracing::subscriber::with_default(subscriber, || handle.scan().unwrap_err());
+    assert_eq!(
+        e.downcast_ref::<skanny::Error>(),
+        Some(&skanny::Error::TimedOut)
+    );
+    let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    assert_eq!(log.matches("Timed out, scanning the page again").count(), 1);
+}